@@ -1,5 +1,5 @@
 shader {
-	glsl_layout float2:0,float2:0
+	glsl_layout float3:0,float2:0,float4:0
 }
 
 !!GLSL
@@ -10,20 +10,24 @@ layout (std140, binding = 0) uniform BlitData {
 };
 
 #ifdef _VERTEX_
-layout(location=0) in vec2 position;
+layout(location=0) in vec3 position;
 layout(location=1) in vec2 texcoords;
+layout(location=2) in vec4 tint;
 out vec2 tc;
+out vec4 vtint;
 void main() {
-	gl_Position = vec4(((position.x)/viewportSize.x)*2-1,(1-(position.y)/viewportSize.y)*2-1,0, 1);
+	gl_Position = vec4(((position.x)/viewportSize.x)*2-1,(1-(position.y)/viewportSize.y)*2-1,position.z*2-1, 1);
 	tc = texcoords;
+	vtint = tint;
 }
 #endif
 
 #ifdef _FRAGMENT_
 layout (binding=0) uniform sampler2D blitTex;
 in vec2 tc;
+in vec4 vtint;
 out vec4 color;
 void main() {
-	color = texture(blitTex, tc);
+	color = texture(blitTex, tc) * vtint;
 }
 #endif
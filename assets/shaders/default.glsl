@@ -1,5 +1,5 @@
 shader {
-	glsl_layout float3:0,float3:0,float3:0,float2:0
+	glsl_layout float3:0,float3:0,float4:0,float2:0
 }
 
 !!GLSL
@@ -10,14 +10,31 @@ layout (std140, binding = 1) uniform ObjectData {
 	mat4 modelMatrix;
 };
 
+layout (std140, binding = 3) uniform MaterialParams {
+	vec3 diffuseColor;
+	vec3 specularColor;
+	float shininess;
+	vec3 emissiveColor;
+	float metallic;
+	float roughness;
+	int textureFlags;
+};
+
+// textureFlags bits (see material.rs)
+#define MATERIAL_NORMAL_MAP 1
+#define MATERIAL_METALLIC_ROUGHNESS_MAP 2
+#define MATERIAL_OCCLUSION_MAP 4
+#define MATERIAL_EMISSIVE_MAP 8
+
 #ifdef _VERTEX_
 layout(location=0) in vec3 position;
 layout(location=1) in vec3 normal;
-layout(location=2) in vec3 tangent;
+layout(location=2) in vec4 tangent;
 layout(location=3) in vec2 texcoords;
 out vec2 tc;
 out vec3 wPos;
 out vec3 wN;
+out vec4 wT;
 #ifdef SHADOWS_SIMPLE
 out vec4 shadowCoords;
 #endif
@@ -29,6 +46,8 @@ void main() {
 	tc = texcoords;
 	wPos = wPos_tmp.xyz;
 	wN = wN_tmp.xyz;
+	// the sign of the bitangent is not affected by the model matrix
+	wT = vec4((modelMatrix * vec4(tangent.xyz, 0.0)).xyz, tangent.w);
 	#ifdef SHADOWS_SIMPLE
 	shadowCoords = shadowDepthMatrix * wPos_tmp;
 	#endif
@@ -42,23 +61,96 @@ layout (binding=0) uniform sampler2D mainTex;
 layout (binding=1) uniform sampler2D shadowMap;
 in vec4 shadowCoords;
 #endif
+layout (binding=2) uniform sampler2D normalMap;
+// metalness in the blue channel, roughness in the green channel
+layout (binding=3) uniform sampler2D metallicRoughnessMap;
+layout (binding=4) uniform sampler2D occlusionMap;
+layout (binding=5) uniform sampler2D emissiveMap;
 in vec2 tc;
 in vec3 wPos;
 in vec3 wN;
+in vec4 wT;
 out vec4 color;
+
+// Normal, perturbed by the normal map if there is one
+vec3 shadingNormal()
+{
+	vec3 N = normalize(wN);
+	// meshes without texture coordinates have no tangents
+	if ((textureFlags & MATERIAL_NORMAL_MAP) == 0 || dot(wT.xyz, wT.xyz) < 1e-8) {
+		return N;
+	}
+	vec3 T = normalize(wT.xyz - dot(wT.xyz, N) * N);
+	vec3 B = wT.w * cross(N, T);
+	vec3 tN = texture(normalMap, tc).xyz * 2.0 - 1.0;
+	return normalize(tN.x * T + tN.y * B + tN.z * N);
+}
+
+// Approximate Blinn-Phong exponent of a roughness (see material.rs)
+float roughnessToShininess(float roughness)
+{
+	float r = max(roughness, 0.01);
+	return 2.0 / (r * r) - 2.0;
+}
+
+// Blinn-Phong specular term
+vec3 specularTerm(vec3 N, vec3 lightDir, vec3 position, vec3 specular, float exponent)
+{
+	vec3 L = normalize(lightDir);
+	vec3 V = normalize(wEye.xyz - position);
+	vec3 H = normalize(L + V);
+	float NdotL = dot(N, L);
+	if (NdotL <= 0.0) {
+		return vec3(0.0);
+	}
+	return specular * pow(max(dot(N, H), 0.0), max(exponent, 1.0)) * NdotL;
+}
+
 void main() {
 	//color = vec4(tc, 0.0, 0.0);
+	vec4 albedo = texture(mainTex, tc) * vec4(diffuseColor, 1.0);
+	vec3 N = shadingNormal();
+	vec3 lightDir = wPos - wLightPos;
+	vec3 Li = lightIntensity * lightColor;
+
+	// MTL materials: Ks and Ns, no metalness
+	float metalness = metallic;
+	float exponent = shininess;
+	if ((textureFlags & MATERIAL_METALLIC_ROUGHNESS_MAP) != 0) {
+		vec4 mr = texture(metallicRoughnessMap, tc);
+		metalness *= mr.b;
+		exponent = roughnessToShininess(roughness * mr.g);
+	}
+	float occlusion = 1.0;
+	if ((textureFlags & MATERIAL_OCCLUSION_MAP) != 0) {
+		occlusion = texture(occlusionMap, tc).r;
+	}
+
+	// metals have no diffuse reflection, and their specular color is the albedo
+	vec4 diffuseAlbedo = vec4(albedo.rgb * (1.0 - metalness), albedo.a);
+	vec3 specular = mix(specularColor, albedo.rgb, metalness);
+
 	color = PhongIllum(
-		texture(mainTex, tc),
-		wN,
-		wPos - wLightPos,
+		diffuseAlbedo,
+		N,
+		lightDir,
 		wPos,
-		0.2, 0.0, 0.8, lightIntensity * lightColor, 0.0, 0.0);
+		0.0, 0.0, 0.8, Li, 0.0, 0.0);
+	// ambient term, darkened by the occlusion map
+	color.rgb += 0.2 * occlusion * Li * diffuseAlbedo.rgb;
+	color.rgb += specularTerm(N, lightDir, wPos, specular, exponent) * Li;
 	#ifdef SHADOWS_SIMPLE
 	// apply shadows
 	float visibility = (texture(shadowMap, shadowCoords.xy).z < shadowCoords.z - 0.005) ? 1.0 : 0.2;
 	color = visibility * color;
 	#endif
+
+	// emission is not affected by lighting
+	vec3 emission = emissiveColor;
+	if ((textureFlags & MATERIAL_EMISSIVE_MAP) != 0) {
+		emission *= texture(emissiveMap, tc).rgb;
+	}
+	color.rgb += emission;
 }
 #endif // !SHADOW_MAP
 
@@ -28,7 +28,7 @@ layout (std140, binding = 1) uniform ObjectData {
 
 layout(location=0) in vec3 position;
 layout(location=1) in vec3 normal;
-layout(location=2) in vec3 tangent;
+layout(location=2) in vec4 tangent;
 layout(location=3) in vec2 texcoords;
 
 out vec2 tc;
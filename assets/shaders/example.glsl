@@ -33,8 +33,8 @@ layout (binding = 0) uniform sampler2D diffuseMap;
 layout(location = 0) in vec3 position;
 // normals: 3 floats
 layout(location = 1) in vec3 normal;
-// tg: 3 floats
-layout(location = 2) in vec3 tangent;
+// tg: 4 floats (w: handedness)
+layout(location = 2) in vec4 tangent;
 // texcoords: 2 floats
 layout(location = 3) in vec2 uv;
 
@@ -56,7 +56,7 @@ void main()
 	vPos = (viewMatrix * modelPos).xyz;
 	// TODO normalmatrix
 	wN = (modelMatrix * vec4(normal, 0.f)).xyz;
-	wT = (modelMatrix * vec4(tangent, 0.f)).xyz;
+	wT = (modelMatrix * vec4(tangent.xyz, 0.f)).xyz;
 	tex = uv;
 }
 
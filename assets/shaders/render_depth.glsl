@@ -1,5 +1,5 @@
 shader {
-	glsl_layout float3:0,float3:0,float3:0,float2:0
+	glsl_layout float3:0,float3:0,float4:0,float2:0
 }
 
 !!GLSL
@@ -13,7 +13,7 @@ layout (std140, binding = 1) uniform ObjectData {
 #ifdef _VERTEX_
 layout(location=0) in vec3 position;
 layout(location=1) in vec3 normal;
-layout(location=2) in vec3 tangent;
+layout(location=2) in vec4 tangent;
 layout(location=3) in vec2 texcoords;
 
 void main() {
@@ -48,4 +48,18 @@ impl<T> AssetCache<T>
 			self.map.borrow().get(id).unwrap().clone()
 		}
 	}
+
+	/// Like load_with, but failures are returned and not cached
+	pub fn try_load_with<'a, E, F: Fn(&'a str) -> Result<T, E>>(&self, id: &'a str, f: F) -> Result<Rc<T>, E>
+	{
+		let key_found = self.map.borrow().contains_key(id);
+		if !key_found {
+			let val = Rc::new(try!(f(id)));
+			self.map.borrow_mut().insert(id.to_string(), val.clone());
+			Ok(val)
+		} else {
+			trace!("Reusing asset {}", id);
+			Ok(self.map.borrow().get(id).unwrap().clone())
+		}
+	}
 }
@@ -0,0 +1,205 @@
+use std::f32;
+use nalgebra::*;
+use graphics::MeshVertex;
+use rendering::MeshPart;
+
+//-------------------------------------------
+// Bounding volumes of meshes, in local or world space
+
+/// Axis-aligned bounding box
+#[derive(Copy, Clone, Debug)]
+pub struct BoundingBox
+{
+	pub min: Vec3<f32>,
+	pub max: Vec3<f32>
+}
+
+fn transform_point(m: &Mat4<f32>, p: &Vec3<f32>) -> Vec3<f32>
+{
+	Vec3::new(
+		m[(0, 0)] * p.x + m[(0, 1)] * p.y + m[(0, 2)] * p.z + m[(0, 3)],
+		m[(1, 0)] * p.x + m[(1, 1)] * p.y + m[(1, 2)] * p.z + m[(1, 3)],
+		m[(2, 0)] * p.x + m[(2, 1)] * p.y + m[(2, 2)] * p.z + m[(2, 3)])
+}
+
+impl BoundingBox
+{
+	/// Box containing nothing
+	pub fn empty() -> BoundingBox
+	{
+		BoundingBox {
+			min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+			max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY)
+		}
+	}
+
+	pub fn is_empty(&self) -> bool
+	{
+		self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+	}
+
+	pub fn add_point(&mut self, p: &Vec3<f32>)
+	{
+		self.min = Vec3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+		self.max = Vec3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+	}
+
+	pub fn union(&self, other: &BoundingBox) -> BoundingBox
+	{
+		let mut result = *self;
+		if !other.is_empty() {
+			result.add_point(&other.min);
+			result.add_point(&other.max);
+		}
+		result
+	}
+
+	pub fn center(&self) -> Vec3<f32>
+	{
+		(self.min + self.max) * 0.5
+	}
+
+	/// Half of the size of the box
+	pub fn extents(&self) -> Vec3<f32>
+	{
+		(self.max - self.min) * 0.5
+	}
+
+	/// Bounding box of the transformed box
+	pub fn transform(&self, m: &Mat4<f32>) -> BoundingBox
+	{
+		if self.is_empty() {
+			return *self;
+		}
+		// the extents along each axis are the sums of the
+		// absolute values of the transformed half-axes
+		let c = transform_point(m, &self.center());
+		let e = self.extents();
+		let extent = |row: usize| m[(row, 0)].abs() * e.x + m[(row, 1)].abs() * e.y + m[(row, 2)].abs() * e.z;
+		let e = Vec3::new(extent(0), extent(1), extent(2));
+		BoundingBox {
+			min: c - e,
+			max: c + e
+		}
+	}
+}
+
+/// Bounding sphere
+#[derive(Copy, Clone, Debug)]
+pub struct BoundingSphere
+{
+	pub center: Vec3<f32>,
+	pub radius: f32
+}
+
+impl BoundingSphere
+{
+	/// Bounding sphere of the transformed sphere
+	pub fn transform(&self, m: &Mat4<f32>) -> BoundingSphere
+	{
+		// largest scale factor of the transform
+		let scale = |col: usize| (m[(0, col)] * m[(0, col)] + m[(1, col)] * m[(1, col)] + m[(2, col)] * m[(2, col)]).sqrt();
+		BoundingSphere {
+			center: transform_point(m, &self.center),
+			radius: self.radius * scale(0).max(scale(1)).max(scale(2))
+		}
+	}
+}
+
+/// Bounding box and sphere of a mesh or a part of a mesh
+#[derive(Copy, Clone, Debug)]
+pub struct Bounds
+{
+	pub aabb: BoundingBox,
+	/// Centered on the box (not the smallest sphere, but a tight one for most meshes)
+	pub sphere: BoundingSphere
+}
+
+impl Bounds
+{
+	pub fn from_vertices(vertices: &[MeshVertex]) -> Bounds
+	{
+		let mut aabb = BoundingBox::empty();
+		for v in vertices.iter() {
+			aabb.add_point(&Vec3::new(v.pos[0], v.pos[1], v.pos[2]));
+		}
+		let center = if aabb.is_empty() { Vec3::new(0.0, 0.0, 0.0) } else { aabb.center() };
+		let radius = vertices.iter()
+			.map(|v| (Vec3::new(v.pos[0], v.pos[1], v.pos[2]) - center).norm())
+			.fold(0.0f32, |r, d| r.max(d));
+		Bounds {
+			aabb: aabb,
+			sphere: BoundingSphere {
+				center: center,
+				radius: radius
+			}
+		}
+	}
+
+	/// Bounds of the vertex range of a part
+	pub fn from_part(vertices: &[MeshVertex], part: &MeshPart) -> Bounds
+	{
+		let start = part.start_vertex as usize;
+		Bounds::from_vertices(&vertices[start..start + part.num_vertices as usize])
+	}
+
+	/// Bounds in the space given by a transform (e.g. world space bounds
+	/// from the model matrix)
+	pub fn transform(&self, m: &Mat4<f32>) -> Bounds
+	{
+		Bounds {
+			aabb: self.aabb.transform(m),
+			sphere: self.sphere.transform(m)
+		}
+	}
+}
+
+#[test]
+fn test_transform_box_rotation()
+{
+	let approx = |a: Vec3<f32>, b: Vec3<f32>| (a - b).norm() < 1e-5;
+	let b = BoundingBox { min: Vec3::new(0.0, 0.0, 0.0), max: Vec3::new(2.0, 1.0, 1.0) };
+
+	// 90 degrees around Z, then translated along X
+	let m = Mat4::new(
+		0.0, -1.0, 0.0, 10.0,
+		1.0, 0.0, 0.0, 0.0,
+		0.0, 0.0, 1.0, 0.0,
+		0.0, 0.0, 0.0, 1.0);
+	let t = b.transform(&m);
+	assert!(approx(t.min, Vec3::new(9.0, 0.0, 0.0)));
+	assert!(approx(t.max, Vec3::new(10.0, 2.0, 1.0)));
+
+	// 45 degrees around Z: the box grows to contain the rotated corners
+	let h = f32::consts::FRAC_1_SQRT_2;
+	let m = Mat4::new(
+		h, -h, 0.0, 0.0,
+		h, h, 0.0, 0.0,
+		0.0, 0.0, 1.0, 0.0,
+		0.0, 0.0, 0.0, 1.0);
+	let cube = BoundingBox { min: Vec3::new(-1.0, -1.0, -1.0), max: Vec3::new(1.0, 1.0, 1.0) };
+	let t = cube.transform(&m);
+	let s = f32::consts::SQRT_2;
+	assert!(approx(t.min, Vec3::new(-s, -s, -1.0)));
+	assert!(approx(t.max, Vec3::new(s, s, 1.0)));
+
+	assert!(BoundingBox::empty().transform(&m).is_empty());
+}
+
+#[test]
+fn test_transform_sphere_non_uniform_scale()
+{
+	let sphere = BoundingSphere { center: Vec3::new(1.0, 0.0, 0.0), radius: 1.0 };
+	let m = Mat4::new(
+		1.0, 0.0, 0.0, 0.0,
+		0.0, 3.0, 0.0, 0.0,
+		0.0, 0.0, 2.0, 5.0,
+		0.0, 0.0, 0.0, 1.0);
+	let t = sphere.transform(&m);
+	assert_eq!((t.center.x, t.center.y, t.center.z), (1.0, 0.0, 5.0));
+	// the radius follows the largest axis scale
+	assert_eq!(t.radius, 3.0);
+	for p in [Vec3::new(2.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(1.0, 0.0, -1.0)].iter() {
+		assert!((transform_point(&m, p) - t.center).norm() <= t.radius + 1e-5);
+	}
+}
@@ -13,6 +13,87 @@ pub struct Camera
 	pub w_eye: Pnt3<f32>
 }
 
+impl Camera
+{
+	/// Perspective camera at eye looking at target, +Y up (yfov in radians)
+	pub fn look_at(eye: Pnt3<f32>, target: Pnt3<f32>, aspect_ratio: f32, yfov: f32, znear: f32, zfar: f32) -> Camera
+	{
+		let mut view = Iso3::<f32>::one();
+		view.look_at_z(&eye, &target, &Vec3::new(0.0, 1.0, 0.0));
+		view.inv_mut();
+		Camera {
+			view_matrix: view.to_homogeneous(),
+			proj_matrix: PerspMat3::new(aspect_ratio, yfov, znear, zfar).to_mat(),
+			w_eye: eye
+		}
+	}
+}
+
+/// Projection of a camera stored in a scene file
+#[derive(Copy, Clone, Debug)]
+pub enum Projection
+{
+	/// Vertical field of view in radians.
+	/// No aspect ratio: use the one of the viewport. No far plane: infinite projection.
+	Perspective { yfov: f32, aspect_ratio: Option<f32>, znear: f32, zfar: Option<f32> },
+	/// Half-width and half-height of the view volume
+	Orthographic { xmag: f32, ymag: f32, znear: f32, zfar: f32 }
+}
+
+impl Projection
+{
+	/// OpenGL projection matrix (clip space z in [-1, 1])
+	pub fn to_mat4(&self, viewport_aspect_ratio: f32) -> Mat4<f32>
+	{
+		match *self {
+			Projection::Perspective { yfov, aspect_ratio, znear, zfar } => {
+				let f = 1.0 / (yfov * 0.5).tan();
+				let a = aspect_ratio.unwrap_or(viewport_aspect_ratio);
+				let (c, d) = match zfar {
+					Some(zfar) => ((zfar + znear) / (znear - zfar), 2.0 * zfar * znear / (znear - zfar)),
+					None => (-1.0, -2.0 * znear)
+				};
+				Mat4::new(
+					f / a, 0.0, 0.0, 0.0,
+					0.0, f, 0.0, 0.0,
+					0.0, 0.0, c, d,
+					0.0, 0.0, -1.0, 0.0)
+			},
+			Projection::Orthographic { xmag, ymag, znear, zfar } => {
+				Mat4::new(
+					1.0 / xmag, 0.0, 0.0, 0.0,
+					0.0, 1.0 / ymag, 0.0, 0.0,
+					0.0, 0.0, 2.0 / (znear - zfar), (zfar + znear) / (znear - zfar),
+					0.0, 0.0, 0.0, 1.0)
+			}
+		}
+	}
+}
+
+/// A camera placed in a scene (e.g. imported from a glTF file).
+/// The camera looks towards -Z, with +Y up, in its local frame.
+#[derive(Clone, Debug)]
+pub struct SceneCamera
+{
+	pub name: String,
+	/// Local to world transform
+	pub world_matrix: Mat4<f32>,
+	pub projection: Projection
+}
+
+impl SceneCamera
+{
+	pub fn to_camera(&self, viewport_aspect_ratio: f32) -> Camera
+	{
+		let m = &self.world_matrix;
+		Camera {
+			view_matrix: self.world_matrix.inv().expect("SceneCamera: singular world matrix"),
+			proj_matrix: self.projection.to_mat4(viewport_aspect_ratio),
+			w_eye: Pnt3::new(m[(0, 3)], m[(1, 3)], m[(2, 3)])
+		}
+	}
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum TrackballCameraMode
 {
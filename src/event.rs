@@ -5,8 +5,10 @@ use glfw::{Key};
 #[derive(Copy,Clone)]
 pub enum Event
 {
-	// Window resize event
+	// Window resize event (in screen coordinates)
 	WindowResize(u32, u32),
+	// Framebuffer resize event (in pixels)
+	FramebufferResize(u32, u32),
 	// Input event
 	MouseButton(glfw::MouseButton, glfw::Action),
 	MouseMove(f64, f64),
@@ -0,0 +1,1245 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str;
+use serde_json;
+use nalgebra::*;
+use num::traits::One;
+use camera::{SceneCamera, Projection};
+use graphics::MeshVertex;
+use material::{MaterialDesc, TextureDesc, TextureSource, roughness_to_shininess};
+use mesh_data::{MeshData, triangles};
+use tangent_space::{generate_normals, generate_tangents};
+use rendering::*;
+use scene::LightSource;
+
+//-------------------------------------------
+// glTF 2.0 import: .gltf (external or data URI buffers) and .glb files.
+// Supported: meshes (all primitive modes), metallic-roughness materials,
+// textures and samplers, node hierarchies, cameras and
+// KHR_lights_punctual directional lights.
+// Not supported: sparse accessors, skins, morph targets, animations,
+// point and spot lights (the renderer only has a directional light).
+
+#[derive(Debug)]
+pub enum GltfError
+{
+	Io(io::Error),
+	Json(serde_json::Error),
+	InvalidGlb(&'static str),
+	/// Out-of-range index, truncated buffer...
+	InvalidData(String),
+	Unsupported(String)
+}
+
+impl From<io::Error> for GltfError
+{
+	fn from(err: io::Error) -> GltfError
+	{
+		GltfError::Io(err)
+	}
+}
+
+impl From<serde_json::Error> for GltfError
+{
+	fn from(err: serde_json::Error) -> GltfError
+	{
+		GltfError::Json(err)
+	}
+}
+
+/// A mesh placed in the scene by a node
+pub struct MeshInstance
+{
+	pub name: String,
+	/// Index in GltfScene::meshes
+	pub mesh: usize,
+	pub world_matrix: Mat4<f32>
+}
+
+/// Contents of a glTF file
+pub struct GltfScene
+{
+	/// One MeshData per glTF mesh, one submesh per primitive.
+	/// The material indices of the submeshes refer to GltfScene::materials
+	/// (MeshData::materials is empty).
+	pub meshes: Vec<MeshData>,
+	pub materials: Vec<MaterialDesc>,
+	/// Meshes referenced by the nodes of the scene, with their world transform
+	pub instances: Vec<MeshInstance>,
+	pub cameras: Vec<SceneCamera>,
+	pub lights: Vec<LightSource>
+}
+
+//-------------------------------------------
+// JSON representation
+#[derive(Deserialize, Debug)]
+struct JsonAsset
+{
+	version: String
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonScene
+{
+	#[serde(default)]
+	nodes: Vec<usize>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonNodeLight
+{
+	light: usize
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonNodeExtensions
+{
+	#[serde(rename="KHR_lights_punctual")]
+	lights_punctual: Option<JsonNodeLight>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonNode
+{
+	name: Option<String>,
+	#[serde(default)]
+	children: Vec<usize>,
+	mesh: Option<usize>,
+	camera: Option<usize>,
+	/// column-major
+	matrix: Option<Vec<f32>>,
+	translation: Option<Vec<f32>>,
+	/// quaternion (x, y, z, w)
+	rotation: Option<Vec<f32>>,
+	scale: Option<Vec<f32>>,
+	extensions: Option<JsonNodeExtensions>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonBuffer
+{
+	uri: Option<String>,
+	#[serde(rename="byteLength")]
+	byte_length: usize
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonBufferView
+{
+	buffer: usize,
+	#[serde(rename="byteOffset")]
+	byte_offset: Option<usize>,
+	#[serde(rename="byteLength")]
+	byte_length: usize,
+	#[serde(rename="byteStride")]
+	byte_stride: Option<usize>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonAccessor
+{
+	#[serde(rename="bufferView")]
+	buffer_view: Option<usize>,
+	#[serde(rename="byteOffset")]
+	byte_offset: Option<usize>,
+	#[serde(rename="componentType")]
+	component_type: u32,
+	normalized: Option<bool>,
+	count: usize,
+	#[serde(rename="type")]
+	ty: String,
+	sparse: Option<serde_json::Value>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonPrimitive
+{
+	attributes: HashMap<String, usize>,
+	indices: Option<usize>,
+	material: Option<usize>,
+	mode: Option<u32>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonMesh
+{
+	name: Option<String>,
+	primitives: Vec<JsonPrimitive>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonTextureInfo
+{
+	index: usize,
+	#[serde(rename="texCoord")]
+	tex_coord: Option<u32>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonPbrMetallicRoughness
+{
+	#[serde(rename="baseColorFactor")]
+	base_color_factor: Option<Vec<f32>>,
+	#[serde(rename="baseColorTexture")]
+	base_color_texture: Option<JsonTextureInfo>,
+	#[serde(rename="metallicFactor")]
+	metallic_factor: Option<f32>,
+	#[serde(rename="roughnessFactor")]
+	roughness_factor: Option<f32>,
+	#[serde(rename="metallicRoughnessTexture")]
+	metallic_roughness_texture: Option<JsonTextureInfo>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonMaterial
+{
+	name: Option<String>,
+	#[serde(rename="pbrMetallicRoughness")]
+	pbr_metallic_roughness: Option<JsonPbrMetallicRoughness>,
+	#[serde(rename="normalTexture")]
+	normal_texture: Option<JsonTextureInfo>,
+	#[serde(rename="occlusionTexture")]
+	occlusion_texture: Option<JsonTextureInfo>,
+	#[serde(rename="emissiveTexture")]
+	emissive_texture: Option<JsonTextureInfo>,
+	#[serde(rename="emissiveFactor")]
+	emissive_factor: Option<Vec<f32>>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonTexture
+{
+	sampler: Option<usize>,
+	source: Option<usize>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonImage
+{
+	uri: Option<String>,
+	#[serde(rename="bufferView")]
+	buffer_view: Option<usize>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonSampler
+{
+	#[serde(rename="magFilter")]
+	mag_filter: Option<u32>,
+	#[serde(rename="minFilter")]
+	min_filter: Option<u32>,
+	#[serde(rename="wrapS")]
+	wrap_s: Option<u32>,
+	#[serde(rename="wrapT")]
+	wrap_t: Option<u32>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonPerspective
+{
+	#[serde(rename="aspectRatio")]
+	aspect_ratio: Option<f32>,
+	yfov: f32,
+	znear: f32,
+	zfar: Option<f32>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonOrthographic
+{
+	xmag: f32,
+	ymag: f32,
+	znear: f32,
+	zfar: f32
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonCamera
+{
+	name: Option<String>,
+	perspective: Option<JsonPerspective>,
+	orthographic: Option<JsonOrthographic>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonLight
+{
+	#[serde(rename="type")]
+	ty: String,
+	color: Option<Vec<f32>>,
+	intensity: Option<f32>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonLightsPunctual
+{
+	#[serde(default)]
+	lights: Vec<JsonLight>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonExtensions
+{
+	#[serde(rename="KHR_lights_punctual")]
+	lights_punctual: Option<JsonLightsPunctual>
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonGltf
+{
+	asset: JsonAsset,
+	scene: Option<usize>,
+	#[serde(default)]
+	scenes: Vec<JsonScene>,
+	#[serde(default)]
+	nodes: Vec<JsonNode>,
+	#[serde(default)]
+	buffers: Vec<JsonBuffer>,
+	#[serde(rename="bufferViews", default)]
+	buffer_views: Vec<JsonBufferView>,
+	#[serde(default)]
+	accessors: Vec<JsonAccessor>,
+	#[serde(default)]
+	meshes: Vec<JsonMesh>,
+	#[serde(default)]
+	materials: Vec<JsonMaterial>,
+	#[serde(default)]
+	textures: Vec<JsonTexture>,
+	#[serde(default)]
+	images: Vec<JsonImage>,
+	#[serde(default)]
+	samplers: Vec<JsonSampler>,
+	#[serde(default)]
+	cameras: Vec<JsonCamera>,
+	#[serde(rename="extensionsRequired", default)]
+	extensions_required: Vec<String>,
+	extensions: Option<JsonExtensions>
+}
+// end JSON repr
+//-------------------------------------------
+
+const GLB_MAGIC: u32 = 0x46546C67;		// "glTF"
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A;	// "JSON"
+const GLB_CHUNK_BIN: u32 = 0x004E4942;	// "BIN\0"
+
+// component types
+const GL_BYTE: u32 = 5120;
+const GL_UNSIGNED_BYTE: u32 = 5121;
+const GL_SHORT: u32 = 5122;
+const GL_UNSIGNED_SHORT: u32 = 5123;
+const GL_UNSIGNED_INT: u32 = 5125;
+const GL_FLOAT: u32 = 5126;
+
+fn read_u16_le(bytes: &[u8], offset: usize) -> u16
+{
+	(bytes[offset] as u16) | ((bytes[offset+1] as u16) << 8)
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> u32
+{
+	(bytes[offset] as u32) |
+	((bytes[offset+1] as u32) << 8) |
+	((bytes[offset+2] as u32) << 16) |
+	((bytes[offset+3] as u32) << 24)
+}
+
+fn invalid<T>(msg: String) -> Result<T, GltfError>
+{
+	Err(GltfError::InvalidData(msg))
+}
+
+fn get<'a, T>(items: &'a [T], index: usize, what: &str) -> Result<&'a T, GltfError>
+{
+	match items.get(index) {
+		Some(item) => Ok(item),
+		None => invalid(format!("{} {} does not exist", what, index))
+	}
+}
+
+/// Fixed-size array from an optional JSON array
+fn array3(v: &Option<Vec<f32>>, default: [f32; 3]) -> Result<[f32; 3], GltfError>
+{
+	match *v {
+		Some(ref v) if v.len() >= 3 => Ok([v[0], v[1], v[2]]),
+		Some(_) => invalid("expected an array of 3 numbers".to_string()),
+		None => Ok(default)
+	}
+}
+
+fn array4(v: &Option<Vec<f32>>, default: [f32; 4]) -> Result<[f32; 4], GltfError>
+{
+	match *v {
+		Some(ref v) if v.len() >= 4 => Ok([v[0], v[1], v[2], v[3]]),
+		Some(_) => invalid("expected an array of 4 numbers".to_string()),
+		None => Ok(default)
+	}
+}
+
+/// Split a GLB file into its JSON and binary chunks
+fn parse_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>), GltfError>
+{
+	if bytes.len() < 20 {
+		return Err(GltfError::InvalidGlb("file too small"));
+	}
+	if read_u32_le(bytes, 4) != 2 {
+		return Err(GltfError::InvalidGlb("unsupported GLB version"));
+	}
+	let length = read_u32_le(bytes, 8) as usize;
+	if length > bytes.len() {
+		return Err(GltfError::InvalidGlb("truncated file"));
+	}
+
+	let mut json = None;
+	let mut bin = None;
+	let mut offset = 12;
+	while offset + 8 <= length {
+		let chunk_length = read_u32_le(bytes, offset) as usize;
+		let chunk_type = read_u32_le(bytes, offset + 4);
+		let start = offset + 8;
+		if start + chunk_length > length {
+			return Err(GltfError::InvalidGlb("truncated chunk"));
+		}
+		match chunk_type {
+			GLB_CHUNK_JSON if json.is_none() => json = Some(&bytes[start..start+chunk_length]),
+			GLB_CHUNK_BIN if bin.is_none() => bin = Some(&bytes[start..start+chunk_length]),
+			// unknown chunks must be ignored
+			_ => {}
+		}
+		// chunks are 4-byte aligned
+		offset = start + (chunk_length + 3) / 4 * 4;
+	}
+
+	match json {
+		Some(json) => Ok((json, bin)),
+		None => Err(GltfError::InvalidGlb("no JSON chunk"))
+	}
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>, GltfError>
+{
+	let mut out = Vec::with_capacity(s.len() * 3 / 4);
+	let mut acc = 0u32;
+	let mut bits = 0;
+	for &c in s.as_bytes() {
+		let v = match c {
+			b'A'...b'Z' => c - b'A',
+			b'a'...b'z' => c - b'a' + 26,
+			b'0'...b'9' => c - b'0' + 52,
+			b'+' => 62,
+			b'/' => 63,
+			b'=' => break,
+			_ => return invalid("invalid base64 data".to_string())
+		};
+		acc = (acc << 6) | v as u32;
+		bits += 6;
+		if bits >= 8 {
+			bits -= 8;
+			out.push((acc >> bits) as u8);
+			acc &= (1 << bits) - 1;
+		}
+	}
+	Ok(out)
+}
+
+/// Contents of a data URI (base64 only)
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, GltfError>
+{
+	match uri.find(";base64,") {
+		Some(pos) => decode_base64(&uri[pos + 8..]),
+		None => Err(GltfError::Unsupported(format!("data URI encoding: {}", &uri[..uri.find(',').unwrap_or(uri.len())])))
+	}
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, GltfError>
+{
+	let mut bytes = Vec::new();
+	try!(try!(File::open(path)).read_to_end(&mut bytes));
+	Ok(bytes)
+}
+
+fn component_size(component_type: u32) -> Result<usize, GltfError>
+{
+	match component_type {
+		GL_BYTE | GL_UNSIGNED_BYTE => Ok(1),
+		GL_SHORT | GL_UNSIGNED_SHORT => Ok(2),
+		GL_UNSIGNED_INT | GL_FLOAT => Ok(4),
+		_ => invalid(format!("invalid component type {}", component_type))
+	}
+}
+
+fn num_components(ty: &str) -> Result<usize, GltfError>
+{
+	match ty {
+		"SCALAR" => Ok(1),
+		"VEC2" => Ok(2),
+		"VEC3" => Ok(3),
+		"VEC4" => Ok(4),
+		_ => Err(GltfError::Unsupported(format!("accessor type {}", ty)))
+	}
+}
+
+/// Read a component as a float, converting normalized integers to [0,1] or [-1,1]
+fn read_component(bytes: &[u8], offset: usize, component_type: u32, normalized: bool) -> f32
+{
+	match component_type {
+		GL_BYTE => {
+			let v = bytes[offset] as i8 as f32;
+			if normalized { (v / 127.0).max(-1.0) } else { v }
+		},
+		GL_UNSIGNED_BYTE => {
+			let v = bytes[offset] as f32;
+			if normalized { v / 255.0 } else { v }
+		},
+		GL_SHORT => {
+			let v = read_u16_le(bytes, offset) as i16 as f32;
+			if normalized { (v / 32767.0).max(-1.0) } else { v }
+		},
+		GL_UNSIGNED_SHORT => {
+			let v = read_u16_le(bytes, offset) as f32;
+			if normalized { v / 65535.0 } else { v }
+		},
+		GL_UNSIGNED_INT => read_u32_le(bytes, offset) as f32,
+		_ => unsafe { mem::transmute::<u32, f32>(read_u32_le(bytes, offset)) }
+	}
+}
+
+/// End of the last element of an accessor (relative to its buffer view),
+/// or None if it overflows
+fn accessor_end(offset: usize, count: usize, stride: usize, element_size: usize) -> Option<usize>
+{
+	if count == 0 {
+		return Some(offset);
+	}
+	(count - 1).checked_mul(stride)
+		.and_then(|end| end.checked_add(offset))
+		.and_then(|end| end.checked_add(element_size))
+}
+
+fn primitive_type(mode: u32) -> Result<PrimitiveType, GltfError>
+{
+	match mode {
+		0 => Ok(PrimitiveType::Point),
+		1 => Ok(PrimitiveType::Line),
+		2 => Ok(PrimitiveType::LineLoop),
+		3 => Ok(PrimitiveType::LineStrip),
+		4 => Ok(PrimitiveType::Triangle),
+		5 => Ok(PrimitiveType::TriangleStrip),
+		6 => Ok(PrimitiveType::TriangleFan),
+		_ => invalid(format!("invalid primitive mode {}", mode))
+	}
+}
+
+fn address_mode(wrap: Option<u32>) -> TextureAddressMode
+{
+	match wrap {
+		Some(33071) => TextureAddressMode::Clamp,
+		Some(33648) => TextureAddressMode::Mirror,
+		// REPEAT is the default
+		_ => TextureAddressMode::Wrap
+	}
+}
+
+fn sampler_desc(sampler: Option<&JsonSampler>) -> Sampler2DDesc
+{
+	let (mag_filter, min_filter, wrap_s, wrap_t) = match sampler {
+		Some(s) => (s.mag_filter, s.min_filter, s.wrap_s, s.wrap_t),
+		None => (None, None, None, None)
+	};
+	Sampler2DDesc {
+		addr_u: address_mode(wrap_s),
+		addr_v: address_mode(wrap_t),
+		min_filter: match min_filter {
+			Some(9728) => TextureMinFilter::Nearest,
+			Some(9729) => TextureMinFilter::Linear,
+			Some(9984) => TextureMinFilter::NearestMipmapNearest,
+			Some(9985) => TextureMinFilter::LinearMipmapNearest,
+			Some(9986) => TextureMinFilter::NearestMipmapLinear,
+			// unspecified: trilinear filtering
+			_ => TextureMinFilter::LinearMipmapLinear
+		},
+		mag_filter: match mag_filter {
+			Some(9728) => TextureMagFilter::Nearest,
+			_ => TextureMagFilter::Linear
+		}
+	}
+}
+
+/// Matrix of a translation, rotation (unit quaternion x, y, z, w) and scale
+fn trs_matrix(t: [f32; 3], r: [f32; 4], s: [f32; 3]) -> Mat4<f32>
+{
+	let (x, y, z, w) = (r[0], r[1], r[2], r[3]);
+	Mat4::new(
+		(1.0 - 2.0*(y*y + z*z)) * s[0], 2.0*(x*y - z*w) * s[1], 2.0*(x*z + y*w) * s[2], t[0],
+		2.0*(x*y + z*w) * s[0], (1.0 - 2.0*(x*x + z*z)) * s[1], 2.0*(y*z - x*w) * s[2], t[1],
+		2.0*(x*z - y*w) * s[0], 2.0*(y*z + x*w) * s[1], (1.0 - 2.0*(x*x + y*y)) * s[2], t[2],
+		0.0, 0.0, 0.0, 1.0)
+}
+
+fn local_matrix(node: &JsonNode) -> Result<Mat4<f32>, GltfError>
+{
+	match node.matrix {
+		Some(ref m) if m.len() == 16 => {
+			// column-major
+			Ok(Mat4::new(
+				m[0], m[4], m[8], m[12],
+				m[1], m[5], m[9], m[13],
+				m[2], m[6], m[10], m[14],
+				m[3], m[7], m[11], m[15]))
+		},
+		Some(_) => invalid("node matrix must have 16 elements".to_string()),
+		None => Ok(trs_matrix(
+			try!(array3(&node.translation, [0.0; 3])),
+			try!(array4(&node.rotation, [0.0, 0.0, 0.0, 1.0])),
+			try!(array3(&node.scale, [1.0; 3])))
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum NodeState
+{
+	Unvisited,
+	/// the node is an ancestor of the node being visited
+	OnStack,
+	Done
+}
+
+struct Document
+{
+	json: JsonGltf,
+	buffers: Vec<Vec<u8>>,
+	/// Image of each glTF image
+	images: Vec<TextureSource>
+}
+
+impl Document
+{
+	fn buffer_view_data(&self, index: usize) -> Result<&[u8], GltfError>
+	{
+		let view = try!(get(&self.json.buffer_views, index, "buffer view"));
+		let buffer = try!(get(&self.buffers, view.buffer, "buffer"));
+		let offset = view.byte_offset.unwrap_or(0);
+		if offset.checked_add(view.byte_length).map_or(true, |end| end > buffer.len()) {
+			return invalid(format!("buffer view {} out of bounds", index));
+		}
+		Ok(&buffer[offset..offset+view.byte_length])
+	}
+
+	/// Elements of an accessor, converted to floats.
+	/// Returns the data and the number of components per element.
+	fn read_accessor(&self, index: usize) -> Result<(Vec<f32>, usize), GltfError>
+	{
+		let a = try!(get(&self.json.accessors, index, "accessor"));
+		if a.sparse.is_some() {
+			return Err(GltfError::Unsupported("sparse accessors".to_string()));
+		}
+		let n = try!(num_components(&a.ty));
+		let size = try!(component_size(a.component_type));
+		let normalized = a.normalized.unwrap_or(false);
+		let len = try!(a.count.checked_mul(n).ok_or(
+			GltfError::InvalidData(format!("accessor {}: too many elements", index))));
+		let view_index = match a.buffer_view {
+			Some(view_index) => view_index,
+			// no buffer view: all zeroes
+			None => return Ok((vec![0.0; len], n))
+		};
+
+		// check the bounds before allocating anything
+		let view = try!(get(&self.json.buffer_views, view_index, "buffer view"));
+		let data = try!(self.buffer_view_data(view_index));
+		let stride = view.byte_stride.unwrap_or(n * size);
+		if stride < n * size {
+			return invalid(format!("accessor {}: stride is smaller than an element", index));
+		}
+		let offset = a.byte_offset.unwrap_or(0);
+		if accessor_end(offset, a.count, stride, n * size).map_or(true, |end| end > data.len()) {
+			return invalid(format!("accessor {} out of bounds", index));
+		}
+
+		let mut out = Vec::with_capacity(len);
+		for i in 0..a.count {
+			for c in 0..n {
+				out.push(read_component(data, offset + i*stride + c*size, a.component_type, normalized));
+			}
+		}
+		Ok((out, n))
+	}
+
+	fn read_indices(&self, index: usize) -> Result<Vec<u32>, GltfError>
+	{
+		let a = try!(get(&self.json.accessors, index, "accessor"));
+		if a.ty != "SCALAR" {
+			return invalid(format!("index accessor {} is not SCALAR", index));
+		}
+		let size = match a.component_type {
+			GL_UNSIGNED_BYTE | GL_UNSIGNED_SHORT | GL_UNSIGNED_INT => try!(component_size(a.component_type)),
+			_ => return invalid(format!("index accessor {}: invalid component type", index))
+		};
+		let view_index = try!(a.buffer_view.ok_or(GltfError::InvalidData(format!("index accessor {} has no buffer view", index))));
+		let data = try!(self.buffer_view_data(view_index));
+		let offset = a.byte_offset.unwrap_or(0);
+		if accessor_end(offset, a.count, size, size).map_or(true, |end| end > data.len()) {
+			return invalid(format!("accessor {} out of bounds", index));
+		}
+		Ok((0..a.count).map(|i| {
+			let o = offset + i * size;
+			match size {
+				1 => data[o] as u32,
+				2 => read_u16_le(data, o) as u32,
+				_ => read_u32_le(data, o)
+			}
+		}).collect())
+	}
+
+	/// Read an attribute with the expected number of components
+	fn read_attribute(&self, name: &str, index: usize, components: usize, count: usize) -> Result<Vec<f32>, GltfError>
+	{
+		let (data, n) = try!(self.read_accessor(index));
+		if n != components {
+			return invalid(format!("attribute {}: expected {} components, got {}", name, components, n));
+		}
+		if data.len() != count * n {
+			return invalid(format!("attribute {}: vertex count mismatch", name));
+		}
+		Ok(data)
+	}
+
+	fn texture_desc(&self, info: &Option<JsonTextureInfo>) -> Result<Option<TextureDesc>, GltfError>
+	{
+		let info = match *info {
+			Some(ref info) => info,
+			None => return Ok(None)
+		};
+		if info.tex_coord.unwrap_or(0) != 0 {
+			warn!("texture {}: only the first set of texture coordinates is supported", info.index);
+		}
+		let texture = try!(get(&self.json.textures, info.index, "texture"));
+		let source = match texture.source {
+			Some(i) => try!(get(&self.images, i, "image")).clone(),
+			None => return Ok(None)
+		};
+		let sampler = match texture.sampler {
+			Some(i) => Some(try!(get(&self.json.samplers, i, "sampler"))),
+			None => None
+		};
+		Ok(Some(TextureDesc {
+			source: source,
+			sampler: Some(sampler_desc(sampler))
+		}))
+	}
+
+	fn material(&self, index: usize) -> Result<MaterialDesc, GltfError>
+	{
+		let m = &self.json.materials[index];
+		let (base_color, metallic, roughness, base_color_texture, metallic_roughness_texture) =
+			match m.pbr_metallic_roughness {
+				Some(ref pbr) => (
+					try!(array4(&pbr.base_color_factor, [1.0; 4])),
+					pbr.metallic_factor.unwrap_or(1.0),
+					pbr.roughness_factor.unwrap_or(1.0),
+					try!(self.texture_desc(&pbr.base_color_texture)),
+					try!(self.texture_desc(&pbr.metallic_roughness_texture))),
+				None => ([1.0; 4], 1.0, 1.0, None, None)
+			};
+		Ok(MaterialDesc {
+			name: m.name.clone().unwrap_or_else(|| format!("material{}", index)),
+			diffuse_color: [base_color[0], base_color[1], base_color[2]],
+			// reflectance of dielectrics
+			specular_color: [0.04; 3],
+			shininess: roughness_to_shininess(roughness),
+			metallic: metallic,
+			roughness: roughness,
+			emissive_color: try!(array3(&m.emissive_factor, [0.0; 3])),
+			diffuse_texture: base_color_texture,
+			normal_texture: try!(self.texture_desc(&m.normal_texture)),
+			metallic_roughness_texture: metallic_roughness_texture,
+			occlusion_texture: try!(self.texture_desc(&m.occlusion_texture)),
+			emissive_texture: try!(self.texture_desc(&m.emissive_texture))
+		})
+	}
+
+	fn mesh(&self, index: usize) -> Result<MeshData, GltfError>
+	{
+		let m = &self.json.meshes[index];
+		let mesh_name = m.name.clone().unwrap_or_else(|| format!("mesh{}", index));
+		let mut data = MeshData::new();
+
+		for (i, p) in m.primitives.iter().enumerate()
+		{
+			let name = if m.primitives.len() > 1 { format!("{}.{}", mesh_name, i) } else { mesh_name.clone() };
+			let mut prim_type = try!(primitive_type(p.mode.unwrap_or(4)));
+
+			let position_index = try!(p.attributes.get("POSITION").cloned().ok_or(
+				GltfError::InvalidData(format!("{}: no POSITION attribute", name))));
+			let (positions, n) = try!(self.read_accessor(position_index));
+			if n != 3 {
+				return invalid(format!("{}: POSITION must be VEC3", name));
+			}
+			let count = positions.len() / 3;
+
+			let read = |attrib: &str, components: usize| -> Result<Option<Vec<f32>>, GltfError> {
+				match p.attributes.get(attrib) {
+					Some(&index) => Ok(Some(try!(self.read_attribute(attrib, index, components, count)))),
+					None => Ok(None)
+				}
+			};
+			let normals = try!(read("NORMAL", 3));
+			let tangents = try!(read("TANGENT", 4));
+			let texcoords = try!(read("TEXCOORD_0", 2));
+			let mut vertices = (0..count).map(|v| MeshVertex {
+				pos: [positions[3*v], positions[3*v+1], positions[3*v+2]],
+				norm: normals.as_ref().map_or([0.0; 3], |n| [n[3*v], n[3*v+1], n[3*v+2]]),
+				tg: tangents.as_ref().map_or([0.0, 0.0, 0.0, 1.0], |t| [t[4*v], t[4*v+1], t[4*v+2], t[4*v+3]]),
+				tex: texcoords.as_ref().map_or([0.0; 2], |t| [t[2*v], t[2*v+1]])
+			}).collect::<Vec<_>>();
+
+			let mut indices = match p.indices {
+				Some(index) => try!(self.read_indices(index)),
+				None => (0..count as u32).collect()
+			};
+			if indices.iter().any(|&i| i as usize >= count) {
+				return invalid(format!("{}: index out of range", name));
+			}
+			// original vertex of each vertex, for the other attributes
+			let mut remap = None;
+			// the spec requires flat normals when they are not provided
+			let is_triangles = match prim_type {
+				PrimitiveType::Triangle | PrimitiveType::TriangleStrip | PrimitiveType::TriangleFan => true,
+				_ => false
+			};
+			if normals.is_none() && is_triangles {
+				indices = triangles(prim_type, &indices, count).iter().flat_map(|t| t.iter().cloned()).collect();
+				prim_type = PrimitiveType::Triangle;
+				remap = Some(generate_normals(&mut vertices, &mut indices, 0.0));
+			}
+			// and MikkTSpace tangents, which can split vertices
+			if tangents.is_none() && texcoords.is_some() && is_triangles {
+				let num_vertices = vertices.len();
+				indices = triangles(prim_type, &indices, num_vertices).iter().flat_map(|t| t.iter().cloned()).collect();
+				prim_type = PrimitiveType::Triangle;
+				let tangent_remap = generate_tangents(&mut vertices, &mut indices);
+				remap = Some(match remap {
+					Some(remap) => tangent_remap.iter().map(|&v| remap[v as usize]).collect(),
+					None => tangent_remap
+				});
+			}
+			if let Some(material) = p.material {
+				try!(get(&self.json.materials, material, "material"));
+			}
+
+			data.add_submesh(&name, prim_type, &vertices, &indices, p.material, texcoords.is_some());
+
+			// other attributes (TEXCOORD_1, COLOR_0, JOINTS_0, WEIGHTS_0...)
+			let mut others = p.attributes.iter()
+				.filter(|&(k, _)| !["POSITION", "NORMAL", "TANGENT", "TEXCOORD_0"].contains(&&k[..]))
+				.collect::<Vec<_>>();
+			others.sort();
+			for &(attrib, &index) in others.iter() {
+				let (values, n) = try!(self.read_accessor(index));
+				if values.len() != count * n {
+					return invalid(format!("attribute {}: vertex count mismatch", attrib));
+				}
+				match remap {
+					Some(ref remap) => {
+						let values = remap.iter()
+							.flat_map(|&v| values[v as usize * n..(v as usize + 1) * n].iter().cloned())
+							.collect::<Vec<_>>();
+						data.set_channel_data(attrib, n, &values);
+					},
+					None => data.set_channel_data(attrib, n, &values)
+				}
+			}
+		}
+
+		Ok(data)
+	}
+
+	fn camera(&self, index: usize, world_matrix: Mat4<f32>) -> Result<SceneCamera, GltfError>
+	{
+		let c = try!(get(&self.json.cameras, index, "camera"));
+		let projection = match (&c.perspective, &c.orthographic) {
+			(&Some(ref p), _) => Projection::Perspective {
+				yfov: p.yfov,
+				aspect_ratio: p.aspect_ratio,
+				znear: p.znear,
+				zfar: p.zfar
+			},
+			(_, &Some(ref o)) => Projection::Orthographic {
+				xmag: o.xmag,
+				ymag: o.ymag,
+				znear: o.znear,
+				zfar: o.zfar
+			},
+			_ => return invalid(format!("camera {} has no projection", index))
+		};
+		Ok(SceneCamera {
+			name: c.name.clone().unwrap_or_else(|| format!("camera{}", index)),
+			world_matrix: world_matrix,
+			projection: projection
+		})
+	}
+
+	/// None for point and spot lights, which are not supported by the renderer
+	fn light(&self, index: usize, world_matrix: &Mat4<f32>) -> Result<Option<LightSource>, GltfError>
+	{
+		let lights = match self.json.extensions.as_ref().and_then(|e| e.lights_punctual.as_ref()) {
+			Some(ext) => &ext.lights[..],
+			None => return invalid(format!("light {} does not exist", index))
+		};
+		let l = try!(get(lights, index, "light"));
+		let m = world_matrix;
+		// lights point towards -Z
+		let direction = Vec3::new(-m[(0, 2)], -m[(1, 2)], -m[(2, 2)]).normalize();
+		let color = try!(array3(&l.color, [1.0; 3]));
+		let color = Vec3::new(color[0], color[1], color[2]);
+		let intensity = l.intensity.unwrap_or(1.0);
+		match &l.ty[..] {
+			"directional" => Ok(Some(LightSource::Directional(direction, color, intensity))),
+			"point" | "spot" => {
+				warn!("glTF: ignoring {} light {}: only directional lights are supported", l.ty, index);
+				Ok(None)
+			},
+			ty => Err(GltfError::Unsupported(format!("light type {}", ty)))
+		}
+	}
+
+	/// Visit a node and its children. Nodes must form disjoint trees:
+	/// a node cannot be its own ancestor, or have several parents.
+	fn visit_node(&self, index: usize, parent: &Mat4<f32>, state: &mut [NodeState], scene: &mut GltfScene) -> Result<(), GltfError>
+	{
+		let node = try!(get(&self.json.nodes, index, "node"));
+		match state[index] {
+			NodeState::Unvisited => {},
+			NodeState::OnStack => return invalid(format!("cycle in the node hierarchy at node {}", index)),
+			NodeState::Done => return invalid(format!("node {} has several parents", index))
+		}
+		state[index] = NodeState::OnStack;
+		let world = *parent * try!(local_matrix(node));
+		let name = node.name.clone().unwrap_or_else(|| format!("node{}", index));
+
+		if let Some(mesh) = node.mesh {
+			try!(get(&self.json.meshes, mesh, "mesh"));
+			scene.instances.push(MeshInstance {
+				name: name.clone(),
+				mesh: mesh,
+				world_matrix: world
+			});
+		}
+		if let Some(camera) = node.camera {
+			scene.cameras.push(try!(self.camera(camera, world)));
+		}
+		if let Some(light) = node.extensions.as_ref().and_then(|e| e.lights_punctual.as_ref()) {
+			if let Some(light) = try!(self.light(light.light, &world)) {
+				scene.lights.push(light);
+			}
+		}
+		for &child in node.children.iter() {
+			try!(self.visit_node(child, &world, state, scene));
+		}
+		state[index] = NodeState::Done;
+		Ok(())
+	}
+
+	/// Root nodes of the default scene
+	fn root_nodes(&self) -> Result<Vec<usize>, GltfError>
+	{
+		if !self.json.scenes.is_empty() {
+			let scene = try!(get(&self.json.scenes, self.json.scene.unwrap_or(0), "scene"));
+			Ok(scene.nodes.clone())
+		} else {
+			// no scene: all nodes that are not children of another node
+			let mut is_child = vec![false; self.json.nodes.len()];
+			for n in self.json.nodes.iter() {
+				for &c in n.children.iter() {
+					if c < is_child.len() { is_child[c] = true; }
+				}
+			}
+			Ok((0..self.json.nodes.len()).filter(|&i| !is_child[i]).collect())
+		}
+	}
+}
+
+/// Load a glTF 2.0 file (.gltf or .glb).
+/// Textures are not loaded: the materials reference image files or embedded images.
+pub fn load_gltf(path: &Path) -> Result<GltfScene, GltfError>
+{
+	let bytes = try!(read_file(path));
+	parse_gltf(&bytes[..], path)
+}
+
+/// Load a glTF file from memory. External files are relative to path.
+fn parse_gltf(bytes: &[u8], path: &Path) -> Result<GltfScene, GltfError>
+{
+	let (json_bytes, bin) = if bytes.len() >= 4 && read_u32_le(bytes, 0) == GLB_MAGIC {
+		try!(parse_glb(bytes))
+	} else {
+		(bytes, None)
+	};
+	let json_str = try!(str::from_utf8(json_bytes).map_err(|_| GltfError::InvalidData("JSON is not valid UTF-8".to_string())));
+	let json: JsonGltf = try!(serde_json::from_str(json_str));
+
+	if !json.asset.version.starts_with("2.") {
+		return Err(GltfError::Unsupported(format!("glTF version {}", json.asset.version)));
+	}
+	for ext in json.extensions_required.iter() {
+		if ext != "KHR_lights_punctual" {
+			return Err(GltfError::Unsupported(format!("required extension {}", ext)));
+		}
+	}
+
+	// external files are relative to the glTF file
+	let base_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+	let mut buffers = Vec::with_capacity(json.buffers.len());
+	for (i, b) in json.buffers.iter().enumerate() {
+		let data = match b.uri {
+			Some(ref uri) if uri.starts_with("data:") => try!(decode_data_uri(uri)),
+			Some(ref uri) => try!(read_file(&base_dir.join(uri))),
+			// the binary chunk of a GLB file
+			None if i == 0 && bin.is_some() => bin.unwrap().to_vec(),
+			None => return invalid(format!("buffer {} has no data", i))
+		};
+		if data.len() < b.byte_length {
+			return invalid(format!("buffer {} is too small", i));
+		}
+		buffers.push(data);
+	}
+
+	let mut doc = Document {
+		json: json,
+		buffers: buffers,
+		images: Vec::new()
+	};
+
+	let mut images = Vec::with_capacity(doc.json.images.len());
+	for (i, img) in doc.json.images.iter().enumerate() {
+		// embedded images are named after the file, for the texture cache
+		let name = format!("{}#image{}", path.display(), i);
+		images.push(match (&img.uri, img.buffer_view) {
+			(&Some(ref uri), _) if uri.starts_with("data:") =>
+				TextureSource::Memory { name: name, data: Rc::new(try!(decode_data_uri(uri))) },
+			(&Some(ref uri), _) => TextureSource::File(base_dir.join(uri)),
+			(&None, Some(view)) =>
+				TextureSource::Memory { name: name, data: Rc::new(try!(doc.buffer_view_data(view)).to_vec()) },
+			_ => return invalid(format!("image {} has no data", i))
+		});
+	}
+	doc.images = images;
+
+	let mut scene = GltfScene {
+		meshes: Vec::with_capacity(doc.json.meshes.len()),
+		materials: Vec::with_capacity(doc.json.materials.len()),
+		instances: Vec::new(),
+		cameras: Vec::new(),
+		lights: Vec::new()
+	};
+	for i in 0..doc.json.materials.len() {
+		scene.materials.push(try!(doc.material(i)));
+	}
+	for i in 0..doc.json.meshes.len() {
+		scene.meshes.push(try!(doc.mesh(i)));
+	}
+	let mut node_state = vec![NodeState::Unvisited; doc.json.nodes.len()];
+	for root in try!(doc.root_nodes()) {
+		try!(doc.visit_node(root, &Mat4::one(), &mut node_state, &mut scene));
+	}
+
+	debug!("Loaded {}: {} meshes, {} materials, {} instances, {} cameras, {} lights",
+		path.display(), scene.meshes.len(), scene.materials.len(),
+		scene.instances.len(), scene.cameras.len(), scene.lights.len());
+	Ok(scene)
+}
+
+#[test]
+fn test_parse_glb()
+{
+	// header, JSON chunk ("{}  ", padded with spaces) and a 4-byte BIN chunk
+	let mut bytes = Vec::new();
+	for &v in [GLB_MAGIC, 2, 12 + 8 + 4 + 8 + 4, 4, GLB_CHUNK_JSON].iter() {
+		bytes.push_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+	}
+	bytes.push_all(b"{}  ");
+	for &v in [4, GLB_CHUNK_BIN].iter() {
+		bytes.push_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+	}
+	bytes.push_all(&[1, 2, 3, 4]);
+
+	let (json, bin) = parse_glb(&bytes[..]).unwrap();
+	assert_eq!(json, b"{}  ");
+	assert_eq!(bin, Some(&[1u8, 2, 3, 4][..]));
+	assert_eq!(decode_base64("AQIDBA==").unwrap(), vec![1, 2, 3, 4]);
+}
+
+#[cfg(test)]
+fn encode_base64(bytes: &[u8]) -> String
+{
+	let chars = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut out = String::new();
+	for chunk in bytes.chunks(3) {
+		let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+		let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+		for i in 0..4 {
+			if i <= chunk.len() {
+				out.push(chars[(n >> (18 - 6 * i)) as usize & 63] as char);
+			} else {
+				out.push('=');
+			}
+		}
+	}
+	out
+}
+
+#[test]
+fn test_node_matrices()
+{
+	use std::f32;
+	let approx = |a: f32, b: f32| (a - b).abs() < 1e-5;
+	let h = f32::consts::FRAC_1_SQRT_2;
+
+	// 90 degrees around Z, then scale x by 2
+	let m = trs_matrix([0.0, 0.0, 5.0], [0.0, 0.0, h, h], [2.0, 1.0, 1.0]);
+	assert!(approx(m[(0, 0)], 0.0) && approx(m[(1, 0)], 2.0) && approx(m[(0, 1)], -1.0));
+	assert_eq!(m[(2, 3)], 5.0);
+
+	// column-major
+	let node: JsonNode = serde_json::from_str(
+		r#"{"matrix": [1,0,0,0, 0,1,0,0, 0,0,1,0, 4,5,6,1]}"#).unwrap();
+	let m = local_matrix(&node).unwrap();
+	assert_eq!((m[(0, 3)], m[(1, 3)], m[(2, 3)], m[(3, 3)]), (4.0, 5.0, 6.0, 1.0));
+	assert_eq!(m[(3, 0)], 0.0);
+
+	let node: JsonNode = serde_json::from_str(r#"{}"#).unwrap();
+	assert!(local_matrix(&node).unwrap() == Mat4::one());
+	let node: JsonNode = serde_json::from_str(r#"{"matrix": [1,0,0]}"#).unwrap();
+	assert!(local_matrix(&node).is_err());
+}
+
+#[test]
+fn test_parse_gltf()
+{
+	let approx = |a: f32, b: f32| (a - b).abs() < 1e-5;
+
+	// positions: 3 floats, interleaved with 4 bytes of garbage (stride 16)
+	let mut bin = Vec::new();
+	for p in [[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]].iter() {
+		for &c in p.iter() {
+			let bits: u32 = unsafe { mem::transmute(c) };
+			bin.push_all(&[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+		}
+		bin.push_all(&[0xFF; 4]);
+	}
+	// normalized unsigned byte texture coordinates, padded to 4 bytes
+	bin.push_all(&[0, 0, 255, 0, 0, 255, 0, 0]);
+	// unsigned short indices
+	bin.push_all(&[0, 0, 1, 0, 2, 0]);
+
+	let json = format!(r#"{{
+		"asset": {{ "version": "2.0" }},
+		"extensionsUsed": ["KHR_lights_punctual"],
+		"extensions": {{ "KHR_lights_punctual": {{ "lights": [
+			{{ "type": "directional", "color": [1.0, 0.5, 0.25], "intensity": 2.0 }},
+			{{ "type": "point" }}
+		] }} }},
+		"buffers": [{{ "uri": "data:application/octet-stream;base64,{}", "byteLength": {} }}],
+		"bufferViews": [
+			{{ "buffer": 0, "byteOffset": 0, "byteLength": 48, "byteStride": 16 }},
+			{{ "buffer": 0, "byteOffset": 48, "byteLength": 6 }},
+			{{ "buffer": 0, "byteOffset": 56, "byteLength": 6 }}
+		],
+		"accessors": [
+			{{ "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" }},
+			{{ "bufferView": 1, "componentType": 5121, "normalized": true, "count": 3, "type": "VEC2" }},
+			{{ "bufferView": 2, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+		],
+		"meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0, "TEXCOORD_0": 1 }}, "indices": 2 }}] }}],
+		"cameras": [{{ "perspective": {{ "yfov": 0.8, "znear": 0.1, "zfar": 100.0, "aspectRatio": 1.5 }} }}],
+		"nodes": [
+			{{ "name": "root", "translation": [1.0, 2.0, 3.0], "children": [1, 2, 3, 5] }},
+			{{ "mesh": 0, "scale": [2.0, 2.0, 2.0] }},
+			{{ "camera": 0, "rotation": [0.0, 0.70710677, 0.0, 0.70710677] }},
+			{{ "rotation": [0.0, 0.70710677, 0.0, 0.70710677],
+			   "extensions": {{ "KHR_lights_punctual": {{ "light": 0 }} }} }},
+			{{ "name": "not in the scene", "mesh": 0 }},
+			{{ "extensions": {{ "KHR_lights_punctual": {{ "light": 1 }} }} }}
+		],
+		"scenes": [{{ "nodes": [0] }}],
+		"scene": 0
+	}}"#, encode_base64(&bin[..]), bin.len());
+	assert_eq!(decode_base64(&encode_base64(&bin[..])).unwrap(), bin);
+
+	let scene = parse_gltf(json.as_bytes(), Path::new("test.gltf")).unwrap();
+
+	// accessors: stride and normalization
+	let mesh = &scene.meshes[0];
+	assert_eq!(mesh.indices.len(), 3);
+	let vertex = |pos: [f32; 3]| mesh.vertices.iter().find(|v| v.pos == pos).expect("missing vertex");
+	assert_eq!(vertex([0.0, 0.0, 0.0]).tex, [0.0, 0.0]);
+	assert_eq!(vertex([1.0, 0.0, 0.0]).tex, [1.0, 0.0]);
+	assert_eq!(vertex([0.0, 1.0, 0.0]).tex, [0.0, 1.0]);
+	// no normals in the file: flat normals
+	assert!(mesh.vertices.iter().all(|v| v.norm == [0.0, 0.0, 1.0]));
+
+	// hierarchy: only the nodes of the scene, with the transform of their parent
+	assert_eq!(scene.instances.len(), 1);
+	let m = scene.instances[0].world_matrix;
+	assert_eq!((m[(0, 0)], m[(1, 1)], m[(2, 2)]), (2.0, 2.0, 2.0));
+	assert_eq!((m[(0, 3)], m[(1, 3)], m[(2, 3)]), (1.0, 2.0, 3.0));
+
+	// camera: looking towards -X (+Z is rotated to +X)
+	assert_eq!(scene.cameras.len(), 1);
+	let m = scene.cameras[0].world_matrix;
+	assert!(approx(m[(0, 2)], 1.0) && approx(m[(2, 2)], 0.0));
+	assert_eq!((m[(0, 3)], m[(1, 3)], m[(2, 3)]), (1.0, 2.0, 3.0));
+	match scene.cameras[0].projection {
+		Projection::Perspective { yfov, aspect_ratio, znear, zfar } => {
+			assert_eq!((yfov, aspect_ratio, znear, zfar), (0.8, Some(1.5), 0.1, Some(100.0)));
+		},
+		_ => panic!("expected a perspective projection")
+	}
+
+	// directional light, pointing along -Z of the node (the point light is ignored)
+	assert_eq!(scene.lights.len(), 1);
+	match scene.lights[0] {
+		LightSource::Directional(dir, color, intensity) => {
+			assert!(approx(dir.x, -1.0) && approx(dir.y, 0.0) && approx(dir.z, 0.0));
+			assert_eq!((color.x, color.y, color.z), (1.0, 0.5, 0.25));
+			assert_eq!(intensity, 2.0);
+		},
+		_ => panic!("expected a directional light")
+	}
+}
+
+#[test]
+fn test_invalid_gltf()
+{
+	let parse = |json: &str| parse_gltf(json.as_bytes(), Path::new("test.gltf"));
+	let is_invalid = |r: Result<GltfScene, GltfError>| match r { Err(GltfError::InvalidData(_)) => true, _ => false };
+
+	// accessors past the end of the buffer, or whose size overflows, are rejected before allocating
+	for &count in ["1000000000", "6148914691236517206"].iter() {
+		let json = format!(r#"{{
+			"asset": {{ "version": "2.0" }},
+			"buffers": [{{ "uri": "data:application/octet-stream;base64,AAAAAA==", "byteLength": 4 }}],
+			"bufferViews": [{{ "buffer": 0, "byteLength": 4 }}],
+			"accessors": [{{ "bufferView": 0, "componentType": 5126, "count": {}, "type": "VEC3" }}],
+			"meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }} }}] }}]
+		}}"#, count);
+		assert!(is_invalid(parse(&json)));
+	}
+
+	// cycle
+	assert!(is_invalid(parse(r#"{
+		"asset": { "version": "2.0" },
+		"nodes": [{ "children": [1] }, { "children": [0] }],
+		"scenes": [{ "nodes": [0] }]
+	}"#)));
+	// node with two parents
+	assert!(is_invalid(parse(r#"{
+		"asset": { "version": "2.0" },
+		"nodes": [{ "children": [2] }, { "children": [2] }, {}],
+		"scenes": [{ "nodes": [0, 1] }]
+	}"#)));
+}
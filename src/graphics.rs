@@ -5,14 +5,20 @@ use std::mem;
 use std::raw;
 use std::path::{Path};
 use scene_data::*;
-use tobj;
-use material::Material;
+use material::{Material, MaterialDesc};
+use mesh_data::{MeshData, MeshLoadOptions, MeshLoadError};
+use bounds::Bounds;
+use mesh_file::{MeshFile, MeshFileError, cache_path, obj_source_stamp};
 use shadow_pass::*;
 use image::{self, GenericImage};
 use asset_loader::*;
+use texture_loader::load_texture2d;
 use rendering::shader::*;
+use sprite_batch::{Sprite, SpriteBatch, SpriteVertex};
 use std::rc::Rc;
+use std::cell::RefCell;
 
+#[derive(Copy, Clone, Debug)]
 pub struct Rect
 {
 	pub top: f32,
@@ -29,14 +35,15 @@ impl Rect
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct MeshVertex
-{
-	pub pos: [f32; 3],
-	pub norm: [f32; 3],
-	pub tg: [f32; 3],
-	pub tex: [f32; 2]
+vertex_format! {
+	pub struct MeshVertex
+	{
+		pub pos: [f32; 3],
+		pub norm: [f32; 3],
+		/// tangent, and sign of the bitangent in w
+		pub tg: [f32; 4],
+		pub tex: [f32; 2]
+	}
 }
 
 impl MeshVertex
@@ -46,7 +53,7 @@ impl MeshVertex
 		MeshVertex {
 			pos: pos,
 			norm: [0.0; 3],
-			tg: [0.0; 3],
+			tg: [0.0, 0.0, 0.0, 1.0],
 			tex: tex
 		}
 	}
@@ -56,66 +63,176 @@ impl MeshVertex
 pub struct Mesh
 {
 	pub vb: Buffer<MeshVertex>,
-	pub ib: Option<Buffer<u16>>,
+	pub ib: Option<RawBuffer>,
 	pub parts: Vec<MeshPart>,
+	/// Name of each part (object or group name in the model file)
+	pub part_names: Vec<String>,
+	/// Material of each part, index in materials
+	pub part_materials: Vec<Option<usize>>,
+	/// Materials of the model file
+	pub materials: Vec<MaterialDesc>,
+	/// Local space bounds of each part
+	pub part_bounds: Vec<Bounds>,
+	/// Local space bounds of the whole mesh
+	pub bounds: Bounds,
+	/// Simplified versions of each part, from the finest to the coarsest
+	pub part_lods: Vec<Vec<MeshPart>>,
+	/// Screen size below which each LOD is used (decreasing)
+	pub lod_screen_sizes: Vec<f32>,
 	pub num_vertices: usize,
 	pub num_indices: usize
 }
 
+impl DrawSource for Mesh
+{
+	fn vertex_buffer(&self) -> RawBufSlice
+	{
+		self.vb.raw.as_raw_buf_slice()
+	}
+
+	fn index_buffer(&self) -> Option<RawBufSlice>
+	{
+		self.ib.as_ref().map(|ib| ib.as_raw_buf_slice())
+	}
+
+	fn parts(&self) -> &[MeshPart]
+	{
+		&self.parts
+	}
+}
+
 impl Mesh
 {
 	/// create a mesh from an OBJ file
+	/// All objects and groups are loaded, each one as a part of the mesh.
 	pub fn load_from_obj(
 		context: &Context,
-		path: &Path) -> Mesh
+		path: &Path,
+		options: &MeshLoadOptions) -> Result<Mesh, MeshLoadError>
 	{
-		let mut vertices = Vec::<MeshVertex>::new();
-		let mut indices = Vec::<u16>::new();
-		let (models, materials) = tobj::load_obj(path).unwrap();
-
-		let ref m = models[0].mesh;
+		let mesh = Mesh::from_data(context, &try!(MeshData::load_obj(path, options)));
+		Ok(mesh.with_labels(path))
+	}
 
-		for i in 0..m.indices.len() {
-			indices.push(m.indices[i] as u16);
+	/// Load an OBJ file through a binary cache file next to it (foo.obj.meshcache, see mesh_file::cache_path).
+	/// The cache is written on the first load, and rewritten when the OBJ file,
+	/// its MTL libraries or the options change.
+	pub fn load_from_obj_cached(
+		context: &Context,
+		path: &Path,
+		options: &MeshLoadOptions) -> Result<Mesh, MeshLoadError>
+	{
+		let cache_path = cache_path(path);
+		// if the OBJ file cannot be read, load_obj reports the error
+		let stamp = obj_source_stamp(path, options).ok();
+		if let Some(stamp) = stamp {
+			match MeshFile::load(&cache_path) {
+				Ok(ref file) if file.source_stamp == stamp => {
+					debug!("Loaded {} from {}", path.display(), cache_path.display());
+					return Ok(Mesh::from_file_contents(context, file).with_labels(path));
+				},
+				Ok(_) => debug!("{} is out of date", cache_path.display()),
+				// no cache yet
+				Err(MeshFileError::Io(_)) => {},
+				Err(e) => warn!("{}: {}", cache_path.display(), e)
+			}
 		}
+		let data = try!(MeshData::load_obj(path, options));
+		if let Some(stamp) = stamp {
+			if let Err(e) = MeshFile::from_data(&data, stamp).save(&cache_path) {
+				warn!("Could not write {}: {}", cache_path.display(), e);
+			}
+		}
+		Ok(Mesh::from_data(context, &data).with_labels(path))
+	}
 
+	/// Load a binary mesh file (see mesh_file)
+	pub fn load_from_file(context: &Context, path: &Path) -> Result<Mesh, MeshFileError>
+	{
+		let mesh = Mesh::from_file_contents(context, &try!(MeshFile::load(path)));
+		Ok(mesh.with_labels(path))
+	}
 
-		// mesh has texture coordinates
-		if m.texcoords.len() > 0 {
-			//println!("texcoords {} positions {}", m.texcoords.len(), m.positions.len());
-			//trace!("Mesh has texcoords!");
-			for i in 0..m.positions.len() / 3 {
-				vertices.push(MeshVertex {
-					pos: [m.positions[3*i], m.positions[3*i+1], m.positions[3*i+2]],
-					norm: [m.normals[3*i], m.normals[3*i+1], m.normals[3*i+2]],
-					tg: [0.0; 3],
-					tex: [m.texcoords[2*i], m.texcoords[2*i+1]]
-				});
-				//trace!("{},{}", m.texcoords[2*i], m.texcoords[2*i+1]);
-			}
-		} else {
-			// mesh doesn't have texture coordinates
-			for i in 0..m.positions.len() / 3 {
-				vertices.push(MeshVertex {
-					pos: [m.positions[3*i], m.positions[3*i+1], m.positions[3*i+2]],
-					norm: [m.normals[3*i], m.normals[3*i+1], m.normals[3*i+2]],
-					tg: [0.0; 3],
-					tex: [m.positions[3*i], m.positions[3*i+1]]    // dummy texture coordinates
-				});
-			}
+	fn with_labels(self, path: &Path) -> Mesh
+	{
+		self.vb.raw.set_label(&format!("{} (vertices)", path.display()));
+		if let Some(ref ib) = self.ib {
+			ib.set_label(&format!("{} (indices)", path.display()));
 		}
+		self
+	}
 
-		Mesh::new(context, PrimitiveType::Triangle,
-			&vertices[..],
-			Some(&indices[..]))
+	/// upload the contents of a mesh file
+	pub fn from_file_contents(context: &Context, file: &MeshFile) -> Mesh
+	{
+		let _alloc = allocation_scope!("Mesh::from_file_contents");
+		let vb = context.alloc_buffer_from_data(
+			&file.vertices[..],
+			BufferAccess::WriteOnly,
+			BufferBindingHint::VertexBuffer,
+			BufferUsage::Static);
+		// u16 or u32 indices, as stored in the file
+		let ib = context.alloc_buffer_from_data(
+			&file.index_data[..],
+			BufferAccess::WriteOnly,
+			BufferBindingHint::IndexBuffer,
+			BufferUsage::Static).raw;
+		Mesh {
+			vb: vb,
+			ib: Some(ib),
+			parts: file.parts.iter().map(|s| s.part).collect(),
+			part_names: file.parts.iter().map(|s| s.name.clone()).collect(),
+			part_materials: file.parts.iter().map(|s| s.material).collect(),
+			materials: file.materials.clone(),
+			part_bounds: file.part_bounds.clone(),
+			bounds: file.bounds,
+			part_lods: file.parts.iter().map(|s| s.lods.clone()).collect(),
+			lod_screen_sizes: file.lod_screen_sizes.clone(),
+			num_vertices: file.vertices.len(),
+			num_indices: file.num_indices
+		}
 	}
 
-	pub fn new(
+	/// upload mesh data
+	pub fn from_data(context: &Context, data: &MeshData) -> Mesh
+	{
+		let _alloc = allocation_scope!("Mesh::from_data");
+		let vb = context.alloc_buffer_from_data(
+			&data.vertices[..],
+			BufferAccess::WriteOnly,
+			BufferBindingHint::VertexBuffer,
+			BufferUsage::Static);
+		let ib = context.alloc_buffer_from_data(
+			&data.indices[..],
+			BufferAccess::WriteOnly,
+			BufferBindingHint::IndexBuffer,
+			BufferUsage::Static);
+		Mesh {
+			vb: vb,
+			ib: Some(ib.raw),
+			parts: data.submeshes.iter().map(|s| s.part).collect(),
+			part_names: data.submeshes.iter().map(|s| s.name.clone()).collect(),
+			part_materials: data.submeshes.iter().map(|s| s.material).collect(),
+			materials: data.materials.clone(),
+			part_bounds: data.submeshes.iter().map(|s| Bounds::from_part(&data.vertices, &s.part)).collect(),
+			bounds: Bounds::from_vertices(&data.vertices),
+			part_lods: data.submeshes.iter().map(|s| s.lods.clone()).collect(),
+			lod_screen_sizes: data.lod_screen_sizes.clone(),
+			num_vertices: data.vertices.len(),
+			num_indices: data.indices.len()
+		}
+	}
+
+	/// indices can be u16 or u32
+	/// The vertices are uploaded as is: their tangents must already be computed
+	/// (see tangent_space::generate_tangents).
+	pub fn new<I: IndexElement>(
 		context: &Context,
 		primitive_type: PrimitiveType,
 		vertices: &[MeshVertex],
-		indices: Option<&[u16]>) -> Mesh
+		indices: Option<&[I]>) -> Mesh
 	{
+		let _alloc = allocation_scope!("Mesh::new");
 		let vb = context.alloc_buffer_from_data(
 			vertices,
 			BufferAccess::WriteOnly,
@@ -126,8 +243,11 @@ impl Mesh
 				start_vertex: 0,
 				start_index: 0,
 				num_vertices: vertices.len() as u32,
-				num_indices: if let Some(inner_indices) = indices { inner_indices.len() as u32 } else { 0 }
+				num_indices: if let Some(inner_indices) = indices { inner_indices.len() as u32 } else { 0 },
+				index_type: I::index_type(),
+				restart_index: None
 				};
+		let bounds = Bounds::from_vertices(vertices);
 		if let Some(inner_indices) = indices {
 			Mesh {
 				vb: vb,
@@ -135,8 +255,15 @@ impl Mesh
 					inner_indices,
 					BufferAccess::WriteOnly,
 					BufferBindingHint::IndexBuffer,
-					BufferUsage::Static)),
+					BufferUsage::Static).raw),
 				parts: vec![part],
+				part_names: vec![String::new()],
+				part_materials: vec![None],
+				materials: Vec::new(),
+				part_bounds: vec![bounds],
+				bounds: bounds,
+				part_lods: vec![Vec::new()],
+				lod_screen_sizes: Vec::new(),
 				num_vertices: part.num_vertices as usize,
 				num_indices: part.num_indices as usize
 			}
@@ -146,12 +273,74 @@ impl Mesh
 				vb: vb,
 				ib: None,
 				parts: vec![part],
+				part_names: vec![String::new()],
+				part_materials: vec![None],
+				materials: Vec::new(),
+				part_bounds: vec![bounds],
+				bounds: bounds,
+				part_lods: vec![Vec::new()],
+				lod_screen_sizes: Vec::new(),
 				num_vertices: part.num_vertices as usize,
 				num_indices: 0
 			}
 		}
 	}
 
+	/// Number of levels of detail, including the full resolution mesh (LOD 0)
+	pub fn num_lods(&self) -> usize
+	{
+		self.lod_screen_sizes.len() + 1
+	}
+
+	/// Range to draw for a part at a level of detail
+	/// (the coarsest LOD of the part if it has fewer levels)
+	pub fn lod_part(&self, part_index: usize, lod: usize) -> MeshPart
+	{
+		let ref lods = self.part_lods[part_index];
+		if lod == 0 || lods.is_empty() {
+			self.parts[part_index]
+		} else {
+			lods[(lod - 1).min(lods.len() - 1)]
+		}
+	}
+
+	/// Level of detail for a projected size of the bounding sphere
+	/// (diameter over viewport height, see Entity::screen_size).
+	/// With hysteresis, the size must go past a threshold by this relative margin
+	/// before the LOD changes from the current one, which avoids popping
+	/// back and forth around a threshold.
+	pub fn select_lod(&self, screen_size: f32, current: usize, hysteresis: f32) -> usize
+	{
+		select_lod(&self.lod_screen_sizes, screen_size, current, hysteresis)
+	}
+
+	/// Split strips, loops and fans at the given index value (for all parts)
+	pub fn with_restart_index(mut self, restart_index: u32) -> Mesh
+	{
+		assert!(self.ib.is_some(), "primitive restart requires an index buffer");
+		for p in self.parts.iter_mut() {
+			assert!(p.primitive_type.is_connected(),
+				"primitive restart has no effect on {:?}", p.primitive_type);
+			assert!(restart_index <= p.index_type.max_value(),
+				"restart index {} does not fit in {:?} indices", restart_index, p.index_type);
+			p.restart_index = Some(restart_index);
+		}
+		self
+	}
+
+}
+
+/// See Mesh::select_lod
+fn select_lod(thresholds: &[f32], screen_size: f32, current: usize, hysteresis: f32) -> usize
+{
+	let mut lod = current.min(thresholds.len());
+	while lod < thresholds.len() && screen_size < thresholds[lod] * (1.0 - hysteresis) {
+		lod += 1;
+	}
+	while lod > 0 && screen_size > thresholds[lod - 1] * (1.0 + hysteresis) {
+		lod -= 1;
+	}
+	lod
 }
 
 // shared rendering resources
@@ -167,28 +356,28 @@ pub struct Graphics<'a>
 	blit_pso: Rc<PipelineState>,
 	default_pso: Rc<PipelineState>,
     // default (missing) texture (material)
-    missing_tex: Texture2D
-}
-
-fn load_texture2d_from_file(path: &Path) -> Texture2D
-{
-    let img = image::open(path).unwrap();
-    let (w, h) = img.dimensions();
-    let img2 = img.as_rgb8().unwrap();
-    Texture2D::with_pixels(w, h, 1, TextureFormat::Unorm8x3, Some(img2))
+    missing_tex: Texture2D,
+	// sprites of blit and blit_part, drawn by flush_sprites
+	sprites: RefCell<SpriteBatch<'a>>
 }
 
 impl<'a> Graphics<'a>
 {
     pub fn new(context: &'a Context) -> Graphics<'a>
     {
-        //...
-		let mut shader_cache = ShaderCache::new();
+        let _alloc = allocation_scope!("Graphics::new");
+		let shader_cache = ShaderCache::new();
 
-		let pso_desc = PipelineStateDesc {
+		let blit_pso_desc = PipelineStateDesc {
 			keywords: Keywords::empty(),
 			pass: StdPass::ForwardBase,
-			default_draw_state: DrawState::default(),
+			// sprites are sorted back to front, and drawn over the scene
+			default_draw_state: DrawState {
+				depth_test_enable: false,
+				depth_write_enable: false,
+				blend: Some(BlendState::alpha()),
+				.. DrawState::default()
+			},
 			sampler_block_base: 0,
 			uniform_block_base: 0
 		};
@@ -203,8 +392,8 @@ impl<'a> Graphics<'a>
 
 		let blit_shader = Shader::load(Path::new("assets/shaders/blit.glsl"));
 		let mesh_shader = Shader::load(Path::new("assets/shaders/debug.glsl"));
-		let blit_pso = shader_cache.get(&blit_shader, &pso_desc);
-		let mesh_pso = shader_cache.get(&mesh_shader, &wire_pso_desc);
+		let blit_pso = shader_cache.get::<SpriteVertex>(&blit_shader, &blit_pso_desc);
+		let mesh_pso = shader_cache.get::<MeshVertex>(&mesh_shader, &wire_pso_desc);
 
         Graphics {
             context: context,
@@ -213,8 +402,9 @@ impl<'a> Graphics<'a>
 			default_shader: mesh_shader,
 			blit_pso: blit_pso,
 			default_pso: mesh_pso,
-            missing_tex: load_texture2d_from_file(Path::new("assets/img/missing_512.png")),
-			default_sampler: Sampler2DDesc::default().build()
+            missing_tex: load_texture2d(Path::new("assets/img/missing_512.png"), ColorSpace::Srgb).unwrap(),
+			default_sampler: Sampler2DDesc::default().build(),
+			sprites: RefCell::new(SpriteBatch::new())
         }
     }
 
@@ -231,79 +421,83 @@ impl<'a> Graphics<'a>
         self.context
     }
 
-	/// Draw a mesh with the specified shader and parameters
+	/// Draw all parts of a mesh with the specified shader and parameters
 	pub fn draw_mesh_with_shader(&self, mesh: &Mesh, pipeline_state: &PipelineState, bindings: &[Binding], frame: &Frame)
 	{
-		frame.draw(
-			mesh.vb.raw.as_raw_buf_slice(),
-			mesh.ib.as_ref().map(|ib| ib.raw.as_raw_buf_slice()),
-			&pipeline_state,
-			mesh.parts[0],
-			bindings,
-			&[]);
+		for i in 0..mesh.parts.len() {
+			self.draw_mesh_part_with_shader(mesh, i, pipeline_state, bindings, frame);
+		}
+	}
+
+	/// Draw one part of a mesh with the specified shader and parameters
+	pub fn draw_mesh_part_with_shader(&self, mesh: &Mesh, part_index: usize, pipeline_state: &PipelineState, bindings: &[Binding], frame: &Frame)
+	{
+		pipeline_state.layout.check_vertex_format::<MeshVertex>();
+		let mut dc = frame.draw_call(pipeline_state).mesh_part(mesh, part_index);
+		for b in bindings.iter() {
+			dc = dc.uniform_buffer(b.slot, b.slice);
+		}
+		dc.submit();
+	}
+
+	/// Draw one part of a mesh at a level of detail
+	pub fn draw_mesh_lod_with_shader(&self, mesh: &Mesh, part_index: usize, lod: usize, pipeline_state: &PipelineState, bindings: &[Binding], frame: &Frame)
+	{
+		pipeline_state.layout.check_vertex_format::<MeshVertex>();
+		let mut dc = frame.draw_call(pipeline_state).mesh(mesh).part(mesh.lod_part(part_index, lod));
+		for b in bindings.iter() {
+			dc = dc.uniform_buffer(b.slot, b.slice);
+		}
+		dc.submit();
 	}
 
     /// Draw a mesh in wireframe
     pub fn draw_wire_mesh(&self, mesh: &Mesh, bindings: &[Binding], frame: &Frame)
     {
-		frame.draw(
-			mesh.vb.raw.as_raw_buf_slice(),
-			mesh.ib.as_ref().map(|ib| ib.raw.as_raw_buf_slice()),
-			&self.default_pso,
-			mesh.parts[0],
-			bindings,
-			&[]);
+		self.draw_mesh_with_shader(mesh, &self.default_pso, bindings, frame);
     }
 
-    /// Blit a texture in the frame
-    pub fn blit(&self, texture: &Texture2D, rect: &Rect, frame: &Frame)
+    /// Draw and clear a batch of sprites
+    pub fn draw_sprites(&self, batch: &mut SpriteBatch, frame: &Frame)
     {
-        #[derive(Copy, Clone)]
-        #[repr(C)]
-        struct Vertex2D {
-            pos: [f32; 2],
-            tex: [f32; 2]
-        }
+        batch.flush(&self.blit_pso, &self.default_sampler, frame);
+    }
+
+    /// Blit a texture in the frame, at the next flush_sprites.
+    /// The texture must outlive the Graphics (e.g. HUD textures loaded at startup):
+    /// draw other textures with a SpriteBatch and draw_sprites.
+    pub fn blit(&self, texture: &'a Texture2D, rect: &Rect)
+    {
+        self.sprites.borrow_mut().draw(Sprite::new(texture, *rect));
+    }
 
-        #[derive(Copy, Clone)]
-        #[repr(C)]
-        struct BlitData {
-            viewport_size: [f32; 2]
-        };
-
-        let (width, height) = frame.dimensions();
-
-        let buf = frame.alloc_temporary_buffer(6, BufferBindingHint::VertexBuffer, Some(&[
-            Vertex2D { pos : [rect.left, rect.top], tex : [0.0, 0.0] },
-            Vertex2D { pos : [rect.right, rect.top],  tex: [1.0, 0.0] },
-            Vertex2D { pos : [rect.left, rect.bottom], tex: [0.0, 1.0] },
-            Vertex2D { pos : [rect.left, rect.bottom], tex: [0.0, 1.0] },
-            Vertex2D { pos : [rect.right, rect.top], tex: [1.0, 0.0] },
-            Vertex2D { pos : [rect.right, rect.bottom], tex: [1.0, 1.0] }
-            ]));
-        let buf_2 = frame.make_uniform_buffer(&BlitData {
-                viewport_size: [width as f32, height as f32]
-            });
-
-        frame.draw(
-            buf.as_raw(),
-            None,
-			&self.blit_pso,
-            MeshPart {
-                primitive_type: PrimitiveType::Triangle,
-                start_vertex: 0,
-                start_index: 0,
-                num_vertices: 6,
-                num_indices: 0
-                },
-            &[Binding {slot:0, slice: buf_2.as_raw() }],
-            &[TextureBinding {slot: 0, sampler: &self.default_sampler, texture: &texture}]
-            );
+    /// Blit a part of a texture (source, in texels) in the frame, at the next flush_sprites
+    pub fn blit_part(&self, texture: &'a Texture2D, source: &Rect, rect: &Rect)
+    {
+        self.sprites.borrow_mut().draw(Sprite::new(texture, *rect).source(*source));
     }
 
-    /// Blit a part of a texture in the frame
-    pub fn blit_part(&self, frame: &Frame)
+    /// Draw the sprites of blit and blit_part (once per frame, after the scene)
+    pub fn flush_sprites(&self, frame: &Frame)
     {
-        // TODO
+        self.draw_sprites(&mut self.sprites.borrow_mut(), frame);
     }
 }
+
+#[test]
+fn test_select_lod()
+{
+	let thresholds = [0.5, 0.25];
+	assert_eq!(select_lod(&thresholds, 1.0, 0, 0.1), 0);
+	assert_eq!(select_lod(&thresholds, 0.1, 0, 0.1), 2);
+	assert_eq!(select_lod(&thresholds, 0.4, 0, 0.0), 1);
+	// within the margin of a threshold: the current LOD is kept
+	assert_eq!(select_lod(&thresholds, 0.48, 0, 0.1), 0);
+	assert_eq!(select_lod(&thresholds, 0.52, 1, 0.1), 1);
+	// past the margin
+	assert_eq!(select_lod(&thresholds, 0.44, 0, 0.1), 1);
+	assert_eq!(select_lod(&thresholds, 0.56, 1, 0.1), 0);
+	// LOD past the last threshold
+	assert_eq!(select_lod(&thresholds, 0.1, 5, 0.1), 2);
+	assert_eq!(select_lod(&[], 0.1, 1, 0.1), 0);
+}
@@ -0,0 +1,417 @@
+//-------------------------------------------
+// Headless GL context creation through EGL, for machines without
+// a display or GPU (e.g. CI with Mesa's llvmpipe).
+//
+// No default framebuffer: render into offscreen Texture2D targets
+// (RenderTarget::Offscreen), and read them back with read_pixels.
+//
+// Usage:
+//     let img = render_scene_to_image(HeadlessSettings::new((1024, 768)),
+//         Path::new("assets"), Path::new("assets/scenes/scene.json")).unwrap();
+//     img.save("out.png");
+//
+// or, from the command line: almagest --headless out.png
+//
+// Requires libEGL (build with --features headless).
+
+use gl;
+use libc::{c_void, c_char};
+use std::f32::consts;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::path::Path;
+use std::ptr;
+use image::{DynamicImage, GenericImage};
+use nalgebra::*;
+use rendering::{self as rd, RenderTarget, Texture2D, TextureFormat};
+use bounds::BoundingBox;
+use camera::Camera;
+use graphics::Graphics;
+use scene::Scene;
+use terrain::TerrainRenderer;
+
+#[allow(non_camel_case_types)]
+mod egl
+{
+	use libc::{c_void, c_char};
+
+	pub type EGLBoolean = u32;
+	pub type EGLint = i32;
+	pub type EGLenum = u32;
+	pub type EGLDisplay = *mut c_void;
+	pub type EGLConfig = *mut c_void;
+	pub type EGLContext = *mut c_void;
+	pub type EGLSurface = *mut c_void;
+	pub type EGLNativeDisplayType = *mut c_void;
+
+	pub const EGL_FALSE: EGLBoolean = 0;
+	pub const EGL_TRUE: EGLBoolean = 1;
+	pub const EGL_NONE: EGLint = 0x3038;
+	pub const EGL_SUCCESS: EGLint = 0x3000;
+	pub const EGL_EXTENSIONS: EGLint = 0x3055;
+	pub const EGL_VENDOR: EGLint = 0x3053;
+	pub const EGL_SURFACE_TYPE: EGLint = 0x3033;
+	pub const EGL_PBUFFER_BIT: EGLint = 0x0001;
+	pub const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+	pub const EGL_OPENGL_BIT: EGLint = 0x0008;
+	pub const EGL_RED_SIZE: EGLint = 0x3024;
+	pub const EGL_GREEN_SIZE: EGLint = 0x3023;
+	pub const EGL_BLUE_SIZE: EGLint = 0x3022;
+	pub const EGL_ALPHA_SIZE: EGLint = 0x3021;
+	pub const EGL_DEPTH_SIZE: EGLint = 0x3025;
+	pub const EGL_WIDTH: EGLint = 0x3057;
+	pub const EGL_HEIGHT: EGLint = 0x3056;
+	pub const EGL_OPENGL_API: EGLenum = 0x30A2;
+	pub const EGL_CONTEXT_MAJOR_VERSION: EGLint = 0x3098;
+	pub const EGL_CONTEXT_MINOR_VERSION: EGLint = 0x30FB;
+	pub const EGL_CONTEXT_OPENGL_PROFILE_MASK: EGLint = 0x30FD;
+	pub const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: EGLint = 0x0001;
+	/// EGL 1.5 only
+	pub const EGL_CONTEXT_OPENGL_DEBUG: EGLint = 0x31B0;
+	/// EGL_KHR_create_context, for EGL 1.4
+	pub const EGL_CONTEXT_FLAGS_KHR: EGLint = 0x30FC;
+	pub const EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR: EGLint = 0x0001;
+	pub const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+
+	pub const EGL_DEFAULT_DISPLAY: EGLNativeDisplayType = 0 as EGLNativeDisplayType;
+	pub const EGL_NO_DISPLAY: EGLDisplay = 0 as EGLDisplay;
+	pub const EGL_NO_CONTEXT: EGLContext = 0 as EGLContext;
+	pub const EGL_NO_SURFACE: EGLSurface = 0 as EGLSurface;
+
+	pub type PFNEGLGETPLATFORMDISPLAYEXTPROC = extern "system" fn(
+		platform: EGLenum,
+		native_display: *mut c_void,
+		attrib_list: *const EGLint) -> EGLDisplay;
+
+	#[link(name = "EGL")]
+	extern "system" {
+		pub fn eglGetError() -> EGLint;
+		pub fn eglGetDisplay(display_id: EGLNativeDisplayType) -> EGLDisplay;
+		pub fn eglInitialize(dpy: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+		pub fn eglTerminate(dpy: EGLDisplay) -> EGLBoolean;
+		pub fn eglQueryString(dpy: EGLDisplay, name: EGLint) -> *const c_char;
+		pub fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+		pub fn eglChooseConfig(dpy: EGLDisplay, attrib_list: *const EGLint, configs: *mut EGLConfig,
+			config_size: EGLint, num_config: *mut EGLint) -> EGLBoolean;
+		pub fn eglCreateContext(dpy: EGLDisplay, config: EGLConfig, share_context: EGLContext,
+			attrib_list: *const EGLint) -> EGLContext;
+		pub fn eglDestroyContext(dpy: EGLDisplay, ctx: EGLContext) -> EGLBoolean;
+		pub fn eglCreatePbufferSurface(dpy: EGLDisplay, config: EGLConfig, attrib_list: *const EGLint) -> EGLSurface;
+		pub fn eglDestroySurface(dpy: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+		pub fn eglMakeCurrent(dpy: EGLDisplay, draw: EGLSurface, read: EGLSurface, ctx: EGLContext) -> EGLBoolean;
+		pub fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+	}
+}
+
+use self::egl::*;
+
+#[derive(Debug)]
+pub enum HeadlessError
+{
+	/// No EGL display could be opened
+	NoDisplay,
+	/// eglInitialize failed
+	Initialize(i32),
+	/// No config supports desktop OpenGL with pbuffers
+	NoMatchingConfig,
+	/// Context creation failed (the requested GL version may be unsupported)
+	CreateContext(i32),
+	CreateSurface(i32),
+	MakeCurrent(i32)
+}
+
+impl fmt::Display for HeadlessError
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match *self
+		{
+			HeadlessError::NoDisplay => write!(f, "no EGL display available"),
+			HeadlessError::Initialize(e) => write!(f, "eglInitialize failed (0x{:X})", e),
+			HeadlessError::NoMatchingConfig => write!(f, "no EGL config supports OpenGL"),
+			HeadlessError::CreateContext(e) => write!(f, "eglCreateContext failed (0x{:X})", e),
+			HeadlessError::CreateSurface(e) => write!(f, "eglCreatePbufferSurface failed (0x{:X})", e),
+			HeadlessError::MakeCurrent(e) => write!(f, "eglMakeCurrent failed (0x{:X})", e)
+		}
+	}
+}
+
+pub struct HeadlessSettings
+{
+	/// Size of the offscreen targets the renderer will use (reported by HeadlessContext::dimensions)
+	dimensions: (u32, u32),
+	gl_version: (u32, u32),
+	debug: bool
+}
+
+impl HeadlessSettings
+{
+	/// OpenGL 4.5 core, debug context
+	pub fn new(dimensions: (u32, u32)) -> HeadlessSettings
+	{
+		HeadlessSettings {
+			dimensions: dimensions,
+			gl_version: (4, 5),
+			debug: true
+		}
+	}
+
+	pub fn gl_version(mut self, major: u32, minor: u32) -> HeadlessSettings
+	{
+		self.gl_version = (major, minor);
+		self
+	}
+
+	pub fn debug(mut self, debug: bool) -> HeadlessSettings
+	{
+		self.debug = debug;
+		self
+	}
+
+	pub fn build(self) -> Result<HeadlessContext, HeadlessError>
+	{
+		HeadlessContext::new(self)
+	}
+}
+
+/// An OpenGL context without a window.
+/// The context is made current on the creating thread; it is
+/// destroyed when dropped (drop all GL resources before).
+pub struct HeadlessContext
+{
+	display: EGLDisplay,
+	context: EGLContext,
+	/// EGL_NO_SURFACE if the implementation supports surfaceless contexts
+	surface: EGLSurface,
+	dimensions: (u32, u32)
+}
+
+fn has_extension(extensions: *const c_char, name: &str) -> bool
+{
+	if extensions.is_null() {
+		return false;
+	}
+	let extensions = unsafe { CStr::from_ptr(extensions) }.to_string_lossy();
+	extensions.split(' ').any(|e| e == name)
+}
+
+unsafe fn open_display() -> EGLDisplay
+{
+	// prefer Mesa's surfaceless platform: it never touches X11 or Wayland
+	let client_extensions = eglQueryString(EGL_NO_DISPLAY, EGL_EXTENSIONS);
+	if has_extension(client_extensions, "EGL_MESA_platform_surfaceless") &&
+		has_extension(client_extensions, "EGL_EXT_platform_base")
+	{
+		let name = CString::new("eglGetPlatformDisplayEXT").unwrap();
+		let f = eglGetProcAddress(name.as_ptr());
+		if !f.is_null() {
+			let get_platform_display: PFNEGLGETPLATFORMDISPLAYEXTPROC = ::std::mem::transmute(f);
+			let display = get_platform_display(EGL_PLATFORM_SURFACELESS_MESA,
+				EGL_DEFAULT_DISPLAY, ptr::null());
+			if display != EGL_NO_DISPLAY {
+				return display;
+			}
+		}
+	}
+	eglGetDisplay(EGL_DEFAULT_DISPLAY)
+}
+
+impl HeadlessContext
+{
+	pub fn new(settings: HeadlessSettings) -> Result<HeadlessContext, HeadlessError>
+	{
+		unsafe
+		{
+			let display = open_display();
+			if display == EGL_NO_DISPLAY {
+				return Err(HeadlessError::NoDisplay);
+			}
+			let (mut major, mut minor) = (0, 0);
+			if eglInitialize(display, &mut major, &mut minor) == EGL_FALSE {
+				return Err(HeadlessError::Initialize(eglGetError()));
+			}
+			let vendor = eglQueryString(display, EGL_VENDOR);
+			if !vendor.is_null() {
+				info!("EGL {}.{} ({})", major, minor, CStr::from_ptr(vendor).to_string_lossy());
+			}
+
+			let mut result = HeadlessContext {
+				display: display,
+				context: EGL_NO_CONTEXT,
+				surface: EGL_NO_SURFACE,
+				dimensions: settings.dimensions
+			};
+
+			// on error, the partially initialized context is cleaned up by drop
+			eglBindAPI(EGL_OPENGL_API);
+			let config_attribs = [
+				EGL_SURFACE_TYPE, EGL_PBUFFER_BIT,
+				EGL_RENDERABLE_TYPE, EGL_OPENGL_BIT,
+				EGL_RED_SIZE, 8,
+				EGL_GREEN_SIZE, 8,
+				EGL_BLUE_SIZE, 8,
+				EGL_ALPHA_SIZE, 8,
+				EGL_DEPTH_SIZE, 24,
+				EGL_NONE];
+			let mut config: EGLConfig = ptr::null_mut();
+			let mut num_configs = 0;
+			if eglChooseConfig(display, config_attribs.as_ptr(), &mut config, 1, &mut num_configs) == EGL_FALSE ||
+				num_configs == 0
+			{
+				return Err(HeadlessError::NoMatchingConfig);
+			}
+
+			let display_extensions = eglQueryString(display, EGL_EXTENSIONS);
+			let mut context_attribs = vec![
+				EGL_CONTEXT_MAJOR_VERSION, settings.gl_version.0 as EGLint,
+				EGL_CONTEXT_MINOR_VERSION, settings.gl_version.1 as EGLint,
+				EGL_CONTEXT_OPENGL_PROFILE_MASK, EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT];
+			if settings.debug {
+				// EGL_CONTEXT_OPENGL_DEBUG is rejected by EGL 1.4 implementations
+				if (major, minor) >= (1, 5) {
+					context_attribs.push_all(&[EGL_CONTEXT_OPENGL_DEBUG, EGL_TRUE as EGLint]);
+				} else if has_extension(display_extensions, "EGL_KHR_create_context") {
+					context_attribs.push_all(&[EGL_CONTEXT_FLAGS_KHR, EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR]);
+				} else {
+					warn!("EGL {}.{} without EGL_KHR_create_context: no debug context", major, minor);
+				}
+			}
+			context_attribs.push(EGL_NONE);
+			result.context = eglCreateContext(display, config, EGL_NO_CONTEXT, context_attribs.as_ptr());
+			if result.context == EGL_NO_CONTEXT {
+				return Err(HeadlessError::CreateContext(eglGetError()));
+			}
+
+			// without EGL_KHR_surfaceless_context, a context must be made current with a surface:
+			// use a dummy 1x1 pbuffer (rendering goes to framebuffer objects anyway)
+			if !has_extension(display_extensions, "EGL_KHR_surfaceless_context") {
+				let pbuffer_attribs = [EGL_WIDTH, 1, EGL_HEIGHT, 1, EGL_NONE];
+				result.surface = eglCreatePbufferSurface(display, config, pbuffer_attribs.as_ptr());
+				if result.surface == EGL_NO_SURFACE {
+					return Err(HeadlessError::CreateSurface(eglGetError()));
+				}
+			}
+
+			try!(result.make_current());
+
+			// Load GL function pointers
+			gl::load_with(|s| {
+				let name = CString::new(s).unwrap();
+				eglGetProcAddress(name.as_ptr())
+			});
+
+			Ok(result)
+		}
+	}
+
+	/// Make the context current on the calling thread
+	pub fn make_current(&self) -> Result<(), HeadlessError>
+	{
+		unsafe {
+			if eglMakeCurrent(self.display, self.surface, self.surface, self.context) == EGL_FALSE {
+				Err(HeadlessError::MakeCurrent(eglGetError()))
+			} else {
+				Ok(())
+			}
+		}
+	}
+
+	/// Size of the offscreen targets, as specified in the settings
+	pub fn dimensions(&self) -> (u32, u32)
+	{
+		self.dimensions
+	}
+
+	/// GL_RENDERER string (e.g. 'llvmpipe (LLVM 15.0.7, 256 bits)')
+	pub fn renderer(&self) -> String
+	{
+		unsafe {
+			let s = gl::GetString(gl::RENDERER);
+			if s.is_null() {
+				String::new()
+			} else {
+				CStr::from_ptr(s as *const c_char).to_string_lossy().into_owned()
+			}
+		}
+	}
+}
+
+impl Drop for HeadlessContext
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			eglMakeCurrent(self.display, EGL_NO_SURFACE, EGL_NO_SURFACE, EGL_NO_CONTEXT);
+			if self.surface != EGL_NO_SURFACE {
+				eglDestroySurface(self.display, self.surface);
+			}
+			if self.context != EGL_NO_CONTEXT {
+				eglDestroyContext(self.display, self.context);
+			}
+			eglTerminate(self.display);
+		}
+	}
+}
+
+/// Camera looking at a bounding box from above and in front, so that the whole box is visible
+fn framing_camera(bounds: &BoundingBox, aspect_ratio: f32) -> Camera
+{
+	let (center, radius) = if bounds.is_empty() {
+		(Vec3::new(0.0, 0.0, 0.0), 1.0)
+	} else {
+		(bounds.center(), bounds.extents().norm().max(0.01))
+	};
+	let yfov = consts::FRAC_PI_4;
+	let distance = radius / (0.5 * yfov.min(yfov * aspect_ratio)).sin();
+	let eye = center + Vec3::new(0.0, 0.5, 1.0).normalize() * distance;
+	Camera::look_at(
+		Pnt3::new(eye.x, eye.y, eye.z),
+		Pnt3::new(center.x, center.y, center.z),
+		aspect_ratio, yfov,
+		0.01 * distance, distance + 2.0 * radius)
+}
+
+/// Render a scene file into an offscreen frame, and read it back.
+/// The scene is seen from its first camera, or from a camera framing all its entities.
+/// All GL resources are released before the headless context is destroyed.
+pub fn render_scene_to_image(settings: HeadlessSettings, asset_root: &Path, scene_path: &Path) -> Result<DynamicImage, HeadlessError>
+{
+	let headless = try!(settings.build());
+	let (width, height) = headless.dimensions();
+	info!("Headless rendering on {}", headless.renderer());
+
+	let image = {
+		let ctx = rd::Context::new((width, height));
+		let graphics = Graphics::new(&ctx);
+		let terrain_renderer = TerrainRenderer::new();
+		let mut scene = Scene::load(&ctx, asset_root, scene_path);
+
+		let aspect_ratio = width as f32 / height as f32;
+		let cam = match scene.cameras().first() {
+			Some(c) => c.to_camera(aspect_ratio),
+			None => framing_camera(&scene.bounding_box(), aspect_ratio)
+		};
+
+		let color = Texture2D::new(width, height, 1, TextureFormat::Srgb8Alpha8);
+		let depth = Texture2D::new(width, height, 1, TextureFormat::Depth24);
+		scene.render(&graphics, &terrain_renderer,
+			&RenderTarget::Offscreen { color: &color, depth: &depth }, &ctx, &cam);
+		ctx.end_frame();
+		color.read_pixels(0)
+	};
+	Ok(image)
+}
+
+#[test]
+fn test_render_scene_headless()
+{
+	let img = render_scene_to_image(
+		HeadlessSettings::new((128, 96)),
+		Path::new("assets"),
+		Path::new("assets/scenes/scene.json")).unwrap();
+	assert_eq!(img.dimensions(), (128, 96));
+	// something else than the clear color was drawn
+	let rgba = img.to_rgba();
+	let first = *rgba.get_pixel(0, 0);
+	assert!(rgba.pixels().any(|p| *p != first));
+}
@@ -0,0 +1,130 @@
+//-------------------------------------------
+// Layout macros
+//
+// Declare a #[repr(C)], Copy structure and implement the layout trait for it.
+// The GPU type of each field is deduced from its Rust type
+// (see AttributeField and Std140Field in rendering::layout).
+// Attributes (including doc comments) on the structure and on the fields are kept.
+
+/// Declare a vertex structure and implement rendering::VertexFormat for it.
+/// Fields are in shader location order.
+///
+///     vertex_format! {
+///         pub struct Vertex2D { pub pos: [f32; 2], pub tex: [f32; 2] }
+///     }
+macro_rules! vertex_format {
+	(@impl $name:ident { $($field:ident : $ty:ty),* }) => {
+		impl ::rendering::VertexFormat for $name
+		{
+			fn name() -> &'static str { stringify!($name) }
+
+			fn attributes() -> Vec<::rendering::VertexAttribute>
+			{
+				// all field types are plain data (AttributeField), so a zeroed instance is valid
+				let v: $name = unsafe { ::std::mem::zeroed() };
+				vec![$(::rendering::VertexAttribute {
+					name: stringify!($field),
+					ty: <$ty as ::rendering::AttributeField>::ATTRIBUTE_TYPE,
+					offset: ::rendering::field_offset(&v, &v.$field),
+					size: ::std::mem::size_of::<$ty>()
+				}),*]
+			}
+		}
+	};
+	($(#[$attr:meta])* pub struct $name:ident { $($(#[$fattr:meta])* pub $field:ident : $ty:ty),* $(,)* }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Copy, Clone)]
+		pub struct $name
+		{
+			$($(#[$fattr])* pub $field: $ty),*
+		}
+
+		vertex_format!(@impl $name { $($field: $ty),* });
+	};
+	($(#[$attr:meta])* struct $name:ident { $($(#[$fattr:meta])* $field:ident : $ty:ty),* $(,)* }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Copy, Clone)]
+		struct $name
+		{
+			$($(#[$fattr])* $field: $ty),*
+		}
+
+		vertex_format!(@impl $name { $($field: $ty),* });
+	}
+}
+
+/// Declare a uniform block structure and implement rendering::UniformBlock for it.
+/// Explicit padding uses rendering::Padding fields. The structure does not
+/// compile if a field is not where the std140 layout puts it.
+///
+///     uniform_block! {
+///         struct SkyParams { model_matrix: Mat4<f32>, turbidity: f32 }
+///     }
+macro_rules! uniform_block {
+	(@std140 $cursor:expr;) => { $cursor };
+	(@std140 $cursor:expr; $ty:ty $(, $rest:ty)*) => {
+		uniform_block!(@std140 ::rendering::std140_next($cursor, <$ty as ::rendering::Std140Field>::LAYOUT); $($rest),*)
+	};
+	(@impl $name:ident { $($field:ident : $ty:ty),* }) => {
+		impl ::rendering::UniformBlock for $name
+		{
+			fn name() -> &'static str { stringify!($name) }
+
+			fn fields() -> Vec<::rendering::UniformField>
+			{
+				// compile-time std140 check: the array length is 0 if a field is misplaced
+				// (check_std140_layout reports which one)
+				let _std140: [(); 1] = [(); uniform_block!(@std140 ::rendering::STD140_START; $($ty),*).ok as usize];
+
+				// all field types are plain data (Std140Field), so a zeroed instance is valid
+				let v: $name = unsafe { ::std::mem::zeroed() };
+				let mut fields = Vec::new();
+				$(if let Some(ty) = <$ty as ::rendering::Std140Field>::UNIFORM_TYPE {
+					fields.push(::rendering::UniformField {
+						name: stringify!($field),
+						ty: ty,
+						offset: ::rendering::field_offset(&v, &v.$field),
+						size: ::std::mem::size_of::<$ty>()
+					});
+				})*
+				fields
+			}
+		}
+	};
+	($(#[$attr:meta])* pub struct $name:ident { $($(#[$fattr:meta])* pub $field:ident : $ty:ty),* $(,)* }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Copy, Clone)]
+		pub struct $name
+		{
+			$($(#[$fattr])* pub $field: $ty),*
+		}
+
+		uniform_block!(@impl $name { $($field: $ty),* });
+	};
+	($(#[$attr:meta])* struct $name:ident { $($(#[$fattr:meta])* $field:ident : $ty:ty),* $(,)* }) => {
+		$(#[$attr])*
+		#[repr(C)]
+		#[derive(Copy, Clone)]
+		struct $name
+		{
+			$($(#[$fattr])* $field: $ty),*
+		}
+
+		uniform_block!(@impl $name { $($field: $ty),* });
+	}
+}
+
+/// Open an AllocationScope named after the current source location
+///
+///     let _alloc = allocation_scope!("Scene::load");	// "Scene::load (src/scene.rs:267)"
+macro_rules! allocation_scope {
+	() => {
+		::rendering::AllocationScope::new(concat!(file!(), ":", line!()))
+	};
+	($name:expr) => {
+		::rendering::AllocationScope::new(concat!($name, " (", file!(), ":", line!(), ")"))
+	}
+}
@@ -27,17 +27,31 @@ extern crate serde_json;
 extern crate bitflags;
 extern crate combine;
 
+#[macro_use]
+mod macros;
 mod scene;
 mod rendering;
 mod camera;
 mod event;
 mod window;
+#[cfg(feature = "headless")]
+mod headless;
 mod material;
 mod scene_data;
 mod terrain;
 mod asset_loader;
+mod texture_loader;
+mod mesh_data;
+mod tangent_space;
+mod primitives;
+mod mesh_optimizer;
+mod mesh_simplify;
+mod mesh_file;
+mod bounds;
+mod gltf_loader;
 mod shadow_pass;
 mod graphics;
+mod sprite_batch;
 mod player;
 
 mod sample_scene;
@@ -6,13 +6,171 @@ use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::collections::HashMap;
 use std::cell::{RefCell};
+use tobj;
+use texture_loader::{load_texture2d, load_texture2d_from_memory, TextureLoadError};
 
+/// Where the image of a texture comes from
+#[derive(Clone)]
+pub enum TextureSource
+{
+	File(PathBuf),
+	/// Encoded image (PNG, JPEG) embedded in a model file
+	Memory { name: String, data: Rc<Vec<u8>> }
+}
+
+impl TextureSource
+{
+	/// Key identifying the image in a texture cache
+	pub fn key(&self) -> String
+	{
+		match *self {
+			TextureSource::File(ref path) => path.to_string_lossy().into_owned(),
+			TextureSource::Memory { ref name, .. } => name.clone()
+		}
+	}
+
+	pub fn load(&self, color_space: ColorSpace) -> Result<Texture2D, TextureLoadError>
+	{
+		match *self {
+			TextureSource::File(ref path) => load_texture2d(path, color_space),
+			TextureSource::Memory { ref name, ref data } => load_texture2d_from_memory(&data[..], name, color_space)
+		}
+	}
+}
+
+/// A texture referenced by a material
+#[derive(Clone)]
+pub struct TextureDesc
+{
+	pub source: TextureSource,
+	/// Sampler state, if specified by the model file
+	pub sampler: Option<Sampler2DDesc>
+}
+
+/// Material parameters as found in model files
+#[derive(Clone)]
+pub struct MaterialDesc
+{
+	pub name: String,
+	pub diffuse_color: [f32; 3],
+	pub specular_color: [f32; 3],
+	pub shininess: f32,
+	/// metallic-roughness parameters
+	pub metallic: f32,
+	pub roughness: f32,
+	pub emissive_color: [f32; 3],
+	pub diffuse_texture: Option<TextureDesc>,
+	pub normal_texture: Option<TextureDesc>,
+	/// metalness in the blue channel, roughness in the green channel
+	pub metallic_roughness_texture: Option<TextureDesc>,
+	pub occlusion_texture: Option<TextureDesc>,
+	pub emissive_texture: Option<TextureDesc>
+}
+
+fn texture_path(base_dir: &Path, name: &str) -> Option<TextureDesc>
+{
+	if name.is_empty() {
+		None
+	} else {
+		Some(TextureDesc {
+			source: TextureSource::File(base_dir.join(name)),
+			sampler: None
+		})
+	}
+}
+
+/// Approximate conversions between the Blinn-Phong exponent and the roughness
+pub fn shininess_to_roughness(shininess: f32) -> f32
+{
+	(2.0 / (shininess + 2.0)).sqrt()
+}
+
+pub fn roughness_to_shininess(roughness: f32) -> f32
+{
+	let r = roughness.max(0.01);
+	2.0 / (r * r) - 2.0
+}
+
+impl MaterialDesc
+{
+	/// Material from a MTL library (Kd, Ks, Ns, map_Kd, norm/map_bump)
+	/// Texture paths are relative to base_dir.
+	pub fn from_mtl(mtl: &tobj::Material, base_dir: &Path) -> MaterialDesc
+	{
+		MaterialDesc {
+			name: mtl.name.clone(),
+			diffuse_color: mtl.diffuse,
+			specular_color: mtl.specular,
+			shininess: mtl.shininess,
+			metallic: 0.0,
+			roughness: shininess_to_roughness(mtl.shininess),
+			emissive_color: [0.0; 3],
+			diffuse_texture: texture_path(base_dir, &mtl.diffuse_texture),
+			normal_texture: texture_path(base_dir, &mtl.normal_texture),
+			metallic_roughness_texture: None,
+			occlusion_texture: None,
+			emissive_texture: None
+		}
+	}
+}
+
+/// Bits of MaterialParams::texture_flags: which optional textures are bound
+pub const MATERIAL_NORMAL_MAP: i32 = 1 << 0;
+pub const MATERIAL_METALLIC_ROUGHNESS_MAP: i32 = 1 << 1;
+pub const MATERIAL_OCCLUSION_MAP: i32 = 1 << 2;
+pub const MATERIAL_EMISSIVE_MAP: i32 = 1 << 3;
+
+uniform_block! {
+	/// Material parameters, as seen by shaders (MaterialParams block, binding 3)
+	pub struct MaterialParams
+	{
+		pub diffuse_color: [f32; 3],
+		pub _pad0: Padding<f32>,
+		pub specular_color: [f32; 3],
+		pub shininess: f32,
+		pub emissive_color: [f32; 3],
+		pub metallic: f32,
+		pub roughness: f32,
+		pub texture_flags: i32,
+		pub _pad1: Padding<[f32; 2]>
+	}
+}
+
+impl MaterialParams
+{
+	pub fn default() -> MaterialParams
+	{
+		MaterialParams {
+			diffuse_color: [1.0; 3],
+			_pad0: Padding::default(),
+			specular_color: [0.0; 3],
+			shininess: 0.0,
+			emissive_color: [0.0; 3],
+			metallic: 0.0,
+			roughness: 1.0,
+			texture_flags: 0,
+			_pad1: Padding::default()
+		}
+	}
+}
 
 /// Describes the appearance of an object
 pub struct Material
 {
 	pub main_tex: Rc<Texture2D>,
+	pub normal_tex: Option<Rc<Texture2D>>,
+	/// Sampler states from the model file (otherwise, the texture parameters are used)
+	pub main_sampler: Option<Sampler2D>,
+	pub normal_sampler: Option<Sampler2D>,
+	/// glTF metallic-roughness, occlusion and emissive textures
+	pub metallic_roughness_tex: Option<Rc<Texture2D>>,
+	pub metallic_roughness_sampler: Option<Sampler2D>,
+	pub occlusion_tex: Option<Rc<Texture2D>>,
+	pub occlusion_sampler: Option<Sampler2D>,
+	pub emissive_tex: Option<Rc<Texture2D>>,
+	pub emissive_sampler: Option<Sampler2D>,
     pub shader: Rc<Shader>,
+	pub params: MaterialParams
 }
 
 impl Material
@@ -23,14 +181,96 @@ impl Material
 		Material
 		{
             shader: shader,
-			main_tex: main_tex
+			main_tex: main_tex,
+			normal_tex: None,
+			main_sampler: None,
+			normal_sampler: None,
+			metallic_roughness_tex: None,
+			metallic_roughness_sampler: None,
+			occlusion_tex: None,
+			occlusion_sampler: None,
+			emissive_tex: None,
+			emissive_sampler: None,
+			params: MaterialParams::default()
 		}
 	}
 
-	/// bind the material to pipeline
-	/// currently, the only state set is the texture unit 0
+	/// create a material from a description
+	/// main_tex is used if the description has no diffuse texture, or if it cannot be loaded
+	/// (load_texture returns None)
+	pub fn from_desc<F: Fn(&TextureSource, ColorSpace) -> Option<Rc<Texture2D>>>(
+		desc: &MaterialDesc,
+		shader: Rc<Shader>,
+		main_tex: Rc<Texture2D>,
+		load_texture: F) -> Material
+	{
+		let sampler = |t: &Option<TextureDesc>| t.as_ref().and_then(|t| t.sampler.as_ref()).map(|s| s.build());
+		let load = |t: &Option<TextureDesc>, color_space: ColorSpace| t.as_ref().and_then(|t| load_texture(&t.source, color_space));
+		// only the albedo and emission are color data
+		let normal_tex = load(&desc.normal_texture, ColorSpace::Linear);
+		let metallic_roughness_tex = load(&desc.metallic_roughness_texture, ColorSpace::Linear);
+		let occlusion_tex = load(&desc.occlusion_texture, ColorSpace::Linear);
+		let emissive_tex = load(&desc.emissive_texture, ColorSpace::Srgb);
+
+		let mut texture_flags = 0;
+		for &(tex, flag) in [(&normal_tex, MATERIAL_NORMAL_MAP),
+		                     (&metallic_roughness_tex, MATERIAL_METALLIC_ROUGHNESS_MAP),
+		                     (&occlusion_tex, MATERIAL_OCCLUSION_MAP),
+		                     (&emissive_tex, MATERIAL_EMISSIVE_MAP)].iter() {
+			if tex.is_some() {
+				texture_flags |= flag;
+			}
+		}
+
+		Material
+		{
+			shader: shader,
+			main_tex: load(&desc.diffuse_texture, ColorSpace::Srgb).unwrap_or(main_tex),
+			normal_tex: normal_tex,
+			main_sampler: sampler(&desc.diffuse_texture),
+			normal_sampler: sampler(&desc.normal_texture),
+			metallic_roughness_tex: metallic_roughness_tex,
+			metallic_roughness_sampler: sampler(&desc.metallic_roughness_texture),
+			occlusion_tex: occlusion_tex,
+			occlusion_sampler: sampler(&desc.occlusion_texture),
+			emissive_tex: emissive_tex,
+			emissive_sampler: sampler(&desc.emissive_texture),
+			params: MaterialParams {
+				diffuse_color: desc.diffuse_color,
+				_pad0: Padding::default(),
+				specular_color: desc.specular_color,
+				shininess: desc.shininess,
+				emissive_color: desc.emissive_color,
+				metallic: desc.metallic,
+				roughness: desc.roughness,
+				texture_flags: texture_flags,
+				_pad1: Padding::default()
+			}
+		}
+	}
+
+	/// bind the material textures to the pipeline
+	/// main texture: unit 0, normal map: unit 2, metallic-roughness: unit 3,
+	/// occlusion: unit 4, emission: unit 5.
+	/// Optional textures are only bound if present (see MaterialParams::texture_flags).
+	/// material parameters must be bound separately (see MaterialParams)
 	pub fn bind(&self)
 	{
-		self.main_tex.bind(0);
+		bind_texture(0, Some(&self.main_tex), &self.main_sampler);
+		bind_texture(2, self.normal_tex.as_ref(), &self.normal_sampler);
+		bind_texture(3, self.metallic_roughness_tex.as_ref(), &self.metallic_roughness_sampler);
+		bind_texture(4, self.occlusion_tex.as_ref(), &self.occlusion_sampler);
+		bind_texture(5, self.emissive_tex.as_ref(), &self.emissive_sampler);
+	}
+}
+
+fn bind_texture(unit: u32, texture: Option<&Rc<Texture2D>>, sampler: &Option<Sampler2D>)
+{
+	if let Some(texture) = texture {
+		texture.bind(unit);
+		match *sampler {
+			Some(ref s) => s.bind(unit),
+			None => Sampler2D::unbind(unit)
+		}
 	}
 }
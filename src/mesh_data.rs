@@ -0,0 +1,532 @@
+use std::f32;
+use std::fmt;
+use std::mem;
+use std::path::{Path, PathBuf};
+use tobj;
+use graphics::MeshVertex;
+use material::MaterialDesc;
+use rendering::*;
+use tangent_space::{generate_normals, generate_tangents};
+use mesh_optimizer::{MeshOptimization, OptimizationStats, optimize_triangles, optimize_vertex_cache};
+use mesh_simplify::simplify;
+
+//-------------------------------------------
+// Mesh data in CPU memory, as read from model files,
+// before upload with Mesh::from_data.
+
+#[derive(Debug)]
+pub enum MeshLoadError
+{
+	Obj(tobj::LoadError),
+	/// Inconsistent file contents (attribute counts, indices out of range...)
+	InvalidData(String)
+}
+
+impl From<tobj::LoadError> for MeshLoadError
+{
+	fn from(err: tobj::LoadError) -> MeshLoadError
+	{
+		MeshLoadError::Obj(err)
+	}
+}
+
+impl fmt::Display for MeshLoadError
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match *self {
+			MeshLoadError::Obj(ref err) => write!(f, "could not parse OBJ file: {:?}", err),
+			MeshLoadError::InvalidData(ref msg) => write!(f, "invalid mesh data: {}", msg)
+		}
+	}
+}
+
+fn invalid<T>(msg: String) -> Result<T, MeshLoadError>
+{
+	Err(MeshLoadError::InvalidData(msg))
+}
+
+/// How normals are generated for meshes that have none
+#[derive(Copy, Clone, Debug)]
+pub enum NormalGeneration
+{
+	/// One normal per face
+	Flat,
+	/// Faces are smoothed together unless they form an angle
+	/// greater than crease_angle (radians)
+	Smooth { crease_angle: f32 }
+}
+
+impl NormalGeneration
+{
+	pub fn crease_angle(self) -> f32
+	{
+		match self {
+			NormalGeneration::Flat => 0.0,
+			NormalGeneration::Smooth { crease_angle } => crease_angle
+		}
+	}
+}
+
+/// Levels of detail generated by MeshData::generate_lods
+#[derive(Copy, Clone, Debug)]
+pub struct LodOptions
+{
+	/// Number of LODs, in addition to the full resolution mesh
+	pub num_levels: usize,
+	/// Ratio of the triangle counts of two successive LODs
+	pub triangle_ratio: f32,
+	/// Maximum simplification error, relative to the radius of the mesh
+	pub max_error: f32,
+	/// Screen size (see Mesh::select_lod) below which the first LOD is used
+	pub screen_size: f32,
+	/// Ratio of the screen sizes of two successive LODs
+	pub screen_size_ratio: f32
+}
+
+impl LodOptions
+{
+	pub fn default() -> LodOptions
+	{
+		LodOptions {
+			num_levels: 3,
+			triangle_ratio: 0.5,
+			max_error: 0.05,
+			screen_size: 0.5,
+			screen_size_ratio: 0.5
+		}
+	}
+
+	pub fn with_num_levels(self, num_levels: usize) -> Self
+	{
+		LodOptions {
+			num_levels: num_levels,
+			.. self
+		}
+	}
+
+	pub fn with_screen_sizes(self, screen_size: f32, screen_size_ratio: f32) -> Self
+	{
+		LodOptions {
+			screen_size: screen_size,
+			screen_size_ratio: screen_size_ratio,
+			.. self
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MeshLoadOptions
+{
+	pub normals: NormalGeneration,
+	/// Optimizations run on the loaded mesh (none by default)
+	pub optimization: Option<MeshOptimization>,
+	/// LODs generated for the loaded mesh (none by default)
+	pub lods: Option<LodOptions>
+}
+
+impl MeshLoadOptions
+{
+	pub fn default() -> MeshLoadOptions
+	{
+		MeshLoadOptions {
+			normals: NormalGeneration::Smooth { crease_angle: f32::consts::PI / 3.0 },
+			optimization: None,
+			lods: None
+		}
+	}
+
+	pub fn with_normals(self, normals: NormalGeneration) -> Self
+	{
+		MeshLoadOptions {
+			normals: normals,
+			.. self
+		}
+	}
+
+	pub fn with_optimization(self, optimization: MeshOptimization) -> Self
+	{
+		MeshLoadOptions {
+			optimization: Some(optimization),
+			.. self
+		}
+	}
+
+	pub fn with_lods(self, lods: LodOptions) -> Self
+	{
+		MeshLoadOptions {
+			lods: Some(lods),
+			.. self
+		}
+	}
+}
+
+/// A range of a mesh drawn with a single material
+/// (an object or a group in an OBJ file)
+#[derive(Clone, Debug)]
+pub struct SubMesh
+{
+	pub name: String,
+	pub part: MeshPart,
+	/// Index in MeshData::materials
+	pub material: Option<usize>,
+	/// false if the model file has no texture coordinates for this submesh
+	/// (they are left at zero, and the tangents are not computed)
+	pub has_texcoords: bool,
+	/// Simplified versions of the part, from the finest to the coarsest.
+	/// They use the vertices of the part.
+	pub lods: Vec<MeshPart>
+}
+
+/// Per-vertex data that does not fit in MeshVertex
+/// (secondary texture coordinates, vertex colors, skinning data),
+/// kept on the CPU side. One element per vertex of the mesh data.
+#[derive(Clone, Debug)]
+pub struct VertexChannel
+{
+	/// Attribute name in the model file (e.g. TEXCOORD_1, COLOR_0)
+	pub name: String,
+	/// Number of components per vertex
+	pub components: usize,
+	pub data: Vec<f32>
+}
+
+pub struct MeshData
+{
+	/// Vertices of all submeshes
+	pub vertices: Vec<MeshVertex>,
+	/// Additional vertex attributes, not uploaded by Mesh::from_data
+	pub channels: Vec<VertexChannel>,
+	/// Indices of all submeshes, relative to the first vertex of their submesh
+	pub indices: Vec<u32>,
+	pub submeshes: Vec<SubMesh>,
+	/// Materials referenced by the submeshes
+	pub materials: Vec<MaterialDesc>,
+	/// Screen size below which each LOD is used (decreasing)
+	pub lod_screen_sizes: Vec<f32>
+}
+
+impl MeshData
+{
+	pub fn new() -> MeshData
+	{
+		MeshData {
+			vertices: Vec::new(),
+			channels: Vec::new(),
+			indices: Vec::new(),
+			submeshes: Vec::new(),
+			materials: Vec::new(),
+			lod_screen_sizes: Vec::new()
+		}
+	}
+
+	/// Append a submesh. Indices are relative to the given vertices.
+	pub fn add_submesh(
+		&mut self,
+		name: &str,
+		primitive_type: PrimitiveType,
+		vertices: &[MeshVertex],
+		indices: &[u32],
+		material: Option<usize>,
+		has_texcoords: bool)
+	{
+		let part = MeshPart::indexed(
+			primitive_type,
+			IndexType::U32,
+			self.indices.len() as u32,
+			indices.len() as u32,
+			self.vertices.len() as u32,
+			vertices.len() as u32);
+		self.vertices.push_all(vertices);
+		self.indices.push_all(indices);
+		// submeshes that do not have a channel get zeroes
+		let num_vertices = self.vertices.len();
+		for c in self.channels.iter_mut() {
+			c.data.resize(num_vertices * c.components, 0.0);
+		}
+		self.submeshes.push(SubMesh {
+			name: name.to_string(),
+			part: part,
+			material: material,
+			has_texcoords: has_texcoords,
+			lods: Vec::new()
+		});
+	}
+
+	/// Set the values of an additional vertex attribute for the vertices
+	/// of the last submesh added
+	pub fn set_channel_data(&mut self, name: &str, components: usize, data: &[f32])
+	{
+		let num_vertices = self.vertices.len();
+		let first_vertex = self.submeshes.last().map(|s| s.part.start_vertex as usize).unwrap_or(0);
+		assert!(data.len() == (num_vertices - first_vertex) * components);
+		let index = match self.channels.iter().position(|c| c.name == name) {
+			Some(i) => i,
+			None => {
+				self.channels.push(VertexChannel {
+					name: name.to_string(),
+					components: components,
+					data: vec![0.0; num_vertices * components]
+				});
+				self.channels.len() - 1
+			}
+		};
+		let channel = &mut self.channels[index];
+		assert!(channel.components == components, "vertex channel {}: component count mismatch", name);
+		channel.data.truncate(first_vertex * components);
+		channel.data.push_all(data);
+	}
+
+	/// Optimize the index and vertex order of all triangle list submeshes
+	/// (see mesh_optimizer). Other submeshes are left as is.
+	/// LODs are discarded: generate them after optimizing.
+	/// Returns the statistics of each optimized submesh.
+	pub fn optimize(&mut self, options: &MeshOptimization) -> Vec<OptimizationStats>
+	{
+		let mut result = MeshData::new();
+		result.materials = mem::replace(&mut self.materials, Vec::new());
+		let mut all_stats = Vec::new();
+
+		for s in self.submeshes.iter()
+		{
+			let first_vertex = s.part.start_vertex as usize;
+			let num_vertices = s.part.num_vertices as usize;
+			let start_index = s.part.start_index as usize;
+			let vertices = &self.vertices[first_vertex..first_vertex + num_vertices];
+			let channels = self.channels.iter()
+				.map(|c| &c.data[first_vertex * c.components..(first_vertex + num_vertices) * c.components])
+				.collect::<Vec<_>>();
+			let mut indices = self.indices[start_index..start_index + s.part.num_indices as usize].to_vec();
+
+			let remap = if s.part.primitive_type == PrimitiveType::Triangle {
+				let (stats, remap) = optimize_triangles(vertices, &channels, &mut indices, options);
+				info!("{}: {} triangles, {} -> {} vertices, ACMR {:.3} -> {:.3}",
+					s.name, stats.num_triangles, stats.vertices_before, stats.vertices_after,
+					stats.acmr_before, stats.acmr_after);
+				all_stats.push(stats);
+				remap
+			} else {
+				(0..num_vertices as u32).collect()
+			};
+
+			let new_vertices = remap.iter().map(|&i| vertices[i as usize]).collect::<Vec<_>>();
+			result.add_submesh(&s.name, s.part.primitive_type, &new_vertices, &indices, s.material, s.has_texcoords);
+			for (c, data) in self.channels.iter().zip(channels.iter()) {
+				let mut new_data = Vec::with_capacity(remap.len() * c.components);
+				for &i in remap.iter() {
+					new_data.push_all(&data[i as usize * c.components..(i as usize + 1) * c.components]);
+				}
+				result.set_channel_data(&c.name, c.components, &new_data);
+			}
+		}
+
+		*self = result;
+		all_stats
+	}
+
+	/// Generate the LODs of all triangle list submeshes by simplification (see mesh_simplify).
+	/// LOD generation stops early for submeshes that cannot be simplified further
+	/// within the error bound.
+	pub fn generate_lods(&mut self, options: &LodOptions)
+	{
+		let mut num_levels = 0;
+		for s in self.submeshes.iter_mut()
+		{
+			if s.part.primitive_type != PrimitiveType::Triangle {
+				continue;
+			}
+			let first_vertex = s.part.start_vertex as usize;
+			let num_vertices = s.part.num_vertices as usize;
+			let start_index = s.part.start_index as usize;
+			let lods = {
+				let vertices = &self.vertices[first_vertex..first_vertex + num_vertices];
+				let indices = &self.indices[start_index..start_index + s.part.num_indices as usize];
+				let mut lods: Vec<Vec<u32>> = Vec::new();
+				for level in 1..options.num_levels + 1 {
+					let target = (indices.len() as f32 * options.triangle_ratio.powi(level as i32)) as usize;
+					let (lod, error) = simplify(vertices, indices, target, options.max_error);
+					// not simplified enough to be worth a LOD
+					let previous = lods.last().map(|l| l.len()).unwrap_or(indices.len());
+					if lod.is_empty() || lod.len() as f32 > 0.9 * previous as f32 {
+						break;
+					}
+					debug!("{}: LOD {}: {} -> {} triangles, error {}", s.name, level, indices.len() / 3, lod.len() / 3, error);
+					lods.push(optimize_vertex_cache(&lod, num_vertices, 32));
+				}
+				lods
+			};
+			s.lods.clear();
+			for lod in lods.iter() {
+				s.lods.push(MeshPart::indexed(
+					PrimitiveType::Triangle,
+					IndexType::U32,
+					self.indices.len() as u32,
+					lod.len() as u32,
+					first_vertex as u32,
+					num_vertices as u32));
+				self.indices.push_all(lod);
+			}
+			num_levels = num_levels.max(lods.len());
+		}
+		self.lod_screen_sizes = (0..num_levels)
+			.map(|l| options.screen_size * options.screen_size_ratio.powi(l as i32))
+			.collect();
+	}
+
+	/// Load all objects and groups of an OBJ file, and the materials of its MTL library
+	pub fn load_obj(path: &Path, options: &MeshLoadOptions) -> Result<MeshData, MeshLoadError>
+	{
+		let (models, materials) = try!(tobj::load_obj(path));
+		// texture paths in MTL files are relative to the OBJ file
+		let base_dir = path.parent().unwrap_or(Path::new(""));
+
+		let mut data = MeshData::new();
+		data.materials = materials.iter().map(|m| MaterialDesc::from_mtl(m, base_dir)).collect();
+
+		for model in models.iter()
+		{
+			let ref m = model.mesh;
+			let name = &model.name;
+			if m.positions.len() % 3 != 0 || m.indices.len() % 3 != 0 {
+				return invalid(format!("{}: truncated positions or faces", name));
+			}
+			let count = m.positions.len() / 3;
+			let has_normals = !m.normals.is_empty();
+			let has_texcoords = !m.texcoords.is_empty();
+			if has_normals && m.normals.len() != 3 * count {
+				return invalid(format!("{}: {} normals for {} vertices", name, m.normals.len() / 3, count));
+			}
+			if has_texcoords && m.texcoords.len() != 2 * count {
+				return invalid(format!("{}: {} texture coordinates for {} vertices", name, m.texcoords.len() / 2, count));
+			}
+			if let Some(&i) = m.indices.iter().find(|&&i| i as usize >= count) {
+				return invalid(format!("{}: vertex index {} out of range ({} vertices)", name, i, count));
+			}
+			let material = match m.material_id {
+				Some(id) if id >= data.materials.len() => {
+					warn!("{}: material {} does not exist", name, id);
+					None
+				},
+				id => id
+			};
+
+			let mut vertices = (0..count).map(|i| MeshVertex {
+				pos: [m.positions[3*i], m.positions[3*i+1], m.positions[3*i+2]],
+				norm: if has_normals { [m.normals[3*i], m.normals[3*i+1], m.normals[3*i+2]] } else { [0.0; 3] },
+				tg: [0.0, 0.0, 0.0, 1.0],
+				// no texture coordinates: left at zero, the submesh is flagged
+				tex: if has_texcoords { [m.texcoords[2*i], m.texcoords[2*i+1]] } else { [0.0; 2] }
+			}).collect::<Vec<_>>();
+			let mut indices = m.indices.clone();
+
+			if !has_normals {
+				generate_normals(&mut vertices, &mut indices, options.normals.crease_angle());
+			}
+			if has_texcoords {
+				generate_tangents(&mut vertices, &mut indices);
+			}
+
+			data.add_submesh(name, PrimitiveType::Triangle, &vertices, &indices, material, has_texcoords);
+		}
+
+		if data.submeshes.is_empty() {
+			return invalid("no geometry".to_string());
+		}
+		if let Some(ref optimization) = options.optimization {
+			data.optimize(optimization);
+		}
+		if let Some(ref lods) = options.lods {
+			data.generate_lods(lods);
+		}
+		debug!("Loaded {}: {} objects, {} materials", path.display(), data.submeshes.len(), data.materials.len());
+		Ok(data)
+	}
+}
+
+/// Split a range of indices into triangles, in the winding order of the primitive.
+/// Strips and fans are restarted at indices >= num_vertices (primitive restart index).
+/// Returns no triangles for points and lines.
+pub fn triangles(primitive_type: PrimitiveType, indices: &[u32], num_vertices: usize) -> Vec<[u32; 3]>
+{
+	let valid = |i: &u32| (*i as usize) < num_vertices;
+	let mut result = Vec::new();
+	match primitive_type {
+		PrimitiveType::Triangle => {
+			for t in indices.chunks(3) {
+				if t.len() == 3 && t.iter().all(&valid) {
+					result.push([t[0], t[1], t[2]]);
+				}
+			}
+		},
+		PrimitiveType::TriangleAdjacency => {
+			// the odd vertices are the adjacent ones
+			for t in indices.chunks(6) {
+				if t.len() == 6 && t.iter().all(&valid) {
+					result.push([t[0], t[2], t[4]]);
+				}
+			}
+		},
+		PrimitiveType::TriangleStrip | PrimitiveType::TriangleFan => {
+			for run in indices.split(|i| !valid(i)) {
+				for k in 2..run.len() {
+					let t = if primitive_type == PrimitiveType::TriangleFan {
+						[run[0], run[k-1], run[k]]
+					} else if k % 2 == 0 {
+						[run[k-2], run[k-1], run[k]]
+					} else {
+						// every other triangle of a strip has its winding reversed
+						[run[k-1], run[k-2], run[k]]
+					};
+					// skip the degenerate triangles used to join strips
+					if t[0] != t[1] && t[1] != t[2] && t[0] != t[2] {
+						result.push(t);
+					}
+				}
+			}
+		},
+		_ => {}
+	}
+	result
+}
+
+#[test]
+fn test_generate_lods()
+{
+	// flat grid, and a line strip that has no LODs
+	let n = 16;
+	let vertices = (0..(n + 1) * (n + 1)).map(|i| MeshVertex {
+		pos: [(i % (n + 1)) as f32, (i / (n + 1)) as f32, 0.0],
+		norm: [0.0, 0.0, 1.0],
+		tg: [1.0, 0.0, 0.0, 1.0],
+		tex: [0.0, 0.0]
+	}).collect::<Vec<_>>();
+	let mut indices = Vec::new();
+	for y in 0..n {
+		for x in 0..n {
+			let i = (y * (n + 1) + x) as u32;
+			let s = (n + 1) as u32;
+			indices.push_all(&[i, i + 1, i + s + 1, i, i + s + 1, i + s]);
+		}
+	}
+	let mut data = MeshData::new();
+	data.add_submesh("line", PrimitiveType::LineStrip, &vertices[..2], &[0, 1], None, false);
+	data.add_submesh("grid", PrimitiveType::Triangle, &vertices, &indices, None, false);
+	let num_indices = data.indices.len();
+	data.generate_lods(&LodOptions::default().with_screen_sizes(0.4, 0.5));
+
+	assert!(data.submeshes[0].lods.is_empty());
+	let ref grid = data.submeshes[1];
+	assert!(grid.lods.len() >= 2 && grid.lods.len() <= 3);
+	assert_eq!(data.lod_screen_sizes, [0.4, 0.2, 0.1][..grid.lods.len()].to_vec());
+	let mut previous = grid.part.num_indices;
+	for lod in grid.lods.iter() {
+		assert_eq!(lod.primitive_type, PrimitiveType::Triangle);
+		assert_eq!((lod.start_vertex, lod.num_vertices), (grid.part.start_vertex, grid.part.num_vertices));
+		// appended after the indices of the submeshes, relative to the first vertex of the part
+		assert!(lod.start_index as usize >= num_indices);
+		assert!(lod.num_indices % 3 == 0 && lod.num_indices < previous);
+		let lod_indices = &data.indices[lod.start_index as usize..(lod.start_index + lod.num_indices) as usize];
+		assert!(lod_indices.iter().all(|&i| i < lod.num_vertices));
+		previous = lod.num_indices;
+	}
+}
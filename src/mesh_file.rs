@@ -0,0 +1,752 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter};
+use std::mem;
+use std::ptr;
+use std::path::{Component, Path, PathBuf};
+use nalgebra::*;
+use graphics::MeshVertex;
+use material::{MaterialDesc, TextureDesc, TextureSource};
+use mesh_data::{MeshData, MeshLoadOptions, SubMesh};
+use bounds::{Bounds, BoundingBox, BoundingSphere};
+use rendering::*;
+
+//-------------------------------------------
+// Binary mesh files (.mesh): the contents of a MeshData, ready to upload.
+// Used as a standalone asset type, and as a cache of imported model files
+// (a .meshcache file next to the source file, see cache_path).
+//
+// All values are little-endian. Strings are a u32 byte length followed by UTF-8.
+//
+//     magic            b"AMSH"
+//     version          u32
+//     source stamp     u64, hash of the source file and import options (0 for standalone files)
+//     vertex format    u32 vertex size, u32 attribute count,
+//                      then for each attribute: name, u32 component count, u32 offset (f32 components)
+//     counts           u32 vertices, u32 indices, u32 parts, u32 materials
+//     index type       u32 (0: u16, 1: u32)
+//     bounds           10 f32 (box min, box max, sphere center, sphere radius)
+//     LOD thresholds   u32 count, f32 screen size of each LOD
+//     parts            name, u32 primitive type, u32 start vertex, u32 start index,
+//                      u32 vertex count, u32 index count, u32 has restart index, u32 restart index,
+//                      i32 material (-1: none), u32 has texture coordinates, bounds,
+//                      u32 LOD count, then for each LOD: u32 start index, u32 index count
+//                      (LODs are triangle lists using the vertices of their part)
+//     materials        name, 12 f32 (diffuse, specular, shininess, metallic, roughness, emissive),
+//                      5 textures (diffuse, normal, metallic-roughness, occlusion, emissive):
+//                      u32 present, path (relative to the mesh file), u32 has sampler, 4 u32 sampler modes
+//     vertex data      vertex count * vertex size bytes
+//     index data       index count * 2 or 4 bytes
+//
+// The vertex and index data are stored as they are laid out in memory (on little-endian hosts),
+// and are read and uploaded without decoding them.
+//
+// Additional vertex channels of the MeshData are not stored: they are never uploaded.
+// Embedded textures of materials are not stored either.
+
+const MAGIC: &'static [u8; 4] = b"AMSH";
+pub const MESH_FILE_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum MeshFileError
+{
+	Io(io::Error),
+	/// Not a mesh file, or truncated
+	InvalidFile(String),
+	UnsupportedVersion(u32)
+}
+
+impl From<io::Error> for MeshFileError
+{
+	fn from(err: io::Error) -> MeshFileError
+	{
+		MeshFileError::Io(err)
+	}
+}
+
+impl fmt::Display for MeshFileError
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match *self {
+			MeshFileError::Io(ref err) => write!(f, "I/O error: {}", err),
+			MeshFileError::InvalidFile(ref msg) => write!(f, "invalid mesh file: {}", msg),
+			MeshFileError::UnsupportedVersion(v) => write!(f, "unsupported mesh file version {} (expected {})", v, MESH_FILE_VERSION)
+		}
+	}
+}
+
+fn invalid<T>(msg: String) -> Result<T, MeshFileError>
+{
+	Err(MeshFileError::InvalidFile(msg))
+}
+
+/// Layout of MeshVertex: name, component count, offset
+const VERTEX_ATTRIBUTES: [(&'static str, u32, u32); 4] = [
+	("pos", 3, 0),
+	("norm", 3, 12),
+	("tg", 4, 24),
+	("tex", 2, 40)];
+
+/// 64-bit FNV-1a hash
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64
+{
+	bytes.iter().fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Path of the cache file of an imported model file: foo.obj -> foo.obj.meshcache
+pub fn cache_path(source: &Path) -> PathBuf
+{
+	let mut name = source.file_name().map(|n| n.to_os_string()).unwrap_or(OsString::new());
+	name.push(".meshcache");
+	source.with_file_name(name)
+}
+
+/// path, relative to base (with .. components where needed).
+/// Paths that cannot be made relative to base (absolute path and relative base,
+/// base going up with ..) are returned unchanged.
+fn relative_path(path: &Path, base: &Path) -> PathBuf
+{
+	if path.is_absolute() != base.is_absolute() {
+		return path.to_path_buf();
+	}
+	let path_components = path.components().filter(|c| *c != Component::CurDir).collect::<Vec<_>>();
+	let base_components = base.components().filter(|c| *c != Component::CurDir).collect::<Vec<_>>();
+	let common = path_components.iter().zip(base_components.iter()).take_while(|&(a, b)| a == b).count();
+	if (path.is_absolute() && common == 0) || base_components[common..].contains(&Component::ParentDir) {
+		return path.to_path_buf();
+	}
+	let mut relative = PathBuf::new();
+	for _ in common..base_components.len() {
+		relative.push("..");
+	}
+	for c in path_components[common..].iter() {
+		relative.push(c.as_os_str());
+	}
+	relative
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>>
+{
+	let mut data = Vec::new();
+	try!(try!(File::open(path)).read_to_end(&mut data));
+	Ok(data)
+}
+
+/// Source stamp of a mesh imported from an OBJ file: hash of the OBJ file,
+/// of the MTL libraries it references, and of the import options.
+/// A cache file with a different stamp is out of date.
+pub fn obj_source_stamp(path: &Path, options: &MeshLoadOptions) -> io::Result<u64>
+{
+	let obj = try!(read_file(path));
+	let mut hash = fnv1a(0xcbf29ce484222325, &obj);
+	let base_dir = path.parent().unwrap_or(Path::new(""));
+	for line in String::from_utf8_lossy(&obj).lines() {
+		let line = line.trim();
+		if line.starts_with("mtllib ") {
+			// a missing library is not an error (the OBJ loader ignores it too)
+			if let Ok(mtl) = read_file(&base_dir.join(line["mtllib ".len()..].trim())) {
+				hash = fnv1a(hash, &mtl);
+			}
+		}
+	}
+	Ok(fnv1a(hash, format!("{:?}", options).as_bytes()))
+}
+
+//-------------------------------------------
+// Little-endian encoding
+
+struct Writer<'a, W: Write + 'a>
+{
+	w: &'a mut W
+}
+
+impl<'a, W: Write> Writer<'a, W>
+{
+	fn u32(&mut self, v: u32) -> io::Result<()>
+	{
+		self.w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+	}
+
+	fn u64(&mut self, v: u64) -> io::Result<()>
+	{
+		try!(self.u32(v as u32));
+		self.u32((v >> 32) as u32)
+	}
+
+	fn f32(&mut self, v: f32) -> io::Result<()>
+	{
+		self.u32(unsafe { mem::transmute(v) })
+	}
+
+	fn floats(&mut self, v: &[f32]) -> io::Result<()>
+	{
+		for &x in v.iter() {
+			try!(self.f32(x));
+		}
+		Ok(())
+	}
+
+	fn str(&mut self, s: &str) -> io::Result<()>
+	{
+		try!(self.u32(s.len() as u32));
+		self.w.write_all(s.as_bytes())
+	}
+
+	fn bounds(&mut self, b: &Bounds) -> io::Result<()>
+	{
+		try!(self.floats(&[b.aabb.min.x, b.aabb.min.y, b.aabb.min.z]));
+		try!(self.floats(&[b.aabb.max.x, b.aabb.max.y, b.aabb.max.z]));
+		try!(self.floats(&[b.sphere.center.x, b.sphere.center.y, b.sphere.center.z]));
+		self.f32(b.sphere.radius)
+	}
+}
+
+struct Reader<'a>
+{
+	data: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Reader<'a>
+{
+	fn remaining(&self) -> usize
+	{
+		self.data.len() - self.pos
+	}
+
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8], MeshFileError>
+	{
+		if self.remaining() < len {
+			return invalid(format!("unexpected end of file at offset {}", self.pos));
+		}
+		let bytes = &self.data[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn u32(&mut self) -> Result<u32, MeshFileError>
+	{
+		let b = try!(self.bytes(4));
+		Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+	}
+
+	fn u64(&mut self) -> Result<u64, MeshFileError>
+	{
+		let lo = try!(self.u32()) as u64;
+		let hi = try!(self.u32()) as u64;
+		Ok(lo | hi << 32)
+	}
+
+	fn f32(&mut self) -> Result<f32, MeshFileError>
+	{
+		Ok(unsafe { mem::transmute(try!(self.u32())) })
+	}
+
+	fn vec3(&mut self) -> Result<Vec3<f32>, MeshFileError>
+	{
+		Ok(Vec3::new(try!(self.f32()), try!(self.f32()), try!(self.f32())))
+	}
+
+	fn floats3(&mut self) -> Result<[f32; 3], MeshFileError>
+	{
+		Ok([try!(self.f32()), try!(self.f32()), try!(self.f32())])
+	}
+
+	fn str(&mut self) -> Result<String, MeshFileError>
+	{
+		let len = try!(self.u32()) as usize;
+		let bytes = try!(self.bytes(len));
+		match String::from_utf8(bytes.to_vec()) {
+			Ok(s) => Ok(s),
+			Err(_) => invalid(format!("invalid string at offset {}", self.pos - len))
+		}
+	}
+
+	fn bounds(&mut self) -> Result<Bounds, MeshFileError>
+	{
+		let min = try!(self.vec3());
+		let max = try!(self.vec3());
+		let center = try!(self.vec3());
+		let radius = try!(self.f32());
+		Ok(Bounds {
+			aabb: BoundingBox { min: min, max: max },
+			sphere: BoundingSphere { center: center, radius: radius }
+		})
+	}
+}
+
+//-------------------------------------------
+// Enum codes
+
+const PRIMITIVE_TYPES: [PrimitiveType; 11] = [
+	PrimitiveType::Point,
+	PrimitiveType::Line,
+	PrimitiveType::LineStrip,
+	PrimitiveType::LineLoop,
+	PrimitiveType::Triangle,
+	PrimitiveType::TriangleStrip,
+	PrimitiveType::TriangleFan,
+	PrimitiveType::LineAdjacency,
+	PrimitiveType::LineStripAdjacency,
+	PrimitiveType::TriangleAdjacency,
+	PrimitiveType::TriangleStripAdjacency];
+
+const ADDRESS_MODES: [TextureAddressMode; 3] = [
+	TextureAddressMode::Clamp,
+	TextureAddressMode::Mirror,
+	TextureAddressMode::Wrap];
+
+const MIN_FILTERS: [TextureMinFilter; 6] = [
+	TextureMinFilter::Nearest,
+	TextureMinFilter::Linear,
+	TextureMinFilter::NearestMipmapNearest,
+	TextureMinFilter::LinearMipmapNearest,
+	TextureMinFilter::NearestMipmapLinear,
+	TextureMinFilter::LinearMipmapLinear];
+
+const MAG_FILTERS: [TextureMagFilter; 2] = [
+	TextureMagFilter::Nearest,
+	TextureMagFilter::Linear];
+
+/// Code of an enum value: its index in the table
+fn encode<T: PartialEq>(table: &[T], value: &T) -> u32
+{
+	table.iter().position(|v| v == value).unwrap() as u32
+}
+
+fn decode<T: Copy>(table: &[T], code: u32, what: &str) -> Result<T, MeshFileError>
+{
+	match table.get(code as usize) {
+		Some(&v) => Ok(v),
+		None => invalid(format!("invalid {} {}", what, code))
+	}
+}
+
+//-------------------------------------------
+
+/// Contents of a mesh file
+pub struct MeshFile
+{
+	/// Hash of the source file and import options, for cache files (0 for standalone files)
+	pub source_stamp: u64,
+	pub vertices: Vec<MeshVertex>,
+	/// Type of the index data (u16 when all parts have less than 65536 vertices)
+	pub index_type: IndexType,
+	pub num_indices: usize,
+	/// Indices of type index_type, relative to the first vertex of their part
+	pub index_data: Vec<u8>,
+	/// Parts, with index_type set to the type of the index data
+	pub parts: Vec<SubMesh>,
+	pub part_bounds: Vec<Bounds>,
+	pub bounds: Bounds,
+	pub materials: Vec<MaterialDesc>,
+	pub lod_screen_sizes: Vec<f32>
+}
+
+impl MeshFile
+{
+	pub fn from_data(data: &MeshData, source_stamp: u64) -> MeshFile
+	{
+		let small = data.submeshes.iter().all(|s| {
+			s.part.num_vertices <= 0xFFFF && s.part.restart_index.map(|r| r <= 0xFFFF).unwrap_or(true)
+		});
+		let index_type = if small { IndexType::U16 } else { IndexType::U32 };
+		let index_data = if small {
+			let indices = data.indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+			as_byte_slice(&indices).to_vec()
+		} else {
+			as_byte_slice(&data.indices).to_vec()
+		};
+		MeshFile {
+			source_stamp: source_stamp,
+			vertices: data.vertices.clone(),
+			index_type: index_type,
+			num_indices: data.indices.len(),
+			index_data: index_data,
+			parts: data.submeshes.iter().map(|s| SubMesh {
+				part: MeshPart { index_type: index_type, .. s.part },
+				lods: s.lods.iter().map(|lod| MeshPart { index_type: index_type, .. *lod }).collect(),
+				.. s.clone()
+			}).collect(),
+			part_bounds: data.submeshes.iter().map(|s| Bounds::from_part(&data.vertices, &s.part)).collect(),
+			bounds: Bounds::from_vertices(&data.vertices),
+			materials: data.materials.clone(),
+			lod_screen_sizes: data.lod_screen_sizes.clone()
+		}
+	}
+
+	/// Texture paths are written relative to base_dir (the directory of the mesh file)
+	pub fn write<W: Write>(&self, w: &mut W, base_dir: &Path) -> io::Result<()>
+	{
+		let mut w = Writer { w: w };
+		try!(w.w.write_all(MAGIC));
+		try!(w.u32(MESH_FILE_VERSION));
+		try!(w.u64(self.source_stamp));
+
+		try!(w.u32(mem::size_of::<MeshVertex>() as u32));
+		try!(w.u32(VERTEX_ATTRIBUTES.len() as u32));
+		for &(name, components, offset) in VERTEX_ATTRIBUTES.iter() {
+			try!(w.str(name));
+			try!(w.u32(components));
+			try!(w.u32(offset));
+		}
+
+		try!(w.u32(self.vertices.len() as u32));
+		try!(w.u32(self.num_indices as u32));
+		try!(w.u32(self.parts.len() as u32));
+		try!(w.u32(self.materials.len() as u32));
+		try!(w.u32(if self.index_type == IndexType::U16 { 0 } else { 1 }));
+		try!(w.bounds(&self.bounds));
+		try!(w.u32(self.lod_screen_sizes.len() as u32));
+		try!(w.floats(&self.lod_screen_sizes));
+
+		for (s, bounds) in self.parts.iter().zip(self.part_bounds.iter()) {
+			try!(w.str(&s.name));
+			try!(w.u32(encode(&PRIMITIVE_TYPES, &s.part.primitive_type)));
+			try!(w.u32(s.part.start_vertex));
+			try!(w.u32(s.part.start_index));
+			try!(w.u32(s.part.num_vertices));
+			try!(w.u32(s.part.num_indices));
+			try!(w.u32(s.part.restart_index.is_some() as u32));
+			try!(w.u32(s.part.restart_index.unwrap_or(0)));
+			try!(w.u32(s.material.map(|m| m as u32).unwrap_or(0xFFFFFFFF)));
+			try!(w.u32(s.has_texcoords as u32));
+			try!(w.bounds(bounds));
+			try!(w.u32(s.lods.len() as u32));
+			for lod in s.lods.iter() {
+				try!(w.u32(lod.start_index));
+				try!(w.u32(lod.num_indices));
+			}
+		}
+
+		for m in self.materials.iter() {
+			try!(w.str(&m.name));
+			try!(w.floats(&m.diffuse_color));
+			try!(w.floats(&m.specular_color));
+			try!(w.floats(&[m.shininess, m.metallic, m.roughness]));
+			try!(w.floats(&m.emissive_color));
+			for tex in [&m.diffuse_texture, &m.normal_texture, &m.metallic_roughness_texture,
+						&m.occlusion_texture, &m.emissive_texture].iter() {
+				match **tex {
+					Some(TextureDesc { source: TextureSource::File(ref path), ref sampler }) => {
+						try!(w.u32(1));
+						try!(w.str(&relative_path(path, base_dir).to_string_lossy()));
+						match *sampler {
+							Some(ref s) => {
+								try!(w.u32(1));
+								try!(w.u32(encode(&ADDRESS_MODES, &s.addr_u)));
+								try!(w.u32(encode(&ADDRESS_MODES, &s.addr_v)));
+								try!(w.u32(encode(&MIN_FILTERS, &s.min_filter)));
+								try!(w.u32(encode(&MAG_FILTERS, &s.mag_filter)));
+							},
+							None => for _ in 0..5 {
+								try!(w.u32(0));
+							}
+						}
+					},
+					Some(TextureDesc { source: TextureSource::Memory { ref name, .. }, .. }) => {
+						warn!("{}: embedded texture {} not written to the mesh file", m.name, name);
+						try!(w.u32(0));
+					},
+					None => try!(w.u32(0))
+				}
+			}
+		}
+
+		try!(w.w.write_all(as_byte_slice(&self.vertices)));
+		w.w.write_all(&self.index_data)
+	}
+
+	/// Texture paths are resolved against base_dir (the directory of the mesh file)
+	pub fn read<R: Read>(r: &mut R, base_dir: &Path) -> Result<MeshFile, MeshFileError>
+	{
+		let mut data = Vec::new();
+		try!(r.read_to_end(&mut data));
+		let mut r = Reader { data: &data, pos: 0 };
+
+		if try!(r.bytes(4)) != &MAGIC[..] {
+			return invalid("not a mesh file".to_string());
+		}
+		let version = try!(r.u32());
+		if version != MESH_FILE_VERSION {
+			return Err(MeshFileError::UnsupportedVersion(version));
+		}
+		let source_stamp = try!(r.u64());
+
+		let vertex_size = try!(r.u32());
+		let num_attributes = try!(r.u32()) as usize;
+		let mut attributes = Vec::new();
+		for _ in 0..num_attributes {
+			attributes.push((try!(r.str()), try!(r.u32()), try!(r.u32())));
+		}
+		let expected = VERTEX_ATTRIBUTES.iter().map(|&(n, c, o)| (n.to_string(), c, o)).collect::<Vec<_>>();
+		if vertex_size as usize != mem::size_of::<MeshVertex>() || attributes != expected {
+			return invalid(format!("unsupported vertex format {:?}", attributes));
+		}
+
+		let num_vertices = try!(r.u32()) as usize;
+		let num_indices = try!(r.u32()) as usize;
+		let num_parts = try!(r.u32()) as usize;
+		let num_materials = try!(r.u32()) as usize;
+		let index_type = match try!(r.u32()) {
+			0 => IndexType::U16,
+			1 => IndexType::U32,
+			t => return invalid(format!("invalid index type {}", t))
+		};
+		let bounds = try!(r.bounds());
+		let num_lods = try!(r.u32());
+		let mut lod_screen_sizes = Vec::new();
+		for _ in 0..num_lods {
+			lod_screen_sizes.push(try!(r.f32()));
+		}
+
+		// the counts are not trusted for allocations before the data is known to be there
+		let mut parts = Vec::new();
+		let mut part_bounds = Vec::new();
+		for _ in 0..num_parts {
+			let name = try!(r.str());
+			let primitive_type = try!(decode(&PRIMITIVE_TYPES, try!(r.u32()), "primitive type"));
+			let start_vertex = try!(r.u32());
+			let start_index = try!(r.u32());
+			let part_vertices = try!(r.u32());
+			let part_indices = try!(r.u32());
+			let has_restart_index = try!(r.u32()) != 0;
+			let restart_index = try!(r.u32());
+			let material = try!(r.u32());
+			let has_texcoords = try!(r.u32()) != 0;
+			if start_vertex as usize + part_vertices as usize > num_vertices
+				|| start_index as usize + part_indices as usize > num_indices {
+				return invalid(format!("part {}: range out of bounds", name));
+			}
+			part_bounds.push(try!(r.bounds()));
+			let part_lods = try!(r.u32());
+			let mut lods = Vec::new();
+			for _ in 0..part_lods {
+				let lod_start_index = try!(r.u32());
+				let lod_indices = try!(r.u32());
+				if lod_start_index as usize + lod_indices as usize > num_indices {
+					return invalid(format!("part {}: LOD range out of bounds", name));
+				}
+				lods.push(MeshPart::indexed(
+					PrimitiveType::Triangle,
+					index_type,
+					lod_start_index,
+					lod_indices,
+					start_vertex,
+					part_vertices));
+			}
+			parts.push(SubMesh {
+				name: name,
+				part: MeshPart {
+					primitive_type: primitive_type,
+					start_vertex: start_vertex,
+					start_index: start_index,
+					num_vertices: part_vertices,
+					num_indices: part_indices,
+					index_type: index_type,
+					restart_index: if has_restart_index { Some(restart_index) } else { None }
+				},
+				material: if material == 0xFFFFFFFF { None } else { Some(material as usize) },
+				has_texcoords: has_texcoords,
+				lods: lods
+			});
+		}
+
+		let mut materials = Vec::new();
+		for _ in 0..num_materials {
+			let name = try!(r.str());
+			let diffuse_color = try!(r.floats3());
+			let specular_color = try!(r.floats3());
+			let shininess = try!(r.f32());
+			let metallic = try!(r.f32());
+			let roughness = try!(r.f32());
+			let emissive_color = try!(r.floats3());
+			let mut textures = Vec::with_capacity(5);
+			for _ in 0..5 {
+				if try!(r.u32()) == 0 {
+					textures.push(None);
+					continue;
+				}
+				let path = base_dir.join(try!(r.str()));
+				let has_sampler = try!(r.u32()) != 0;
+				let addr_u = try!(decode(&ADDRESS_MODES, try!(r.u32()), "address mode"));
+				let addr_v = try!(decode(&ADDRESS_MODES, try!(r.u32()), "address mode"));
+				let min_filter = try!(decode(&MIN_FILTERS, try!(r.u32()), "filter"));
+				let mag_filter = try!(decode(&MAG_FILTERS, try!(r.u32()), "filter"));
+				textures.push(Some(TextureDesc {
+					source: TextureSource::File(path),
+					sampler: if has_sampler {
+						Some(Sampler2DDesc {
+							addr_u: addr_u,
+							addr_v: addr_v,
+							min_filter: min_filter,
+							mag_filter: mag_filter
+						})
+					} else {
+						None
+					}
+				}));
+			}
+			let mut textures = textures.into_iter();
+			materials.push(MaterialDesc {
+				name: name,
+				diffuse_color: diffuse_color,
+				specular_color: specular_color,
+				shininess: shininess,
+				metallic: metallic,
+				roughness: roughness,
+				emissive_color: emissive_color,
+				diffuse_texture: textures.next().unwrap(),
+				normal_texture: textures.next().unwrap(),
+				metallic_roughness_texture: textures.next().unwrap(),
+				occlusion_texture: textures.next().unwrap(),
+				emissive_texture: textures.next().unwrap()
+			});
+		}
+
+		let index_size = if index_type == IndexType::U16 { 2 } else { 4 };
+		let data_size = num_vertices.checked_mul(vertex_size as usize)
+			.and_then(|v| num_indices.checked_mul(index_size).and_then(|i| v.checked_add(i)));
+		match data_size {
+			Some(size) if size <= r.remaining() => {},
+			_ => return invalid(format!("{} vertices and {} indices do not fit in the remaining {} bytes",
+				num_vertices, num_indices, r.remaining()))
+		}
+
+		let vertex_data = try!(r.bytes(num_vertices * vertex_size as usize));
+		let mut vertices = Vec::<MeshVertex>::with_capacity(num_vertices);
+		unsafe {
+			ptr::copy_nonoverlapping(vertex_data.as_ptr(), vertices.as_mut_ptr() as *mut u8, vertex_data.len());
+			vertices.set_len(num_vertices);
+		}
+		let file = MeshFile {
+			source_stamp: source_stamp,
+			vertices: vertices,
+			index_type: index_type,
+			num_indices: num_indices,
+			index_data: try!(r.bytes(num_indices * index_size)).to_vec(),
+			parts: parts,
+			part_bounds: part_bounds,
+			bounds: bounds,
+			materials: materials,
+			lod_screen_sizes: lod_screen_sizes
+		};
+
+		// indices are relative to the first vertex of their part
+		for s in file.parts.iter() {
+			for part in Some(&s.part).into_iter().chain(s.lods.iter()) {
+				let start = part.start_index as usize;
+				let found = (start..start + part.num_indices as usize).map(|i| file.index(i))
+					.find(|&i| i >= part.num_vertices && Some(i) != part.restart_index);
+				if let Some(i) = found {
+					return invalid(format!("part {}: index {} out of range ({} vertices)", s.name, i, part.num_vertices));
+				}
+			}
+		}
+		Ok(file)
+	}
+
+	/// Index i of the index data
+	pub fn index(&self, i: usize) -> u32
+	{
+		let b = &self.index_data;
+		if self.index_type == IndexType::U16 {
+			b[2 * i] as u32 | (b[2 * i + 1] as u32) << 8
+		} else {
+			b[4 * i] as u32 | (b[4 * i + 1] as u32) << 8 | (b[4 * i + 2] as u32) << 16 | (b[4 * i + 3] as u32) << 24
+		}
+	}
+
+	pub fn save(&self, path: &Path) -> io::Result<()>
+	{
+		let mut w = BufWriter::new(try!(File::create(path)));
+		self.write(&mut w, path.parent().unwrap_or(Path::new("")))
+	}
+
+	pub fn load(path: &Path) -> Result<MeshFile, MeshFileError>
+	{
+		MeshFile::read(&mut try!(File::open(path)), path.parent().unwrap_or(Path::new("")))
+	}
+}
+
+#[test]
+fn test_mesh_file_roundtrip()
+{
+	use std::io::Cursor;
+
+	let vertex = |x: f32, y: f32| MeshVertex {
+		pos: [x, y, 0.5],
+		norm: [0.0, 0.0, 1.0],
+		tg: [1.0, 0.0, 0.0, -1.0],
+		tex: [x, y]
+	};
+	let mut data = MeshData::new();
+	data.materials.push(MaterialDesc {
+		name: "material".to_string(),
+		diffuse_color: [1.0, 0.5, 0.25],
+		specular_color: [0.0; 3],
+		shininess: 10.0,
+		metallic: 0.0,
+		roughness: 0.5,
+		emissive_color: [0.0; 3],
+		diffuse_texture: Some(TextureDesc {
+			source: TextureSource::File(PathBuf::from("models/textures/diffuse.png")),
+			sampler: None
+		}),
+		normal_texture: None,
+		metallic_roughness_texture: None,
+		occlusion_texture: None,
+		emissive_texture: None
+	});
+	data.add_submesh("quad", PrimitiveType::Triangle,
+		&[vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0)],
+		&[0, 1, 2, 2, 3, 0], Some(0), true);
+	data.add_submesh("line", PrimitiveType::LineStrip,
+		&[vertex(-1.0, 0.0), vertex(-2.0, 3.0)], &[0, 1], None, false);
+
+	let file = MeshFile::from_data(&data, 42);
+	let mut bytes = Vec::new();
+	file.write(&mut bytes, Path::new("models")).unwrap();
+	let read = MeshFile::read(&mut Cursor::new(&bytes[..]), Path::new("models")).unwrap();
+
+	assert_eq!(read.source_stamp, 42);
+	assert_eq!(read.index_type, IndexType::U16);
+	assert_eq!(read.num_indices, 8);
+	assert_eq!(read.index_data.len(), 16);
+	assert_eq!((0..8).map(|i| read.index(i)).collect::<Vec<_>>(), data.indices);
+	assert_eq!(read.vertices.len(), 6);
+	assert_eq!(read.vertices[5].pos, [-2.0, 3.0, 0.5]);
+	assert_eq!(read.vertices[1].tg, [1.0, 0.0, 0.0, -1.0]);
+	assert_eq!(read.parts.len(), 2);
+	assert_eq!(read.parts[1].part.primitive_type, PrimitiveType::LineStrip);
+	assert_eq!(read.parts[1].part.start_vertex, 4);
+	assert_eq!(read.parts[0].material, Some(0));
+	assert_eq!(read.part_bounds[1].aabb.min.x, -2.0);
+	assert_eq!(read.bounds.aabb.max.y, 3.0);
+	assert_eq!(read.materials.len(), 1);
+	assert_eq!(read.materials[0].diffuse_color, [1.0, 0.5, 0.25]);
+	assert_eq!(read.materials[0].diffuse_texture.as_ref().unwrap().source.key(), "models/textures/diffuse.png");
+
+	// moved with its textures
+	let moved = MeshFile::read(&mut Cursor::new(&bytes[..]), Path::new("assets/models")).unwrap();
+	assert_eq!(moved.materials[0].diffuse_texture.as_ref().unwrap().source.key(), "assets/models/textures/diffuse.png");
+
+	// truncated
+	assert!(MeshFile::read(&mut Cursor::new(&bytes[..bytes.len() - 1]), Path::new("")).is_err());
+	// index past the vertices of its part (the line has 2 vertices)
+	let n = bytes.len();
+	bytes[n - 2] = 2;
+	assert!(MeshFile::read(&mut Cursor::new(&bytes[..]), Path::new("")).is_err());
+}
+
+#[test]
+fn test_relative_path()
+{
+	assert_eq!(relative_path(Path::new("models/textures/a.png"), Path::new("models")), Path::new("textures/a.png"));
+	assert_eq!(relative_path(Path::new("textures/a.png"), Path::new("models")), Path::new("../textures/a.png"));
+	assert_eq!(relative_path(Path::new("./a.png"), Path::new("")), Path::new("a.png"));
+	assert_eq!(relative_path(Path::new("/data/a.png"), Path::new("models")), Path::new("/data/a.png"));
+	assert_eq!(relative_path(Path::new("/data/textures/a.png"), Path::new("/data/models")), Path::new("../textures/a.png"));
+}
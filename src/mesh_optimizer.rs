@@ -0,0 +1,482 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::u32;
+use nalgebra::*;
+use graphics::MeshVertex;
+
+//-------------------------------------------
+// Index and vertex buffer optimizations for indexed triangle lists:
+// - vertex deduplication and welding
+// - post-transform vertex cache reordering (Forsyth, "Linear-Speed Vertex Cache Optimisation")
+// - overdraw reduction: the cache-optimized triangles are split into clusters,
+//   sorted front to back from the outside of the mesh (Sander et al., "Fast Triangle Reordering")
+// - vertex fetch reordering: vertices are renumbered in the order of first use
+//
+// The efficiency of the vertex cache is measured by the ACMR
+// (average cache miss ratio: transformed vertices per triangle) of a FIFO cache.
+
+/// Which optimizations are run by MeshData::optimize
+#[derive(Copy, Clone, Debug)]
+pub struct MeshOptimization
+{
+	/// Merge identical vertices
+	pub deduplicate: bool,
+	/// Merge vertices that have the same attributes and positions closer than
+	/// this distance (0: only bit-identical vertices are merged)
+	pub weld_distance: f32,
+	pub vertex_cache: bool,
+	pub overdraw: bool,
+	/// Overdraw clusters end as soon as their ACMR is below this factor times the ACMR
+	/// of the whole mesh (lambda in Sander et al.). Larger values make smaller clusters:
+	/// less overdraw, more vertex cache misses.
+	pub overdraw_threshold: f32,
+	pub vertex_fetch: bool,
+	/// Size of the simulated vertex cache
+	pub cache_size: usize
+}
+
+impl MeshOptimization
+{
+	/// All optimizations, exact deduplication
+	pub fn default() -> MeshOptimization
+	{
+		MeshOptimization {
+			deduplicate: true,
+			weld_distance: 0.0,
+			vertex_cache: true,
+			overdraw: true,
+			overdraw_threshold: 1.05,
+			vertex_fetch: true,
+			cache_size: 32
+		}
+	}
+
+	pub fn with_weld_distance(self, weld_distance: f32) -> Self
+	{
+		MeshOptimization {
+			weld_distance: weld_distance,
+			.. self
+		}
+	}
+
+	pub fn with_cache_size(self, cache_size: usize) -> Self
+	{
+		MeshOptimization {
+			cache_size: cache_size,
+			.. self
+		}
+	}
+
+	pub fn with_overdraw_threshold(self, overdraw_threshold: f32) -> Self
+	{
+		MeshOptimization {
+			overdraw_threshold: overdraw_threshold,
+			.. self
+		}
+	}
+}
+
+/// Result of the optimization of a submesh
+#[derive(Copy, Clone, Debug)]
+pub struct OptimizationStats
+{
+	pub num_triangles: usize,
+	pub vertices_before: usize,
+	pub vertices_after: usize,
+	pub acmr_before: f32,
+	pub acmr_after: f32
+}
+
+/// Put a vertex in a FIFO cache, returns true on a miss
+fn cache_access(cache: &mut VecDeque<u32>, cache_size: usize, v: u32) -> bool
+{
+	if cache.contains(&v) {
+		false
+	} else {
+		if cache.len() == cache_size {
+			cache.pop_front();
+		}
+		cache.push_back(v);
+		true
+	}
+}
+
+/// Average cache miss ratio of a triangle list with a FIFO cache
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32
+{
+	let num_triangles = indices.len() / 3;
+	if num_triangles == 0 {
+		return 0.0;
+	}
+	let mut cache = VecDeque::with_capacity(cache_size);
+	let misses = indices.iter().filter(|&&v| cache_access(&mut cache, cache_size, v)).count();
+	misses as f32 / num_triangles as f32
+}
+
+fn bits(x: f32) -> u32
+{
+	// -0.0 and 0.0 are merged
+	unsafe { mem::transmute(x + 0.0) }
+}
+
+/// Rewrite the indices of vertices that have the same attributes (and the same values
+/// in each channel) to the first of them. With a weld distance, positions are snapped
+/// to a grid of that size first, so vertices closer than the distance but on both
+/// sides of a grid line are not merged. Triangles that become degenerate are removed.
+/// Merged vertices are left unreferenced.
+pub fn weld_vertices(vertices: &[MeshVertex], channels: &[&[f32]], indices: &mut Vec<u32>, weld_distance: f32)
+{
+	let mut first_with_key = HashMap::<Vec<u32>, u32>::new();
+	let mut remap = Vec::with_capacity(vertices.len());
+	for (i, v) in vertices.iter().enumerate()
+	{
+		let mut key = Vec::with_capacity(12);
+		for &x in v.pos.iter() {
+			if weld_distance > 0.0 {
+				key.push((x / weld_distance).round() as i32 as u32);
+			} else {
+				key.push(bits(x));
+			}
+		}
+		key.extend(v.norm.iter().chain(v.tg.iter()).chain(v.tex.iter()).map(|&x| bits(x)));
+		for c in channels.iter() {
+			let components = c.len() / vertices.len();
+			key.extend(c[i * components..(i + 1) * components].iter().map(|&x| bits(x)));
+		}
+		remap.push(*first_with_key.entry(key).or_insert(i as u32));
+	}
+
+	let mut welded = Vec::with_capacity(indices.len());
+	for t in indices.chunks(3).filter(|t| t.len() == 3) {
+		let t = [remap[t[0] as usize], remap[t[1] as usize], remap[t[2] as usize]];
+		if t[0] != t[1] && t[1] != t[2] && t[0] != t[2] {
+			welded.push_all(&t);
+		}
+	}
+	*indices = welded;
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize, cache_size: usize) -> f32
+{
+	if remaining_triangles == 0 {
+		return -1.0;
+	}
+	let cache_score = match cache_position {
+		// the vertices of the last triangle get a fixed score, so that
+		// it is not reused immediately (which would favor strip-like orders)
+		Some(p) if p < 3 => 0.75,
+		Some(p) => (1.0 - (p - 3) as f32 / (cache_size - 3) as f32).powf(1.5),
+		None => 0.0
+	};
+	// favor vertices with few triangles left, to avoid leaving isolated triangles behind
+	cache_score + 2.0 * (remaining_triangles as f32).powf(-0.5)
+}
+
+/// Reorder triangles to improve the post-transform vertex cache hit rate
+/// (Forsyth's algorithm, with a LRU cache model of the given size).
+pub fn optimize_vertex_cache(indices: &[u32], num_vertices: usize, cache_size: usize) -> Vec<u32>
+{
+	let cache_size = cache_size.max(4);
+	let num_triangles = indices.len() / 3;
+	let mut vertex_triangles = vec![Vec::new(); num_vertices];
+	for t in 0..num_triangles {
+		for c in 0..3 {
+			vertex_triangles[indices[3*t + c] as usize].push(t);
+		}
+	}
+	let mut cache_position = vec![None; num_vertices];
+	let mut scores = (0..num_vertices)
+		.map(|v| vertex_score(None, vertex_triangles[v].len(), cache_size))
+		.collect::<Vec<_>>();
+	let triangle_score = |scores: &[f32], t: usize| {
+		scores[indices[3*t] as usize] + scores[indices[3*t + 1] as usize] + scores[indices[3*t + 2] as usize]
+	};
+
+	let mut emitted = vec![false; num_triangles];
+	let mut result = Vec::with_capacity(num_triangles * 3);
+	let mut cache: Vec<u32> = Vec::with_capacity(cache_size + 3);
+	let mut best = (0..num_triangles).fold(None, |best: Option<(usize, f32)>, t| {
+		let s = triangle_score(&scores, t);
+		match best {
+			Some((_, best_score)) if best_score >= s => best,
+			_ => Some((t, s))
+		}
+	}).map(|(t, _)| t);
+	let mut next_unemitted = 0;
+
+	for _ in 0..num_triangles
+	{
+		let t = match best {
+			Some(t) => t,
+			None => {
+				// no candidate around the cache: take the next triangle in order
+				while emitted[next_unemitted] {
+					next_unemitted += 1;
+				}
+				next_unemitted
+			}
+		};
+		emitted[t] = true;
+		let tri = [indices[3*t], indices[3*t + 1], indices[3*t + 2]];
+		result.push_all(&tri);
+
+		let mut new_cache = Vec::with_capacity(cache_size + 3);
+		for &v in tri.iter() {
+			let ref mut triangles = vertex_triangles[v as usize];
+			if let Some(p) = triangles.iter().position(|&other| other == t) {
+				triangles.swap_remove(p);
+			}
+			if !new_cache.contains(&v) {
+				new_cache.push(v);
+			}
+		}
+		for &v in cache.iter() {
+			if !new_cache.contains(&v) {
+				new_cache.push(v);
+			}
+		}
+		for (p, &v) in new_cache.iter().enumerate() {
+			cache_position[v as usize] = if p < cache_size { Some(p) } else { None };
+			scores[v as usize] = vertex_score(cache_position[v as usize], vertex_triangles[v as usize].len(), cache_size);
+		}
+
+		// next triangle: the best one that uses a vertex in the cache
+		best = None;
+		let mut best_score = -1.0;
+		for &v in new_cache.iter() {
+			for &other in vertex_triangles[v as usize].iter() {
+				let s = triangle_score(&scores, other);
+				if s > best_score {
+					best = Some(other);
+					best_score = s;
+				}
+			}
+		}
+
+		new_cache.truncate(cache_size);
+		cache = new_cache;
+	}
+	result
+}
+
+/// Reorder clusters of triangles so that the ones on the outside of the mesh, facing
+/// away from its center, are drawn first. A cluster ends at a vertex cache flush
+/// (a triangle with 3 cache misses), or as soon as its own ACMR drops below
+/// threshold times the ACMR of the whole index buffer, so the vertex cache
+/// efficiency of a cache-optimized index buffer is mostly preserved.
+pub fn optimize_overdraw(indices: &[u32], vertices: &[MeshVertex], cache_size: usize, threshold: f32) -> Vec<u32>
+{
+	let num_triangles = indices.len() / 3;
+	if num_triangles == 0 {
+		return Vec::new();
+	}
+	let pos = |i: u32| {
+		let p = vertices[i as usize].pos;
+		Vec3::new(p[0], p[1], p[2])
+	};
+
+	let max_acmr = acmr(indices, cache_size) * threshold;
+	let mut cluster_starts = vec![0];
+	let mut cluster_misses = 0;
+	let mut cache = VecDeque::with_capacity(cache_size);
+	for t in 0..num_triangles {
+		let misses = indices[3*t..3*t + 3].iter().filter(|&&v| cache_access(&mut cache, cache_size, v)).count();
+		// hard boundary: the cache was flushed
+		if misses == 3 && t > *cluster_starts.last().unwrap() {
+			cluster_starts.push(t);
+			cluster_misses = 0;
+		}
+		cluster_misses += misses;
+		// soft boundary: the cluster is already as cache-efficient as the mesh
+		let start = *cluster_starts.last().unwrap();
+		if t + 1 < num_triangles && cluster_misses as f32 <= max_acmr * (t + 1 - start) as f32 {
+			cluster_starts.push(t + 1);
+			cluster_misses = 0;
+		}
+	}
+	cluster_starts.push(num_triangles);
+
+	let centroid = |t: usize| (pos(indices[3*t]) + pos(indices[3*t + 1]) + pos(indices[3*t + 2])) * (1.0 / 3.0);
+	let mesh_center = (0..num_triangles).fold(Vec3::new(0.0f32, 0.0, 0.0), |c, t| c + centroid(t))
+		* (1.0 / num_triangles as f32);
+
+	let mut clusters = cluster_starts.windows(2).map(|w| {
+		let (start, end) = (w[0], w[1]);
+		let mut center = Vec3::new(0.0f32, 0.0, 0.0);
+		let mut normal = Vec3::new(0.0f32, 0.0, 0.0);
+		for t in start..end {
+			let (p0, p1, p2) = (pos(indices[3*t]), pos(indices[3*t + 1]), pos(indices[3*t + 2]));
+			// area-weighted
+			normal = normal + (p1 - p0).cross(&(p2 - p0));
+			center = center + centroid(t);
+		}
+		center = center * (1.0 / (end - start) as f32);
+		let key = if normal.norm() > 1e-12 { (center - mesh_center).dot(&normal.normalize()) } else { 0.0 };
+		(key, start, end)
+	}).collect::<Vec<_>>();
+	clusters.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+	let mut result = Vec::with_capacity(indices.len());
+	for &(_, start, end) in clusters.iter() {
+		result.push_all(&indices[3*start..3*end]);
+	}
+	result
+}
+
+/// Renumber vertices in the order of their first use, and drop the unreferenced ones.
+/// Returns the original index of each new vertex.
+pub fn optimize_vertex_fetch(indices: &mut [u32], num_vertices: usize) -> Vec<u32>
+{
+	let mut new_index = vec![u32::MAX; num_vertices];
+	let mut remap = Vec::with_capacity(num_vertices);
+	for i in indices.iter_mut() {
+		let old = *i as usize;
+		if new_index[old] == u32::MAX {
+			new_index[old] = remap.len() as u32;
+			remap.push(*i);
+		}
+		*i = new_index[old];
+	}
+	remap
+}
+
+/// Drop the unreferenced vertices, keeping the order of the others.
+/// Returns the original index of each new vertex.
+pub fn remove_unused_vertices(indices: &mut [u32], num_vertices: usize) -> Vec<u32>
+{
+	let mut used = vec![false; num_vertices];
+	for &i in indices.iter() {
+		used[i as usize] = true;
+	}
+	let mut new_index = vec![u32::MAX; num_vertices];
+	let mut remap = Vec::with_capacity(num_vertices);
+	for v in 0..num_vertices {
+		if used[v] {
+			new_index[v] = remap.len() as u32;
+			remap.push(v as u32);
+		}
+	}
+	for i in indices.iter_mut() {
+		*i = new_index[*i as usize];
+	}
+	remap
+}
+
+/// Run the enabled optimizations on an indexed triangle list.
+/// channels are additional per-vertex attributes, taken into account for deduplication.
+/// Returns the statistics and the original index of each vertex
+/// referenced by the new indices.
+pub fn optimize_triangles(
+	vertices: &[MeshVertex],
+	channels: &[&[f32]],
+	indices: &mut Vec<u32>,
+	options: &MeshOptimization) -> (OptimizationStats, Vec<u32>)
+{
+	let acmr_before = acmr(indices, options.cache_size);
+	if options.deduplicate {
+		weld_vertices(vertices, channels, indices, options.weld_distance);
+	}
+	if options.vertex_cache {
+		*indices = optimize_vertex_cache(indices, vertices.len(), options.cache_size);
+	}
+	if options.overdraw {
+		*indices = optimize_overdraw(indices, vertices, options.cache_size, options.overdraw_threshold);
+	}
+	let remap = if options.vertex_fetch {
+		optimize_vertex_fetch(indices, vertices.len())
+	} else {
+		remove_unused_vertices(indices, vertices.len())
+	};
+	let stats = OptimizationStats {
+		num_triangles: indices.len() / 3,
+		vertices_before: vertices.len(),
+		vertices_after: remap.len(),
+		acmr_before: acmr_before,
+		acmr_after: acmr(indices, options.cache_size)
+	};
+	(stats, remap)
+}
+
+#[test]
+fn test_optimize_triangles_grid()
+{
+	// grid of quads, with unshared vertices and triangles in a scattered order
+	let n = 16;
+	let mut vertices = Vec::new();
+	let mut indices = Vec::new();
+	for k in 0..n*n {
+		let (x, y) = ((k * 7 % (n*n)) % n, (k * 7 % (n*n)) / n);
+		for &(dx, dy) in [(0, 0), (1, 0), (1, 1), (0, 0), (1, 1), (0, 1)].iter() {
+			indices.push(vertices.len() as u32);
+			vertices.push(MeshVertex {
+				pos: [(x + dx) as f32, (y + dy) as f32, 0.0],
+				norm: [0.0, 0.0, 1.0],
+				tg: [1.0, 0.0, 0.0, 1.0],
+				tex: [0.0, 0.0]
+			});
+		}
+	}
+	let (stats, remap) = optimize_triangles(&vertices, &[], &mut indices, &MeshOptimization::default());
+	assert_eq!(stats.num_triangles, 2 * n * n);
+	assert_eq!(stats.vertices_after, (n + 1) * (n + 1));
+	assert_eq!(remap.len(), stats.vertices_after);
+	assert!(indices.iter().all(|&i| (i as usize) < remap.len()));
+	assert!(stats.acmr_after < 1.0 && stats.acmr_after < stats.acmr_before);
+}
+
+#[test]
+fn test_optimize_overdraw_clusters()
+{
+	fn vertex(vertices: &mut Vec<MeshVertex>, p: [f32; 3]) -> u32
+	{
+		match vertices.iter().position(|v| v.pos == p) {
+			Some(i) => i as u32,
+			None => {
+				vertices.push(MeshVertex { pos: p, norm: [0.0; 3], tg: [0.0; 4], tex: [0.0; 2] });
+				(vertices.len() - 1) as u32
+			}
+		}
+	}
+
+	// two 3x3 grids sharing an edge: one facing the inside of the mesh (drawn first),
+	// one facing outside. No vertex cache flush between them: only the ACMR
+	// threshold can separate them.
+	let mut vertices = Vec::new();
+	let mut indices = Vec::new();
+	// y = 0, facing +Y (towards the other grid)
+	for z in 0..3 {
+		for x in 0..3 {
+			let (x, z) = (x as f32, z as f32);
+			let a = vertex(&mut vertices, [x, 0.0, z]);
+			let b = vertex(&mut vertices, [x + 1.0, 0.0, z]);
+			let c = vertex(&mut vertices, [x + 1.0, 0.0, z + 1.0]);
+			let d = vertex(&mut vertices, [x, 0.0, z + 1.0]);
+			indices.push_all(&[a, c, b, a, d, c]);
+		}
+	}
+	let inner_triangles = indices.len() / 3;
+	// x = 0, facing -X, starting from the shared edge
+	for y in 0..3 {
+		for z in 0..3 {
+			let (y, z) = (y as f32, z as f32);
+			let a = vertex(&mut vertices, [0.0, y, z]);
+			let b = vertex(&mut vertices, [0.0, y, z + 1.0]);
+			let c = vertex(&mut vertices, [0.0, y + 1.0, z + 1.0]);
+			let d = vertex(&mut vertices, [0.0, y + 1.0, z]);
+			indices.push_all(&[a, b, c, a, c, d]);
+		}
+	}
+
+	let result = optimize_overdraw(&indices, &vertices, 32, 1.5);
+	// same triangles
+	let sorted = |indices: &[u32]| {
+		let mut t = indices.chunks(3).map(|t| t.to_vec()).collect::<Vec<_>>();
+		t.sort();
+		t
+	};
+	assert_eq!(sorted(&result), sorted(&indices));
+	// the outer grid comes first
+	let first = &result[0..3];
+	assert!(indices[3*inner_triangles..].chunks(3).any(|t| t == first));
+}
@@ -0,0 +1,330 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem;
+use nalgebra::*;
+use graphics::MeshVertex;
+
+//-------------------------------------------
+// Mesh simplification by edge collapses ordered by quadric error
+// (Garland and Heckbert, "Surface Simplification Using Quadric Error Metrics").
+//
+// Vertices are collapsed onto one of their neighbors instead of an optimal
+// position, so the simplified triangles index the original vertices: a LOD
+// is only a new range of indices. Vertices on borders and on texture seams
+// (vertices at the same position with different texture coordinates) are never
+// removed, which preserves the outline of the mesh and its texture mapping.
+// Vertices that only differ by their normal (hard edges) are collapsed together.
+
+/// Symmetric 4x4 matrix (upper triangle), and the total weight of the planes
+#[derive(Copy, Clone)]
+struct Quadric
+{
+	m: [f64; 10],
+	weight: f64
+}
+
+impl Quadric
+{
+	fn zero() -> Quadric
+	{
+		Quadric { m: [0.0; 10], weight: 0.0 }
+	}
+
+	/// Squared distance to the plane n.p + d = 0, weighted
+	fn plane(n: Vec3<f64>, d: f64, weight: f64) -> Quadric
+	{
+		let (a, b, c) = (n.x, n.y, n.z);
+		let w = weight;
+		Quadric {
+			m: [w*a*a, w*a*b, w*a*c, w*a*d,
+				w*b*b, w*b*c, w*b*d,
+				w*c*c, w*c*d,
+				w*d*d],
+			weight: weight
+		}
+	}
+
+	fn add(&self, other: &Quadric) -> Quadric
+	{
+		let mut m = self.m;
+		for k in 0..10 {
+			m[k] += other.m[k];
+		}
+		Quadric { m: m, weight: self.weight + other.weight }
+	}
+
+	/// Weighted mean of the squared distances of p to the planes
+	fn error(&self, p: [f32; 3]) -> f64
+	{
+		let (x, y, z) = (p[0] as f64, p[1] as f64, p[2] as f64);
+		let m = &self.m;
+		let e = m[0]*x*x + 2.0*m[1]*x*y + 2.0*m[2]*x*z + 2.0*m[3]*x
+			+ m[4]*y*y + 2.0*m[5]*y*z + 2.0*m[6]*y
+			+ m[7]*z*z + 2.0*m[8]*z
+			+ m[9];
+		if self.weight > 0.0 { e.max(0.0) / self.weight } else { 0.0 }
+	}
+}
+
+fn vec3(p: [f32; 3]) -> Vec3<f32>
+{
+	Vec3::new(p[0], p[1], p[2])
+}
+
+fn position_key(p: [f32; 3]) -> [u32; 3]
+{
+	unsafe {
+		[mem::transmute(p[0] + 0.0), mem::transmute(p[1] + 0.0), mem::transmute(p[2] + 0.0)]
+	}
+}
+
+/// Simplify an indexed triangle list until it has at most target_index_count indices,
+/// or until the next collapse would move the surface by more than max_error
+/// (relative to the radius of the mesh).
+/// Returns the new indices, and the error reached, relative to the radius of the mesh.
+pub fn simplify(vertices: &[MeshVertex], indices: &[u32], target_index_count: usize, max_error: f32) -> (Vec<u32>, f32)
+{
+	let num_vertices = vertices.len();
+	let pos = |i: u32| vertices[i as usize].pos;
+	let mut indices = indices[..indices.len() / 3 * 3].to_vec();
+	if indices.is_empty() {
+		return (indices, 0.0);
+	}
+
+	// vertices at the same position share a quadric, stored at the first of them
+	let mut first_at_position = HashMap::<[u32; 3], u32>::new();
+	let group = (0..num_vertices)
+		.map(|i| *first_at_position.entry(position_key(vertices[i].pos)).or_insert(i as u32))
+		.collect::<Vec<_>>();
+	let mut members = vec![Vec::new(); num_vertices];
+	for i in 0..num_vertices {
+		members[group[i] as usize].push(i as u32);
+	}
+
+	// size of the mesh, for relative errors
+	let (mut lo, mut hi) = (vec3(pos(indices.first().cloned().unwrap_or(0))), vec3(pos(indices.first().cloned().unwrap_or(0))));
+	for &i in indices.iter() {
+		let p = pos(i);
+		lo = Vec3::new(lo.x.min(p[0]), lo.y.min(p[1]), lo.z.min(p[2]));
+		hi = Vec3::new(hi.x.max(p[0]), hi.y.max(p[1]), hi.z.max(p[2]));
+	}
+	let radius = ((hi - lo).norm() * 0.5).max(1e-12) as f64;
+	let max_error_sq = (max_error as f64 * radius) * (max_error as f64 * radius);
+
+	let mut quadrics = vec![Quadric::zero(); num_vertices];
+	let mut edge_count = HashMap::<(u32, u32), u32>::new();
+	for t in indices.chunks(3) {
+		let p = [pos(t[0]), pos(t[1]), pos(t[2])];
+		let p0 = Vec3::new(p[0][0] as f64, p[0][1] as f64, p[0][2] as f64);
+		let p1 = Vec3::new(p[1][0] as f64, p[1][1] as f64, p[1][2] as f64);
+		let p2 = Vec3::new(p[2][0] as f64, p[2][1] as f64, p[2][2] as f64);
+		let n = (p1 - p0).cross(&(p2 - p0));
+		let area = n.norm() * 0.5;
+		if area > 0.0 {
+			let n = n.normalize();
+			let q = Quadric::plane(n, -n.dot(&p0), area);
+			for &i in t.iter() {
+				let g = group[i as usize] as usize;
+				quadrics[g] = quadrics[g].add(&q);
+			}
+		}
+		for k in 0..3 {
+			let (a, b) = (group[t[k] as usize], group[t[(k + 1) % 3] as usize]);
+			*edge_count.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+		}
+	}
+
+	// texture seams, borders and non-manifold edges (by group)
+	let mut locked = (0..num_vertices)
+		.map(|g| members[g].iter().any(|&i| vertices[i as usize].tex != vertices[g].tex))
+		.collect::<Vec<_>>();
+	for (&(a, b), &count) in edge_count.iter() {
+		if count != 2 {
+			locked[a as usize] = true;
+			locked[b as usize] = true;
+		}
+	}
+
+	let mut error_reached = 0.0f64;
+	while indices.len() > target_index_count
+	{
+		let mut vertex_triangles = vec![Vec::new(); num_vertices];
+		for (t, tri) in indices.chunks(3).enumerate() {
+			for &i in tri.iter() {
+				vertex_triangles[i as usize].push(t);
+			}
+		}
+
+		// collapse the group of a onto b, for each edge (a, b)
+		let mut candidates = Vec::new();
+		for tri in indices.chunks(3) {
+			for k in 0..3 {
+				let (a, b) = (tri[k], tri[(k + 1) % 3]);
+				for &(a, b) in [(a, b), (b, a)].iter() {
+					let (ga, gb) = (group[a as usize] as usize, group[b as usize] as usize);
+					if !locked[ga] && ga != gb {
+						let q = quadrics[ga].add(&quadrics[gb]);
+						candidates.push((q.error(pos(b)), a, b));
+					}
+				}
+			}
+		}
+		candidates.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(Ordering::Equal));
+
+		// independent collapses: positions around a collapse are not touched again in the same pass
+		let mut touched = vec![false; num_vertices];
+		let mut collapse = (0..num_vertices as u32).collect::<Vec<_>>();
+		let triangles_to_remove = (indices.len() - target_index_count + 2) / 3;
+		let mut removed = 0;
+		for &(cost, a, b) in candidates.iter()
+		{
+			if cost > max_error_sq || removed >= triangles_to_remove {
+				break;
+			}
+			let (ga, gb) = (group[a as usize] as usize, group[b as usize] as usize);
+			if touched[ga] || touched[gb] {
+				continue;
+			}
+			// reject collapses that flip triangles
+			let (pa, pb) = (vec3(pos(a)), vec3(pos(b)));
+			let flips = members[ga].iter().any(|&v| vertex_triangles[v as usize].iter().any(|&t| {
+				let tri = &indices[3*t..3*t + 3];
+				if tri.iter().any(|&i| group[i as usize] as usize == gb) {
+					// removed by the collapse
+					return false;
+				}
+				let k = tri.iter().position(|&i| i == v).unwrap();
+				let (p1, p2) = (vec3(pos(tri[(k + 1) % 3])), vec3(pos(tri[(k + 2) % 3])));
+				let before = (p1 - pa).cross(&(p2 - pa));
+				let after = (p1 - pb).cross(&(p2 - pb));
+				before.dot(&after) <= 0.0
+			}));
+			if flips {
+				continue;
+			}
+
+			// the group of a is not on a texture seam, so b has the texture coordinates of its triangles:
+			// each vertex of the group goes to the vertex at b with these coordinates and the closest normal
+			for &v in members[ga].iter() {
+				let n = vec3(vertices[v as usize].norm);
+				let mut target = b;
+				let mut best = vec3(vertices[b as usize].norm).dot(&n);
+				for &w in members[gb].iter() {
+					let d = vec3(vertices[w as usize].norm).dot(&n);
+					if vertices[w as usize].tex == vertices[b as usize].tex && d > best {
+						target = w;
+						best = d;
+					}
+				}
+				collapse[v as usize] = target;
+			}
+			quadrics[gb] = quadrics[gb].add(&quadrics[ga]);
+			error_reached = error_reached.max(cost);
+			touched[gb] = true;
+			for &v in members[ga].iter() {
+				for &t in vertex_triangles[v as usize].iter() {
+					let tri = &indices[3*t..3*t + 3];
+					for &i in tri.iter() {
+						touched[group[i as usize] as usize] = true;
+					}
+					if tri.iter().any(|&i| group[i as usize] as usize == gb) {
+						removed += 1;
+					}
+				}
+			}
+		}
+		if removed == 0 {
+			break;
+		}
+
+		// triangles that have two corners at the same position are removed
+		let mut collapsed = Vec::with_capacity(indices.len());
+		for tri in indices.chunks(3) {
+			let t = [collapse[tri[0] as usize], collapse[tri[1] as usize], collapse[tri[2] as usize]];
+			let g = [group[t[0] as usize], group[t[1] as usize], group[t[2] as usize]];
+			if g[0] != g[1] && g[1] != g[2] && g[0] != g[2] {
+				collapsed.push_all(&t);
+			}
+		}
+		indices = collapsed;
+	}
+
+	(indices, (error_reached.sqrt() / radius) as f32)
+}
+
+#[test]
+fn test_simplify_plane()
+{
+	// flat grid: the interior vertices can all be removed without error
+	let n = 8;
+	let vertices = (0..(n + 1) * (n + 1)).map(|i| MeshVertex {
+		pos: [(i % (n + 1)) as f32, (i / (n + 1)) as f32, 0.0],
+		norm: [0.0, 0.0, 1.0],
+		tg: [1.0, 0.0, 0.0, 1.0],
+		tex: [0.0, 0.0]
+	}).collect::<Vec<_>>();
+	let mut indices = Vec::new();
+	for y in 0..n {
+		for x in 0..n {
+			let i = (y * (n + 1) + x) as u32;
+			let s = (n + 1) as u32;
+			indices.push_all(&[i, i + 1, i + s + 1, i, i + s + 1, i + s]);
+		}
+	}
+	let (simplified, error) = simplify(&vertices, &indices, 0, 0.01);
+	assert!(simplified.len() < indices.len() / 2);
+	assert!(error < 1e-3);
+	// the border vertices are kept
+	assert!(simplified.contains(&0) && simplified.contains(&(n as u32)));
+}
+
+#[test]
+fn test_simplify_seams()
+{
+	// flat grid, with its right half using copies of the vertices of the middle column:
+	// with a different normal (hard edge), the middle column can be simplified;
+	// with different texture coordinates (texture seam), it is kept
+	let n = 8;
+	let s = (n + 1) as u32;
+	let grid = |seam: MeshVertex| {
+		let mut vertices = (0..(n + 1) * (n + 1)).map(|i| MeshVertex {
+			pos: [(i % (n + 1)) as f32, (i / (n + 1)) as f32, 0.0],
+			norm: [0.0, 0.0, 1.0],
+			tg: [1.0, 0.0, 0.0, 1.0],
+			tex: [0.0, 0.0]
+		}).collect::<Vec<_>>();
+		for y in 0..n + 1 {
+			vertices.push(MeshVertex { pos: [(n / 2) as f32, y as f32, 0.0], .. seam });
+		}
+		let mut indices = Vec::new();
+		for y in 0..n as u32 {
+			for x in 0..n as u32 {
+				let i = y * s + x;
+				let mut quad = [i, i + 1, i + s + 1, i, i + s + 1, i + s];
+				if x >= n as u32 / 2 {
+					for k in quad.iter_mut() {
+						if *k % s == n as u32 / 2 {
+							*k = s * s + *k / s;
+						}
+					}
+				}
+				indices.push_all(&quad);
+			}
+		}
+		(vertices, indices)
+	};
+	let middle_kept = |vertices: &[MeshVertex], simplified: &[u32]| {
+		(1..n).all(|y| simplified.iter().any(|&i| vertices[i as usize].pos == [(n / 2) as f32, y as f32, 0.0]))
+	};
+
+	let hard_edge = MeshVertex { pos: [0.0; 3], norm: [0.0, 1.0, 0.0], tg: [1.0, 0.0, 0.0, 1.0], tex: [0.0, 0.0] };
+	let (vertices, indices) = grid(hard_edge);
+	let (simplified, _) = simplify(&vertices, &indices, 0, 0.01);
+	assert!(simplified.len() < indices.len() / 2);
+	assert!(!middle_kept(&vertices, &simplified));
+
+	let texture_seam = MeshVertex { tex: [1.0, 0.0], .. hard_edge };
+	let (vertices, indices) = grid(texture_seam);
+	let (simplified, _) = simplify(&vertices, &indices, 0, 0.01);
+	assert!(middle_kept(&vertices, &simplified));
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::mem;
+use nalgebra::*;
+use graphics::MeshVertex;
+use mesh_data::{MeshData, MeshLoadError};
+use tangent_space::generate_tangents;
+use rendering::*;
+
+//-------------------------------------------
+// Procedural meshes: spheres, boxes, cylinders, cones, tori, planes and capsules.
+// Y is up, shapes are centered on the origin, triangles are counter-clockwise
+// seen from the outside. Surfaces of revolution have a seam at u = 0,
+// where the vertices are duplicated to get continuous texture coordinates.
+//
+// In scene files, meshes named "builtin:<shape>?<param>=<value>&..." are
+// generated with from_spec, e.g. "builtin:sphere?segments=32".
+
+struct Builder
+{
+	vertices: Vec<MeshVertex>,
+	indices: Vec<u32>
+}
+
+fn vec3(v: [f32; 3]) -> Vec3<f32>
+{
+	Vec3::new(v[0], v[1], v[2])
+}
+
+impl Builder
+{
+	fn new() -> Builder
+	{
+		Builder {
+			vertices: Vec::new(),
+			indices: Vec::new()
+		}
+	}
+
+	fn vertex(&mut self, pos: Vec3<f32>, norm: Vec3<f32>, tex: [f32; 2]) -> u32
+	{
+		self.vertices.push(MeshVertex {
+			pos: [pos.x, pos.y, pos.z],
+			norm: [norm.x, norm.y, norm.z],
+			tg: [0.0, 0.0, 0.0, 1.0],
+			tex: tex
+		});
+		(self.vertices.len() - 1) as u32
+	}
+
+	/// Add a triangle, wound so that it faces the direction of the vertex normals.
+	/// Degenerate triangles (at the poles of spheres and cones) are skipped.
+	fn triangle(&mut self, a: u32, b: u32, c: u32)
+	{
+		let (va, vb, vc) = (self.vertices[a as usize], self.vertices[b as usize], self.vertices[c as usize]);
+		let n = (vec3(vb.pos) - vec3(va.pos)).cross(&(vec3(vc.pos) - vec3(va.pos)));
+		if n.norm() < 1e-12 {
+			return;
+		}
+		if n.dot(&(vec3(va.norm) + vec3(vb.norm) + vec3(vc.norm))) < 0.0 {
+			self.indices.push_all(&[a, c, b]);
+		} else {
+			self.indices.push_all(&[a, b, c]);
+		}
+	}
+
+	/// Quads between rows of stride vertices
+	fn quads(&mut self, first: u32, rows: usize, stride: usize)
+	{
+		let stride = stride as u32;
+		for i in 0..rows as u32 {
+			for j in 0..stride - 1 {
+				let a = first + i * stride + j;
+				let c = a + stride;
+				self.triangle(a, c, c + 1);
+				self.triangle(a, c + 1, a + 1);
+			}
+		}
+	}
+
+	/// Planar grid: origin + u * du + v * dv, with u and v in [0, 1]
+	fn grid(&mut self, origin: Vec3<f32>, du: Vec3<f32>, dv: Vec3<f32>, normal: Vec3<f32>, cols: usize, rows: usize)
+	{
+		let first = self.vertices.len() as u32;
+		for i in 0..rows + 1 {
+			for j in 0..cols + 1 {
+				let (u, v) = (j as f32 / cols as f32, i as f32 / rows as f32);
+				self.vertex(origin + du * u + dv * v, normal, [u, v]);
+			}
+		}
+		self.quads(first, rows, cols + 1);
+	}
+
+	/// Surface of revolution around Y. Each profile point is
+	/// (radius, y, radial component of the normal, y component of the normal, v),
+	/// from top to bottom.
+	fn lathe(&mut self, profile: &[(f32, f32, f32, f32, f32)], segments: usize)
+	{
+		let first = self.vertices.len() as u32;
+		for &(r, y, nr, ny, v) in profile.iter() {
+			for j in 0..segments + 1 {
+				let u = j as f32 / segments as f32;
+				let (s, c) = (u * 2.0 * PI).sin_cos();
+				self.vertex(
+					Vec3::new(r * c, y, -r * s),
+					Vec3::new(nr * c, ny, -nr * s),
+					[u, 1.0 - v]);
+			}
+		}
+		self.quads(first, profile.len() - 1, segments + 1);
+	}
+
+	/// Disc in a horizontal plane, facing up (normal_y = 1) or down (normal_y = -1)
+	fn disc(&mut self, y: f32, radius: f32, normal_y: f32, segments: usize)
+	{
+		let normal = Vec3::new(0.0, normal_y, 0.0);
+		let center = self.vertex(Vec3::new(0.0, y, 0.0), normal, [0.5, 0.5]);
+		for j in 0..segments + 1 {
+			let (s, c) = (j as f32 / segments as f32 * 2.0 * PI).sin_cos();
+			self.vertex(Vec3::new(radius * c, y, -radius * s), normal, [0.5 + 0.5 * c, 0.5 - 0.5 * s * normal_y]);
+		}
+		for j in 0..segments as u32 {
+			self.triangle(center, center + 1 + j, center + 2 + j);
+		}
+	}
+
+	fn finish(mut self, name: &str) -> MeshData
+	{
+		generate_tangents(&mut self.vertices, &mut self.indices);
+		let mut data = MeshData::new();
+		data.add_submesh(name, PrimitiveType::Triangle, &self.vertices, &self.indices, None, true);
+		data
+	}
+}
+
+/// Sphere with segments meridians and rings parallels
+pub fn uv_sphere(radius: f32, segments: usize, rings: usize) -> MeshData
+{
+	let (segments, rings) = (segments.max(3), rings.max(2));
+	let profile = (0..rings + 1).map(|i| {
+		let v = i as f32 / rings as f32;
+		let (s, c) = (v * PI).sin_cos();
+		(radius * s, radius * c, s, c, v)
+	}).collect::<Vec<_>>();
+	let mut b = Builder::new();
+	b.lathe(&profile, segments);
+	b.finish("sphere")
+}
+
+/// Sphere made of a subdivided icosahedron (20 * 4^subdivisions triangles)
+pub fn icosphere(radius: f32, subdivisions: usize) -> MeshData
+{
+	let t = (1.0 + 5.0f32.sqrt()) / 2.0;
+	let mut positions = [
+		[-1.0, t, 0.0], [1.0, t, 0.0], [-1.0, -t, 0.0], [1.0, -t, 0.0],
+		[0.0, -1.0, t], [0.0, 1.0, t], [0.0, -1.0, -t], [0.0, 1.0, -t],
+		[t, 0.0, -1.0], [t, 0.0, 1.0], [-t, 0.0, -1.0], [-t, 0.0, 1.0]
+	].iter().map(|&p| vec3(p).normalize()).collect::<Vec<_>>();
+	let mut triangles = vec![
+		[0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+		[1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+		[3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+		[4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1]];
+
+	for _ in 0..subdivisions.min(8) {
+		let mut midpoints = HashMap::<(usize, usize), usize>::new();
+		let mut subdivided = Vec::with_capacity(triangles.len() * 4);
+		for tri in triangles.iter() {
+			let mut m = [0; 3];
+			for k in 0..3 {
+				let (a, b) = (tri[k], tri[(k + 1) % 3]);
+				m[k] = *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+					let midpoint = (positions[a] + positions[b]).normalize();
+					positions.push(midpoint);
+					positions.len() - 1
+				});
+			}
+			subdivided.push_all(&[[tri[0], m[0], m[2]], [tri[1], m[1], m[0]], [tri[2], m[2], m[1]], [m[0], m[1], m[2]]]);
+		}
+		triangles = subdivided;
+	}
+
+	// spherical texture coordinates, as uv_sphere: vertices are split along the seam and at the poles
+	let uv = |p: Vec3<f32>| {
+		let u = (-p.z).atan2(p.x) / (2.0 * PI);
+		[if u < 0.0 { u + 1.0 } else { u }, 1.0 - p.y.max(-1.0).min(1.0).acos() / PI]
+	};
+	let mut b = Builder::new();
+	let mut vertex_map = HashMap::<(usize, [u32; 2]), u32>::new();
+	for tri in triangles.iter() {
+		let p = [positions[tri[0]], positions[tri[1]], positions[tri[2]]];
+		let mut tex = [uv(p[0]), uv(p[1]), uv(p[2])];
+		let max_u = tex.iter().fold(0.0f32, |m, t| m.max(t[0]));
+		for t in tex.iter_mut() {
+			if max_u - t[0] > 0.5 {
+				t[0] += 1.0;
+			}
+		}
+		for k in 0..3 {
+			if p[k].y.abs() > 1.0 - 1e-6 {
+				tex[k][0] = (tex[(k + 1) % 3][0] + tex[(k + 2) % 3][0]) * 0.5;
+			}
+		}
+		let mut v = [0; 3];
+		for k in 0..3 {
+			let key = (tri[k], unsafe { [mem::transmute(tex[k][0]), mem::transmute(tex[k][1])] });
+			v[k] = match vertex_map.get(&key) {
+				Some(&i) => i,
+				None => b.vertex(p[k] * radius, p[k], tex[k])
+			};
+			vertex_map.insert(key, v[k]);
+		}
+		b.triangle(v[0], v[1], v[2]);
+	}
+	b.finish("icosphere")
+}
+
+/// Box of the given size, each face divided in segments x segments quads
+pub fn cube(size: [f32; 3], segments: usize) -> MeshData
+{
+	let segments = segments.max(1);
+	let h = Vec3::new(size[0], size[1], size[2]) * 0.5;
+	let axes = [Vec3::new(1.0f32, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)];
+	let mut b = Builder::new();
+	for k in 0..3 {
+		for &sign in [1.0f32, -1.0].iter() {
+			let n = axes[k] * sign;
+			// u and v axes: right and up, seen from outside the face
+			let up = if k == 1 { axes[2] * -sign } else { axes[1] };
+			let right = up.cross(&n);
+			let extent = |axis: Vec3<f32>| axis.x.abs() * size[0] + axis.y.abs() * size[1] + axis.z.abs() * size[2];
+			let (du, dv) = (right * extent(right), up * extent(up));
+			let center = Vec3::new(n.x * h.x, n.y * h.y, n.z * h.z);
+			b.grid(center - du * 0.5 - dv * 0.5, du, dv, n, segments, segments);
+		}
+	}
+	b.finish("box")
+}
+
+/// Capped cylinder along Y, with rings divisions along its height
+pub fn cylinder(radius: f32, height: f32, segments: usize, rings: usize) -> MeshData
+{
+	let (segments, rings) = (segments.max(3), rings.max(1));
+	let profile = (0..rings + 1).map(|i| {
+		let v = i as f32 / rings as f32;
+		(radius, height * (0.5 - v), 1.0, 0.0, v)
+	}).collect::<Vec<_>>();
+	let mut b = Builder::new();
+	b.lathe(&profile, segments);
+	b.disc(height * 0.5, radius, 1.0, segments);
+	b.disc(-height * 0.5, radius, -1.0, segments);
+	b.finish("cylinder")
+}
+
+/// Cone along Y, apex up, with a base cap
+pub fn cone(radius: f32, height: f32, segments: usize, rings: usize) -> MeshData
+{
+	let (segments, rings) = (segments.max(3), rings.max(1));
+	// normal of the sides
+	let slant = Vec3::new(height, radius, 0.0).normalize();
+	let profile = (0..rings + 1).map(|i| {
+		let v = i as f32 / rings as f32;
+		(radius * v, height * (0.5 - v), slant.x, slant.y, v)
+	}).collect::<Vec<_>>();
+	let mut b = Builder::new();
+	b.lathe(&profile, segments);
+	b.disc(-height * 0.5, radius, -1.0, segments);
+	b.finish("cone")
+}
+
+/// Torus around Y: radius is the distance from the center to the center of the tube
+pub fn torus(radius: f32, tube_radius: f32, segments: usize, sides: usize) -> MeshData
+{
+	let (segments, sides) = (segments.max(3), sides.max(3));
+	let profile = (0..sides + 1).map(|i| {
+		let v = i as f32 / sides as f32;
+		let (s, c) = (v * 2.0 * PI).sin_cos();
+		(radius + tube_radius * c, tube_radius * s, c, s, v)
+	}).collect::<Vec<_>>();
+	let mut b = Builder::new();
+	b.lathe(&profile, segments);
+	b.finish("torus")
+}
+
+/// Horizontal plane facing up, divided in segments x segments quads
+pub fn plane(width: f32, depth: f32, segments: usize) -> MeshData
+{
+	let segments = segments.max(1);
+	let mut b = Builder::new();
+	b.grid(
+		Vec3::new(-width * 0.5, 0.0, depth * 0.5),
+		Vec3::new(width, 0.0, 0.0),
+		Vec3::new(0.0, 0.0, -depth),
+		Vec3::new(0.0, 1.0, 0.0),
+		segments,
+		segments);
+	b.finish("plane")
+}
+
+/// Cylinder of the given height along Y, closed by two hemispheres of rings parallels
+/// (the total height is height + 2 * radius)
+pub fn capsule(radius: f32, height: f32, segments: usize, rings: usize) -> MeshData
+{
+	let (segments, rings) = (segments.max(3), rings.max(1));
+	// v proportional to the length along the profile
+	let length = PI * radius + height;
+	let mut profile = Vec::with_capacity(2 * rings + 2);
+	for i in 0..rings + 1 {
+		let a = i as f32 / rings as f32 * PI * 0.5;
+		let (s, c) = a.sin_cos();
+		profile.push((radius * s, height * 0.5 + radius * c, s, c, a * radius / length));
+	}
+	for i in 0..rings + 1 {
+		let a = (1.0 + i as f32 / rings as f32) * PI * 0.5;
+		let (s, c) = a.sin_cos();
+		profile.push((radius * s, -height * 0.5 + radius * c, s, c, (a * radius + height) / length));
+	}
+	let mut b = Builder::new();
+	b.lathe(&profile, segments);
+	b.finish("capsule")
+}
+
+/// Parameters that are dimensions, and must be positive
+const DIMENSIONS: [&'static str; 6] = ["radius", "tube", "size", "width", "height", "depth"];
+
+/// Parameters of a primitive specification
+struct Params
+{
+	values: HashMap<String, f32>
+}
+
+impl Params
+{
+	fn get(&mut self, name: &str, default: f32) -> f32
+	{
+		self.values.remove(name).unwrap_or(default)
+	}
+
+	fn count(&mut self, name: &str, default: usize) -> usize
+	{
+		self.values.remove(name).map(|v| v.max(0.0).round() as usize).unwrap_or(default)
+	}
+}
+
+/// Generate a primitive from its specification: "<shape>?<param>=<value>&...",
+/// optionally prefixed with "builtin:". Shapes and parameters (with default values):
+///
+///     sphere      radius=1 segments=32 rings=16
+///     icosphere   radius=1 subdivisions=3
+///     box, cube   size=1 width=size height=size depth=size segments=1
+///     cylinder    radius=0.5 height=1 segments=32 rings=1
+///     cone        radius=0.5 height=1 segments=32 rings=1
+///     torus       radius=1 tube=0.25 segments=48 sides=16
+///     plane       size=1 width=size depth=size segments=1
+///     capsule     radius=0.5 height=1 segments=32 rings=8
+pub fn from_spec(spec: &str) -> Result<MeshData, MeshLoadError>
+{
+	let invalid = |msg: String| Err(MeshLoadError::InvalidData(format!("{}: {}", spec, msg)));
+	let s = if spec.starts_with("builtin:") { &spec["builtin:".len()..] } else { spec };
+	let (shape, query) = match s.find('?') {
+		Some(i) => (&s[..i], &s[i + 1..]),
+		None => (s, "")
+	};
+
+	let mut p = Params { values: HashMap::new() };
+	for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+		let mut kv = pair.splitn(2, '=');
+		let key = kv.next().unwrap();
+		match kv.next().unwrap_or("").parse::<f32>() {
+			Ok(value) if DIMENSIONS.contains(&key) && !(value > 0.0 && value.is_finite()) =>
+				return invalid(format!("{} must be positive", key)),
+			Ok(value) => { p.values.insert(key.to_string(), value); },
+			Err(_) => return invalid(format!("invalid value for {}", key))
+		}
+	}
+
+	let data = match shape {
+		"sphere" => uv_sphere(p.get("radius", 1.0), p.count("segments", 32), p.count("rings", 16)),
+		"icosphere" => icosphere(p.get("radius", 1.0), p.count("subdivisions", 3)),
+		"box" | "cube" => {
+			let size = p.get("size", 1.0);
+			let size = [p.get("width", size), p.get("height", size), p.get("depth", size)];
+			cube(size, p.count("segments", 1))
+		},
+		"cylinder" => cylinder(p.get("radius", 0.5), p.get("height", 1.0), p.count("segments", 32), p.count("rings", 1)),
+		"cone" => cone(p.get("radius", 0.5), p.get("height", 1.0), p.count("segments", 32), p.count("rings", 1)),
+		"torus" => torus(p.get("radius", 1.0), p.get("tube", 0.25), p.count("segments", 48), p.count("sides", 16)),
+		"plane" => {
+			let size = p.get("size", 1.0);
+			plane(p.get("width", size), p.get("depth", size), p.count("segments", 1))
+		},
+		"capsule" => capsule(p.get("radius", 0.5), p.get("height", 1.0), p.count("segments", 32), p.count("rings", 8)),
+		_ => return invalid(format!("unknown primitive {}", shape))
+	};
+	if let Some(name) = p.values.keys().next() {
+		return invalid(format!("unknown parameter {}", name));
+	}
+	Ok(data)
+}
+
+#[test]
+fn test_primitives_face_outwards()
+{
+	for spec in ["sphere", "icosphere?subdivisions=2", "box?segments=2", "cylinder?rings=2",
+				 "cone", "capsule", "plane?segments=3"].iter() {
+		let data = from_spec(spec).unwrap();
+		assert!(!data.indices.is_empty(), "{}", spec);
+		for v in data.vertices.iter() {
+			assert!((vec3(v.norm).norm() - 1.0).abs() < 1e-4, "{}", spec);
+			assert!(vec3(v.norm).dot(&vec3([v.tg[0], v.tg[1], v.tg[2]])).abs() < 1e-3, "{}", spec);
+		}
+		for t in data.indices.chunks(3) {
+			let p = [vec3(data.vertices[t[0] as usize].pos), vec3(data.vertices[t[1] as usize].pos), vec3(data.vertices[t[2] as usize].pos)];
+			let n = (p[1] - p[0]).cross(&(p[2] - p[0]));
+			// convex shapes centered on the origin (the plane faces up)
+			let outwards = if *spec == "plane?segments=3" { Vec3::new(0.0, 1.0, 0.0) } else { p[0] + p[1] + p[2] };
+			assert!(n.dot(&outwards) > 0.0, "{}", spec);
+		}
+	}
+	assert!(from_spec("builtin:sphere?segments=8&radius=2").is_ok());
+	assert!(from_spec("builtin:sphere?side=8").is_err());
+	assert!(from_spec("builtin:teapot").is_err());
+	assert!(from_spec("sphere?radius=0").is_err());
+	assert!(from_spec("box?height=-1").is_err());
+	assert!(from_spec("torus?tube=-0.5").is_err());
+}
+
+#[test]
+fn test_torus()
+{
+	let (radius, tube) = (1.0, 0.25);
+	let data = torus(radius, tube, 12, 8);
+	// (segments + 1) * (sides + 1) vertices: the seams are duplicated for the texture coordinates
+	assert_eq!(data.vertices.len(), 13 * 9);
+	assert_eq!(data.indices.len(), 12 * 8 * 2 * 3);
+	for v in data.vertices.iter() {
+		let pos = vec3(v.pos);
+		// the normal points away from the center of the tube
+		let center = Vec3::new(pos.x, 0.0, pos.z) * (radius / Vec3::new(pos.x, 0.0, pos.z).norm());
+		let expected = (pos - center) / tube;
+		assert!((expected.norm() - 1.0).abs() < 1e-4);
+		assert!((vec3(v.norm) - expected).norm() < 1e-4);
+	}
+}
@@ -8,8 +8,11 @@ use nalgebra::*;
 use std::mem;
 use std::raw;
 use rendering::shader::{GLProgram};
+use rendering::layout::*;
+use std::cell::Cell;
+use std::any::TypeId;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum AttributeType
 {
 	 // 32x4
@@ -205,6 +208,7 @@ impl AttributeType
 	}
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct Attribute
 {
 	pub slot: u32,
@@ -215,7 +219,12 @@ pub struct Attribute
 pub struct InputLayout
 {
 	pub vao: GLuint,
-	pub strides: Vec<i32>
+	pub strides: Vec<i32>,
+	attribs: Vec<Attribute>,
+	/// vertex structure the layout was built from, and its name
+	vertex_format: Option<(TypeId, &'static str)>,
+	/// last vertex format that passed check_vertex_format
+	checked_format: Cell<Option<TypeId>>
 }
 
 impl InputLayout
@@ -250,7 +259,82 @@ impl InputLayout
 			assert!(strides[i] != 0, "A buffer slot has no attributes.");
 		}
 
-		InputLayout { vao: vao, strides: strides }
+		InputLayout {
+			vao: vao,
+			strides: strides,
+			attribs: attribs.to_vec(),
+			vertex_format: None,
+			checked_format: Cell::new(None)
+		}
+	}
+
+	/// Layout of a vertex structure, in buffer slot 0, with the offsets and the stride of the structure
+	pub fn from_vertex_format<V: VertexFormat>() -> InputLayout
+	{
+		let attributes = V::attributes();
+		let mut vao : GLuint = 0;
+
+		unsafe {
+			gl::GenVertexArrays(1, &mut vao);
+			gl::BindVertexArray(vao);
+		}
+
+		for (i, a) in attributes.iter().enumerate()
+		{
+			let (ty, nb_comp, norm) = a.ty.gl_description();
+			unsafe {
+				gl::EnableVertexAttribArray(i as u32);
+				gl::VertexAttribFormat(i as u32, nb_comp as i32, ty, norm as u8, a.offset as u32);
+				gl::VertexAttribBinding(i as u32, 0);
+			}
+		}
+
+		InputLayout {
+			vao: vao,
+			strides: vec![V::stride() as i32],
+			attribs: attributes.iter().map(|a| Attribute { slot: 0, ty: a.ty }).collect(),
+			vertex_format: Some((TypeId::of::<V>(), V::name())),
+			checked_format: Cell::new(None)
+		}
+	}
+
+	/// Vertex structure the layout was built from, if any
+	pub fn vertex_format(&self) -> Option<TypeId>
+	{
+		self.vertex_format.map(|(id, _)| id)
+	}
+
+	pub fn attributes(&self) -> &[Attribute]
+	{
+		&self.attribs
+	}
+
+	/// Panics if the vertex structure is not the one the layout was built from.
+	/// For layouts built from a list of attributes, panics if the structure does not
+	/// match the (tightly packed) attributes of buffer slot 0; skipped if the same
+	/// format was checked last.
+	pub fn check_vertex_format<V: VertexFormat>(&self)
+	{
+		let id = TypeId::of::<V>();
+		if let Some((format, name)) = self.vertex_format {
+			assert!(format == id, "vertex format {} used with an input layout built for {}", V::name(), name);
+			return;
+		}
+		if self.checked_format.get() == Some(id) {
+			return;
+		}
+		let attributes = V::attributes();
+		let inputs = self.attribs.iter().filter(|a| a.slot == 0).map(|a| a.ty).collect::<Vec<_>>();
+		if let Err(e) = check_vertex_layout(V::name(), &attributes, &inputs) {
+			panic!("{}", e);
+		}
+		let mut offset = 0;
+		for a in attributes.iter() {
+			assert!(a.offset == offset, "vertex format {}: {} is at offset {}, the input layout expects {}", V::name(), a.name, a.offset, offset);
+			offset += a.size;
+		}
+		assert!(V::stride() == self.strides[0] as usize, "vertex format {}: stride is {}, the input layout expects {}", V::name(), V::stride(), self.strides[0]);
+		self.checked_format.set(Some(id));
 	}
 
 	pub fn bind(&self)
@@ -8,6 +8,9 @@ use std::cell::RefCell;
 use std::mem;
 use std::raw;
 use super::attrib::InputLayout;
+use super::debug::set_object_label;
+use super::state_cache::with_state_cache;
+use super::resource_tracker::*;
 
 /// Treat a given slice as `&[u8]` for the given function call
 pub fn as_byte_slice<T>(slice: &[T]) -> &[u8] {
@@ -42,6 +45,8 @@ pub enum BufferBindingHint {
     VertexBuffer,
     IndexBuffer,
     UniformBuffer,
+    StorageBuffer,
+    PixelPackBuffer,
 }
 
 #[derive(Copy, Clone)]
@@ -57,6 +62,40 @@ pub struct Binding<'a> {
     pub slice: RawBufSlice<'a>,
 }
 
+/// Access to a resource by shaders
+/// Should match the qualifiers (readonly, writeonly) in the shader
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShaderAccess {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl ShaderAccess {
+    pub fn to_gl(self) -> GLenum {
+        match self {
+            ShaderAccess::ReadOnly => gl::READ_ONLY,
+            ShaderAccess::WriteOnly => gl::WRITE_ONLY,
+            ShaderAccess::ReadWrite => gl::READ_WRITE,
+        }
+    }
+
+    pub fn is_write(self) -> bool {
+        match self {
+            ShaderAccess::ReadOnly => false,
+            _ => true,
+        }
+    }
+}
+
+/// Shader storage buffer binding
+#[derive(Copy, Clone)]
+pub struct StorageBinding<'a> {
+    pub slot: u32,
+    pub slice: RawBufSlice<'a>,
+    pub access: ShaderAccess,
+}
+
 #[derive(Debug)]
 pub struct RawBuffer {
     access: BufferAccess,
@@ -89,6 +128,26 @@ impl RawBuffer
             gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.obj);
         }
     }
+
+    pub fn bind_as_pixel_pack_buffer(&self) {
+        unsafe {
+            gl::BindBuffer(gl::PIXEL_PACK_BUFFER, self.obj);
+        }
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.size
+    }
+
+    /// GL buffer object name
+    pub fn gl_object(&self) -> GLuint {
+        self.obj
+    }
+
+    /// Name of the buffer in GL debuggers
+    pub fn set_label(&self, label: &str) {
+        set_object_label(gl::BUFFER, self.obj, label);
+    }
 }
 
 // type-safe wrapper around a buffer object
@@ -173,6 +232,8 @@ impl<T> Buffer<T>
 impl Drop for RawBuffer
 {
     fn drop(&mut self) {
+        with_state_cache(|c| c.forget_buffer(self.obj));
+        untrack_resource(ResourceCategory::Buffer, self.obj);
         unsafe {
 			//trace!("Deleting buffer {}", self.obj);
             gl::DeleteBuffers(1, &mut self.obj)
@@ -229,7 +290,9 @@ fn get_gl_binding(binding: BufferBindingHint) -> u32 {
 	{
 		BufferBindingHint::VertexBuffer => gl::ARRAY_BUFFER,
 		BufferBindingHint::IndexBuffer => gl::ELEMENT_ARRAY_BUFFER,
-		BufferBindingHint::UniformBuffer => gl::UNIFORM_BUFFER
+		BufferBindingHint::UniformBuffer => gl::UNIFORM_BUFFER,
+		BufferBindingHint::StorageBuffer => gl::SHADER_STORAGE_BUFFER,
+		BufferBindingHint::PixelPackBuffer => gl::PIXEL_PACK_BUFFER
 	}
 }
 
@@ -247,9 +310,13 @@ pub fn alloc_raw_buffer(byte_size: usize,
     }
     unsafe {
         let binding_gl = get_gl_binding(binding);
-        let map_flags = get_gl_access_flags(access) | gl::MAP_PERSISTENT_BIT |
-                        gl::MAP_COHERENT_BIT | gl::MAP_INVALIDATE_BUFFER_BIT/* |
+        let mut map_flags = get_gl_access_flags(access) | gl::MAP_PERSISTENT_BIT |
+                        gl::MAP_COHERENT_BIT/* |
 			gl::MAP_UNSYNCHRONIZED_BIT*/;
+        // invalidation is an error when the mapping is readable
+        if let BufferAccess::WriteOnly = access {
+            map_flags |= gl::MAP_INVALIDATE_BUFFER_BIT;
+        }
         let storage_flags = get_gl_storage_flags(access, usage) | gl::MAP_PERSISTENT_BIT;/*|
 			gl::MAP_PERSISTENT_BIT |
 			gl::MAP_COHERENT_BIT*/;
@@ -268,6 +335,7 @@ pub fn alloc_raw_buffer(byte_size: usize,
 			0, byte_size as i64,
 			map_flags);
     }
+    track_resource(ResourceCategory::Buffer, obj, byte_size);
     RawBuffer {
         access: BufferAccess::ReadWrite,
         obj: obj,
@@ -309,9 +377,17 @@ pub fn bind_index_buffer(index_buffer: &RawBufSlice) {
 }
 
 pub fn bind_uniform_buffers(uniform_buffers: &[Binding]) {
-    for binding in uniform_buffers {
+    with_state_cache(|c| {
+        for binding in uniform_buffers {
+            c.bind_uniform_buffer(binding.slot, binding.slice.raw.obj, binding.slice.offset, binding.slice.size);
+        }
+    });
+}
+
+pub fn bind_storage_buffers(storage_buffers: &[StorageBinding]) {
+    for binding in storage_buffers {
         unsafe {
-            gl::BindBufferRange(gl::UNIFORM_BUFFER,
+            gl::BindBufferRange(gl::SHADER_STORAGE_BUFFER,
                                 binding.slot,
                                 binding.slice.raw.obj,
                                 binding.slice.offset as i64,
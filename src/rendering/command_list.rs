@@ -0,0 +1,431 @@
+use gl;
+use gl::types::*;
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use rendering::buffer::*;
+use rendering::context::*;
+use rendering::sampler::Sampler2D;
+use rendering::shader::PipelineState;
+use rendering::state_cache::with_state_cache;
+use rendering::texture::Texture2D;
+
+/// 64-bit draw sort key
+/// From most to least significant:
+/// pass (8 bits), pipeline (12), material (12), mesh (16), depth (16)
+/// Pipelines, materials and meshes are identified by dense ids (see SortIds).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SortKey(pub u64);
+
+pub const SORT_KEY_PIPELINE_BITS: u32 = 12;
+pub const SORT_KEY_MATERIAL_BITS: u32 = 12;
+pub const SORT_KEY_MESH_BITS: u32 = 16;
+
+impl SortKey
+{
+	/// pipeline and material must fit in 12 bits.
+	/// depth is a normalized view depth in [0,1] (clamped), quantized to 16 bits.
+	pub fn new(pass: u8, pipeline: u16, material: u16, mesh: u16, depth: f32) -> SortKey
+	{
+		debug_assert!(pipeline <= 0xFFF && material <= 0xFFF,
+			"sort key: pipeline {} or material {} does not fit in 12 bits", pipeline, material);
+		let depth_bits = (depth.max(0.0).min(1.0) * 65535.0) as u64;
+		SortKey(
+			((pass as u64) << 56) |
+			(((pipeline as u64) & 0xFFF) << 44) |
+			(((material as u64) & 0xFFF) << 32) |
+			((mesh as u64) << 16) |
+			depth_bits)
+	}
+
+	/// Same as new, but sorts back-to-front within a pass (for transparent objects)
+	/// Depth takes precedence over the pipeline and material.
+	pub fn new_back_to_front(pass: u8, pipeline: u16, material: u16, depth: f32) -> SortKey
+	{
+		let depth_bits = ((1.0 - depth.max(0.0).min(1.0)) * 65535.0) as u64;
+		SortKey(
+			((pass as u64) << 56) |
+			(depth_bits << 40) |
+			(((pipeline as u64) & 0xFFF) << 28) |
+			(((material as u64) & 0xFFF) << 16))
+	}
+
+	pub fn pass(self) -> u8
+	{
+		(self.0 >> 56) as u8
+	}
+}
+
+/// Dense ids for a field of the sort keys: objects (GL names, addresses) are numbered
+/// in order of first use, so that they fit in the field instead of being truncated.
+pub struct SortIds<K>
+{
+	ids: HashMap<K, u16>,
+	max_id: u16
+}
+
+impl<K: Hash + Eq> SortIds<K>
+{
+	/// Ids for a field of the given width (at most 16 bits)
+	pub fn new(bits: u32) -> SortIds<K>
+	{
+		assert!(bits > 0 && bits <= 16, "sort key fields are 1 to 16 bits wide");
+		SortIds {
+			ids: HashMap::new(),
+			max_id: ((1u32 << bits) - 1) as u16
+		}
+	}
+
+	/// Past the capacity of the field, new objects share the last id:
+	/// the commands are still correct, only sorted less well.
+	pub fn get(&mut self, key: K) -> u16
+	{
+		let next = cmp::min(self.ids.len(), self.max_id as usize) as u16;
+		*self.ids.entry(key).or_insert(next)
+	}
+}
+
+/// GL objects of a pipeline state, as stored in recorded commands.
+/// Handles only hold GL object names: they can be sent to the threads recording
+/// command lists, and the lifetime ensures that the objects outlive the lists.
+#[derive(Copy, Clone, Debug)]
+pub struct PipelineRef<'a>
+{
+	draw_state: DrawState,
+	program: GLuint,
+	vao: GLuint,
+	vertex_stride: i32,
+	_r: PhantomData<&'a ()>
+}
+
+impl<'a> PipelineRef<'a>
+{
+	pub fn new(pipeline_state: &'a PipelineState) -> PipelineRef<'a>
+	{
+		PipelineRef {
+			draw_state: pipeline_state.draw_state,
+			program: pipeline_state.program.obj,
+			vao: pipeline_state.layout.vao,
+			vertex_stride: pipeline_state.layout.strides[0],
+			_r: PhantomData
+		}
+	}
+
+	/// The objects must outlive the command lists using the handle
+	pub unsafe fn from_raw(draw_state: DrawState, program: GLuint, vao: GLuint, vertex_stride: i32) -> PipelineRef<'a>
+	{
+		PipelineRef {
+			draw_state: draw_state,
+			program: program,
+			vao: vao,
+			vertex_stride: vertex_stride,
+			_r: PhantomData
+		}
+	}
+}
+
+/// A buffer range, as stored in recorded commands (see PipelineRef)
+#[derive(Copy, Clone, Debug)]
+pub struct BufferRef<'a>
+{
+	obj: GLuint,
+	offset: usize,
+	size: usize,
+	_r: PhantomData<&'a ()>
+}
+
+impl<'a> BufferRef<'a>
+{
+	pub fn new(slice: RawBufSlice<'a>) -> BufferRef<'a>
+	{
+		BufferRef {
+			obj: slice.raw.gl_object(),
+			offset: slice.offset,
+			size: slice.size,
+			_r: PhantomData
+		}
+	}
+
+	/// The buffer must outlive the command lists using the handle
+	pub unsafe fn from_raw(obj: GLuint, offset: usize, size: usize) -> BufferRef<'a>
+	{
+		BufferRef { obj: obj, offset: offset, size: size, _r: PhantomData }
+	}
+}
+
+/// A texture and its sampler, as stored in recorded commands (see PipelineRef)
+#[derive(Copy, Clone, Debug)]
+pub struct TextureRef<'a>
+{
+	texture: GLuint,
+	sampler: GLuint,
+	_r: PhantomData<&'a ()>
+}
+
+impl<'a> TextureRef<'a>
+{
+	pub fn new(texture: &'a Texture2D, sampler: &'a Sampler2D) -> TextureRef<'a>
+	{
+		TextureRef { texture: texture.obj, sampler: sampler.gl_object(), _r: PhantomData }
+	}
+
+	/// The texture and sampler must outlive the command lists using the handle
+	pub unsafe fn from_raw(texture: GLuint, sampler: GLuint) -> TextureRef<'a>
+	{
+		TextureRef { texture: texture, sampler: sampler, _r: PhantomData }
+	}
+}
+
+struct DrawItem
+{
+	key: SortKey,
+	draw_state: DrawState,
+	program: GLuint,
+	vao: GLuint,
+	vertex_stride: i32,
+	vertex_buffer: (GLuint, usize),
+	index_buffer: Option<(GLuint, usize)>,
+	part: MeshPart,
+	/// range in CommandList::uniform_buffers
+	uniform_buffers: (usize, usize),
+	/// range in CommandList::textures
+	textures: (usize, usize)
+}
+
+/// A list of draw calls, recorded without issuing any GL command, then
+/// sorted and submitted with Frame::submit.
+///
+/// Commands only hold GL object names; the lifetime ensures that the
+/// resources referenced by the commands outlive the list.
+/// Lists can be recorded on several threads with record, from handles
+/// (PipelineRef, BufferRef, TextureRef) created on the thread that owns the context,
+/// then moved to that thread, merged with append, sorted and submitted.
+pub struct CommandList<'a>
+{
+	items: Vec<DrawItem>,
+	/// (slot, buffer, offset, size)
+	uniform_buffers: Vec<(u32, GLuint, usize, usize)>,
+	/// (slot, texture, sampler)
+	textures: Vec<(u32, GLuint, GLuint)>,
+	_r: PhantomData<&'a ()>
+}
+
+impl<'a> CommandList<'a>
+{
+	pub fn new() -> CommandList<'a>
+	{
+		CommandList {
+			items: Vec::new(),
+			uniform_buffers: Vec::new(),
+			textures: Vec::new(),
+			_r: PhantomData
+		}
+	}
+
+	pub fn len(&self) -> usize
+	{
+		self.items.len()
+	}
+
+	pub fn is_empty(&self) -> bool
+	{
+		self.items.is_empty()
+	}
+
+	pub fn clear(&mut self)
+	{
+		self.items.clear();
+		self.uniform_buffers.clear();
+		self.textures.clear();
+	}
+
+	/// Record a draw call
+	pub fn draw(
+		&mut self,
+		key: SortKey,
+		vertex_buffer: RawBufSlice<'a>,
+		index_buffer: Option<RawBufSlice<'a>>,
+		pipeline_state: &'a PipelineState,
+		part: MeshPart,
+		uniform_buffers: &[Binding<'a>],
+		textures: &[TextureBinding<'a>])
+	{
+		let uniform_buffers = uniform_buffers.iter().map(|b| (b.slot, BufferRef::new(b.slice))).collect::<Vec<_>>();
+		let textures = textures.iter().map(|t| (t.slot, TextureRef::new(t.texture, t.sampler))).collect::<Vec<_>>();
+		self.record(
+			key,
+			BufferRef::new(vertex_buffer),
+			index_buffer.map(BufferRef::new),
+			PipelineRef::new(pipeline_state),
+			part,
+			&uniform_buffers,
+			&textures);
+	}
+
+	/// Record a draw call from handles (on any thread)
+	pub fn record(
+		&mut self,
+		key: SortKey,
+		vertex_buffer: BufferRef<'a>,
+		index_buffer: Option<BufferRef<'a>>,
+		pipeline: PipelineRef<'a>,
+		part: MeshPart,
+		uniform_buffers: &[(u32, BufferRef<'a>)],
+		textures: &[(u32, TextureRef<'a>)])
+	{
+		let ub_start = self.uniform_buffers.len();
+		self.uniform_buffers.extend(uniform_buffers.iter().map(|&(slot, b)| (slot, b.obj, b.offset, b.size)));
+		let tex_start = self.textures.len();
+		self.textures.extend(textures.iter().map(|&(slot, t)| (slot, t.texture, t.sampler)));
+
+		self.items.push(DrawItem {
+			key: key,
+			draw_state: pipeline.draw_state,
+			program: pipeline.program,
+			vao: pipeline.vao,
+			vertex_stride: pipeline.vertex_stride,
+			vertex_buffer: (vertex_buffer.obj, vertex_buffer.offset),
+			index_buffer: index_buffer.map(|ib| (ib.obj, ib.offset)),
+			part: part,
+			uniform_buffers: (ub_start, uniform_buffers.len()),
+			textures: (tex_start, textures.len())
+		});
+	}
+
+	/// Move all commands of another list (e.g. recorded on another thread) into this one
+	pub fn append(&mut self, other: CommandList<'a>)
+	{
+		let CommandList { items, uniform_buffers, textures, .. } = other;
+		let ub_base = self.uniform_buffers.len();
+		let tex_base = self.textures.len();
+		self.uniform_buffers.extend(uniform_buffers.into_iter());
+		self.textures.extend(textures.into_iter());
+		self.items.extend(items.into_iter().map(|mut item| {
+			item.uniform_buffers.0 += ub_base;
+			item.textures.0 += tex_base;
+			item
+		}));
+	}
+
+	/// Sort commands by key. The sort is stable: commands with equal keys
+	/// are submitted in the order they were recorded.
+	pub fn sort(&mut self)
+	{
+		self.items.sort_by(|a, b| a.key.cmp(&b.key));
+	}
+
+	/// Issue the commands, in order, to the currently bound framebuffer.
+	/// Use Frame::submit instead.
+	pub fn execute(&self)
+	{
+		// vertex array object and vertex buffer last bound
+		let mut last_vertex_input = None;
+
+		for item in self.items.iter() {
+			let (ub_start, ub_count) = item.uniform_buffers;
+			let (tex_start, tex_count) = item.textures;
+
+			with_state_cache(|c| {
+				c.set_draw_state(&item.draw_state);
+				if item.index_buffer.is_some() {
+					c.set_primitive_restart(item.part.restart_index);
+				}
+				c.use_program(item.program);
+				for &(slot, obj, offset, size) in self.uniform_buffers[ub_start..ub_start+ub_count].iter() {
+					c.bind_uniform_buffer(slot, obj, offset, size);
+				}
+				for &(slot, tex, sampler) in self.textures[tex_start..tex_start+tex_count].iter() {
+					c.bind_texture(slot, tex);
+					c.bind_sampler(slot, sampler);
+				}
+			});
+
+			unsafe {
+				let vertex_input = (item.vao, item.vertex_buffer, item.index_buffer.map(|ib| ib.0));
+				if last_vertex_input != Some(vertex_input) {
+					gl::BindVertexArray(item.vao);
+					gl::BindVertexBuffer(0, item.vertex_buffer.0, item.vertex_buffer.1 as i64, item.vertex_stride);
+					if let Some((ib, _)) = item.index_buffer {
+						gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ib);
+					}
+					last_vertex_input = Some(vertex_input);
+				}
+
+				let part = item.part;
+				if let Some((_, ib_offset)) = item.index_buffer {
+					gl::DrawElementsInstancedBaseVertexBaseInstance(
+						part.primitive_type.to_gl_mode(),
+						part.num_indices as i32,
+						part.index_type.to_gl(),
+						(ib_offset + part.start_index as usize * part.index_type.byte_size()) as *const GLvoid,
+						1, part.start_vertex as i32, 0);
+				}
+				else
+				{
+					gl::DrawArraysInstanced(
+						part.primitive_type.to_gl_mode(),
+						part.start_vertex as i32,
+						part.num_vertices as i32,
+						1);
+				}
+			}
+		}
+	}
+}
+
+#[test]
+fn test_record_on_threads()
+{
+	use std::thread;
+
+	// handles to objects that outlive the lists (never dereferenced: nothing is submitted)
+	fn record(meshes: &[u16]) -> CommandList<'static>
+	{
+		let pipeline = unsafe { PipelineRef::from_raw(DrawState::default(), 1, 1, 48) };
+		let vertex_buffer = unsafe { BufferRef::from_raw(1, 0, 4800) };
+		let mut commands = CommandList::new();
+		for &mesh in meshes.iter() {
+			let uniforms = unsafe { BufferRef::from_raw(2, 256 * mesh as usize, 64) };
+			commands.record(
+				SortKey::new(0, 1, 0, mesh, 0.5),
+				vertex_buffer,
+				None,
+				pipeline,
+				MeshPart::vertices(PrimitiveType::Triangle, 3 * mesh as u32, 3),
+				&[(0, uniforms)],
+				&[]);
+		}
+		commands
+	}
+
+	let a = thread::spawn(|| record(&[5, 1, 3]));
+	let b = thread::spawn(|| record(&[4, 2, 0]));
+	let mut commands = a.join().unwrap();
+	commands.append(b.join().unwrap());
+	commands.sort();
+
+	assert_eq!(commands.len(), 6);
+	for (i, item) in commands.items.iter().enumerate() {
+		assert_eq!(item.key, SortKey::new(0, 1, 0, i as u16, 0.5));
+		assert_eq!(item.part.start_vertex, 3 * i as u32);
+		// the uniform buffers of the appended list follow their commands
+		assert_eq!(item.uniform_buffers.1, 1);
+		assert_eq!(commands.uniform_buffers[item.uniform_buffers.0], (0, 2, 256 * i, 64));
+	}
+}
+
+#[test]
+fn test_sort_ids()
+{
+	// GL names do not fit in the field: dense ids do
+	let mut ids = SortIds::new(2);
+	assert_eq!(ids.get(0x12345u32), 0);
+	assert_eq!(ids.get(0x22345), 1);
+	assert_eq!(ids.get(0x12345), 0);
+	assert_eq!(ids.get(7), 2);
+	assert_eq!(ids.get(8), 3);
+	// full: new objects share the last id
+	assert_eq!(ids.get(9), 3);
+	assert_eq!(ids.get(0x22345), 1);
+}
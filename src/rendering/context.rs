@@ -2,6 +2,15 @@ use rendering::buffer::*;
 use rendering::texture::*;
 use rendering::attrib::*;
 use rendering::sampler::Sampler2D;
+use rendering::readback::*;
+use rendering::profiler::*;
+use rendering::debug::*;
+use rendering::state_cache::*;
+use rendering::command_list::CommandList;
+use rendering::resource_tracker::*;
+use rendering::layout::*;
+use rendering::draw_call::DrawCall;
+use image::DynamicImage;
 use rendering::shader::*;
 use gl::types::*;
 use gl;
@@ -10,6 +19,8 @@ use std::ffi::CStr;
 use event::*;
 use window::Window;
 use std::mem;
+use std::cmp;
+use std::cell::{Cell, RefCell};
 use typed_arena::Arena;
 
 
@@ -19,25 +30,60 @@ pub struct TextureView
 	pub texture: GLuint
 }
 
+/// GL object that can be attached to a framebuffer
+#[derive(Copy, Clone, Debug)]
+pub enum AttachmentObject
+{
+	Texture(GLuint),
+	Renderbuffer(GLuint)
+}
+
+impl AttachmentObject
+{
+	unsafe fn attach(self, attachment: GLenum)
+	{
+		match self {
+			AttachmentObject::Texture(tex) =>
+				gl::FramebufferTexture(gl::FRAMEBUFFER, attachment, tex, 0),
+			AttachmentObject::Renderbuffer(rb) =>
+				gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, attachment, gl::RENDERBUFFER, rb)
+		}
+	}
+}
+
 #[derive(Copy, Clone)]
 pub struct RenderTargetView
 {
-	pub texture: GLuint,
-	pub viewport: (u32, u32, u32, u32)
+	pub object: AttachmentObject,
+	pub viewport: (u32, u32, u32, u32),
+	/// Number of samples (1 if not multisampled)
+	pub samples: u32
 }
 
 #[derive(Copy, Clone)]
 pub struct DepthStencilView
 {
-	pub texture: GLuint,
-	pub viewport: (u32, u32, u32, u32)
+	pub object: AttachmentObject,
+	pub viewport: (u32, u32, u32, u32),
+	/// Number of samples (1 if not multisampled)
+	pub samples: u32
+}
+
+/// Final destination of the rendering of a frame
+#[derive(Copy, Clone)]
+pub enum RenderTarget<'a>
+{
+	/// Default framebuffer (the window, or the surface of a headless context)
+	Screen,
+	/// Offscreen color and depth textures (e.g. headless rendering, render to texture)
+	Offscreen { color: &'a Texture2D, depth: &'a Texture2D }
 }
 
-// TODO the context should know the window dimensions
-// (and be notified when the window size changes)
 pub struct Context
 {
-	window_size: (i32, i32)
+	/// Size of the default framebuffer, in pixels
+	backbuffer_size: Cell<(u32, u32)>,
+	profiler: RefCell<Profiler>
 	// three previous frames
 	//last_frames: [Option<Frame<'a>>; 3]
 	// TODO ref to window
@@ -84,23 +130,7 @@ impl<'a> CommandList<'a>
 	}
 }*/
 
-extern "system" fn debug_callback(
-	source: GLenum,
-	ty: GLenum,
-	id: GLuint,
-	severity: GLenum,
-	length: GLsizei,
-	msg: *const GLchar,
-	data: *mut c_void)
-{
-	unsafe {
-		let msg_str = CStr::from_ptr(msg);
-		//trace!("{}", msg_str.to_str().unwrap());
-	}
-}
-
-
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CullMode
 {
 	None, Front, Back, FrontAndBack
@@ -108,7 +138,7 @@ pub enum CullMode
 
 impl CullMode
 {
-	fn to_gl(self) -> GLenum
+	pub fn to_gl(self) -> GLenum
 	{
 		match self
 		{
@@ -120,7 +150,7 @@ impl CullMode
 	}
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PolygonFillMode
 {
 	Fill, Wireframe
@@ -128,7 +158,7 @@ pub enum PolygonFillMode
 
 impl PolygonFillMode
 {
-	fn to_gl(self) -> GLenum
+	pub fn to_gl(self) -> GLenum
 	{
 		match self
 		{
@@ -138,7 +168,7 @@ impl PolygonFillMode
 	}
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BlendOp
 {
 	Add,
@@ -148,7 +178,22 @@ pub enum BlendOp
 	Max,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl BlendOp
+{
+	pub fn to_gl(self) -> GLenum
+	{
+		match self
+		{
+			BlendOp::Add => gl::FUNC_ADD,
+			BlendOp::Subtract => gl::FUNC_SUBTRACT,
+			BlendOp::ReverseSubtract => gl::FUNC_REVERSE_SUBTRACT,
+			BlendOp::Min => gl::MIN,
+			BlendOp::Max => gl::MAX
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BlendFactor
 {
 	Zero,
@@ -163,7 +208,49 @@ pub enum BlendFactor
 	InvDestAlpha
 }
 
-#[derive(Copy, Clone, Debug)]
+impl BlendFactor
+{
+	pub fn to_gl(self) -> GLenum
+	{
+		match self
+		{
+			BlendFactor::Zero => gl::ZERO,
+			BlendFactor::One => gl::ONE,
+			BlendFactor::SrcRgb => gl::SRC_COLOR,
+			BlendFactor::InvSrcRgb => gl::ONE_MINUS_SRC_COLOR,
+			BlendFactor::DestRgb => gl::DST_COLOR,
+			BlendFactor::InvDestRgb => gl::ONE_MINUS_DST_COLOR,
+			BlendFactor::SrcAlpha => gl::SRC_ALPHA,
+			BlendFactor::InvSrcAlpha => gl::ONE_MINUS_SRC_ALPHA,
+			BlendFactor::DestAlpha => gl::DST_ALPHA,
+			BlendFactor::InvDestAlpha => gl::ONE_MINUS_DST_ALPHA
+		}
+	}
+}
+
+/// Blending of all color targets: op(src * src_factor, dest * dest_factor)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlendState
+{
+	pub op: BlendOp,
+	pub src_factor: BlendFactor,
+	pub dest_factor: BlendFactor
+}
+
+impl BlendState
+{
+	/// Non-premultiplied alpha blending
+	pub const fn alpha() -> BlendState
+	{
+		BlendState {
+			op: BlendOp::Add,
+			src_factor: BlendFactor::SrcAlpha,
+			dest_factor: BlendFactor::InvSrcAlpha
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DrawState
 {
 	pub cull_mode: CullMode,
@@ -171,7 +258,9 @@ pub struct DrawState
 	pub depth_clip_enable: bool,
 	// depth-stencil state
 	pub depth_test_enable: bool,
-	pub depth_write_enable: bool
+	pub depth_write_enable: bool,
+	/// None: no blending
+	pub blend: Option<BlendState>
 }
 
 pub struct TextureBinding<'a>
@@ -181,6 +270,54 @@ pub struct TextureBinding<'a>
 	pub texture: &'a Texture2D
 }
 
+/// Image load/store binding (image units)
+pub struct ImageBinding<'a>
+{
+	pub slot: u32,
+	pub texture: &'a Texture2D,
+	pub level: u32,
+	pub access: ShaderAccess
+}
+
+fn bind_images(images: &[ImageBinding])
+{
+	for img in images.iter() {
+		let format = img.texture.format();
+		let int_fmt = match format.image_format() {
+			Some(int_fmt) => int_fmt,
+			None => panic!("Textures with format {:?} cannot be bound as images", format)
+		};
+		unsafe {
+			gl::BindImageTexture(img.slot, img.texture.obj, img.level as i32,
+				gl::FALSE, 0, img.access.to_gl(), int_fmt);
+		}
+	}
+}
+
+/// Memory barrier to issue after a draw or dispatch, so that the writes done
+/// through storage buffers and images are visible to the following commands
+fn write_barrier_bits(storage_buffers: &[StorageBinding], images: &[ImageBinding]) -> GLbitfield
+{
+	let mut bits = 0;
+	if storage_buffers.iter().any(|b| b.access.is_write()) {
+		bits |= gl::SHADER_STORAGE_BARRIER_BIT | gl::VERTEX_ATTRIB_ARRAY_BARRIER_BIT |
+			gl::ELEMENT_ARRAY_BARRIER_BIT | gl::UNIFORM_BARRIER_BIT | gl::COMMAND_BARRIER_BIT;
+	}
+	if images.iter().any(|i| i.access.is_write()) {
+		bits |= gl::SHADER_IMAGE_ACCESS_BARRIER_BIT | gl::TEXTURE_FETCH_BARRIER_BIT |
+			gl::FRAMEBUFFER_BARRIER_BIT | gl::PIXEL_BUFFER_BARRIER_BIT;
+	}
+	bits
+}
+
+unsafe fn bind_textures(textures: &[TextureBinding])
+{
+	for t in textures.iter() {
+		t.texture.bind(t.slot as u32);
+		t.sampler.bind(t.slot as u32);
+	}
+}
+
 impl DrawState
 {
 	pub const fn default() -> DrawState
@@ -190,7 +327,8 @@ impl DrawState
 			polygon_fill_mode: PolygonFillMode::Fill,
 			depth_clip_enable: true,
 			depth_test_enable: true,
-			depth_write_enable: true
+			depth_write_enable: true,
+			blend: None
 		}
 	}
 
@@ -202,42 +340,28 @@ impl DrawState
 		}
 	}
 
-	// TODO state cache, redundant state call elimination
+	/// Apply the draw state, skipping redundant state changes
 	pub fn sync_state(&self)
 	{
-		unsafe {
-			// set GL states
-			if self.depth_test_enable {
-				gl::Enable(gl::DEPTH_TEST);
-			} else {
-				gl::Disable(gl::DEPTH_TEST);
-			}
-
-			gl::DepthMask(if self.depth_write_enable { gl::TRUE } else { gl::FALSE });
-			// TODO? fill mode per face
-			gl::PolygonMode(gl::FRONT_AND_BACK, self.polygon_fill_mode.to_gl());
-
-			match self.cull_mode
-			{
-				CullMode::None => gl::Disable(gl::CULL_FACE),
-				_ => {
-					gl::Enable(gl::CULL_FACE);
-					gl::CullFace(self.cull_mode.to_gl());
-				}
-			}
-			// TODO specify this
-			gl::Disable(gl::STENCIL_TEST);
-			gl::DepthFunc(gl::LEQUAL);
-		}
+		with_state_cache(|c| c.set_draw_state(self));
 	}
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PrimitiveType
 {
 	Point,
 	Line,
-	Triangle
+	LineStrip,
+	LineLoop,
+	Triangle,
+	TriangleStrip,
+	TriangleFan,
+	// adjacency variants (for geometry shaders)
+	LineAdjacency,
+	LineStripAdjacency,
+	TriangleAdjacency,
+	TriangleStripAdjacency
 }
 
 impl PrimitiveType
@@ -247,50 +371,192 @@ impl PrimitiveType
 		match self {
 			PrimitiveType::Point => gl::POINTS,
 			PrimitiveType::Line => gl::LINES,
-			PrimitiveType::Triangle => gl::TRIANGLES
+			PrimitiveType::LineStrip => gl::LINE_STRIP,
+			PrimitiveType::LineLoop => gl::LINE_LOOP,
+			PrimitiveType::Triangle => gl::TRIANGLES,
+			PrimitiveType::TriangleStrip => gl::TRIANGLE_STRIP,
+			PrimitiveType::TriangleFan => gl::TRIANGLE_FAN,
+			PrimitiveType::LineAdjacency => gl::LINES_ADJACENCY,
+			PrimitiveType::LineStripAdjacency => gl::LINE_STRIP_ADJACENCY,
+			PrimitiveType::TriangleAdjacency => gl::TRIANGLES_ADJACENCY,
+			PrimitiveType::TriangleStripAdjacency => gl::TRIANGLE_STRIP_ADJACENCY
+		}
+	}
+
+	/// Strips, loops and fans: topologies that can be split with a primitive restart index
+	pub fn is_connected(self) -> bool
+	{
+		match self {
+			PrimitiveType::LineStrip |
+			PrimitiveType::LineLoop |
+			PrimitiveType::TriangleStrip |
+			PrimitiveType::TriangleFan |
+			PrimitiveType::LineStripAdjacency |
+			PrimitiveType::TriangleStripAdjacency => true,
+			_ => false
+		}
+	}
+
+	/// Number of primitives drawn from a number of vertices (or indices),
+	/// without primitive restart
+	pub fn num_primitives(self, n: u32) -> u32
+	{
+		match self {
+			PrimitiveType::Point => n,
+			PrimitiveType::Line => n / 2,
+			PrimitiveType::LineStrip => if n >= 2 { n - 1 } else { 0 },
+			PrimitiveType::LineLoop => if n >= 2 { n } else { 0 },
+			PrimitiveType::Triangle => n / 3,
+			PrimitiveType::TriangleStrip |
+			PrimitiveType::TriangleFan => if n >= 3 { n - 2 } else { 0 },
+			PrimitiveType::LineAdjacency => n / 4,
+			PrimitiveType::LineStripAdjacency => if n >= 4 { n - 3 } else { 0 },
+			PrimitiveType::TriangleAdjacency => n / 6,
+			PrimitiveType::TriangleStripAdjacency => if n >= 6 { (n - 4) / 2 } else { 0 }
 		}
 	}
 }
 
-#[derive(Copy, Clone)]
+/// Type of the elements of an index buffer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexType
+{
+	U16,
+	U32
+}
+
+impl IndexType
+{
+	pub fn to_gl(self) -> GLenum
+	{
+		match self {
+			IndexType::U16 => gl::UNSIGNED_SHORT,
+			IndexType::U32 => gl::UNSIGNED_INT
+		}
+	}
+
+	pub fn byte_size(self) -> usize
+	{
+		match self {
+			IndexType::U16 => 2,
+			IndexType::U32 => 4
+		}
+	}
+
+	/// Largest index value (the usual primitive restart index)
+	pub fn max_value(self) -> u32
+	{
+		match self {
+			IndexType::U16 => 0xFFFF,
+			IndexType::U32 => 0xFFFFFFFF
+		}
+	}
+}
+
+/// Types that can be stored in an index buffer
+pub trait IndexElement: Copy
+{
+	fn index_type() -> IndexType;
+}
+
+impl IndexElement for u16
+{
+	fn index_type() -> IndexType { IndexType::U16 }
+}
+
+impl IndexElement for u32
+{
+	fn index_type() -> IndexType { IndexType::U32 }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct MeshPart
 {
 	pub primitive_type: PrimitiveType,
 	pub start_vertex: u32,
 	pub start_index: u32,
 	pub num_vertices: u32,
-	pub num_indices: u32
+	pub num_indices: u32,
+	/// Type of the indices (ignored for non-indexed draws)
+	pub index_type: IndexType,
+	/// Index value that restarts the strip, loop or fan (indexed draws only)
+	pub restart_index: Option<u32>
+}
+
+impl MeshPart
+{
+	/// Non-indexed range of vertices
+	pub fn vertices(primitive_type: PrimitiveType, start_vertex: u32, num_vertices: u32) -> MeshPart
+	{
+		MeshPart {
+			primitive_type: primitive_type,
+			start_vertex: start_vertex,
+			start_index: 0,
+			num_vertices: num_vertices,
+			num_indices: 0,
+			index_type: IndexType::U16,
+			restart_index: None
+		}
+	}
+
+	/// Indexed range. base_vertex is added to the indices.
+	pub fn indexed(primitive_type: PrimitiveType, index_type: IndexType, start_index: u32, num_indices: u32, base_vertex: u32, num_vertices: u32) -> MeshPart
+	{
+		MeshPart {
+			primitive_type: primitive_type,
+			start_vertex: base_vertex,
+			start_index: start_index,
+			num_vertices: num_vertices,
+			num_indices: num_indices,
+			index_type: index_type,
+			restart_index: None
+		}
+	}
+
+	/// Enable primitive restart with the given index
+	pub fn with_restart_index(mut self, restart_index: u32) -> MeshPart
+	{
+		assert!(restart_index <= self.index_type.max_value(),
+			"restart index {} does not fit in {:?} indices", restart_index, self.index_type);
+		self.restart_index = Some(restart_index);
+		self
+	}
 }
 
 
+/// Low-level draw call. The current framebuffer is used as the target.
+/// draw_state replaces the draw state of the pipeline.
 pub fn draw_instanced(
 		vertex_buffer: RawBufSlice,
 		index_buffer: Option<RawBufSlice>,
 		part: MeshPart,
+		num_instances: u32,
 		pipeline_state: &PipelineState,
+		draw_state: &DrawState,
 		uniform_buffers: &[Binding],
+		storage_buffers: &[StorageBinding],
+		images: &[ImageBinding],
 		textures: &[TextureBinding])
 {
-	pipeline_state.draw_state.sync_state();
+	draw_state.sync_state();
 	unsafe
 	{
-		gl::UseProgram(pipeline_state.program.obj);
+		with_state_cache(|c| c.use_program(pipeline_state.program.obj));
 		super::buffer::bind_uniform_buffers(uniform_buffers);
+		super::buffer::bind_storage_buffers(storage_buffers);
 		super::buffer::bind_vertex_buffers(&pipeline_state.layout, &[vertex_buffer]);
-
-		for t in textures.iter() {
-			t.texture.bind(t.slot as u32);
-			t.sampler.bind(t.slot as u32);
-		}
+		bind_images(images);
+		bind_textures(textures);
 
 		if let Some(ref ib) = index_buffer {
+			with_state_cache(|c| c.set_primitive_restart(part.restart_index));
 			ib.raw.bind_as_element_array();
 			gl::DrawElementsInstancedBaseVertexBaseInstance(
 				part.primitive_type.to_gl_mode(),
 				part.num_indices as i32,
-				gl::UNSIGNED_SHORT,
-				(part.start_index * 2) as *const GLvoid,
-				1, part.start_vertex as i32, 0);
+				part.index_type.to_gl(),
+				(ib.offset + part.start_index as usize * part.index_type.byte_size()) as *const GLvoid,
+				num_instances as i32, part.start_vertex as i32, 0);
 		}
 		else
 		{
@@ -298,28 +564,95 @@ pub fn draw_instanced(
 				part.primitive_type.to_gl_mode(),
 				part.start_vertex as i32,
 				part.num_vertices as i32,
-				1);
+				num_instances as i32);
+		}
+
+		let barrier = write_barrier_bits(storage_buffers, images);
+		if barrier != 0 {
+			gl::MemoryBarrier(barrier);
+		}
+	}
+}
+
+/// Run a compute shader
+pub fn dispatch_compute(
+		pipeline: &ComputePipeline,
+		num_groups: (u32, u32, u32),
+		uniform_buffers: &[Binding],
+		storage_buffers: &[StorageBinding],
+		images: &[ImageBinding],
+		textures: &[TextureBinding])
+{
+	unsafe
+	{
+		with_state_cache(|c| c.use_program(pipeline.program.obj));
+		super::buffer::bind_uniform_buffers(uniform_buffers);
+		super::buffer::bind_storage_buffers(storage_buffers);
+		bind_images(images);
+		bind_textures(textures);
+		gl::DispatchCompute(num_groups.0, num_groups.1, num_groups.2);
+
+		let barrier = write_barrier_bits(storage_buffers, images);
+		if barrier != 0 {
+			gl::MemoryBarrier(barrier);
 		}
 	}
 }
 
 impl Context
 {
-	pub fn new() -> Context {
-		unsafe {
-			// enable OpenGL debug output
-			gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
-			gl::DebugMessageCallback(debug_callback, 0 as *const c_void);
-			gl::DebugMessageControl(gl::DONT_CARE, gl::DONT_CARE, gl::DONT_CARE, 0, 0 as *const u32, gl::TRUE);
-			gl::DebugMessageInsert(
-				gl::DEBUG_SOURCE_APPLICATION,
-				gl::DEBUG_TYPE_MARKER,
-				1111,
-				gl::DEBUG_SEVERITY_NOTIFICATION, -1,
-				"Started logging OpenGL messages".as_ptr() as *const i8);
+	/// backbuffer_size is the initial size of the default framebuffer
+	/// (see Window::framebuffer_dimensions)
+	pub fn new(backbuffer_size: (u32, u32)) -> Context {
+		enable_debug_output();
+
+		Context {
+			backbuffer_size: Cell::new(backbuffer_size),
+			profiler: RefCell::new(Profiler::new())
 		}
+	}
+
+	/// Screen-sized render targets should be declared as transient textures of a
+	/// FrameGraph with this size: they follow the window size from one frame to the next.
+	pub fn backbuffer_size(&self) -> (u32, u32)
+	{
+		self.backbuffer_size.get()
+	}
+
+	pub fn profiler(&self) -> &RefCell<Profiler>
+	{
+		&self.profiler
+	}
+
+	/// Open a named profiler scope, closed when the returned guard is dropped
+	pub fn profile_scope(&self, name: &'static str) -> ProfileScope
+	{
+		ProfileScope::new(&self.profiler, name)
+	}
 
-		Context { window_size: (800, 600) }
+	/// Open a named debug group, visible in GL debuggers
+	/// The group is closed when the returned guard is dropped.
+	pub fn debug_group(&self, name: &str) -> DebugGroup
+	{
+		DebugGroup::new(name)
+	}
+
+	/// Memory limits (in bytes) above which a warning is logged
+	pub fn set_memory_budget(&self, texture_bytes: Option<usize>, buffer_bytes: Option<usize>)
+	{
+		set_memory_budget(texture_bytes, buffer_bytes);
+	}
+
+	/// Count and total size of live objects of a category
+	pub fn resource_stats(&self, category: ResourceCategory) -> CategoryStats
+	{
+		resource_stats(category)
+	}
+
+	/// Must be called once per frame, after all rendering is done
+	pub fn end_frame(&self)
+	{
+		self.profiler.borrow_mut().end_frame();
 	}
 
 	pub fn create_texture() -> ! {
@@ -355,27 +688,109 @@ impl Context
 		Frame::new(render_target_views, depth_stencil_view)
 	}
 
+	/// Frame drawing into an existing framebuffer, which is not deleted with the frame
+	pub fn create_frame_in(&self, framebuffer: &Framebuffer) -> Frame
+	{
+		Frame::with_framebuffer(framebuffer.obj, false,
+			&framebuffer.render_target_views[..], framebuffer.depth_stencil_view)
+	}
+
 	pub fn create_screen_frame<'a>(&'a self, window: &Window) -> Frame
 	{
-		Frame::default(window.dimensions())
+		Frame::default(self.backbuffer_size.get())
+	}
+
+	/// Frame drawing into a render target
+	pub fn create_target_frame(&self, target: &RenderTarget) -> Frame
+	{
+		match *target {
+			RenderTarget::Screen => Frame::default(self.backbuffer_size.get()),
+			RenderTarget::Offscreen { color, depth } =>
+				Frame::new(&[color.view_as_render_target()], Some(depth.view_as_depth_stencil_target()))
+		}
 	}
 
 	pub fn event(&self, ev: &Event)
 	{
-		/*match ev
+		match ev
 		{
-			&Event::WindowResize(w, h) => self.window_size = (w as i32, h as i32),
+			&Event::FramebufferResize(w, h) => {
+				// minimized windows have a zero-sized framebuffer: keep the old size
+				if w != 0 && h != 0 && (w, h) != self.backbuffer_size.get() {
+					debug!("Backbuffer resized to {}x{}", w, h);
+					self.backbuffer_size.set((w, h));
+				}
+			},
 			_ => {}
-		}*/
+		}
 	}
 }
 
 
+impl Drop for Context
+{
+	fn drop(&mut self)
+	{
+		// everything should have been released before the context
+		let num_leaks = report_leaks();
+		if num_leaks != 0 {
+			warn!("{} GPU objects leaked", num_leaks);
+		}
+	}
+}
+
+/// A framebuffer object that outlives the frames drawing into it
+/// (see Context::create_frame_in)
+pub struct Framebuffer
+{
+	obj: GLuint,
+	render_target_views: Vec<RenderTargetView>,
+	depth_stencil_view: Option<DepthStencilView>
+}
+
+impl Framebuffer
+{
+	pub fn new(
+		render_target_views: &[RenderTargetView],
+		depth_stencil_view: Option<DepthStencilView>) -> Framebuffer
+	{
+		Framebuffer {
+			obj: create_framebuffer(render_target_views, depth_stencil_view),
+			render_target_views: render_target_views.to_vec(),
+			depth_stencil_view: depth_stencil_view
+		}
+	}
+
+	pub fn set_label(&self, label: &str)
+	{
+		set_object_label(gl::FRAMEBUFFER, self.obj, label);
+	}
+}
+
+impl Drop for Framebuffer
+{
+	fn drop(&mut self)
+	{
+		untrack_resource(ResourceCategory::Framebuffer, self.obj);
+		unsafe {
+			gl::DeleteFramebuffers(1, &mut self.obj);
+		}
+	}
+}
+
 pub struct Frame
 {
 	framebuffer: GLuint,
+	// false if the framebuffer is the default one, or a Framebuffer
+	owns_framebuffer: bool,
 	temporary_buffers: Arena<RawBuffer>,
-	viewport: (u32, u32, u32, u32)
+	viewport: (u32, u32, u32, u32),
+	// linear -> sRGB conversion on write (GL_FRAMEBUFFER_SRGB)
+	// only affects sRGB render targets
+	srgb: bool,
+	num_color_targets: u32,
+	has_depth: bool,
+	samples: u32
 }
 
 
@@ -384,39 +799,46 @@ fn create_framebuffer(color_render_targets: &[RenderTargetView], depth_target: O
 	let mut fbo : GLuint = 0;
 	unsafe {
 		gl::GenFramebuffers(1, &mut fbo);
+		track_resource(ResourceCategory::Framebuffer, fbo, 0);
 		gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
 
 		// bind color attachements
 		for (i, rtv) in color_render_targets.iter().enumerate() {
 			// TODO support targets other than 2d textures
 			// (texture layers, cube map faces, whole cube map, etc.)
-			gl::FramebufferTexture(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0 + i as u32, rtv.texture, 0);
+			rtv.object.attach(gl::COLOR_ATTACHMENT0 + i as u32);
 		}
 
 		// working with borrows of &Option<&mut T> are a bit awkward
 		if let Some(ref depth_target) = depth_target
 		{
-			gl::FramebufferTexture(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT, depth_target.texture, 0);
+			depth_target.object.attach(gl::DEPTH_ATTACHMENT);
 		}
 
-		let draw_buffers = [
-			gl::COLOR_ATTACHMENT0,
-			gl::COLOR_ATTACHMENT0 + 1,
-			gl::COLOR_ATTACHMENT0 + 2,
-			gl::COLOR_ATTACHMENT0 + 3,
-			gl::COLOR_ATTACHMENT0 + 4,
-			gl::COLOR_ATTACHMENT0 + 5,
-			gl::COLOR_ATTACHMENT0 + 6,
-			gl::COLOR_ATTACHMENT0 + 7
-		];
-
-		gl::DrawBuffers(color_render_targets.len() as GLsizei, draw_buffers[..].as_ptr());
+		set_draw_buffers(color_render_targets.len());
 		assert!(gl::CheckFramebufferStatus(gl::FRAMEBUFFER) == gl::FRAMEBUFFER_COMPLETE);
 	}
 
 	fbo
 }
 
+/// Enable the first num_color_targets draw buffers of the bound framebuffer
+unsafe fn set_draw_buffers(num_color_targets: usize)
+{
+	let draw_buffers = [
+		gl::COLOR_ATTACHMENT0,
+		gl::COLOR_ATTACHMENT0 + 1,
+		gl::COLOR_ATTACHMENT0 + 2,
+		gl::COLOR_ATTACHMENT0 + 3,
+		gl::COLOR_ATTACHMENT0 + 4,
+		gl::COLOR_ATTACHMENT0 + 5,
+		gl::COLOR_ATTACHMENT0 + 6,
+		gl::COLOR_ATTACHMENT0 + 7
+	];
+
+	gl::DrawBuffers(num_color_targets as GLsizei, draw_buffers[..].as_ptr());
+}
+
 impl Frame
 {
 	pub fn dimensions(&self) -> (u32, u32)
@@ -429,8 +851,26 @@ impl Frame
 		depth_stencil_view: Option<DepthStencilView>) -> Frame
 	{
 		let fbo = create_framebuffer(render_target_views, depth_stencil_view);
+		Frame::with_framebuffer(fbo, true, render_target_views, depth_stencil_view)
+	}
 
+	fn with_framebuffer(
+		fbo: GLuint,
+		owns_framebuffer: bool,
+		render_target_views: &[RenderTargetView],
+		depth_stencil_view: Option<DepthStencilView>) -> Frame
+	{
 		// TODO check that all dimensions match
+		let samples =
+			if !render_target_views.is_empty() {
+				render_target_views[0].samples
+			} else {
+				depth_stencil_view.map(|dsv| dsv.samples).unwrap_or(1)
+			};
+		assert!(render_target_views.iter().all(|rtv| rtv.samples == samples) &&
+			depth_stencil_view.map(|dsv| dsv.samples == samples).unwrap_or(true),
+			"All attachments of a frame must have the same number of samples");
+
 		let viewport =
 			if !render_target_views.is_empty() {
 				render_target_views[0].viewport
@@ -448,22 +888,139 @@ impl Frame
 
 		Frame {
 			framebuffer: fbo,
+			owns_framebuffer: owns_framebuffer,
 			temporary_buffers: Arena::new(),
-			viewport: viewport
+			viewport: viewport,
+			srgb: true,
+			num_color_targets: render_target_views.len() as u32,
+			has_depth: depth_stencil_view.is_some(),
+			samples: samples
 		}
 	}
 
 	fn default(window_size: (u32, u32)) -> Frame
 	{
+		// 0 if the default framebuffer is not multisampled
+		let mut samples = 0;
+		unsafe {
+			gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+			gl::GetIntegerv(gl::SAMPLES, &mut samples);
+		}
 		Frame {
 			framebuffer: 0,
+			owns_framebuffer: false,
 			temporary_buffers: Arena::new(),
-			viewport: (0, 0, window_size.0, window_size.1)
+			viewport: (0, 0, window_size.0, window_size.1),
+			srgb: true,
+			num_color_targets: 1,
+			has_depth: true,
+			samples: cmp::max(samples, 1) as u32
+		}
+	}
+
+	/// Number of samples of the attachments of this frame
+	pub fn samples(&self) -> u32
+	{
+		self.samples
+	}
+
+	fn blit_to(&self, target: &Frame, color: bool, depth: bool)
+	{
+		assert!(self.dimensions() == target.dimensions(),
+			"Resolve: source and target frames must have the same dimensions");
+		assert!(target.samples == 1 || target.samples == self.samples,
+			"Resolve: cannot blit {} samples into a frame with {} samples", self.samples, target.samples);
+		let (src, dst) = (self.viewport, target.viewport);
+		unsafe {
+			gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+			gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, target.framebuffer);
+			if color {
+				// blit each color target into the target with the same index
+				let n = cmp::min(self.num_color_targets, target.num_color_targets);
+				for i in 0..n {
+					gl::ReadBuffer(self.read_buffer(i));
+					if target.framebuffer == 0 {
+						gl::DrawBuffer(gl::BACK);
+					} else {
+						gl::DrawBuffer(gl::COLOR_ATTACHMENT0 + i);
+					}
+					gl::BlitFramebuffer(
+						src.0 as i32, src.1 as i32, (src.0 + src.2) as i32, (src.1 + src.3) as i32,
+						dst.0 as i32, dst.1 as i32, (dst.0 + dst.2) as i32, (dst.1 + dst.3) as i32,
+						gl::COLOR_BUFFER_BIT, gl::NEAREST);
+				}
+				if target.framebuffer != 0 {
+					set_draw_buffers(target.num_color_targets as usize);
+				}
+			}
+			if depth && self.has_depth && target.has_depth {
+				gl::BlitFramebuffer(
+					src.0 as i32, src.1 as i32, (src.0 + src.2) as i32, (src.1 + src.3) as i32,
+					dst.0 as i32, dst.1 as i32, (dst.0 + dst.2) as i32, (dst.1 + dst.3) as i32,
+					gl::DEPTH_BUFFER_BIT, gl::NEAREST);
+			}
+		}
+	}
+
+	/// Resolve the color and depth targets of this (multisampled) frame
+	/// into the targets of another frame of the same dimensions
+	pub fn resolve_to(&self, target: &Frame)
+	{
+		self.blit_to(target, true, true);
+	}
+
+	/// Resolve only the color targets
+	pub fn resolve_color_to(&self, target: &Frame)
+	{
+		self.blit_to(target, true, false);
+	}
+
+	/// Resolve only the depth target
+	/// Depth samples are not averaged: the value of one sample is kept
+	pub fn resolve_depth_to(&self, target: &Frame)
+	{
+		self.blit_to(target, false, true);
+	}
+
+	/// Enable or disable linear to sRGB conversion when writing to sRGB targets
+	/// (enabled by default). The screen frame is sRGB if the window was created
+	/// with an sRGB-capable framebuffer.
+	pub fn set_srgb(&mut self, enable: bool)
+	{
+		self.srgb = enable;
+	}
+
+	pub fn is_srgb(&self) -> bool
+	{
+		self.srgb
+	}
+
+	/// Name of the framebuffer in GL debuggers (no effect on the screen frame)
+	pub fn set_label(&self, label: &str)
+	{
+		if self.framebuffer != 0 {
+			set_object_label(gl::FRAMEBUFFER, self.framebuffer, label);
+		}
+	}
+
+	/// Bind the framebuffer and set the viewport and framebuffer states
+	pub fn bind(&self)
+	{
+		// TODO rebind framebuffer only if necessary
+		unsafe {
+			gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+			gl::Viewport(self.viewport.0 as i32, self.viewport.1 as i32, self.viewport.2 as i32, self.viewport.3 as i32);
+			if self.srgb {
+				gl::Enable(gl::FRAMEBUFFER_SRGB);
+			} else {
+				gl::Disable(gl::FRAMEBUFFER_SRGB);
+			}
 		}
 	}
 
 	pub fn clear(&mut self, color: Option<[f32; 4]>, depth: Option<f32>)
 	{
+		self.bind();
 		match (color, depth) {
 			(Some(color), Some(depth)) => {
 				unsafe {
@@ -497,6 +1054,7 @@ impl Frame
 		if let Some(d) = initial_data {
 			assert!(num_elements == d.len());
 		}
+		let _alloc = allocation_scope!("Frame::alloc_temporary_buffer");
 		let buf = alloc_raw_buffer(
 			num_elements * mem::size_of::<T>(),
 			BufferAccess::WriteOnly,
@@ -508,10 +1066,20 @@ impl Frame
 		}
 	}
 
+	/// Same as make_uniform_buffer, but checks the std140 layout of T the first time it is used
+	pub fn make_uniform_block<'b, T: UniformBlock>(
+		&'b self,
+		initial_data: &T) -> BufSlice<'b, T>
+	{
+		check_uniform_block::<T>();
+		self.make_uniform_buffer(initial_data)
+	}
+
 	pub fn make_uniform_buffer<'b, T: Copy>(
 		&'b self,
 		initial_data: &T) -> BufSlice<'b, T>
 	{
+		let _alloc = allocation_scope!("Frame::make_uniform_buffer");
 		let buf = alloc_raw_buffer(
 			mem::size_of::<T>(),
 			BufferAccess::WriteOnly,
@@ -523,7 +1091,189 @@ impl Frame
 		}
 	}
 
-	// TODO make a 'DrawCallBuilder' with sensible defaults for the arguments
+	fn read_buffer(&self, color_index: u32) -> GLenum
+	{
+		if self.framebuffer == 0 {
+			assert!(color_index == 0, "The screen frame has only one color buffer");
+			gl::BACK
+		} else {
+			gl::COLOR_ATTACHMENT0 + color_index
+		}
+	}
+
+	/// Internal format of an attachment of the bound read framebuffer
+	unsafe fn attachment_internal_format(attachment: GLenum) -> GLenum
+	{
+		let (mut object_type, mut name, mut format) = (0, 0, 0);
+		gl::GetFramebufferAttachmentParameteriv(gl::READ_FRAMEBUFFER, attachment,
+			gl::FRAMEBUFFER_ATTACHMENT_OBJECT_TYPE, &mut object_type);
+		gl::GetFramebufferAttachmentParameteriv(gl::READ_FRAMEBUFFER, attachment,
+			gl::FRAMEBUFFER_ATTACHMENT_OBJECT_NAME, &mut name);
+		if object_type as GLenum == gl::FRAMEBUFFER_DEFAULT {
+			// no object to query: deduce the format from the sizes and encoding of the buffer
+			if attachment == gl::DEPTH {
+				let (mut depth_bits, mut stencil_bits) = (0, 0);
+				gl::GetFramebufferAttachmentParameteriv(gl::READ_FRAMEBUFFER, gl::DEPTH,
+					gl::FRAMEBUFFER_ATTACHMENT_DEPTH_SIZE, &mut depth_bits);
+				gl::GetFramebufferAttachmentParameteriv(gl::READ_FRAMEBUFFER, gl::STENCIL,
+					gl::FRAMEBUFFER_ATTACHMENT_STENCIL_SIZE, &mut stencil_bits);
+				return match (depth_bits, stencil_bits) {
+					(_, s) if s > 0 => gl::DEPTH24_STENCIL8,
+					(16, _) => gl::DEPTH_COMPONENT16,
+					(32, _) => gl::DEPTH_COMPONENT32,
+					_ => gl::DEPTH_COMPONENT24
+				};
+			}
+			let mut encoding = 0;
+			gl::GetFramebufferAttachmentParameteriv(gl::READ_FRAMEBUFFER, attachment,
+				gl::FRAMEBUFFER_ATTACHMENT_COLOR_ENCODING, &mut encoding);
+			return if encoding as GLenum == gl::SRGB { gl::SRGB8_ALPHA8 } else { gl::RGBA8 };
+		} else if object_type as GLenum == gl::RENDERBUFFER {
+			gl::BindRenderbuffer(gl::RENDERBUFFER, name as GLuint);
+			gl::GetRenderbufferParameteriv(gl::RENDERBUFFER, gl::RENDERBUFFER_INTERNAL_FORMAT, &mut format);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+		} else {
+			gl::GetTextureLevelParameteriv(name as GLuint, 0, gl::TEXTURE_INTERNAL_FORMAT, &mut format);
+		}
+		format as GLenum
+	}
+
+	/// glReadPixels does not accept multisampled framebuffers: resolve the color target
+	/// read_buffer (or the depth target if gl::NONE) into a temporary framebuffer with
+	/// a renderbuffer of the same format.
+	/// Returns the framebuffer and the renderbuffer, to delete after the read.
+	unsafe fn resolve_for_readback(&self, read_buffer: GLenum) -> (GLuint, GLuint)
+	{
+		let (w, h) = self.dimensions();
+		let depth = read_buffer == gl::NONE;
+		// the default framebuffer has no attachment points, but buffers
+		let attachment = match (depth, self.framebuffer) {
+			(true, 0) => gl::DEPTH,
+			(true, _) => gl::DEPTH_ATTACHMENT,
+			(false, _) => read_buffer
+		};
+		gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+		let internal_format = Frame::attachment_internal_format(attachment);
+
+		let mut renderbuffer = 0;
+		gl::GenRenderbuffers(1, &mut renderbuffer);
+		gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+		gl::RenderbufferStorage(gl::RENDERBUFFER, internal_format, w as i32, h as i32);
+		gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+
+		let mut framebuffer = 0;
+		gl::GenFramebuffers(1, &mut framebuffer);
+		gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, framebuffer);
+		let v = self.viewport;
+		if depth {
+			gl::FramebufferRenderbuffer(gl::DRAW_FRAMEBUFFER, gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, renderbuffer);
+			gl::DrawBuffer(gl::NONE);
+			gl::BlitFramebuffer(
+				v.0 as i32, v.1 as i32, (v.0 + v.2) as i32, (v.1 + v.3) as i32,
+				0, 0, w as i32, h as i32,
+				gl::DEPTH_BUFFER_BIT, gl::NEAREST);
+		} else {
+			gl::FramebufferRenderbuffer(gl::DRAW_FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, renderbuffer);
+			gl::ReadBuffer(read_buffer);
+			gl::DrawBuffer(gl::COLOR_ATTACHMENT0);
+			gl::BlitFramebuffer(
+				v.0 as i32, v.1 as i32, (v.0 + v.2) as i32, (v.1 + v.3) as i32,
+				0, 0, w as i32, h as i32,
+				gl::COLOR_BUFFER_BIT, gl::NEAREST);
+		}
+		gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+		(framebuffer, renderbuffer)
+	}
+
+	/// Issue a glReadPixels of the whole viewport, into dest
+	/// (a client pointer, or an offset into the bound pixel pack buffer)
+	/// Multisampled frames are resolved first.
+	unsafe fn read_pixels_into(&self, read_buffer: GLenum, format: ReadbackFormat, dest: *mut GLvoid)
+	{
+		let (ext_fmt, ty) = format.gl_format();
+		let (w, h) = self.dimensions();
+		gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+		if self.samples > 1 {
+			let (mut framebuffer, mut renderbuffer) = self.resolve_for_readback(read_buffer);
+			gl::BindFramebuffer(gl::READ_FRAMEBUFFER, framebuffer);
+			gl::ReadBuffer(if read_buffer == gl::NONE { gl::NONE } else { gl::COLOR_ATTACHMENT0 });
+			gl::ReadPixels(0, 0, w as i32, h as i32, ext_fmt, ty, dest);
+			// deletion is deferred by GL until the pending read has completed
+			gl::DeleteFramebuffers(1, &mut framebuffer);
+			gl::DeleteRenderbuffers(1, &mut renderbuffer);
+		} else {
+			gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+			gl::ReadBuffer(read_buffer);
+			gl::ReadPixels(
+				self.viewport.0 as i32, self.viewport.1 as i32,
+				w as i32, h as i32,
+				ext_fmt, ty, dest);
+		}
+	}
+
+	fn read_blocking(&self, read_buffer: GLenum, format: ReadbackFormat) -> Vec<u8>
+	{
+		let (w, h) = self.dimensions();
+		let mut data = vec![0u8; w as usize * h as usize * format.pixel_byte_size()];
+		unsafe {
+			self.read_pixels_into(read_buffer, format, data.as_mut_ptr() as *mut GLvoid);
+		}
+		data
+	}
+
+	fn read_async(&self, read_buffer: GLenum, format: ReadbackFormat) -> PendingReadback
+	{
+		let (w, h) = self.dimensions();
+		let readback = PendingReadback::begin(w, h, format);
+		unsafe {
+			self.read_pixels_into(read_buffer, format, 0 as *mut GLvoid);
+		}
+		readback.fence()
+	}
+
+	/// Read back a color target of the frame, as RGBA8 (blocking)
+	/// Multisampled frames are resolved into a temporary target first.
+	pub fn read_color(&self, color_index: u32) -> DynamicImage
+	{
+		let (w, h) = self.dimensions();
+		let format = ReadbackFormat::Unorm8(4);
+		pixels_to_image(format, w, h, &self.read_blocking(self.read_buffer(color_index), format)[..])
+	}
+
+	/// Start an asynchronous readback of a color target of the frame
+	pub fn read_color_async(&self, color_index: u32) -> PendingReadback
+	{
+		self.read_async(self.read_buffer(color_index), ReadbackFormat::Unorm8(4))
+	}
+
+	/// Read back the depth buffer of the frame (blocking)
+	/// Rows are returned in GL order (bottom row first)
+	pub fn read_depth(&self) -> Vec<f32>
+	{
+		depth_to_floats(&self.read_blocking(gl::NONE, ReadbackFormat::Depth)[..])
+	}
+
+	/// Start an asynchronous readback of the depth buffer of the frame
+	pub fn read_depth_async(&self) -> PendingReadback
+	{
+		self.read_async(gl::NONE, ReadbackFormat::Depth)
+	}
+
+	/// Execute a command list on this frame
+	/// The list should be sorted beforehand with CommandList::sort.
+	pub fn submit(&self, command_list: &CommandList)
+	{
+		self.bind();
+		command_list.execute();
+	}
+
+	/// Start building a draw call with the given pipeline (see DrawCall)
+	pub fn draw_call<'b>(&'b self, pipeline_state: &'b PipelineState) -> DrawCall<'b>
+	{
+		DrawCall::new(self, pipeline_state)
+	}
+
+	/// Draw with all arguments specified (see also draw_call)
 	pub fn draw(
 		&self,
 		vertex_buffer: RawBufSlice,
@@ -531,13 +1281,13 @@ impl Frame
 		pipeline_state: &PipelineState,
 		mesh_part: MeshPart,
 		uniform_buffers: &[Binding],
+		storage_buffers: &[StorageBinding],
+		images: &[ImageBinding],
 		textures: &[TextureBinding])
 	{
-		// TODO rebind framebuffer only if necessary
-		unsafe {
-			gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
-		}
-		draw_instanced(vertex_buffer, index_buffer, mesh_part, pipeline_state, uniform_buffers, textures);
+		self.bind();
+		draw_instanced(vertex_buffer, index_buffer, mesh_part, 1, pipeline_state, &pipeline_state.draw_state,
+			uniform_buffers, storage_buffers, images, textures);
 	}
 }
 
@@ -545,8 +1295,11 @@ impl Drop for Frame
 {
 	fn drop(&mut self)
 	{
-		unsafe {
-			gl::DeleteFramebuffers(1, &mut self.framebuffer);
+		if self.owns_framebuffer {
+			untrack_resource(ResourceCategory::Framebuffer, self.framebuffer);
+			unsafe {
+				gl::DeleteFramebuffers(1, &mut self.framebuffer);
+			}
 		}
 	}
 }
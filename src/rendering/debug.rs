@@ -0,0 +1,219 @@
+use gl;
+use gl::types::*;
+use libc::c_void;
+use log::LogLevel;
+use std::slice;
+use std::str;
+use rendering::resource_tracker::{ResourceCategory, set_resource_name};
+
+/// Source of a debug message (GL_DEBUG_SOURCE_*)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugSource
+{
+	Api,
+	WindowSystem,
+	ShaderCompiler,
+	ThirdParty,
+	Application,
+	Other
+}
+
+impl DebugSource
+{
+	pub fn to_gl(self) -> GLenum
+	{
+		match self
+		{
+			DebugSource::Api => gl::DEBUG_SOURCE_API,
+			DebugSource::WindowSystem => gl::DEBUG_SOURCE_WINDOW_SYSTEM,
+			DebugSource::ShaderCompiler => gl::DEBUG_SOURCE_SHADER_COMPILER,
+			DebugSource::ThirdParty => gl::DEBUG_SOURCE_THIRD_PARTY,
+			DebugSource::Application => gl::DEBUG_SOURCE_APPLICATION,
+			DebugSource::Other => gl::DEBUG_SOURCE_OTHER
+		}
+	}
+}
+
+/// Type of a debug message (GL_DEBUG_TYPE_*)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugType
+{
+	Error,
+	DeprecatedBehavior,
+	UndefinedBehavior,
+	Portability,
+	Performance,
+	Marker,
+	PushGroup,
+	PopGroup,
+	Other
+}
+
+impl DebugType
+{
+	pub fn to_gl(self) -> GLenum
+	{
+		match self
+		{
+			DebugType::Error => gl::DEBUG_TYPE_ERROR,
+			DebugType::DeprecatedBehavior => gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR,
+			DebugType::UndefinedBehavior => gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR,
+			DebugType::Portability => gl::DEBUG_TYPE_PORTABILITY,
+			DebugType::Performance => gl::DEBUG_TYPE_PERFORMANCE,
+			DebugType::Marker => gl::DEBUG_TYPE_MARKER,
+			DebugType::PushGroup => gl::DEBUG_TYPE_PUSH_GROUP,
+			DebugType::PopGroup => gl::DEBUG_TYPE_POP_GROUP,
+			DebugType::Other => gl::DEBUG_TYPE_OTHER
+		}
+	}
+}
+
+fn source_name(source: GLenum) -> &'static str
+{
+	match source
+	{
+		gl::DEBUG_SOURCE_API => "API",
+		gl::DEBUG_SOURCE_WINDOW_SYSTEM => "window system",
+		gl::DEBUG_SOURCE_SHADER_COMPILER => "shader compiler",
+		gl::DEBUG_SOURCE_THIRD_PARTY => "third party",
+		gl::DEBUG_SOURCE_APPLICATION => "application",
+		_ => "other"
+	}
+}
+
+fn type_name(ty: GLenum) -> &'static str
+{
+	match ty
+	{
+		gl::DEBUG_TYPE_ERROR => "error",
+		gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR => "deprecated behavior",
+		gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR => "undefined behavior",
+		gl::DEBUG_TYPE_PORTABILITY => "portability",
+		gl::DEBUG_TYPE_PERFORMANCE => "performance",
+		gl::DEBUG_TYPE_MARKER => "marker",
+		gl::DEBUG_TYPE_PUSH_GROUP => "push group",
+		gl::DEBUG_TYPE_POP_GROUP => "pop group",
+		_ => "other"
+	}
+}
+
+fn severity_to_log_level(severity: GLenum) -> LogLevel
+{
+	match severity
+	{
+		gl::DEBUG_SEVERITY_HIGH => LogLevel::Error,
+		gl::DEBUG_SEVERITY_MEDIUM => LogLevel::Warn,
+		gl::DEBUG_SEVERITY_LOW => LogLevel::Info,
+		_ => LogLevel::Trace
+	}
+}
+
+extern "system" fn debug_callback(
+	source: GLenum,
+	ty: GLenum,
+	id: GLuint,
+	severity: GLenum,
+	length: GLsizei,
+	msg: *const GLchar,
+	data: *mut c_void)
+{
+	// the message is not always null-terminated: use the length
+	let bytes = unsafe { slice::from_raw_parts(msg as *const u8, length as usize) };
+	let msg_str = str::from_utf8(bytes).unwrap_or("<invalid UTF-8>");
+	// group push/pop messages are only noise in the log
+	if ty == gl::DEBUG_TYPE_PUSH_GROUP || ty == gl::DEBUG_TYPE_POP_GROUP {
+		return;
+	}
+	log!(target: "gl", severity_to_log_level(severity), "[{}][{}][{}] {}",
+		source_name(source), type_name(ty), id, msg_str.trim_right());
+}
+
+/// Enable KHR_debug output, forwarded to the log
+pub fn enable_debug_output()
+{
+	unsafe {
+		gl::Enable(gl::DEBUG_OUTPUT);
+		gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+		gl::DebugMessageCallback(debug_callback, 0 as *const c_void);
+		gl::DebugMessageControl(gl::DONT_CARE, gl::DONT_CARE, gl::DONT_CARE, 0, 0 as *const u32, gl::TRUE);
+	}
+	insert_debug_message("Started logging OpenGL messages");
+}
+
+/// Enable or disable messages by source, type and IDs.
+/// None matches all sources (or types). If ids is not empty, source and type
+/// must both be specified (GL restriction).
+pub fn set_debug_message_filter(source: Option<DebugSource>, ty: Option<DebugType>, ids: &[u32], enabled: bool)
+{
+	assert!(ids.is_empty() || (source.is_some() && ty.is_some()),
+		"filtering by message ID requires a source and a type");
+	unsafe {
+		gl::DebugMessageControl(
+			source.map(|s| s.to_gl()).unwrap_or(gl::DONT_CARE),
+			ty.map(|t| t.to_gl()).unwrap_or(gl::DONT_CARE),
+			gl::DONT_CARE,
+			ids.len() as i32,
+			if ids.is_empty() { 0 as *const u32 } else { ids.as_ptr() },
+			if enabled { gl::TRUE } else { gl::FALSE });
+	}
+}
+
+/// Insert an application marker in the command stream
+pub fn insert_debug_message(msg: &str)
+{
+	unsafe {
+		gl::DebugMessageInsert(
+			gl::DEBUG_SOURCE_APPLICATION,
+			gl::DEBUG_TYPE_MARKER,
+			0,
+			gl::DEBUG_SEVERITY_NOTIFICATION,
+			msg.len() as i32,
+			msg.as_ptr() as *const i8);
+	}
+}
+
+pub fn push_debug_group(name: &str)
+{
+	unsafe {
+		gl::PushDebugGroup(gl::DEBUG_SOURCE_APPLICATION, 0, name.len() as i32, name.as_ptr() as *const i8);
+	}
+}
+
+pub fn pop_debug_group()
+{
+	unsafe {
+		gl::PopDebugGroup();
+	}
+}
+
+/// RAII guard for a debug group
+pub struct DebugGroup;
+
+impl DebugGroup
+{
+	pub fn new(name: &str) -> DebugGroup
+	{
+		push_debug_group(name);
+		DebugGroup
+	}
+}
+
+impl Drop for DebugGroup
+{
+	fn drop(&mut self)
+	{
+		pop_debug_group();
+	}
+}
+
+/// Set the debug label of a GL object
+/// identifier is GL_BUFFER, GL_TEXTURE, GL_PROGRAM, GL_FRAMEBUFFER, ...
+pub fn set_object_label(identifier: GLenum, obj: GLuint, label: &str)
+{
+	unsafe {
+		gl::ObjectLabel(identifier, obj, label.len() as i32, label.as_ptr() as *const i8);
+	}
+	if let Some(category) = ResourceCategory::from_gl_identifier(identifier) {
+		set_resource_name(category, obj, label);
+	}
+}
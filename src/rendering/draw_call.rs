@@ -0,0 +1,216 @@
+use rendering::buffer::*;
+use rendering::context::*;
+use rendering::sampler::Sampler2D;
+use rendering::shader::PipelineState;
+use rendering::texture::Texture2D;
+
+/// Vertex data that can be drawn with DrawCall::mesh
+pub trait DrawSource
+{
+	fn vertex_buffer(&self) -> RawBufSlice;
+	fn index_buffer(&self) -> Option<RawBufSlice>;
+	/// Parts of the mesh. The first one is drawn by default.
+	fn parts(&self) -> &[MeshPart];
+}
+
+/// Draw call builder, created by Frame::draw_call.
+/// Defaults: one instance, the first part of the mesh, the draw state of the pipeline.
+///
+///     frame.draw_call(&pso)
+///         .mesh(&mesh)
+///         .uniform_buffer(0, scene_data.buffer)
+///         .uniform_buffer_by_name("ObjectData", model_data.as_raw())
+///         .texture_by_name("mainTex", &texture, &sampler)
+///         .submit();
+///
+/// Resources looked up by name use the bindings declared in the shader
+/// (layout(binding=N)). Names that are not active in the program are ignored.
+pub struct DrawCall<'a>
+{
+	frame: &'a Frame,
+	pipeline_state: &'a PipelineState,
+	vertex_buffer: Option<RawBufSlice<'a>>,
+	index_buffer: Option<RawBufSlice<'a>>,
+	part: Option<MeshPart>,
+	num_instances: u32,
+	draw_state: DrawState,
+	uniform_buffers: Vec<Binding<'a>>,
+	storage_buffers: Vec<StorageBinding<'a>>,
+	images: Vec<ImageBinding<'a>>,
+	textures: Vec<TextureBinding<'a>>
+}
+
+impl<'a> DrawCall<'a>
+{
+	pub fn new(frame: &'a Frame, pipeline_state: &'a PipelineState) -> DrawCall<'a>
+	{
+		DrawCall {
+			frame: frame,
+			pipeline_state: pipeline_state,
+			vertex_buffer: None,
+			index_buffer: None,
+			part: None,
+			num_instances: 1,
+			draw_state: pipeline_state.draw_state,
+			uniform_buffers: Vec::new(),
+			storage_buffers: Vec::new(),
+			images: Vec::new(),
+			textures: Vec::new()
+		}
+	}
+
+	/// Draw the first part of a mesh
+	pub fn mesh<M: DrawSource>(mut self, mesh: &'a M) -> DrawCall<'a>
+	{
+		self.vertex_buffer = Some(mesh.vertex_buffer());
+		self.index_buffer = mesh.index_buffer();
+		self.part = mesh.parts().first().cloned();
+		self
+	}
+
+	/// Draw the given part of a mesh
+	pub fn mesh_part<M: DrawSource>(mut self, mesh: &'a M, part_index: usize) -> DrawCall<'a>
+	{
+		self.vertex_buffer = Some(mesh.vertex_buffer());
+		self.index_buffer = mesh.index_buffer();
+		self.part = Some(mesh.parts()[part_index]);
+		self
+	}
+
+	pub fn vertex_buffer(mut self, vertex_buffer: RawBufSlice<'a>) -> DrawCall<'a>
+	{
+		self.vertex_buffer = Some(vertex_buffer);
+		self
+	}
+
+	pub fn index_buffer(mut self, index_buffer: RawBufSlice<'a>) -> DrawCall<'a>
+	{
+		self.index_buffer = Some(index_buffer);
+		self
+	}
+
+	/// Range of vertices or indices to draw (overrides the mesh part)
+	pub fn part(mut self, part: MeshPart) -> DrawCall<'a>
+	{
+		self.part = Some(part);
+		self
+	}
+
+	/// Draw num_vertices vertices, without index buffer
+	pub fn vertices(self, primitive_type: PrimitiveType, start_vertex: u32, num_vertices: u32) -> DrawCall<'a>
+	{
+		self.part(MeshPart::vertices(primitive_type, start_vertex, num_vertices))
+	}
+
+	pub fn instances(mut self, num_instances: u32) -> DrawCall<'a>
+	{
+		self.num_instances = num_instances;
+		self
+	}
+
+	/// Replace the draw state of the pipeline
+	pub fn draw_state(mut self, draw_state: DrawState) -> DrawCall<'a>
+	{
+		self.draw_state = draw_state;
+		self
+	}
+
+	pub fn cull_mode(mut self, cull_mode: CullMode) -> DrawCall<'a>
+	{
+		self.draw_state.cull_mode = cull_mode;
+		self
+	}
+
+	pub fn polygon_fill_mode(mut self, polygon_fill_mode: PolygonFillMode) -> DrawCall<'a>
+	{
+		self.draw_state.polygon_fill_mode = polygon_fill_mode;
+		self
+	}
+
+	pub fn depth_test(mut self, depth_test_enable: bool, depth_write_enable: bool) -> DrawCall<'a>
+	{
+		self.draw_state.depth_test_enable = depth_test_enable;
+		self.draw_state.depth_write_enable = depth_write_enable;
+		self
+	}
+
+	pub fn uniform_buffer(mut self, slot: u32, slice: RawBufSlice<'a>) -> DrawCall<'a>
+	{
+		self.uniform_buffers.push(Binding { slot: slot, slice: slice });
+		self
+	}
+
+	pub fn uniform_buffer_by_name(self, block_name: &str, slice: RawBufSlice<'a>) -> DrawCall<'a>
+	{
+		match self.pipeline_state.program.uniform_block_binding(block_name)
+		{
+			Some(slot) => self.uniform_buffer(slot, slice),
+			None => self
+		}
+	}
+
+	pub fn storage_buffer(mut self, slot: u32, slice: RawBufSlice<'a>, access: ShaderAccess) -> DrawCall<'a>
+	{
+		self.storage_buffers.push(StorageBinding { slot: slot, slice: slice, access: access });
+		self
+	}
+
+	pub fn storage_buffer_by_name(self, block_name: &str, slice: RawBufSlice<'a>, access: ShaderAccess) -> DrawCall<'a>
+	{
+		match self.pipeline_state.program.storage_block_binding(block_name)
+		{
+			Some(slot) => self.storage_buffer(slot, slice, access),
+			None => self
+		}
+	}
+
+	pub fn texture(mut self, slot: u32, texture: &'a Texture2D, sampler: &'a Sampler2D) -> DrawCall<'a>
+	{
+		self.textures.push(TextureBinding { slot: slot, texture: texture, sampler: sampler });
+		self
+	}
+
+	pub fn texture_by_name(self, sampler_name: &str, texture: &'a Texture2D, sampler: &'a Sampler2D) -> DrawCall<'a>
+	{
+		match self.pipeline_state.program.sampler_binding(sampler_name)
+		{
+			Some(slot) => self.texture(slot, texture, sampler),
+			None => self
+		}
+	}
+
+	pub fn image(mut self, slot: u32, texture: &'a Texture2D, level: u32, access: ShaderAccess) -> DrawCall<'a>
+	{
+		self.images.push(ImageBinding { slot: slot, texture: texture, level: level, access: access });
+		self
+	}
+
+	pub fn image_by_name(self, image_name: &str, texture: &'a Texture2D, level: u32, access: ShaderAccess) -> DrawCall<'a>
+	{
+		match self.pipeline_state.program.sampler_binding(image_name)
+		{
+			Some(slot) => self.image(slot, texture, level, access),
+			None => self
+		}
+	}
+
+	/// Issue the draw call.
+	/// Panics if no vertex buffer or no part was specified.
+	pub fn submit(self)
+	{
+		let vertex_buffer = self.vertex_buffer.expect("DrawCall: no vertex buffer specified");
+		let part = self.part.expect("DrawCall: no mesh part specified");
+		self.frame.bind();
+		draw_instanced(
+			vertex_buffer,
+			self.index_buffer,
+			part,
+			self.num_instances,
+			self.pipeline_state,
+			&self.draw_state,
+			&self.uniform_buffers,
+			&self.storage_buffers,
+			&self.images,
+			&self.textures);
+	}
+}
@@ -0,0 +1,666 @@
+use gl;
+use gl::types::*;
+use rendering::buffer::RawBuffer;
+use rendering::context::*;
+use rendering::texture::*;
+
+/// Number of frames a pooled texture or framebuffer can stay unused before it is released
+const POOL_MAX_UNUSED_FRAMES: u32 = 3;
+
+/// Description of a transient texture
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TextureDesc
+{
+	pub width: u32,
+	pub height: u32,
+	pub format: TextureFormat,
+	pub samples: u32
+}
+
+impl TextureDesc
+{
+	pub fn new(width: u32, height: u32, format: TextureFormat) -> TextureDesc
+	{
+		TextureDesc { width: width, height: height, format: format, samples: 1 }
+	}
+
+	fn create(&self) -> Texture2D
+	{
+		if self.samples > 1 {
+			Texture2D::new_multisample(self.width, self.height, self.samples, self.format)
+		} else {
+			Texture2D::new(self.width, self.height, 1, self.format)
+		}
+	}
+}
+
+struct PooledTexture
+{
+	/// identifies the texture in framebuffer keys
+	id: u64,
+	desc: TextureDesc,
+	texture: Texture2D,
+	unused_frames: u32
+}
+
+/// Pooled textures attached to a framebuffer: (color attachments, depth attachment)
+type AttachmentKey = (Vec<u64>, Option<u64>);
+
+struct PooledFramebuffer
+{
+	attachments: AttachmentKey,
+	framebuffer: Framebuffer,
+	unused_frames: u32
+}
+
+/// Pool of textures reused between frames (and between passes of the
+/// same frame, when their lifetimes do not overlap)
+///
+/// Also caches the framebuffers created for sets of pooled textures,
+/// so that passes do not create a framebuffer object every frame.
+pub struct TexturePool
+{
+	free: Vec<PooledTexture>,
+	framebuffers: Vec<PooledFramebuffer>,
+	next_id: u64
+}
+
+impl TexturePool
+{
+	pub fn new() -> TexturePool
+	{
+		TexturePool { free: Vec::new(), framebuffers: Vec::new(), next_id: 0 }
+	}
+
+	fn acquire(&mut self, desc: &TextureDesc) -> (u64, Texture2D)
+	{
+		if let Some(pos) = self.free.iter().position(|t| t.desc == *desc) {
+			let t = self.free.swap_remove(pos);
+			(t.id, t.texture)
+		} else {
+			debug!("TexturePool: allocating {:?}", desc);
+			let _alloc = allocation_scope!("TexturePool");
+			self.next_id += 1;
+			(self.next_id, desc.create())
+		}
+	}
+
+	fn release(&mut self, id: u64, desc: TextureDesc, texture: Texture2D)
+	{
+		self.free.push(PooledTexture { id: id, desc: desc, texture: texture, unused_frames: 0 });
+	}
+
+	/// Framebuffer with the given pooled textures attached, created on first use
+	fn framebuffer(
+		&mut self,
+		name: &str,
+		color: &[(u64, RenderTargetView)],
+		depth: Option<(u64, DepthStencilView)>) -> &Framebuffer
+	{
+		let key = (color.iter().map(|&(id, _)| id).collect::<Vec<_>>(), depth.map(|(id, _)| id));
+		let found = self.framebuffers.iter().position(|f| f.attachments == key);
+		let pos = match found {
+			Some(pos) => pos,
+			None => {
+				debug!("TexturePool: creating framebuffer for pass '{}'", name);
+				let views = color.iter().map(|&(_, view)| view).collect::<Vec<_>>();
+				let framebuffer = Framebuffer::new(&views[..], depth.map(|(_, view)| view));
+				framebuffer.set_label(name);
+				self.framebuffers.push(PooledFramebuffer {
+					attachments: key,
+					framebuffer: framebuffer,
+					unused_frames: 0
+				});
+				self.framebuffers.len() - 1
+			}
+		};
+		let f = &mut self.framebuffers[pos];
+		f.unused_frames = 0;
+		&f.framebuffer
+	}
+
+	/// Release textures and framebuffers that have not been used for a few frames
+	pub fn end_frame(&mut self)
+	{
+		for t in self.free.iter_mut() {
+			t.unused_frames += 1;
+		}
+		self.free.retain(|t| t.unused_frames <= POOL_MAX_UNUSED_FRAMES);
+
+		// all textures are back in the pool at the end of a frame: also drop
+		// the framebuffers referencing released textures
+		let free = &self.free;
+		for f in self.framebuffers.iter_mut() {
+			f.unused_frames += 1;
+		}
+		self.framebuffers.retain(|f| {
+			let (ref color, depth) = f.attachments;
+			f.unused_frames <= POOL_MAX_UNUSED_FRAMES &&
+				color.iter().chain(depth.iter()).all(|&id| free.iter().any(|t| t.id == id))
+		});
+	}
+
+	pub fn num_free(&self) -> usize
+	{
+		self.free.len()
+	}
+
+	pub fn num_framebuffers(&self) -> usize
+	{
+		self.framebuffers.len()
+	}
+}
+
+/// Handle to a texture in a frame graph
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TextureHandle(usize);
+
+/// Handle to a buffer in a frame graph
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BufferHandle(usize);
+
+enum TextureResource<'a>
+{
+	Transient(TextureDesc),
+	Imported(&'a Texture2D),
+	Backbuffer
+}
+
+struct TextureNode<'a>
+{
+	name: &'static str,
+	resource: TextureResource<'a>,
+	/// last pass that writes to this resource
+	producer: Option<usize>
+}
+
+struct BufferNode<'a>
+{
+	name: &'static str,
+	buffer: &'a RawBuffer
+}
+
+/// How a pass writes to a texture
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum WriteKind
+{
+	Color,
+	Depth,
+	/// image load/store
+	Image
+}
+
+/// Declaration of the inputs and outputs of a pass
+pub struct PassDesc
+{
+	name: &'static str,
+	texture_reads: Vec<TextureHandle>,
+	texture_writes: Vec<(TextureHandle, WriteKind)>,
+	buffer_reads: Vec<BufferHandle>,
+	buffer_writes: Vec<BufferHandle>,
+	clear_color: Option<[f32; 4]>,
+	clear_depth: Option<f32>,
+	side_effects: bool
+}
+
+impl PassDesc
+{
+	pub fn new(name: &'static str) -> PassDesc
+	{
+		PassDesc {
+			name: name,
+			texture_reads: Vec::new(),
+			texture_writes: Vec::new(),
+			buffer_reads: Vec::new(),
+			buffer_writes: Vec::new(),
+			clear_color: None,
+			clear_depth: None,
+			side_effects: false
+		}
+	}
+
+	/// The pass samples the texture
+	pub fn read(mut self, texture: TextureHandle) -> PassDesc
+	{
+		self.texture_reads.push(texture);
+		self
+	}
+
+	/// The pass renders into the texture (color attachments are
+	/// bound in the order of the calls)
+	pub fn write_color(mut self, texture: TextureHandle) -> PassDesc
+	{
+		self.texture_writes.push((texture, WriteKind::Color));
+		self
+	}
+
+	pub fn write_depth(mut self, texture: TextureHandle) -> PassDesc
+	{
+		self.texture_writes.push((texture, WriteKind::Depth));
+		self
+	}
+
+	/// The pass writes to the texture through image load/store
+	pub fn write_image(mut self, texture: TextureHandle) -> PassDesc
+	{
+		self.texture_writes.push((texture, WriteKind::Image));
+		self
+	}
+
+	pub fn read_buffer(mut self, buffer: BufferHandle) -> PassDesc
+	{
+		self.buffer_reads.push(buffer);
+		self
+	}
+
+	/// The pass writes to the buffer (storage buffer or transform feedback)
+	pub fn write_buffer(mut self, buffer: BufferHandle) -> PassDesc
+	{
+		self.buffer_writes.push(buffer);
+		self
+	}
+
+	pub fn clear_color(mut self, color: [f32; 4]) -> PassDesc
+	{
+		self.clear_color = Some(color);
+		self
+	}
+
+	pub fn clear_depth(mut self, depth: f32) -> PassDesc
+	{
+		self.clear_depth = Some(depth);
+		self
+	}
+
+	/// Never cull this pass, even if nothing reads its outputs
+	pub fn has_side_effects(mut self) -> PassDesc
+	{
+		self.side_effects = true;
+		self
+	}
+}
+
+/// Resources available to a pass during execution
+pub struct PassContext<'g>
+{
+	frame: Option<Frame>,
+	textures: &'g [Option<Texture2D>],
+	imported: &'g [Option<&'g Texture2D>],
+	buffers: &'g [&'g RawBuffer]
+}
+
+impl<'g> PassContext<'g>
+{
+	/// Frame with the render targets of the pass
+	/// Panics if the pass does not write to any render target
+	pub fn frame(&self) -> &Frame
+	{
+		self.frame.as_ref().expect("pass has no render targets")
+	}
+
+	pub fn texture(&self, handle: TextureHandle) -> &Texture2D
+	{
+		texture_of(self.textures, self.imported, handle)
+	}
+
+	pub fn buffer(&self, handle: BufferHandle) -> &RawBuffer
+	{
+		self.buffers[handle.0]
+	}
+}
+
+fn texture_of<'t>(allocated: &'t [Option<Texture2D>], imported: &[Option<&'t Texture2D>], h: TextureHandle) -> &'t Texture2D
+{
+	match (allocated[h.0].as_ref(), imported[h.0]) {
+		(Some(tex), _) => tex,
+		(None, Some(tex)) => tex,
+		_ => panic!("texture is not allocated, or is the backbuffer")
+	}
+}
+
+struct PassNode<'a>
+{
+	desc: PassDesc,
+	execute: Box<FnMut(&PassContext) + 'a>
+}
+
+/// A graph of render passes for one frame
+///
+/// Passes declare the textures and buffers they read and write. On execution,
+/// passes whose outputs are not used are culled, transient textures are
+/// allocated from a pool (and returned to it after their last use), framebuffers
+/// are created (or reused from the pool) and cleared, and memory barriers are
+/// issued after image and buffer writes.
+///
+/// Passes run in the order they were added: a pass sees the writes
+/// of all the passes added before it.
+pub struct FrameGraph<'a>
+{
+	textures: Vec<TextureNode<'a>>,
+	buffers: Vec<BufferNode<'a>>,
+	passes: Vec<PassNode<'a>>
+}
+
+impl<'a> FrameGraph<'a>
+{
+	pub fn new() -> FrameGraph<'a>
+	{
+		FrameGraph {
+			textures: Vec::new(),
+			buffers: Vec::new(),
+			passes: Vec::new()
+		}
+	}
+
+	fn add_texture(&mut self, name: &'static str, resource: TextureResource<'a>) -> TextureHandle
+	{
+		self.textures.push(TextureNode {
+			name: name,
+			resource: resource,
+			producer: None
+		});
+		TextureHandle(self.textures.len() - 1)
+	}
+
+	/// Declare a texture allocated by the graph for the duration of the frame
+	pub fn create_texture(&mut self, name: &'static str, desc: TextureDesc) -> TextureHandle
+	{
+		self.add_texture(name, TextureResource::Transient(desc))
+	}
+
+	/// Use a texture owned outside the graph. Writes to imported textures
+	/// are visible outside of the graph, so the passes that write them are never culled.
+	pub fn import_texture(&mut self, name: &'static str, texture: &'a Texture2D) -> TextureHandle
+	{
+		self.add_texture(name, TextureResource::Imported(texture))
+	}
+
+	/// The final render target (see RenderTarget), passed to execute
+	pub fn backbuffer(&mut self) -> TextureHandle
+	{
+		self.add_texture("backbuffer", TextureResource::Backbuffer)
+	}
+
+	pub fn import_buffer(&mut self, name: &'static str, buffer: &'a RawBuffer) -> BufferHandle
+	{
+		self.buffers.push(BufferNode {
+			name: name,
+			buffer: buffer
+		});
+		BufferHandle(self.buffers.len() - 1)
+	}
+
+	pub fn add_pass<F>(&mut self, desc: PassDesc, execute: F) where F: FnMut(&PassContext) + 'a
+	{
+		let index = self.passes.len();
+		for &h in desc.texture_reads.iter() {
+			assert!(self.textures[h.0].producer.is_some() || !self.is_transient(h),
+				"pass '{}' reads transient texture '{}' before it is written", desc.name, self.textures[h.0].name);
+		}
+		for &(h, _) in desc.texture_writes.iter() {
+			self.textures[h.0].producer = Some(index);
+		}
+		self.passes.push(PassNode { desc: desc, execute: Box::new(execute) });
+	}
+
+	fn is_transient(&self, h: TextureHandle) -> bool
+	{
+		match self.textures[h.0].resource {
+			TextureResource::Transient(_) => true,
+			_ => false
+		}
+	}
+
+	/// Returns the live passes
+	fn cull(&self) -> Vec<bool>
+	{
+		let num_passes = self.passes.len();
+		let mut live = vec![false; num_passes];
+
+		// roots: passes with side effects, or writing to resources visible outside the graph
+		for (i, p) in self.passes.iter().enumerate() {
+			live[i] = p.desc.side_effects ||
+				!p.desc.buffer_writes.is_empty() ||
+				p.desc.texture_writes.iter().any(|&(h, _)| !self.is_transient(h));
+		}
+
+		// walk backwards: the producers of the inputs of a live pass are live.
+		// The producer of a resource read by pass i is the last writer before i.
+		for i in (0..num_passes).rev() {
+			if !live[i] {
+				continue;
+			}
+			for &h in self.passes[i].desc.texture_reads.iter() {
+				if let Some(j) = self.last_texture_writer(h, i) {
+					live[j] = true;
+				}
+			}
+			for &h in self.passes[i].desc.buffer_reads.iter() {
+				if let Some(j) = (0..i).rev().find(|&j| self.passes[j].desc.buffer_writes.contains(&h)) {
+					live[j] = true;
+				}
+			}
+		}
+
+		for (i, p) in self.passes.iter().enumerate() {
+			if !live[i] {
+				trace!("FrameGraph: culled pass '{}'", p.desc.name);
+			}
+		}
+		live
+	}
+
+	fn last_texture_writer(&self, h: TextureHandle, before: usize) -> Option<usize>
+	{
+		(0..before).rev().find(|&j| self.passes[j].desc.texture_writes.iter().any(|&(w, _)| w == h))
+	}
+
+	/// Run the passes
+	/// Passes writing to the backbuffer draw into target.
+	pub fn execute(mut self, context: &Context, target: &RenderTarget, pool: &mut TexturePool)
+	{
+		let live = self.cull();
+		let num_textures = self.textures.len();
+
+		// first and last use of each transient texture, for allocation
+		let mut first_use = vec![None; num_textures];
+		let mut last_use = vec![None; num_textures];
+		for (i, p) in self.passes.iter().enumerate() {
+			if !live[i] {
+				continue;
+			}
+			let used = p.desc.texture_reads.iter().cloned()
+				.chain(p.desc.texture_writes.iter().map(|&(h, _)| h));
+			for h in used {
+				if first_use[h.0].is_none() {
+					first_use[h.0] = Some(i);
+				}
+				last_use[h.0] = Some(i);
+			}
+		}
+
+		let mut allocated: Vec<Option<Texture2D>> = (0..num_textures).map(|_| None).collect();
+		// pool ids of the allocated textures
+		let mut ids: Vec<Option<u64>> = vec![None; num_textures];
+		let imported: Vec<Option<&Texture2D>> = self.textures.iter().map(|t| match t.resource {
+			TextureResource::Imported(tex) => Some(tex),
+			_ => None
+		}).collect();
+		let buffers: Vec<&RawBuffer> = self.buffers.iter().map(|b| b.buffer).collect();
+
+		// barrier bits required before the next pass, by texture/buffer
+		let mut pending_image_writes = vec![false; num_textures];
+		let mut pending_buffer_writes = vec![false; self.buffers.len()];
+
+		let passes = ::std::mem::replace(&mut self.passes, Vec::new());
+		for (i, mut pass) in passes.into_iter().enumerate() {
+			if !live[i] {
+				continue;
+			}
+
+			// allocate transient textures used for the first time
+			for t in 0..num_textures {
+				if first_use[t] == Some(i) {
+					if let TextureResource::Transient(ref desc) = self.textures[t].resource {
+						let (id, tex) = pool.acquire(desc);
+						ids[t] = Some(id);
+						allocated[t] = Some(tex);
+					}
+				}
+			}
+
+			// barriers for reads of resources written with image stores or storage writes
+			let mut barrier: GLbitfield = 0;
+			for &h in pass.desc.texture_reads.iter() {
+				if pending_image_writes[h.0] {
+					barrier |= gl::TEXTURE_FETCH_BARRIER_BIT | gl::SHADER_IMAGE_ACCESS_BARRIER_BIT;
+					pending_image_writes[h.0] = false;
+				}
+			}
+			for &(h, kind) in pass.desc.texture_writes.iter() {
+				if kind != WriteKind::Image && pending_image_writes[h.0] {
+					barrier |= gl::FRAMEBUFFER_BARRIER_BIT;
+					pending_image_writes[h.0] = false;
+				}
+			}
+			for &h in pass.desc.buffer_reads.iter() {
+				if pending_buffer_writes[h.0] {
+					barrier |= gl::SHADER_STORAGE_BARRIER_BIT | gl::VERTEX_ATTRIB_ARRAY_BARRIER_BIT |
+						gl::ELEMENT_ARRAY_BARRIER_BIT | gl::UNIFORM_BARRIER_BIT | gl::COMMAND_BARRIER_BIT;
+					pending_buffer_writes[h.0] = false;
+				}
+			}
+			if barrier != 0 {
+				unsafe { gl::MemoryBarrier(barrier); }
+			}
+
+			let _scope = context.profile_scope(pass.desc.name);
+			let _group = context.debug_group(pass.desc.name);
+
+			// framebuffer setup
+			let writes_backbuffer = pass.desc.texture_writes.iter()
+				.any(|&(h, _)| match self.textures[h.0].resource { TextureResource::Backbuffer => true, _ => false });
+			let mut frame = if writes_backbuffer {
+				Some(context.create_target_frame(target))
+			} else {
+				let color = pass.desc.texture_writes.iter()
+					.filter(|&&(_, kind)| kind == WriteKind::Color)
+					.map(|&(h, _)| (ids[h.0], texture_of(&allocated[..], &imported[..], h).view_as_render_target()))
+					.collect::<Vec<_>>();
+				let depth = pass.desc.texture_writes.iter()
+					.find(|&&(_, kind)| kind == WriteKind::Depth)
+					.map(|&(h, _)| (ids[h.0], texture_of(&allocated[..], &imported[..], h).view_as_depth_stencil_target()));
+				let all_pooled = color.iter().map(|&(id, _)| id).chain(depth.map(|(id, _)| id)).all(|id| id.is_some());
+
+				if color.is_empty() && depth.is_none() {
+					None
+				} else if all_pooled {
+					// only pooled textures: reuse the framebuffer created in a previous frame
+					let color = color.iter().map(|&(id, view)| (id.unwrap(), view)).collect::<Vec<_>>();
+					let depth = depth.map(|(id, view)| (id.unwrap(), view));
+					Some(context.create_frame_in(pool.framebuffer(pass.desc.name, &color[..], depth)))
+				} else {
+					// imported textures can change between frames
+					let color_views = color.iter().map(|&(_, view)| view).collect::<Vec<_>>();
+					let frame = context.create_frame(&color_views[..], depth.map(|(_, view)| view));
+					frame.set_label(pass.desc.name);
+					Some(frame)
+				}
+			};
+
+			if let Some(ref mut frame) = frame {
+				if pass.desc.clear_color.is_some() || pass.desc.clear_depth.is_some() {
+					frame.clear(pass.desc.clear_color, pass.desc.clear_depth);
+				}
+			}
+
+			{
+				let ctx = PassContext {
+					frame: frame,
+					textures: &allocated[..],
+					imported: &imported[..],
+					buffers: &buffers[..]
+				};
+				(pass.execute)(&ctx);
+			}
+
+			for &(h, kind) in pass.desc.texture_writes.iter() {
+				if kind == WriteKind::Image {
+					pending_image_writes[h.0] = true;
+				}
+			}
+			for &h in pass.desc.buffer_writes.iter() {
+				pending_buffer_writes[h.0] = true;
+			}
+
+			// return transient textures to the pool after their last use
+			for t in 0..num_textures {
+				if last_use[t] == Some(i) {
+					if let TextureResource::Transient(desc) = self.textures[t].resource {
+						if let (Some(id), Some(tex)) = (ids[t].take(), allocated[t].take()) {
+							pool.release(id, desc, tex);
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+#[test]
+fn test_cull_unused_outputs()
+{
+	let desc = TextureDesc::new(64, 64, TextureFormat::Rgba8);
+	let mut graph = FrameGraph::new();
+	let gbuffer = graph.create_texture("gbuffer", desc);
+	let unused = graph.create_texture("unused", desc);
+	let debug = graph.create_texture("debug", desc);
+	let backbuffer = graph.backbuffer();
+
+	graph.add_pass(PassDesc::new("gbuffer").write_color(gbuffer), |_| {});
+	// nobody reads its output
+	graph.add_pass(PassDesc::new("unused").read(gbuffer).write_color(unused), |_| {});
+	graph.add_pass(PassDesc::new("debug").write_color(debug).has_side_effects(), |_| {});
+	graph.add_pass(PassDesc::new("final").read(gbuffer).write_color(backbuffer), |_| {});
+
+	assert_eq!(graph.cull(), vec![true, false, true, true]);
+}
+
+#[cfg(feature = "headless")]
+#[test]
+fn test_dependent_pass_order()
+{
+	use std::cell::RefCell;
+	use headless::HeadlessSettings;
+
+	let _headless = HeadlessSettings::new((64, 64)).build().unwrap();
+	let context = Context::new((64, 64));
+	let color = Texture2D::new(64, 64, 1, TextureFormat::Rgba8);
+	let depth = Texture2D::new(64, 64, 1, TextureFormat::Depth24);
+	let order = RefCell::new(Vec::new());
+	let mut pool = TexturePool::new();
+	let desc = TextureDesc::new(64, 64, TextureFormat::Rgba8);
+
+	for _ in 0..2 {
+		let order = &order;
+		let mut graph = FrameGraph::new();
+		let scene = graph.create_texture("scene", desc);
+		let blurred = graph.create_texture("blurred", desc);
+		let unused = graph.create_texture("unused", desc);
+		let backbuffer = graph.backbuffer();
+
+		graph.add_pass(PassDesc::new("scene").write_color(scene), move |_| order.borrow_mut().push("scene"));
+		graph.add_pass(PassDesc::new("unused").read(scene).write_color(unused), move |_| order.borrow_mut().push("unused"));
+		graph.add_pass(PassDesc::new("blur").read(scene).write_color(blurred), move |_| order.borrow_mut().push("blur"));
+		graph.add_pass(PassDesc::new("final").read(blurred).write_color(backbuffer), move |_| order.borrow_mut().push("final"));
+		graph.execute(&context, &RenderTarget::Offscreen { color: &color, depth: &depth }, &mut pool);
+		pool.end_frame();
+	}
+
+	// producers run before their consumers, culled passes do not run
+	assert_eq!(&order.borrow()[..], &["scene", "blur", "final", "scene", "blur", "final"][..]);
+	// two textures, and the framebuffers of the scene and blur passes,
+	// reused on the second frame
+	assert_eq!(pool.num_free(), 2);
+	assert_eq!(pool.num_framebuffers(), 2);
+}
@@ -0,0 +1,296 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem;
+use nalgebra::{Vec2, Vec3, Vec4, Mat4};
+use rendering::attrib::*;
+use rendering::shader::UniformType;
+
+//-------------------------------------------
+// CPU-side description of vertex formats and uniform blocks.
+// Declared with the vertex_format! and uniform_block! macros
+// (see macros.rs): the GPU types of the fields are deduced from their
+// Rust types, through the AttributeField and Std140Field traits.
+// Pipelines build their input layout from a vertex format, and
+// uniform blocks are checked against the std140 rules at compile time.
+
+/// A field of a vertex structure
+#[derive(Copy, Clone, Debug)]
+pub struct VertexAttribute
+{
+	pub name: &'static str,
+	pub ty: AttributeType,
+	/// Offset of the field in the structure
+	pub offset: usize,
+	/// Size of the field in the structure
+	pub size: usize
+}
+
+/// A structure that can be used as vertex data.
+/// Use vertex_format! to implement this trait.
+pub trait VertexFormat: Copy + Any
+{
+	/// Name of the structure (for error messages)
+	fn name() -> &'static str;
+
+	/// Attributes, in shader location order
+	fn attributes() -> Vec<VertexAttribute>;
+
+	fn stride() -> usize where Self: Sized
+	{
+		mem::size_of::<Self>()
+	}
+}
+
+/// Rust types that can be used as fields of a vertex structure.
+/// Unsafe: the type must be plain data (valid when zeroed), with
+/// the size of its attribute type.
+pub unsafe trait AttributeField: Copy
+{
+	const ATTRIBUTE_TYPE: AttributeType;
+}
+
+macro_rules! attribute_field {
+	($($ty:ty => $attr:ident),*) => {
+		$(unsafe impl AttributeField for $ty
+		{
+			const ATTRIBUTE_TYPE: AttributeType = AttributeType::$attr;
+		})*
+	}
+}
+
+attribute_field!(
+	f32 => Float,
+	[f32; 2] => Float2,
+	[f32; 3] => Float3,
+	[f32; 4] => Float4,
+	Vec2<f32> => Float2,
+	Vec3<f32> => Float3,
+	Vec4<f32> => Float4,
+	u32 => Uint32,
+	i32 => Sint32,
+	[u8; 4] => Unorm8x4);
+
+/// A field of a uniform block structure
+#[derive(Copy, Clone, Debug)]
+pub struct UniformField
+{
+	pub name: &'static str,
+	pub ty: UniformType,
+	pub offset: usize,
+	pub size: usize
+}
+
+/// A structure that can be uploaded in a std140 uniform block.
+/// Use uniform_block! to implement this trait.
+pub trait UniformBlock: Copy + Any
+{
+	/// Name of the structure (for error messages)
+	fn name() -> &'static str;
+
+	/// Fields visible to shaders (padding excluded)
+	fn fields() -> Vec<UniformField>;
+}
+
+/// Size and alignment of a field type, in a #[repr(C)] structure and in the std140 layout
+#[derive(Copy, Clone, Debug)]
+pub struct FieldLayout
+{
+	pub size: usize,
+	pub align: usize,
+	pub std140_size: usize,
+	pub std140_align: usize,
+	/// Padding only takes space, it is not visible to shaders
+	pub padding: bool
+}
+
+/// Rust types that can be used as fields of a uniform block structure.
+/// Unsafe: the type must be plain data (valid when zeroed), and LAYOUT
+/// must match its actual size and alignment.
+pub unsafe trait Std140Field: Copy
+{
+	/// None for padding
+	const UNIFORM_TYPE: Option<UniformType>;
+	const LAYOUT: FieldLayout;
+}
+
+/// Explicit padding in a uniform block structure
+///
+///     pub _pad0: Padding<[f32; 2]>,
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Padding<T>(pub T);
+
+macro_rules! std140_field {
+	($($ty:ty => $uniform:expr, ($size:expr, $align:expr), ($std140_size:expr, $std140_align:expr)),*) => {
+		$(unsafe impl Std140Field for $ty
+		{
+			const UNIFORM_TYPE: Option<UniformType> = $uniform;
+			const LAYOUT: FieldLayout = FieldLayout {
+				size: $size,
+				align: $align,
+				std140_size: $std140_size,
+				std140_align: $std140_align,
+				padding: false
+			};
+		})*
+	}
+}
+
+// rust (size, alignment), std140 (size, alignment): must agree with UniformType::std140_layout
+std140_field!(
+	f32 => Some(UniformType::Float), (4, 4), (4, 4),
+	i32 => Some(UniformType::Int), (4, 4), (4, 4),
+	[f32; 2] => Some(UniformType::Float2), (8, 4), (8, 8),
+	[f32; 3] => Some(UniformType::Float3), (12, 4), (12, 16),
+	[f32; 4] => Some(UniformType::Float4), (16, 4), (16, 16),
+	Vec2<f32> => Some(UniformType::Float2), (8, 4), (8, 8),
+	Vec3<f32> => Some(UniformType::Float3), (12, 4), (12, 16),
+	Vec4<f32> => Some(UniformType::Float4), (16, 4), (16, 16),
+	Mat4<f32> => Some(UniformType::Mat4), (64, 4), (64, 16));
+
+macro_rules! std140_padding {
+	($($ty:ty => $size:expr),*) => {
+		$(unsafe impl Std140Field for Padding<$ty>
+		{
+			const UNIFORM_TYPE: Option<UniformType> = None;
+			const LAYOUT: FieldLayout = FieldLayout {
+				size: $size,
+				align: 4,
+				std140_size: $size,
+				std140_align: 4,
+				padding: true
+			};
+		})*
+	}
+}
+
+std140_padding!(f32 => 4, [f32; 2] => 8, [f32; 3] => 12);
+
+/// End of the last placed field of a uniform block, and whether all fields so far
+/// are where std140 expects them. Evaluated at compile time by uniform_block!.
+#[derive(Copy, Clone, Debug)]
+pub struct Std140Cursor
+{
+	pub end: usize,
+	pub ok: bool
+}
+
+pub const STD140_START: Std140Cursor = Std140Cursor { end: 0, ok: true };
+
+/// Place the next field of a #[repr(C)] structure, and check its offset and
+/// size against the std140 rules (padding fields are not checked)
+pub const fn std140_next(cursor: Std140Cursor, field: FieldLayout) -> Std140Cursor
+{
+	Std140Cursor {
+		end: round_up(cursor.end, field.align) + field.size,
+		ok: cursor.ok & (field.padding |
+			((round_up(cursor.end, field.align) == round_up(cursor.end, field.std140_align)) &
+			 (field.size == field.std140_size)))
+	}
+}
+
+const fn round_up(value: usize, alignment: usize) -> usize
+{
+	(value + alignment - 1) / alignment * alignment
+}
+
+/// Offset of a field in a structure. Used by the layout macros
+/// on a zeroed instance of the structure.
+pub fn field_offset<S, F>(base: &S, field: &F) -> usize
+{
+	field as *const F as usize - base as *const S as usize
+}
+
+impl UniformType
+{
+	/// (alignment, size) in the std140 layout
+	pub fn std140_layout(self) -> (usize, usize)
+	{
+		match self
+		{
+			UniformType::Float | UniformType::Int => (4, 4),
+			UniformType::Float2 | UniformType::Int2 => (8, 8),
+			UniformType::Float3 | UniformType::Int3 => (16, 12),
+			UniformType::Float4 | UniformType::Int4 => (16, 16),
+			// matrices are arrays of column vectors, each padded to a vec4
+			UniformType::Mat2 => (16, 2 * 16),
+			UniformType::Mat3 => (16, 3 * 16),
+			UniformType::Mat4 => (16, 4 * 16),
+			UniformType::Mat3x4 => (16, 3 * 16),
+			UniformType::Mat4x3 => (16, 4 * 16)
+		}
+	}
+}
+
+/// Check that the fields of a uniform block follow the std140 rules.
+/// On error, the message lists the fields that are misplaced and the
+/// padding that must be inserted before them.
+pub fn check_std140_layout(block_name: &str, fields: &[UniformField]) -> Result<(), String>
+{
+	let mut errors = String::new();
+	let mut expected_offset = 0;
+	for f in fields.iter() {
+		let (alignment, size) = f.ty.std140_layout();
+		expected_offset = round_up(expected_offset, alignment);
+		if f.size != size {
+			errors.push_str(&format!("\n  {}: size is {}, expected {} for {:?}", f.name, f.size, size, f.ty));
+		}
+		if f.offset != expected_offset {
+			if f.offset < expected_offset {
+				errors.push_str(&format!("\n  {}: offset is {}, expected {} (insert {} bytes of padding before)",
+					f.name, f.offset, expected_offset, expected_offset - f.offset));
+			} else {
+				errors.push_str(&format!("\n  {}: offset is {}, expected {} (remove {} bytes of padding before)",
+					f.name, f.offset, expected_offset, f.offset - expected_offset));
+			}
+		}
+		// continue from the actual position of the field to report each error only once
+		expected_offset = f.offset + size;
+	}
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(format!("{} does not follow the std140 layout:{}", block_name, errors))
+	}
+}
+
+/// Check that the attributes of a vertex structure have the types of a list of shader inputs
+/// (e.g. the glsl_layout of a shader)
+pub fn check_vertex_layout(format_name: &str, attribs: &[VertexAttribute], inputs: &[AttributeType]) -> Result<(), String>
+{
+	let mut errors = String::new();
+	if attribs.len() != inputs.len() {
+		errors.push_str(&format!("\n  {} attributes, the shader expects {}", attribs.len(), inputs.len()));
+	}
+	for (a, &ty) in attribs.iter().zip(inputs.iter()) {
+		if a.size != a.ty.byte_size() {
+			errors.push_str(&format!("\n  {}: size is {}, but {:?} is {} bytes", a.name, a.size, a.ty, a.ty.byte_size()));
+		}
+		if a.ty != ty {
+			errors.push_str(&format!("\n  {}: type is {:?}, the shader expects {:?}", a.name, a.ty, ty));
+		}
+	}
+	if errors.is_empty() {
+		Ok(())
+	} else {
+		Err(format!("vertex format {} does not match the shader inputs:{}", format_name, errors))
+	}
+}
+
+// uniform block types already checked
+thread_local!(static CHECKED_BLOCKS: RefCell<HashSet<TypeId>> = RefCell::new(HashSet::new()));
+
+/// Check the layout of a uniform block type, once per type.
+/// uniform_block! already rejects invalid layouts at compile time,
+/// this also catches hand-written UniformBlock implementations.
+/// Panics if the layout is invalid.
+pub fn check_uniform_block<T: UniformBlock>()
+{
+	let first_use = CHECKED_BLOCKS.with(|c| c.borrow_mut().insert(TypeId::of::<T>()));
+	if first_use {
+		if let Err(e) = check_std140_layout(T::name(), &T::fields()) {
+			panic!("{}", e);
+		}
+	}
+}
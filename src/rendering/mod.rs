@@ -4,9 +4,28 @@ pub mod context;
 pub mod shader;
 pub mod texture;
 pub mod sampler;
+pub mod readback;
+pub mod renderbuffer;
+pub mod profiler;
+pub mod debug;
+pub mod state_cache;
+pub mod command_list;
+pub mod frame_graph;
+pub mod resource_tracker;
+pub mod layout;
+pub mod draw_call;
 
 pub use self::texture::*;
 pub use self::buffer::*;
 pub use self::context::*;
 pub use self::attrib::*;
 pub use self::sampler::*;
+pub use self::readback::*;
+pub use self::renderbuffer::*;
+pub use self::profiler::*;
+pub use self::debug::*;
+pub use self::command_list::*;
+pub use self::frame_graph::*;
+pub use self::resource_tracker::*;
+pub use self::layout::*;
+pub use self::draw_call::*;
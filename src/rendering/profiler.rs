@@ -0,0 +1,369 @@
+use gl;
+use gl::types::*;
+use time;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+/// Maximum number of frames waiting for their query results. Results are read
+/// when they are available, so that reading the queries never stalls; when the GPU
+/// is further behind, frames are not timed (queries in flight are never reused).
+const MAX_FRAMES_IN_FLIGHT: usize = 4;
+
+/// One timed scope, as recorded during a frame
+struct ScopeRecord
+{
+	name: &'static str,
+	parent: Option<usize>,
+	depth: u32,
+	cpu_begin: u64,
+	cpu_end: u64,
+	/// timestamp queries (begin, end)
+	gpu_queries: (GLuint, GLuint)
+}
+
+/// Timing results of a scope
+#[derive(Clone, Debug)]
+pub struct ScopeTiming
+{
+	pub name: &'static str,
+	/// Index of the parent scope in FrameTimings::scopes
+	pub parent: Option<usize>,
+	pub depth: u32,
+	/// Start time, in microseconds since the beginning of the frame
+	pub cpu_start_us: f64,
+	pub cpu_duration_us: f64,
+	pub gpu_start_us: f64,
+	pub gpu_duration_us: f64
+}
+
+/// Timing results for a whole frame
+/// Scopes are stored in the order they were opened, so that
+/// a parent always comes before its children.
+#[derive(Clone, Debug)]
+pub struct FrameTimings
+{
+	pub frame_index: u64,
+	pub scopes: Vec<ScopeTiming>
+}
+
+impl FrameTimings
+{
+	/// Indices of the direct children of a scope (or the root scopes if None)
+	pub fn children(&self, parent: Option<usize>) -> Vec<usize>
+	{
+		self.scopes.iter().enumerate()
+			.filter(|&(_, s)| s.parent == parent)
+			.map(|(i, _)| i)
+			.collect()
+	}
+
+	/// Print the scope tree to the log
+	pub fn dump(&self)
+	{
+		for s in self.scopes.iter() {
+			let indent = (0..s.depth).map(|_| "  ").collect::<String>();
+			debug!("{}{}: CPU {:.3}ms, GPU {:.3}ms", indent, s.name,
+				s.cpu_duration_us / 1000.0, s.gpu_duration_us / 1000.0);
+		}
+	}
+
+	/// Export in the Chrome trace event format (chrome://tracing)
+	/// CPU scopes are on thread 0, GPU scopes on thread 1.
+	pub fn to_chrome_trace(&self) -> String
+	{
+		let mut out = String::new();
+		out.push_str("{\"traceEvents\":[");
+		let mut first = true;
+		for s in self.scopes.iter() {
+			for &(tid, start, dur) in [(0, s.cpu_start_us, s.cpu_duration_us),
+									   (1, s.gpu_start_us, s.gpu_duration_us)].iter() {
+				if !first {
+					out.push(',');
+				}
+				first = false;
+				write!(out, "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":{}}}",
+					escape_json(s.name), if tid == 0 { "cpu" } else { "gpu" }, start, dur, tid).unwrap();
+			}
+		}
+		out.push_str("]}");
+		out
+	}
+}
+
+/// Escape a string for a JSON string literal
+fn escape_json(s: &str) -> String
+{
+	let mut out = String::with_capacity(s.len());
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+			c => out.push(c)
+		}
+	}
+	out
+}
+
+struct FrameSlot
+{
+	frame_index: u64,
+	scopes: Vec<ScopeRecord>,
+	/// Number of queries in use in this slot
+	num_queries: usize,
+	/// Query objects, reused between frames
+	queries: Vec<GLuint>
+}
+
+impl FrameSlot
+{
+	fn new() -> FrameSlot
+	{
+		FrameSlot {
+			frame_index: 0,
+			scopes: Vec::new(),
+			num_queries: 0,
+			queries: Vec::new()
+		}
+	}
+
+	fn alloc_query(&mut self) -> GLuint
+	{
+		if self.num_queries == self.queries.len() {
+			let mut obj = 0;
+			unsafe {
+				gl::GenQueries(1, &mut obj);
+			}
+			self.queries.push(obj);
+		}
+		self.num_queries += 1;
+		self.queries[self.num_queries-1]
+	}
+
+	fn reset(&mut self, frame_index: u64)
+	{
+		self.frame_index = frame_index;
+		self.scopes.clear();
+		self.num_queries = 0;
+	}
+
+	fn results_available(&self) -> bool
+	{
+		if self.num_queries == 0 {
+			return true;
+		}
+		let mut available = 0;
+		unsafe {
+			gl::GetQueryObjectiv(self.queries[self.num_queries-1], gl::QUERY_RESULT_AVAILABLE, &mut available);
+		}
+		available != 0
+	}
+
+	fn collect(&self) -> FrameTimings
+	{
+		let query_result = |q: GLuint| -> u64 {
+			let mut v = 0u64;
+			unsafe {
+				gl::GetQueryObjectui64v(q, gl::QUERY_RESULT, &mut v);
+			}
+			v
+		};
+
+		let cpu_origin = self.scopes.first().map(|s| s.cpu_begin).unwrap_or(0);
+		let gpu_origin = self.scopes.first().map(|s| query_result(s.gpu_queries.0)).unwrap_or(0);
+
+		let scopes = self.scopes.iter().map(|s| {
+			let gpu_begin = query_result(s.gpu_queries.0);
+			let gpu_end = query_result(s.gpu_queries.1);
+			ScopeTiming {
+				name: s.name,
+				parent: s.parent,
+				depth: s.depth,
+				cpu_start_us: (s.cpu_begin - cpu_origin) as f64 / 1000.0,
+				cpu_duration_us: (s.cpu_end - s.cpu_begin) as f64 / 1000.0,
+				gpu_start_us: (gpu_begin.saturating_sub(gpu_origin)) as f64 / 1000.0,
+				gpu_duration_us: (gpu_end.saturating_sub(gpu_begin)) as f64 / 1000.0
+			}
+		}).collect();
+
+		FrameTimings {
+			frame_index: self.frame_index,
+			scopes: scopes
+		}
+	}
+}
+
+impl Drop for FrameSlot
+{
+	fn drop(&mut self)
+	{
+		if !self.queries.is_empty() {
+			unsafe {
+				gl::DeleteQueries(self.queries.len() as i32, self.queries.as_ptr());
+			}
+		}
+	}
+}
+
+/// GPU and CPU frame profiler
+/// Scopes are timed with GL_TIMESTAMP queries (which, unlike GL_TIME_ELAPSED,
+/// can be nested). The queries of a frame stay in flight until their results are
+/// available (see MAX_FRAMES_IN_FLIGHT).
+pub struct Profiler
+{
+	enabled: bool,
+	/// Applied at the end of the frame, so that scopes are never left half-open
+	enabled_next_frame: bool,
+	frame_index: u64,
+	/// Slot of the current frame (None if the frame is not timed)
+	current: Option<FrameSlot>,
+	/// Frames waiting for their query results, oldest first
+	in_flight: VecDeque<FrameSlot>,
+	/// Slots whose results have been collected, reused for the next frames
+	free: Vec<FrameSlot>,
+	/// Stack of open scopes (indices into the current slot)
+	stack: Vec<usize>,
+	last_timings: Option<FrameTimings>
+}
+
+impl Profiler
+{
+	pub fn new() -> Profiler
+	{
+		Profiler {
+			enabled: true,
+			enabled_next_frame: true,
+			frame_index: 0,
+			current: Some(FrameSlot::new()),
+			in_flight: VecDeque::new(),
+			free: Vec::new(),
+			stack: Vec::new(),
+			last_timings: None
+		}
+	}
+
+	/// Takes effect at the next end_frame
+	pub fn set_enabled(&mut self, enabled: bool)
+	{
+		self.enabled_next_frame = enabled;
+	}
+
+	pub fn begin_scope(&mut self, name: &'static str)
+	{
+		let parent = self.stack.last().cloned();
+		let depth = self.stack.len() as u32;
+		// in frames that are not timed, the stack is only kept balanced
+		let mut index = 0;
+		if let Some(ref mut slot) = self.current {
+			let q_begin = slot.alloc_query();
+			let q_end = slot.alloc_query();
+			unsafe {
+				gl::QueryCounter(q_begin, gl::TIMESTAMP);
+			}
+			slot.scopes.push(ScopeRecord {
+				name: name,
+				parent: parent,
+				depth: depth,
+				cpu_begin: time::precise_time_ns(),
+				cpu_end: 0,
+				gpu_queries: (q_begin, q_end)
+			});
+			index = slot.scopes.len() - 1;
+		}
+		self.stack.push(index);
+	}
+
+	pub fn end_scope(&mut self)
+	{
+		let index = self.stack.pop().expect("end_scope called without a matching begin_scope");
+		if let Some(ref mut slot) = self.current {
+			let scope = &mut slot.scopes[index];
+			unsafe {
+				gl::QueryCounter(scope.gpu_queries.1, gl::TIMESTAMP);
+			}
+			scope.cpu_end = time::precise_time_ns();
+		}
+	}
+
+	/// Mark the end of the current frame, and collect the results of
+	/// the frames in flight that are available.
+	pub fn end_frame(&mut self)
+	{
+		assert!(self.stack.is_empty(), "unterminated profiler scopes at end of frame");
+		if let Some(slot) = self.current.take() {
+			if slot.scopes.is_empty() {
+				self.free.push(slot);
+			} else {
+				self.in_flight.push_back(slot);
+			}
+		}
+
+		// results are available in submission order
+		while self.in_flight.front().map(|slot| slot.results_available()).unwrap_or(false) {
+			let slot = self.in_flight.pop_front().unwrap();
+			self.last_timings = Some(slot.collect());
+			self.free.push(slot);
+		}
+
+		self.frame_index += 1;
+		self.enabled = self.enabled_next_frame;
+		if self.enabled && self.in_flight.len() < MAX_FRAMES_IN_FLIGHT {
+			let mut slot = self.free.pop().unwrap_or_else(FrameSlot::new);
+			slot.reset(self.frame_index);
+			self.current = Some(slot);
+		}
+	}
+
+	/// Most recent complete timing results
+	pub fn last_frame_timings(&self) -> Option<&FrameTimings>
+	{
+		self.last_timings.as_ref()
+	}
+}
+
+/// RAII guard for a profiler scope
+pub struct ProfileScope<'a>
+{
+	profiler: &'a RefCell<Profiler>
+}
+
+impl<'a> ProfileScope<'a>
+{
+	pub fn new(profiler: &'a RefCell<Profiler>, name: &'static str) -> ProfileScope<'a>
+	{
+		profiler.borrow_mut().begin_scope(name);
+		ProfileScope { profiler: profiler }
+	}
+}
+
+impl<'a> Drop for ProfileScope<'a>
+{
+	fn drop(&mut self)
+	{
+		self.profiler.borrow_mut().end_scope();
+	}
+}
+
+#[test]
+fn test_chrome_trace_escaping()
+{
+	let timings = FrameTimings {
+		frame_index: 0,
+		scopes: vec![ScopeTiming {
+			name: "pass \"main\"\\shadow\n",
+			parent: None,
+			depth: 0,
+			cpu_start_us: 0.0,
+			cpu_duration_us: 1.0,
+			gpu_start_us: 0.0,
+			gpu_duration_us: 2.0
+		}]
+	};
+	let trace = timings.to_chrome_trace();
+	assert!(trace.contains("\"name\":\"pass \\\"main\\\"\\\\shadow\\n\""));
+	assert_eq!(escape_json("a\u{1}b"), "a\\u0001b");
+}
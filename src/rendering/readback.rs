@@ -0,0 +1,231 @@
+use gl;
+use gl::types::*;
+use image::{self, DynamicImage, ImageBuffer};
+use std::fmt;
+use std::ptr;
+use rendering::buffer::*;
+use rendering::texture::TextureFormat;
+
+/// Layout of pixel data read back from the GPU
+#[derive(Copy, Clone, Debug)]
+pub enum ReadbackFormat
+{
+	/// One byte per channel, 1 to 4 channels
+	Unorm8(u8),
+	/// One 32-bit float per pixel
+	Depth
+}
+
+impl ReadbackFormat
+{
+	/// Readback format for the given texture format, if supported
+	pub fn from_texture_format(format: TextureFormat) -> Option<ReadbackFormat>
+	{
+		match format {
+			TextureFormat::Unorm8 => Some(ReadbackFormat::Unorm8(1)),
+			TextureFormat::Unorm8x2 => Some(ReadbackFormat::Unorm8(2)),
+			TextureFormat::Unorm8x3 |
+			TextureFormat::Srgb8 => Some(ReadbackFormat::Unorm8(3)),
+			TextureFormat::Unorm8x4 |
+			TextureFormat::Srgb8Alpha8 => Some(ReadbackFormat::Unorm8(4)),
+			TextureFormat::Depth16 |
+			TextureFormat::Depth24 |
+			TextureFormat::Depth32 => Some(ReadbackFormat::Depth),
+			_ => None
+		}
+	}
+
+	/// external format, type
+	pub fn gl_format(self) -> (GLenum, GLenum)
+	{
+		match self {
+			ReadbackFormat::Unorm8(1) => (gl::RED, gl::UNSIGNED_BYTE),
+			ReadbackFormat::Unorm8(2) => (gl::RG, gl::UNSIGNED_BYTE),
+			ReadbackFormat::Unorm8(3) => (gl::RGB, gl::UNSIGNED_BYTE),
+			ReadbackFormat::Unorm8(_) => (gl::RGBA, gl::UNSIGNED_BYTE),
+			ReadbackFormat::Depth => (gl::DEPTH_COMPONENT, gl::FLOAT)
+		}
+	}
+
+	pub fn pixel_byte_size(self) -> usize
+	{
+		match self {
+			ReadbackFormat::Unorm8(n) => n as usize,
+			ReadbackFormat::Depth => 4
+		}
+	}
+}
+
+/// Flip rows: GL returns the bottom row first
+fn flip_rows(data: &[u8], row_size: usize) -> Vec<u8>
+{
+	let mut out = Vec::with_capacity(data.len());
+	for row in data.chunks(row_size).rev() {
+		out.extend(row.iter().cloned());
+	}
+	out
+}
+
+/// Convert raw pixel data (as returned by GL) into an image
+/// Depth values are mapped to 8-bit grayscale
+pub fn pixels_to_image(format: ReadbackFormat, width: u32, height: u32, data: &[u8]) -> DynamicImage
+{
+	let row_size = width as usize * format.pixel_byte_size();
+	let flipped = flip_rows(data, row_size);
+	match format {
+		ReadbackFormat::Unorm8(1) =>
+			DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, flipped).unwrap()),
+		ReadbackFormat::Unorm8(2) =>
+			DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, flipped).unwrap()),
+		ReadbackFormat::Unorm8(3) =>
+			DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, flipped).unwrap()),
+		ReadbackFormat::Unorm8(_) =>
+			DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, flipped).unwrap()),
+		ReadbackFormat::Depth => {
+			let gray = depth_to_floats(&flipped[..]).iter()
+				.map(|&d| (d.max(0.0).min(1.0) * 255.0) as u8)
+				.collect::<Vec<_>>();
+			DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, gray).unwrap())
+		}
+	}
+}
+
+/// Reinterpret raw depth readback data as floats
+pub fn depth_to_floats(data: &[u8]) -> Vec<f32>
+{
+	let mut out = vec![0.0f32; data.len() / 4];
+	unsafe {
+		ptr::copy_nonoverlapping(data.as_ptr(), out.as_mut_ptr() as *mut u8, out.len() * 4);
+	}
+	out
+}
+
+#[derive(Debug)]
+pub enum ReadbackError
+{
+	/// glClientWaitSync returned GL_WAIT_FAILED (lost context, or invalid fence):
+	/// the data will never arrive
+	WaitFailed
+}
+
+impl fmt::Display for ReadbackError
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match *self {
+			ReadbackError::WaitFailed => write!(f, "waiting on the readback fence failed")
+		}
+	}
+}
+
+/// A readback operation in flight
+/// The data is copied into a pixel buffer object, and can be
+/// retrieved without stalling once the GPU has signaled the fence.
+pub struct PendingReadback
+{
+	pbo: RawBuffer,
+	fence: GLsync,
+	width: u32,
+	height: u32,
+	format: ReadbackFormat
+}
+
+impl PendingReadback
+{
+	/// Allocate the PBO and bind it as the pixel pack buffer.
+	/// The caller then issues the glReadPixels/glGetTextureImage call with
+	/// a null pointer, followed by PendingReadback::fence.
+	pub fn begin(width: u32, height: u32, format: ReadbackFormat) -> PendingReadback
+	{
+		let byte_size = width as usize * height as usize * format.pixel_byte_size();
+		let pbo = alloc_raw_buffer(
+			byte_size,
+			BufferAccess::ReadOnly,
+			BufferBindingHint::PixelPackBuffer,
+			BufferUsage::Stream,
+			None);
+		pbo.bind_as_pixel_pack_buffer();
+		PendingReadback {
+			pbo: pbo,
+			fence: ptr::null(),
+			width: width,
+			height: height,
+			format: format
+		}
+	}
+
+	/// Insert the fence after the transfer command, and unbind the PBO
+	pub fn fence(mut self) -> PendingReadback
+	{
+		unsafe {
+			gl::MemoryBarrier(gl::CLIENT_MAPPED_BUFFER_BARRIER_BIT);
+			self.fence = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+			gl::BindBuffer(gl::PIXEL_PACK_BUFFER, 0);
+		}
+		self
+	}
+
+	pub fn byte_size(&self) -> usize
+	{
+		self.pbo.byte_size()
+	}
+
+	pub fn dimensions(&self) -> (u32, u32)
+	{
+		(self.width, self.height)
+	}
+
+	fn wait_fence(&self, timeout_ns: u64) -> Result<bool, ReadbackError>
+	{
+		let result = unsafe { gl::ClientWaitSync(self.fence, gl::SYNC_FLUSH_COMMANDS_BIT, timeout_ns) };
+		match result {
+			gl::ALREADY_SIGNALED | gl::CONDITION_SATISFIED => Ok(true),
+			gl::TIMEOUT_EXPIRED => Ok(false),
+			_ => Err(ReadbackError::WaitFailed)
+		}
+	}
+
+	/// Returns true if the data is available (does not block)
+	pub fn is_ready(&self) -> Result<bool, ReadbackError>
+	{
+		self.wait_fence(0)
+	}
+
+	/// Raw pixel data, in GL order (bottom row first)
+	/// Returns None if the transfer has not completed yet.
+	pub fn try_get_raw(&self) -> Result<Option<Vec<u8>>, ReadbackError>
+	{
+		if !try!(self.is_ready()) {
+			return Ok(None);
+		}
+		unsafe {
+			Ok(Some(self.pbo.as_buf_slice::<u8>(0, self.byte_size()).as_read_slice().to_vec()))
+		}
+	}
+
+	/// Returns None if the transfer has not completed yet.
+	pub fn try_get(&self) -> Result<Option<DynamicImage>, ReadbackError>
+	{
+		Ok(try!(self.try_get_raw()).map(|data| pixels_to_image(self.format, self.width, self.height, &data[..])))
+	}
+
+	/// Block until the data is available.
+	/// Fails if the wait fails (e.g. the context was lost).
+	pub fn wait(self) -> Result<DynamicImage, ReadbackError>
+	{
+		while !try!(self.wait_fence(1000000)) {}
+		Ok(try!(self.try_get()).unwrap())
+	}
+}
+
+impl Drop for PendingReadback
+{
+	fn drop(&mut self)
+	{
+		if !self.fence.is_null() {
+			unsafe {
+				gl::DeleteSync(self.fence);
+			}
+		}
+	}
+}
@@ -0,0 +1,85 @@
+use gl;
+use gl::types::*;
+use rendering::context::*;
+use rendering::texture::*;
+use rendering::debug::set_object_label;
+use rendering::resource_tracker::*;
+use std::cmp;
+
+/// A render target that cannot be sampled
+/// Use for multisampled color or depth targets that are only resolved
+pub struct RenderBuffer
+{
+	pub obj: GLuint,
+	width: u32,
+	height: u32,
+	format: TextureFormat,
+	samples: u32
+}
+
+impl RenderBuffer
+{
+	pub fn new(width: u32, height: u32, samples: u32, format: TextureFormat) -> RenderBuffer
+	{
+		assert!(format.capabilities().contains(FORMAT_RENDERABLE),
+			"{:?} is not a renderable format", format);
+		let mut rb : GLuint = 0;
+		let (_, int_fmt, _, _) = format.gl_description();
+		unsafe {
+			gl::GenRenderbuffers(1, &mut rb);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, rb);
+			gl::RenderbufferStorageMultisample(gl::RENDERBUFFER, samples as i32, int_fmt, width as i32, height as i32);
+			gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+		}
+		track_resource(ResourceCategory::Renderbuffer, rb,
+			format.level_byte_size(width, height) * cmp::max(samples, 1) as usize);
+		RenderBuffer {
+			obj: rb,
+			width: width,
+			height: height,
+			format: format,
+			samples: samples
+		}
+	}
+
+	pub fn view_as_render_target(&self) -> RenderTargetView
+	{
+		assert!(!self.format.is_depth(), "{:?} is not a color-renderable format", self.format);
+		RenderTargetView {
+			object: AttachmentObject::Renderbuffer(self.obj),
+			viewport: (0, 0, self.width, self.height),
+			samples: self.samples
+		}
+	}
+
+	pub fn view_as_depth_stencil_target(&self) -> DepthStencilView
+	{
+		assert!(self.format.is_depth(), "{:?} is not a depth format", self.format);
+		DepthStencilView {
+			object: AttachmentObject::Renderbuffer(self.obj),
+			viewport: (0, 0, self.width, self.height),
+			samples: self.samples
+		}
+	}
+
+	pub fn format(&self) -> TextureFormat { self.format }
+	pub fn samples(&self) -> u32 { self.samples }
+	pub fn dimensions(&self) -> (u32, u32) { (self.width, self.height) }
+
+	/// Name of the renderbuffer in GL debuggers
+	pub fn set_label(&self, label: &str)
+	{
+		set_object_label(gl::RENDERBUFFER, self.obj, label);
+	}
+}
+
+impl Drop for RenderBuffer
+{
+	fn drop(&mut self)
+	{
+		untrack_resource(ResourceCategory::Renderbuffer, self.obj);
+		unsafe {
+			gl::DeleteRenderbuffers(1, &self.obj);
+		}
+	}
+}
@@ -0,0 +1,248 @@
+use gl;
+use gl::types::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Kind of GPU object
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceCategory
+{
+	Buffer,
+	Texture,
+	Renderbuffer,
+	Sampler,
+	Program,
+	Framebuffer
+}
+
+const ALL_CATEGORIES: [ResourceCategory; 6] = [
+	ResourceCategory::Buffer,
+	ResourceCategory::Texture,
+	ResourceCategory::Renderbuffer,
+	ResourceCategory::Sampler,
+	ResourceCategory::Program,
+	ResourceCategory::Framebuffer];
+
+impl ResourceCategory
+{
+	/// Category of a GL object identifier (as used by glObjectLabel)
+	pub fn from_gl_identifier(identifier: GLenum) -> Option<ResourceCategory>
+	{
+		match identifier
+		{
+			gl::BUFFER => Some(ResourceCategory::Buffer),
+			gl::TEXTURE => Some(ResourceCategory::Texture),
+			gl::RENDERBUFFER => Some(ResourceCategory::Renderbuffer),
+			gl::SAMPLER => Some(ResourceCategory::Sampler),
+			gl::PROGRAM => Some(ResourceCategory::Program),
+			gl::FRAMEBUFFER => Some(ResourceCategory::Framebuffer),
+			_ => None
+		}
+	}
+}
+
+/// A live GPU object
+#[derive(Clone, Debug)]
+pub struct ResourceInfo
+{
+	pub category: ResourceCategory,
+	pub obj: GLuint,
+	/// Approximate size in GPU memory
+	pub byte_size: usize,
+	/// Debug name (see set_object_label)
+	pub name: Option<String>,
+	/// Allocation scope active when the object was created
+	pub creation_site: &'static str
+}
+
+/// Number of objects and memory used by a category
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CategoryStats
+{
+	pub count: usize,
+	pub byte_size: usize
+}
+
+struct ResourceRegistry
+{
+	resources: HashMap<(ResourceCategory, GLuint), ResourceInfo>,
+	/// Running totals, updated on track and untrack
+	totals: HashMap<ResourceCategory, CategoryStats>,
+	scopes: Vec<&'static str>,
+	texture_budget: Option<usize>,
+	buffer_budget: Option<usize>,
+	texture_over_budget: bool,
+	buffer_over_budget: bool
+}
+
+thread_local!(static REGISTRY: RefCell<ResourceRegistry> = RefCell::new(ResourceRegistry {
+	resources: HashMap::new(),
+	totals: HashMap::new(),
+	scopes: Vec::new(),
+	texture_budget: None,
+	buffer_budget: None,
+	texture_over_budget: false,
+	buffer_over_budget: false
+}));
+
+impl ResourceRegistry
+{
+	fn stats(&self, category: ResourceCategory) -> CategoryStats
+	{
+		self.totals.get(&category).cloned().unwrap_or(CategoryStats::default())
+	}
+
+	fn insert(&mut self, info: ResourceInfo)
+	{
+		if let Some(old) = self.resources.insert((info.category, info.obj), info.clone()) {
+			// name reused without untracking the previous object
+			self.subtract(&old);
+		}
+		let totals = self.totals.entry(info.category).or_insert(CategoryStats::default());
+		totals.count += 1;
+		totals.byte_size += info.byte_size;
+	}
+
+	fn remove(&mut self, category: ResourceCategory, obj: GLuint)
+	{
+		if let Some(old) = self.resources.remove(&(category, obj)) {
+			self.subtract(&old);
+		}
+	}
+
+	fn subtract(&mut self, info: &ResourceInfo)
+	{
+		if let Some(totals) = self.totals.get_mut(&info.category) {
+			totals.count -= 1;
+			totals.byte_size -= info.byte_size;
+		}
+	}
+
+	/// Warn once when a budget is exceeded (re-armed when usage goes back under)
+	fn check_budgets(&mut self)
+	{
+		if let Some(budget) = self.texture_budget {
+			let used = self.stats(ResourceCategory::Texture).byte_size +
+				self.stats(ResourceCategory::Renderbuffer).byte_size;
+			if used > budget && !self.texture_over_budget {
+				warn!("Texture memory over budget: {} KiB used, budget is {} KiB", used / 1024, budget / 1024);
+			}
+			self.texture_over_budget = used > budget;
+		}
+		if let Some(budget) = self.buffer_budget {
+			let used = self.stats(ResourceCategory::Buffer).byte_size;
+			if used > budget && !self.buffer_over_budget {
+				warn!("Buffer memory over budget: {} KiB used, budget is {} KiB", used / 1024, budget / 1024);
+			}
+			self.buffer_over_budget = used > budget;
+		}
+	}
+}
+
+/// Register a newly created GPU object
+pub fn track_resource(category: ResourceCategory, obj: GLuint, byte_size: usize)
+{
+	REGISTRY.with(|r| {
+		let mut r = r.borrow_mut();
+		let site = r.scopes.last().cloned().unwrap_or("<no allocation scope>");
+		r.insert(ResourceInfo {
+			category: category,
+			obj: obj,
+			byte_size: byte_size,
+			name: None,
+			creation_site: site
+		});
+		r.check_budgets();
+	});
+}
+
+/// Unregister a GPU object (on deletion)
+pub fn untrack_resource(category: ResourceCategory, obj: GLuint)
+{
+	REGISTRY.with(|r| {
+		let mut r = r.borrow_mut();
+		r.remove(category, obj);
+		r.check_budgets();
+	});
+}
+
+/// Set the debug name of a tracked object
+pub fn set_resource_name(category: ResourceCategory, obj: GLuint, name: &str)
+{
+	REGISTRY.with(|r| {
+		if let Some(info) = r.borrow_mut().resources.get_mut(&(category, obj)) {
+			info.name = Some(name.to_string());
+		}
+	});
+}
+
+/// Total count and size of live objects of a category
+pub fn resource_stats(category: ResourceCategory) -> CategoryStats
+{
+	REGISTRY.with(|r| r.borrow().stats(category))
+}
+
+/// All live objects
+pub fn live_resources() -> Vec<ResourceInfo>
+{
+	REGISTRY.with(|r| r.borrow().resources.values().cloned().collect())
+}
+
+/// Memory limits (in bytes) above which a warning is logged.
+/// The texture budget includes renderbuffers.
+pub fn set_memory_budget(texture_bytes: Option<usize>, buffer_bytes: Option<usize>)
+{
+	REGISTRY.with(|r| {
+		let mut r = r.borrow_mut();
+		r.texture_budget = texture_bytes;
+		r.buffer_budget = buffer_bytes;
+		r.texture_over_budget = false;
+		r.buffer_over_budget = false;
+		r.check_budgets();
+	});
+}
+
+/// Log the totals per category
+pub fn log_resource_stats()
+{
+	for &c in ALL_CATEGORIES.iter() {
+		let stats = resource_stats(c);
+		info!("{:?}: {} objects, {} KiB", c, stats.count, stats.byte_size / 1024);
+	}
+}
+
+/// Log all live objects as leaks. Returns the number of leaked objects.
+pub fn report_leaks() -> usize
+{
+	let mut leaks = live_resources();
+	leaks.sort_by(|a, b| (a.category as u32, a.obj).cmp(&(b.category as u32, b.obj)));
+	for l in leaks.iter() {
+		warn!("Leaked {:?} #{} ({} bytes), name: {}, created in: {}",
+			l.category, l.obj, l.byte_size,
+			l.name.as_ref().map(|n| &n[..]).unwrap_or("<unnamed>"),
+			l.creation_site);
+	}
+	leaks.len()
+}
+
+/// Names the creation site of the objects created while the guard is alive.
+/// Scopes can be nested: the innermost one is recorded.
+/// Use the allocation_scope! macro, which appends the source location to the name.
+pub struct AllocationScope;
+
+impl AllocationScope
+{
+	pub fn new(name: &'static str) -> AllocationScope
+	{
+		REGISTRY.with(|r| r.borrow_mut().scopes.push(name));
+		AllocationScope
+	}
+}
+
+impl Drop for AllocationScope
+{
+	fn drop(&mut self)
+	{
+		REGISTRY.with(|r| { r.borrow_mut().scopes.pop(); });
+	}
+}
@@ -1,7 +1,9 @@
 use gl;
 use gl::types::*;
+use rendering::state_cache::with_state_cache;
+use rendering::resource_tracker::*;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TextureAddressMode
 {
     Clamp,
@@ -21,11 +23,17 @@ impl TextureAddressMode
 	}
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TextureMinFilter
 {
     Nearest,
-    Linear
+    Linear,
+    // filter within the nearest mip level
+    NearestMipmapNearest,
+    LinearMipmapNearest,
+    // filter between the two nearest mip levels
+    NearestMipmapLinear,
+    LinearMipmapLinear
 }
 
 impl TextureMinFilter
@@ -35,12 +43,16 @@ impl TextureMinFilter
         match self
         {
             TextureMinFilter::Nearest => gl::NEAREST,
-            TextureMinFilter::Linear => gl::LINEAR
+            TextureMinFilter::Linear => gl::LINEAR,
+            TextureMinFilter::NearestMipmapNearest => gl::NEAREST_MIPMAP_NEAREST,
+            TextureMinFilter::LinearMipmapNearest => gl::LINEAR_MIPMAP_NEAREST,
+            TextureMinFilter::NearestMipmapLinear => gl::NEAREST_MIPMAP_LINEAR,
+            TextureMinFilter::LinearMipmapLinear => gl::LINEAR_MIPMAP_LINEAR
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum TextureMagFilter
 {
     Nearest,
@@ -60,7 +72,7 @@ impl TextureMagFilter
 }
 
 // 2D sampler
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Sampler2DDesc
 {
     pub addr_u: TextureAddressMode,
@@ -79,9 +91,19 @@ impl Sampler2D
 {
     pub fn bind(&self, texunit: u32)
     {
-        unsafe {
-            gl::BindSampler(self.obj, texunit);
-        }
+        with_state_cache(|c| c.bind_sampler(texunit, self.obj));
+    }
+
+    /// Unbind the sampler of a texture unit: the parameters of the texture are used
+    pub fn unbind(texunit: u32)
+    {
+        with_state_cache(|c| c.bind_sampler(texunit, 0));
+    }
+
+    /// GL sampler object name
+    pub fn gl_object(&self) -> GLuint
+    {
+        self.obj
     }
 }
 
@@ -106,13 +128,26 @@ impl Sampler2DDesc
             gl::SamplerParameteri(sampler, gl::TEXTURE_MIN_FILTER, self.min_filter.to_gl() as i32);
             gl::SamplerParameteri(sampler, gl::TEXTURE_MAG_FILTER, self.mag_filter.to_gl() as i32);
             gl::SamplerParameteri(sampler, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as i32);
-            gl::SamplerParameteri(sampler, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
-            gl::SamplerParameteri(sampler, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+            gl::SamplerParameteri(sampler, gl::TEXTURE_WRAP_S, self.addr_u.to_gl() as i32);
+            gl::SamplerParameteri(sampler, gl::TEXTURE_WRAP_T, self.addr_v.to_gl() as i32);
         }
 
+        track_resource(ResourceCategory::Sampler, sampler, 0);
         Sampler2D {
             desc: self.clone(),
             obj: sampler
         }
     }
 }
+
+impl Drop for Sampler2D
+{
+    fn drop(&mut self)
+    {
+        with_state_cache(|c| c.forget_sampler(self.obj));
+        untrack_resource(ResourceCategory::Sampler, self.obj);
+        unsafe {
+            gl::DeleteSamplers(1, &self.obj);
+        }
+    }
+}
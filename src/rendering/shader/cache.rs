@@ -1,13 +1,15 @@
 use super::parser::*;
 use super::keywords::*;
-use super::{PipelineState, PipelineStateDesc};
+use super::{PipelineState, PipelineStateDesc, ComputePipeline};
 use rendering::context::*;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::io::Write;
-use super::{Shader, UniformType, GLSLInput};
+use super::{Shader, UniformType};
 use super::gl_program::*;
 use rendering::attrib::*;
+use rendering::layout::*;
+use std::any::TypeId;
 use gl;
 use gl::types::*;
 
@@ -81,23 +83,65 @@ pub fn compile_program(shader: &Shader, config: Keywords, query: &PipelineStateD
 
     trace!("{}", &vs[..]);
 
-    GLProgram::from_source(&vs[..], &fs[..]).unwrap()
+    let program = GLProgram::from_source(&vs[..], &fs[..]).unwrap();
+    program.set_label(&format!("{} {:?}", shader.source_path.display(), keywords)[..]);
+    program
 }
 
-fn build_vao(inputs: &[GLSLInput]) -> InputLayout
+/// Compile the compute stage of a shader (the section guarded by _COMPUTE_)
+pub fn compile_compute_program(shader: &Shader, config: Keywords) -> GLProgram
 {
-    let attribs = inputs.iter().map(|i| Attribute { slot: i.slot, ty: i.attrib_type }).collect::<Vec<_>>();
-    let layout = InputLayout::new(1, &attribs[..]);
-    layout
+    let keywords = variant_bits_to_keywords(config);
+	let mut out_cs = Vec::<u8>::new();
+	writeln!(out_cs, "#version {}", shader.glsl_version).unwrap();
+	writeln!(out_cs, "#define _COMPUTE_").unwrap();
+	for kw in keywords.iter() {
+		writeln!(out_cs, "#define {}", kw).unwrap();
+	}
+    if !shader.uniforms.is_empty() {
+        writeln!(out_cs, r"layout(std140, binding = 0) uniform MaterialBlock {{").unwrap();
+        for u in shader.uniforms.iter() {
+            writeln!(out_cs, "{} {};", shader_type_to_glsl(u.ty), u.name).unwrap();
+        }
+        writeln!(out_cs, "}};").unwrap();
+    }
+    writeln!(out_cs, "{}", &shader.glsl_source[..]).unwrap();
+
+	let cs = String::from_utf8(out_cs).unwrap();
+    trace!("{}", &cs[..]);
+
+    let program = GLProgram::compute_from_source(&cs[..]).unwrap();
+    program.set_label(&format!("{} {:?}", shader.source_path.display(), keywords)[..]);
+    program
+}
+
+pub fn compile_compute_pipeline(shader: &Shader, config: Keywords) -> ComputePipeline
+{
+    ComputePipeline {
+        config: config,
+        program: compile_compute_program(shader, config)
+    }
+}
+
+/// Input layout of a vertex structure, checked against the glsl_layout declared by the shader (if any)
+fn build_vao<V: VertexFormat>(shader: &Shader) -> InputLayout
+{
+    if !shader.glsl_input_layout.is_empty() {
+        let inputs = shader.glsl_input_layout.iter().map(|i| i.attrib_type).collect::<Vec<_>>();
+        if let Err(e) = check_vertex_layout(V::name(), &V::attributes(), &inputs) {
+            panic!("{}: {}", shader.source_path.display(), e);
+        }
+    }
+    InputLayout::from_vertex_format::<V>()
 }
 
-pub fn compile_pipeline_state(shader: &Shader, config: Keywords, query: &PipelineStateDesc) -> PipelineState
+pub fn compile_pipeline_state<V: VertexFormat>(shader: &Shader, config: Keywords, query: &PipelineStateDesc) -> PipelineState
 {
     PipelineState {
         draw_state: query.default_draw_state,
         config: config,
         program: compile_program(shader, config, query),
-		layout: build_vao(&shader.glsl_input_layout[..])}
+		layout: build_vao::<V>(shader)}
 }
 
 impl ShaderCache
@@ -107,15 +151,15 @@ impl ShaderCache
         ShaderCache
     }
 
-    fn load_variant(&mut self, shader: &Shader, config: Keywords, query: &PipelineStateDesc) -> Rc<PipelineState>
+    fn load_variant<V: VertexFormat>(&self, shader: &Shader, config: Keywords, query: &PipelineStateDesc) -> Rc<PipelineState>
 	{
-		shader.cache.borrow_mut().entry(config)
-				.or_insert_with(|| Rc::new(compile_pipeline_state(shader, config, query))).clone()
+		shader.cache.borrow_mut().entry((config, TypeId::of::<V>()))
+				.or_insert_with(|| Rc::new(compile_pipeline_state::<V>(shader, config, query))).clone()
 	}
 
     // helper method
-    fn get_and_cache_variant(
-        &mut self,
+    fn get_and_cache_variant<V: VertexFormat>(
+        &self,
         shader: &Shader,
         variant: &RefCell<Option<Rc<PipelineState>>>,
         config: Keywords,
@@ -123,18 +167,19 @@ impl ShaderCache
     {
         if let Some(ref variant) = *(variant.borrow())
         {
-            if variant.config == config {
+            if variant.config == config && variant.layout.vertex_format() == Some(TypeId::of::<V>()) {
                 return variant.clone();
             }
         }
 
         // wrong config or config not loaded yet, reload and cache
-        let result = self.load_variant(shader, config, query);
+        let result = self.load_variant::<V>(shader, config, query);
         *(variant.borrow_mut()) = Some(result.clone());
         result
     }
 
-    pub fn get(&mut self, shader: &Shader, query: &PipelineStateDesc) -> Rc<PipelineState>
+    /// Pipeline state of a shader variant, with the input layout of the vertex structure V
+    pub fn get<V: VertexFormat>(&self, shader: &Shader, query: &PipelineStateDesc) -> Rc<PipelineState>
     {
         // Add pass specific keyword
         let config = query.keywords | match query.pass {
@@ -145,15 +190,15 @@ impl ShaderCache
         };
 
         if config.contains(FORWARD_BASE | POINT_LIGHT) {
-            self.get_and_cache_variant(shader, &shader.forward_pass_point_light_prog, config, query)
+            self.get_and_cache_variant::<V>(shader, &shader.forward_pass_point_light_prog, config, query)
         } else if config.contains(FORWARD_BASE | DIRECTIONAL_LIGHT) {
-            self.get_and_cache_variant(shader, &shader.forward_pass_directional_light_prog, config, query)
+            self.get_and_cache_variant::<V>(shader, &shader.forward_pass_directional_light_prog, config, query)
         } else if config.contains(FORWARD_BASE | SPOT_LIGHT) {
-            self.get_and_cache_variant(shader, &shader.forward_pass_spot_light_prog, config, query)
+            self.get_and_cache_variant::<V>(shader, &shader.forward_pass_spot_light_prog, config, query)
         } else if config.contains(SHADOW) {
-            self.get_and_cache_variant(shader, &shader.shadow_pass_prog, config, query)
+            self.get_and_cache_variant::<V>(shader, &shader.shadow_pass_prog, config, query)
         } else {
-            self.get_and_cache_variant(shader, &shader.forward_pass_unlit_prog, config, query)
+            self.get_and_cache_variant::<V>(shader, &shader.forward_pass_unlit_prog, config, query)
         }
     }
 
@@ -8,9 +8,14 @@ use nalgebra::*;
 use std::mem;
 use std::raw;
 use rendering::context::{Context};
+use rendering::debug::set_object_label;
+use rendering::state_cache::with_state_cache;
+use rendering::resource_tracker::*;
 use std::path::{Path, PathBuf};
 use std::fs::{File};
 use std::io::{BufReader, BufWriter, BufRead, Read, Write};
+use std::cell::RefCell;
+use std::collections::HashMap;
 
 
 #[derive(Copy, Clone)]
@@ -20,7 +25,8 @@ pub enum ShaderStage
 	Fragment,
 	Geometry,
 	TessControl,
-	TessEval
+	TessEval,
+	Compute
 }
 
 impl ShaderStage
@@ -33,7 +39,8 @@ impl ShaderStage
 			ShaderStage::Fragment => gl::FRAGMENT_SHADER,
 			ShaderStage::TessEval => gl::TESS_EVALUATION_SHADER,
 			ShaderStage::TessControl => gl::TESS_CONTROL_SHADER,
-			ShaderStage::Geometry => gl::GEOMETRY_SHADER
+			ShaderStage::Geometry => gl::GEOMETRY_SHADER,
+			ShaderStage::Compute => gl::COMPUTE_SHADER
 		}
 	}
 }
@@ -124,11 +131,23 @@ pub struct ShaderPipelineDesc<'a>
 
 pub struct GLProgram
 {
-	pub obj: GLuint
+	pub obj: GLuint,
+	/// binding points of named resources, queried on first use
+	/// key: (program interface, name)
+	bindings: RefCell<HashMap<(GLenum, String), Option<u32>>>
 }
 
 impl GLProgram
 {
+	fn from_obj(obj: GLuint) -> GLProgram
+	{
+		track_resource(ResourceCategory::Program, obj, 0);
+		GLProgram {
+			obj: obj,
+			bindings: RefCell::new(HashMap::new())
+		}
+	}
+
 	pub fn from_source(vs_source: &str, ps_source: &str) -> Option<GLProgram>
 	{
 		let vs = GLShader::new(vs_source, ShaderStage::Vertex).unwrap();
@@ -154,7 +173,7 @@ impl GLProgram
 				gl::DetachShader(obj, gs.obj);
 			}
 			if let Some(_) = result {
-				Some(GLProgram {obj: obj})
+				Some(GLProgram::from_obj(obj))
 			}
 			else {
 				gl::DeleteProgram(obj);
@@ -162,6 +181,90 @@ impl GLProgram
 			}
 		}
 	}
+
+	/// Create a program with a single compute stage
+	pub fn new_compute(cs: &GLShader) -> Option<GLProgram>
+	{
+		unsafe
+		{
+			let obj = gl::CreateProgram();
+			gl::AttachShader(obj, cs.obj);
+			let result = link_program(obj);
+			gl::DetachShader(obj, cs.obj);
+			if let Some(_) = result {
+				Some(GLProgram::from_obj(obj))
+			}
+			else {
+				gl::DeleteProgram(obj);
+				None
+			}
+		}
+	}
+
+	/// Name of the program in GL debuggers
+	pub fn set_label(&self, label: &str)
+	{
+		set_object_label(gl::PROGRAM, self.obj, label);
+	}
+
+	pub fn compute_from_source(cs_source: &str) -> Option<GLProgram>
+	{
+		let cs = GLShader::new(cs_source, ShaderStage::Compute).unwrap();
+		GLProgram::new_compute(&cs)
+	}
+
+	/// Binding point of a resource, or None if the program has no active resource with this name
+	fn query_binding(&self, interface: GLenum, name: &str) -> Option<u32>
+	{
+		let key = (interface, name.to_string());
+		if let Some(&binding) = self.bindings.borrow().get(&key) {
+			return binding;
+		}
+		let cname = CString::new(name).unwrap();
+		let binding = unsafe {
+			if interface == gl::UNIFORM {
+				// samplers and images: the binding is the value of the uniform
+				let loc = gl::GetProgramResourceLocation(self.obj, gl::UNIFORM, cname.as_ptr());
+				if loc == -1 {
+					None
+				} else {
+					let mut unit = 0;
+					gl::GetUniformiv(self.obj, loc, &mut unit);
+					Some(unit as u32)
+				}
+			} else {
+				let index = gl::GetProgramResourceIndex(self.obj, interface, cname.as_ptr());
+				if index == gl::INVALID_INDEX {
+					None
+				} else {
+					let prop = gl::BUFFER_BINDING;
+					let mut binding = 0;
+					gl::GetProgramResourceiv(self.obj, interface, index, 1, &prop, 1, 0 as *mut i32, &mut binding);
+					Some(binding as u32)
+				}
+			}
+		};
+		self.bindings.borrow_mut().insert(key, binding);
+		binding
+	}
+
+	/// Binding point of a uniform block
+	pub fn uniform_block_binding(&self, name: &str) -> Option<u32>
+	{
+		self.query_binding(gl::UNIFORM_BLOCK, name)
+	}
+
+	/// Binding point of a shader storage block
+	pub fn storage_block_binding(&self, name: &str) -> Option<u32>
+	{
+		self.query_binding(gl::SHADER_STORAGE_BLOCK, name)
+	}
+
+	/// Texture unit of a sampler, or image unit of an image uniform
+	pub fn sampler_binding(&self, name: &str) -> Option<u32>
+	{
+		self.query_binding(gl::UNIFORM, name)
+	}
 }
 
 pub fn load_shader_source(path: &Path) -> String
@@ -178,6 +281,8 @@ impl Drop for GLProgram
 {
 	fn drop(&mut self)
 	{
+		with_state_cache(|c| c.forget_program(self.obj));
+		untrack_resource(ResourceCategory::Program, self.obj);
 		unsafe {
 			gl::DeleteProgram(self.obj);
 		}
@@ -6,6 +6,7 @@ mod cache;
 use rendering::context::*;
 use rendering::sampler::*;
 use rendering::attrib::*;
+use rendering::layout::VertexFormat;
 pub use self::keywords::*;
 pub use self::gl_program::*;
 pub use self::cache::*;
@@ -14,9 +15,10 @@ use self::parser::*;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::collections::HashMap;
-use std::path::Path;
+use std::any::TypeId;
+use std::path::{Path, PathBuf};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum UniformType
 {
     Float,
@@ -90,6 +92,13 @@ pub struct PipelineState
     pub layout: InputLayout
 }
 
+/// Compiled compute program
+pub struct ComputePipeline
+{
+    pub config: Keywords,
+    pub program: GLProgram
+}
+
 struct GLSLInput
 {
     slot: u32,
@@ -110,6 +119,8 @@ pub struct PipelineStateDesc
 /// Parsed shader
 pub struct Shader
 {
+    /// Path of the source file (used for debug labels)
+    pub source_path: PathBuf,
     /// List of sampler parameters
     pub samplers: Vec<Sampler>,
     /// List of uniform parameters
@@ -123,6 +134,7 @@ pub struct Shader
     /// Must be reinserted with #version ___
     glsl_version: u32,
     /// Input layout (unpacked shader type, raw type in buffer, buffer slot)
+    /// Pipelines take their layout from a vertex format, this is only checked against it
     glsl_input_layout: Vec<GLSLInput>,
 
     //--------------------------------------
@@ -140,8 +152,8 @@ pub struct Shader
     deferred_pass_prog: RefCell<Option<Rc<PipelineState>>>,
     /// Cached result of shader resolution
     shadow_pass_prog: RefCell<Option<Rc<PipelineState>>>,
-    /// Cache of all loaded variants of this shader
-    cache: RefCell<HashMap<Keywords, Rc<PipelineState>>>
+    /// Cache of all loaded variants of this shader, per vertex format
+    cache: RefCell<HashMap<(Keywords, TypeId), Rc<PipelineState>>>
 }
 
 impl Shader
@@ -152,8 +164,14 @@ impl Shader
         parse_shader(source_path)
     }
 
-    pub fn make_pipeline_state(&self, desc: &PipelineStateDesc) -> PipelineState
+    /// Pipeline state with the input layout of the vertex structure V
+    pub fn make_pipeline_state<V: VertexFormat>(&self, desc: &PipelineStateDesc) -> PipelineState
+    {
+        compile_pipeline_state::<V>(self, desc.keywords, desc)
+    }
+
+    pub fn make_compute_pipeline(&self, keywords: Keywords) -> ComputePipeline
     {
-        compile_pipeline_state(self, desc.keywords, desc)
+        compile_compute_pipeline(self, keywords)
     }
 }
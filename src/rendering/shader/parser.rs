@@ -125,6 +125,7 @@ pub fn parse_shader(source_path: &Path) -> Shader
     }
 
     Shader {
+        source_path: source_path.to_path_buf(),
         samplers: samplers,
         uniforms: uniforms,
         passes: passes,
@@ -0,0 +1,224 @@
+use gl;
+use gl::types::*;
+use std::cell::RefCell;
+use rendering::context::{DrawState, CullMode};
+
+/// Number of texture units and uniform buffer slots tracked by the cache
+pub const MAX_CACHED_SLOTS: usize = 16;
+
+/// Shadow copy of the GL state, used to skip redundant state changes.
+/// None means 'unknown': the next call always goes to GL.
+///
+/// GL state is per-thread (the current context), so the cache is thread-local.
+/// Any code that changes the tracked state directly must call one of the
+/// invalidate/forget functions.
+pub struct StateCache
+{
+	draw_state: Option<DrawState>,
+	program: Option<GLuint>,
+	textures: [Option<GLuint>; MAX_CACHED_SLOTS],
+	samplers: [Option<GLuint>; MAX_CACHED_SLOTS],
+	uniform_buffers: [Option<(GLuint, usize, usize)>; MAX_CACHED_SLOTS],
+	/// Some(None): primitive restart disabled
+	primitive_restart: Option<Option<u32>>,
+	/// number of GL calls skipped since the last reset of the counters
+	pub skipped: u32,
+	/// number of GL calls issued since the last reset of the counters
+	pub issued: u32
+}
+
+thread_local!(static STATE_CACHE: RefCell<StateCache> = RefCell::new(StateCache::new()));
+
+/// Run a function with the state cache of the current thread
+pub fn with_state_cache<R, F: FnOnce(&mut StateCache) -> R>(f: F) -> R
+{
+	STATE_CACHE.with(|c| f(&mut c.borrow_mut()))
+}
+
+impl StateCache
+{
+	fn new() -> StateCache
+	{
+		StateCache {
+			draw_state: None,
+			program: None,
+			textures: [None; MAX_CACHED_SLOTS],
+			samplers: [None; MAX_CACHED_SLOTS],
+			uniform_buffers: [None; MAX_CACHED_SLOTS],
+			primitive_restart: None,
+			skipped: 0,
+			issued: 0
+		}
+	}
+
+	/// Forget everything
+	pub fn invalidate(&mut self)
+	{
+		self.draw_state = None;
+		self.program = None;
+		self.invalidate_textures();
+		self.uniform_buffers = [None; MAX_CACHED_SLOTS];
+		self.primitive_restart = None;
+	}
+
+	pub fn invalidate_textures(&mut self)
+	{
+		self.textures = [None; MAX_CACHED_SLOTS];
+	}
+
+	/// Called when a texture object is deleted
+	pub fn forget_texture(&mut self, obj: GLuint)
+	{
+		for t in self.textures.iter_mut() {
+			if *t == Some(obj) { *t = None; }
+		}
+	}
+
+	/// Called when a buffer object is deleted
+	pub fn forget_buffer(&mut self, obj: GLuint)
+	{
+		for b in self.uniform_buffers.iter_mut() {
+			if b.map(|(o, _, _)| o == obj).unwrap_or(false) { *b = None; }
+		}
+	}
+
+	/// Called when a sampler object is deleted
+	/// (GL unbinds it from all units, and may reuse its name)
+	pub fn forget_sampler(&mut self, obj: GLuint)
+	{
+		for s in self.samplers.iter_mut() {
+			if *s == Some(obj) { *s = None; }
+		}
+	}
+
+	/// Called when a program object is deleted
+	pub fn forget_program(&mut self, obj: GLuint)
+	{
+		if self.program == Some(obj) {
+			self.program = None;
+		}
+	}
+
+	pub fn reset_counters(&mut self)
+	{
+		self.skipped = 0;
+		self.issued = 0;
+	}
+
+	fn count(&mut self, redundant: bool) -> bool
+	{
+		if redundant { self.skipped += 1; } else { self.issued += 1; }
+		!redundant
+	}
+
+	pub fn set_draw_state(&mut self, ds: &DrawState)
+	{
+		let prev = self.draw_state;
+		if !self.count(prev == Some(*ds)) {
+			return;
+		}
+		unsafe {
+			if prev.map(|p| p.depth_test_enable != ds.depth_test_enable).unwrap_or(true) {
+				if ds.depth_test_enable {
+					gl::Enable(gl::DEPTH_TEST);
+				} else {
+					gl::Disable(gl::DEPTH_TEST);
+				}
+			}
+			if prev.map(|p| p.depth_write_enable != ds.depth_write_enable).unwrap_or(true) {
+				gl::DepthMask(if ds.depth_write_enable { gl::TRUE } else { gl::FALSE });
+			}
+			if prev.map(|p| p.polygon_fill_mode != ds.polygon_fill_mode).unwrap_or(true) {
+				// TODO? fill mode per face
+				gl::PolygonMode(gl::FRONT_AND_BACK, ds.polygon_fill_mode.to_gl());
+			}
+			if prev.map(|p| p.cull_mode != ds.cull_mode).unwrap_or(true) {
+				match ds.cull_mode
+				{
+					CullMode::None => gl::Disable(gl::CULL_FACE),
+					_ => {
+						gl::Enable(gl::CULL_FACE);
+						gl::CullFace(ds.cull_mode.to_gl());
+					}
+				}
+			}
+			if prev.map(|p| p.blend != ds.blend).unwrap_or(true) {
+				match ds.blend
+				{
+					Some(ref b) => {
+						gl::Enable(gl::BLEND);
+						gl::BlendEquation(b.op.to_gl());
+						gl::BlendFunc(b.src_factor.to_gl(), b.dest_factor.to_gl());
+					},
+					None => gl::Disable(gl::BLEND)
+				}
+			}
+			if prev.is_none() {
+				// TODO specify this
+				gl::Disable(gl::STENCIL_TEST);
+				gl::DepthFunc(gl::LEQUAL);
+			}
+		}
+		self.draw_state = Some(*ds);
+	}
+
+	/// Enable primitive restart with the given index, or disable it
+	pub fn set_primitive_restart(&mut self, restart_index: Option<u32>)
+	{
+		let prev = self.primitive_restart;
+		if !self.count(prev == Some(restart_index)) {
+			return;
+		}
+		unsafe {
+			match restart_index
+			{
+				Some(index) => {
+					if prev.map(|p| p.is_none()).unwrap_or(true) {
+						gl::Enable(gl::PRIMITIVE_RESTART);
+					}
+					gl::PrimitiveRestartIndex(index);
+				},
+				None => gl::Disable(gl::PRIMITIVE_RESTART)
+			}
+		}
+		self.primitive_restart = Some(restart_index);
+	}
+
+	pub fn use_program(&mut self, obj: GLuint)
+	{
+		if self.count(self.program == Some(obj)) {
+			unsafe { gl::UseProgram(obj); }
+			self.program = Some(obj);
+		}
+	}
+
+	pub fn bind_texture(&mut self, unit: u32, obj: GLuint)
+	{
+		let u = unit as usize;
+		if u < MAX_CACHED_SLOTS && !self.count(self.textures[u] == Some(obj)) {
+			return;
+		}
+		unsafe { gl::BindTextures(unit, 1, &obj); }
+		if u < MAX_CACHED_SLOTS { self.textures[u] = Some(obj); }
+	}
+
+	pub fn bind_sampler(&mut self, unit: u32, obj: GLuint)
+	{
+		let u = unit as usize;
+		if u < MAX_CACHED_SLOTS && !self.count(self.samplers[u] == Some(obj)) {
+			return;
+		}
+		unsafe { gl::BindSampler(unit, obj); }
+		if u < MAX_CACHED_SLOTS { self.samplers[u] = Some(obj); }
+	}
+
+	pub fn bind_uniform_buffer(&mut self, slot: u32, obj: GLuint, offset: usize, size: usize)
+	{
+		let s = slot as usize;
+		if s < MAX_CACHED_SLOTS && !self.count(self.uniform_buffers[s] == Some((obj, offset, size))) {
+			return;
+		}
+		unsafe { gl::BindBufferRange(gl::UNIFORM_BUFFER, slot, obj, offset as i64, size as i64); }
+		if s < MAX_CACHED_SLOTS { self.uniform_buffers[s] = Some((obj, offset, size)); }
+	}
+}
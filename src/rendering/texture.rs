@@ -1,11 +1,50 @@
 use gl;
 use gl::types::*;
 use std::mem;
+use std::cmp;
 use rendering::context::*;
+use rendering::readback::*;
+use rendering::debug::set_object_label;
+use rendering::state_cache::with_state_cache;
+use rendering::resource_tracker::*;
+use image::DynamicImage;
 
-#[derive(Copy, Clone, Debug)]
+// S3TC formats are not part of core GL, and not in the generated bindings
+const COMPRESSED_RGB_S3TC_DXT1_EXT: GLenum = 0x83F0;
+const COMPRESSED_RGBA_S3TC_DXT1_EXT: GLenum = 0x83F1;
+const COMPRESSED_RGBA_S3TC_DXT3_EXT: GLenum = 0x83F2;
+const COMPRESSED_RGBA_S3TC_DXT5_EXT: GLenum = 0x83F3;
+const COMPRESSED_SRGB_S3TC_DXT1_EXT: GLenum = 0x8C4C;
+const COMPRESSED_SRGB_ALPHA_S3TC_DXT1_EXT: GLenum = 0x8C4D;
+const COMPRESSED_SRGB_ALPHA_S3TC_DXT3_EXT: GLenum = 0x8C4E;
+const COMPRESSED_SRGB_ALPHA_S3TC_DXT5_EXT: GLenum = 0x8C4F;
+
+/// Color space of the data stored in a texture
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorSpace
+{
+	/// Color data (albedo, UI...): decoded to linear when sampled
+	Srgb,
+	/// Non-color data (normals, heights, masks...)
+	Linear
+}
+
+/// Capabilities of a texture format
+/// Only the capabilities required by the GL 4.5 core specification are reported
+bitflags! {
+	flags FormatCaps: u32 {
+		const FORMAT_RENDERABLE = 0b00000001,
+		const FORMAT_FILTERABLE = 0b00000010,
+		const FORMAT_BLENDABLE  = 0b00000100,
+		const FORMAT_DEPTH      = 0b00001000,
+		const FORMAT_COMPRESSED = 0b00010000,
+		const FORMAT_SRGB       = 0b00100000,
+		const FORMAT_INTEGER    = 0b01000000
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 // Note: same format as vertex attributes, for now
-// TODO: add compressed texture formats
 // TODO: add depth formats
 pub enum TextureFormat
 {
@@ -66,12 +105,191 @@ pub enum TextureFormat
     // Depth formats
     Depth32,
     Depth24,
-    Depth16
+    Depth16,
+    // sRGB
+    Srgb8,
+    Srgb8Alpha8,
+    // Block-compressed formats (4x4 blocks)
+    Bc1Unorm,       // DXT1, RGB
+    Bc1AlphaUnorm,  // DXT1, RGB + 1-bit alpha
+    Bc2Unorm,       // DXT3
+    Bc3Unorm,       // DXT5
+    Bc4Unorm,
+    Bc4Snorm,
+    Bc5Unorm,
+    Bc5Snorm,
+    Bc6hUfloat,
+    Bc6hSfloat,
+    Bc7Unorm,
+    Etc2Rgb8,
+    Etc2Rgb8A1,
+    Etc2Rgba8,
+    // Block-compressed sRGB
+    Bc1Srgb,
+    Bc1AlphaSrgb,
+    Bc2Srgb,
+    Bc3Srgb,
+    Bc7Srgb,
+    Etc2Srgb8,
+    Etc2Srgb8A1,
+    Etc2Srgb8Alpha8
 }
 
 impl TextureFormat
 {
-	// TODO: invalid for compressed formats
+	/// Returns true if this is a block-compressed format
+	pub fn is_compressed(self) -> bool
+	{
+		self.block_byte_size().is_some()
+	}
+
+	/// Size in bytes of a 4x4 block, for block-compressed formats
+	pub fn block_byte_size(self) -> Option<usize>
+	{
+		match self {
+			TextureFormat::Bc1Unorm => Some(8),
+			TextureFormat::Bc1AlphaUnorm => Some(8),
+			TextureFormat::Bc2Unorm => Some(16),
+			TextureFormat::Bc3Unorm => Some(16),
+			TextureFormat::Bc4Unorm => Some(8),
+			TextureFormat::Bc4Snorm => Some(8),
+			TextureFormat::Bc5Unorm => Some(16),
+			TextureFormat::Bc5Snorm => Some(16),
+			TextureFormat::Bc6hUfloat => Some(16),
+			TextureFormat::Bc6hSfloat => Some(16),
+			TextureFormat::Bc7Unorm => Some(16),
+			TextureFormat::Etc2Rgb8 => Some(8),
+			TextureFormat::Etc2Rgb8A1 => Some(8),
+			TextureFormat::Etc2Rgba8 => Some(16),
+			TextureFormat::Bc1Srgb => Some(8),
+			TextureFormat::Bc1AlphaSrgb => Some(8),
+			TextureFormat::Bc2Srgb => Some(16),
+			TextureFormat::Bc3Srgb => Some(16),
+			TextureFormat::Bc7Srgb => Some(16),
+			TextureFormat::Etc2Srgb8 => Some(8),
+			TextureFormat::Etc2Srgb8A1 => Some(8),
+			TextureFormat::Etc2Srgb8Alpha8 => Some(16),
+			_ => None
+		}
+	}
+
+	/// (linear, sRGB) pairs of formats that differ only by color space
+	const SRGB_PAIRS: &'static [(TextureFormat, TextureFormat)] = &[
+		(TextureFormat::Unorm8x3, TextureFormat::Srgb8),
+		(TextureFormat::Unorm8x4, TextureFormat::Srgb8Alpha8),
+		(TextureFormat::Bc1Unorm, TextureFormat::Bc1Srgb),
+		(TextureFormat::Bc1AlphaUnorm, TextureFormat::Bc1AlphaSrgb),
+		(TextureFormat::Bc2Unorm, TextureFormat::Bc2Srgb),
+		(TextureFormat::Bc3Unorm, TextureFormat::Bc3Srgb),
+		(TextureFormat::Bc7Unorm, TextureFormat::Bc7Srgb),
+		(TextureFormat::Etc2Rgb8, TextureFormat::Etc2Srgb8),
+		(TextureFormat::Etc2Rgb8A1, TextureFormat::Etc2Srgb8A1),
+		(TextureFormat::Etc2Rgba8, TextureFormat::Etc2Srgb8Alpha8)];
+
+	pub fn is_srgb(self) -> bool
+	{
+		TextureFormat::SRGB_PAIRS.iter().any(|&(_, srgb)| srgb == self)
+	}
+
+	/// Returns the equivalent format in the given color space
+	/// Formats without a counterpart are returned unchanged
+	pub fn with_color_space(self, color_space: ColorSpace) -> TextureFormat
+	{
+		for &(linear, srgb) in TextureFormat::SRGB_PAIRS.iter() {
+			match color_space {
+				ColorSpace::Srgb if linear == self => return srgb,
+				ColorSpace::Linear if srgb == self => return linear,
+				_ => {}
+			}
+		}
+		self
+	}
+
+	pub fn is_depth(self) -> bool
+	{
+		match self {
+			TextureFormat::Depth32 | TextureFormat::Depth24 | TextureFormat::Depth16 => true,
+			_ => false
+		}
+	}
+
+	pub fn is_integer(self) -> bool
+	{
+		match self {
+			TextureFormat::Uint32x4 | TextureFormat::Sint32x4 |
+			TextureFormat::Uint32x3 | TextureFormat::Sint32x3 |
+			TextureFormat::Uint16x4 | TextureFormat::Sint16x4 |
+			TextureFormat::Uint16x2 | TextureFormat::Sint16x2 |
+			TextureFormat::Uint8x4 | TextureFormat::Sint8x4 |
+			TextureFormat::Uint8x3 | TextureFormat::Sint8x3 |
+			TextureFormat::Uint8x2 | TextureFormat::Sint8x2 |
+			TextureFormat::Uint32 | TextureFormat::Sint32 |
+			TextureFormat::Uint16 | TextureFormat::Sint16 |
+			TextureFormat::Uint8 | TextureFormat::Sint8 => true,
+			_ => false
+		}
+	}
+
+	/// Formats that must be color-renderable in GL 4.5 core
+	/// (RGB formats and snorm formats are not required to be renderable)
+	fn is_color_renderable(self) -> bool
+	{
+		match self {
+			TextureFormat::Uint32x4 | TextureFormat::Sint32x4 | TextureFormat::Float4 |
+			TextureFormat::Float2 |
+			TextureFormat::Uint16x4 | TextureFormat::Sint16x4 | TextureFormat::Unorm16x4 | TextureFormat::Float16x4 |
+			TextureFormat::Uint16x2 | TextureFormat::Sint16x2 | TextureFormat::Unorm16x2 | TextureFormat::Float16x2 |
+			TextureFormat::Uint8x4 | TextureFormat::Sint8x4 | TextureFormat::Unorm8x4 |
+			TextureFormat::Uint8x2 | TextureFormat::Sint8x2 | TextureFormat::Unorm8x2 |
+			TextureFormat::Unorm10x3_1x2 |
+			TextureFormat::Uint32 | TextureFormat::Sint32 |
+			TextureFormat::Uint16 | TextureFormat::Sint16 | TextureFormat::Unorm16 |
+			TextureFormat::Uint8 | TextureFormat::Sint8 | TextureFormat::Unorm8 |
+			TextureFormat::Float16 | TextureFormat::Float |
+			TextureFormat::Srgb8Alpha8 => true,
+			_ => false
+		}
+	}
+
+	/// Query the capabilities of the format
+	pub fn capabilities(self) -> FormatCaps
+	{
+		let mut caps = FormatCaps::empty();
+		if self.is_compressed() {
+			caps = caps | FORMAT_COMPRESSED | FORMAT_FILTERABLE;
+		} else if self.is_depth() {
+			caps = caps | FORMAT_DEPTH | FORMAT_RENDERABLE | FORMAT_FILTERABLE;
+		} else if self.is_integer() {
+			caps = caps | FORMAT_INTEGER;
+			if self.is_color_renderable() {
+				caps = caps | FORMAT_RENDERABLE;
+			}
+		} else {
+			caps = caps | FORMAT_FILTERABLE;
+			if self.is_color_renderable() {
+				caps = caps | FORMAT_RENDERABLE | FORMAT_BLENDABLE;
+			}
+		}
+		if self.is_srgb() {
+			caps = caps | FORMAT_SRGB;
+		}
+		caps
+	}
+
+	/// Size in bytes of a mip level of the given dimensions
+	pub fn level_byte_size(self, width: u32, height: u32) -> usize
+	{
+		if let Some(block_size) = self.block_byte_size() {
+			let blocks_x = ((width + 3) / 4) as usize;
+			let blocks_y = ((height + 3) / 4) as usize;
+			blocks_x * blocks_y * block_size
+		} else {
+			(width * height) as usize * self.byte_size()
+		}
+	}
+
+	/// Size in bytes of one texel
+	/// Panics for block-compressed formats: use level_byte_size instead
 	pub fn byte_size(self) -> usize
 	{
 		match self {
@@ -132,12 +350,37 @@ impl TextureFormat
 			TextureFormat::Float => 4,
             TextureFormat::Depth32 => 4,
             TextureFormat::Depth24 => 3,
-            TextureFormat::Depth16 => 2
+            TextureFormat::Depth16 => 2,
+			TextureFormat::Srgb8 => 3,
+			TextureFormat::Srgb8Alpha8 => 4,
+			_ => panic!("byte_size: {:?} is a block-compressed format", self)
+		}
+	}
+
+	/// Format to pass to glBindImageTexture, or None if the texture cannot be
+	/// used for image load/store. Only the formats of the image format table are
+	/// accepted: sRGB8_ALPHA8 is viewed as RGBA8 (same texel size, no conversion),
+	/// 3-component, depth and compressed formats have no image counterpart.
+	pub fn image_format(self) -> Option<GLenum>
+	{
+		let (_, int_fmt, _, _) = self.gl_description();
+		match int_fmt {
+			gl::RGBA32F | gl::RGBA16F | gl::RG32F | gl::RG16F | gl::R11F_G11F_B10F |
+			gl::R32F | gl::R16F |
+			gl::RGBA32UI | gl::RGBA16UI | gl::RGB10_A2UI | gl::RGBA8UI |
+			gl::RG32UI | gl::RG16UI | gl::RG8UI | gl::R32UI | gl::R16UI | gl::R8UI |
+			gl::RGBA32I | gl::RGBA16I | gl::RGBA8I | gl::RG32I | gl::RG16I | gl::RG8I |
+			gl::R32I | gl::R16I | gl::R8I |
+			gl::RGBA16 | gl::RGB10_A2 | gl::RGBA8 | gl::RG16 | gl::RG8 | gl::R16 | gl::R8 |
+			gl::RGBA16_SNORM | gl::RGBA8_SNORM | gl::RG16_SNORM | gl::RG8_SNORM |
+			gl::R16_SNORM | gl::R8_SNORM => Some(int_fmt),
+			gl::SRGB8_ALPHA8 => Some(gl::RGBA8),
+			_ => None
 		}
 	}
 
 	// num components, internalFormat, externalFormat, externalType
-	fn gl_description(self) -> (u8, GLenum, GLenum, GLenum)
+	pub fn gl_description(self) -> (u8, GLenum, GLenum, GLenum)
 	{
 		match self {
 			// 32x4
@@ -196,7 +439,32 @@ impl TextureFormat
 			TextureFormat::Float =>      (1, gl::R32F, gl::RED, gl::HALF_FLOAT),
 			TextureFormat::Depth32 =>     (1, gl::DEPTH_COMPONENT32, gl::RED, gl::UNSIGNED_INT),
 			TextureFormat::Depth24 =>    (1, gl::DEPTH_COMPONENT24, gl::RED, gl::UNSIGNED_INT),
-			TextureFormat::Depth16 =>      (1, gl::DEPTH_COMPONENT16, gl::RED, gl::UNSIGNED_INT)
+			TextureFormat::Depth16 =>      (1, gl::DEPTH_COMPONENT16, gl::RED, gl::UNSIGNED_INT),
+			TextureFormat::Srgb8 =>        (3, gl::SRGB8, gl::RGB, gl::UNSIGNED_BYTE),
+			TextureFormat::Srgb8Alpha8 =>  (4, gl::SRGB8_ALPHA8, gl::RGBA, gl::UNSIGNED_BYTE),
+			// compressed: no external format
+			TextureFormat::Bc1Unorm =>      (3, COMPRESSED_RGB_S3TC_DXT1_EXT, 0, 0),
+			TextureFormat::Bc1AlphaUnorm => (4, COMPRESSED_RGBA_S3TC_DXT1_EXT, 0, 0),
+			TextureFormat::Bc2Unorm =>      (4, COMPRESSED_RGBA_S3TC_DXT3_EXT, 0, 0),
+			TextureFormat::Bc3Unorm =>      (4, COMPRESSED_RGBA_S3TC_DXT5_EXT, 0, 0),
+			TextureFormat::Bc4Unorm =>      (1, gl::COMPRESSED_RED_RGTC1, 0, 0),
+			TextureFormat::Bc4Snorm =>      (1, gl::COMPRESSED_SIGNED_RED_RGTC1, 0, 0),
+			TextureFormat::Bc5Unorm =>      (2, gl::COMPRESSED_RG_RGTC2, 0, 0),
+			TextureFormat::Bc5Snorm =>      (2, gl::COMPRESSED_SIGNED_RG_RGTC2, 0, 0),
+			TextureFormat::Bc6hUfloat =>    (3, gl::COMPRESSED_RGB_BPTC_UNSIGNED_FLOAT, 0, 0),
+			TextureFormat::Bc6hSfloat =>    (3, gl::COMPRESSED_RGB_BPTC_SIGNED_FLOAT, 0, 0),
+			TextureFormat::Bc7Unorm =>      (4, gl::COMPRESSED_RGBA_BPTC_UNORM, 0, 0),
+			TextureFormat::Etc2Rgb8 =>      (3, gl::COMPRESSED_RGB8_ETC2, 0, 0),
+			TextureFormat::Etc2Rgb8A1 =>    (4, gl::COMPRESSED_RGB8_PUNCHTHROUGH_ALPHA1_ETC2, 0, 0),
+			TextureFormat::Etc2Rgba8 =>     (4, gl::COMPRESSED_RGBA8_ETC2_EAC, 0, 0),
+			TextureFormat::Bc1Srgb =>       (3, COMPRESSED_SRGB_S3TC_DXT1_EXT, 0, 0),
+			TextureFormat::Bc1AlphaSrgb =>  (4, COMPRESSED_SRGB_ALPHA_S3TC_DXT1_EXT, 0, 0),
+			TextureFormat::Bc2Srgb =>       (4, COMPRESSED_SRGB_ALPHA_S3TC_DXT3_EXT, 0, 0),
+			TextureFormat::Bc3Srgb =>       (4, COMPRESSED_SRGB_ALPHA_S3TC_DXT5_EXT, 0, 0),
+			TextureFormat::Bc7Srgb =>       (4, gl::COMPRESSED_SRGB_ALPHA_BPTC_UNORM, 0, 0),
+			TextureFormat::Etc2Srgb8 =>     (3, gl::COMPRESSED_SRGB8_ETC2, 0, 0),
+			TextureFormat::Etc2Srgb8A1 =>   (4, gl::COMPRESSED_SRGB8_PUNCHTHROUGH_ALPHA1_ETC2, 0, 0),
+			TextureFormat::Etc2Srgb8Alpha8 => (4, gl::COMPRESSED_SRGB8_ALPHA8_ETC2_EAC, 0, 0)
 		}
 	}
 }
@@ -206,7 +474,9 @@ pub struct Texture2D
 	pub obj: GLuint,
 	width: u32,
 	height: u32,
-	format: TextureFormat
+	format: TextureFormat,
+	// 1 if not multisampled
+	samples: u32
 }
 
 
@@ -214,6 +484,8 @@ impl Drop for Texture2D
 {
 	fn drop(&mut self)
 	{
+		with_state_cache(|c| c.forget_texture(self.obj));
+		untrack_resource(ResourceCategory::Texture, self.obj);
 		unsafe {
 			gl::DeleteTextures(1, &self.obj);
 		}
@@ -236,9 +508,38 @@ unsafe fn create_texture_2d<T>(
 		gl::TexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, width as i32, height as i32, ext_fmt, ty, data.as_ptr() as *const GLvoid);
 	}
 	gl::BindTexture(gl::TEXTURE_2D, 0);
+	with_state_cache(|c| c.invalidate_textures());
+	return tex;
+}
+
+unsafe fn create_compressed_texture_2d(
+		width: u32,
+		height: u32,
+		format: TextureFormat,
+		mip_levels: &[&[u8]]) -> GLuint
+{
+	let mut tex : GLuint = 0;
+	let (_, int_fmt, _, _) = format.gl_description();
+	gl::GenTextures(1, &mut tex);
+	gl::BindTexture(gl::TEXTURE_2D, tex);
+	gl::TexStorage2D(gl::TEXTURE_2D, mip_levels.len() as i32, int_fmt, width as i32, height as i32);
+	for (level, data) in mip_levels.iter().enumerate() {
+		let w = cmp::max(1, width >> level);
+		let h = cmp::max(1, height >> level);
+		gl::CompressedTexSubImage2D(gl::TEXTURE_2D, level as i32, 0, 0, w as i32, h as i32,
+			int_fmt, data.len() as i32, data.as_ptr() as *const GLvoid);
+	}
+	gl::BindTexture(gl::TEXTURE_2D, 0);
+	with_state_cache(|c| c.invalidate_textures());
 	return tex;
 }
 
+/// Number of levels of a full mip chain, down to 1x1
+pub fn max_mip_levels(width: u32, height: u32) -> u32
+{
+	32 - cmp::max(1, cmp::max(width, height)).leading_zeros()
+}
+
 impl Texture2D
 {
     pub fn new(
@@ -249,12 +550,15 @@ impl Texture2D
     {
 		unsafe
 		{
-			Texture2D {
+			let tex = Texture2D {
 				obj: create_texture_2d::<u32>(width, height, num_mip_levels, format, None),
 				width: width,
 				height: height,
-				format: format
-			}
+				format: format,
+				samples: 1
+			};
+			tex.track(num_mip_levels as u32);
+			tex
 		}
     }
 
@@ -265,7 +569,7 @@ impl Texture2D
 		format: TextureFormat,
 		initial_data: Option<&[T]>) -> Texture2D
 	{
-
+		assert!(!format.is_compressed(), "use with_compressed_mips for compressed formats");
 		let byte_size = mem::size_of::<T>() * (width * height * format.gl_description().0 as u32) as usize;
 		trace!("{} x {}, {} mip levels, format: {:?}, byte_size: {}, initial_data byte size: {}",
 			width, height, num_mip_levels, format, byte_size, if let Some(data) = initial_data { data.len() * mem::size_of::<T>() } else {0});
@@ -276,20 +580,159 @@ impl Texture2D
 
 		unsafe
 		{
-			Texture2D {
+			let tex = Texture2D {
 				obj: create_texture_2d(width, height, num_mip_levels, format, initial_data),
 				width: width,
 				height: height,
-				format: format
-			}
+				format: format,
+				samples: 1
+			};
+			tex.track(num_mip_levels as u32);
+			tex
 		}
 	}
 
-	pub fn bind(&self, unit: u32)
+	/// Create a texture with a full mip chain: the base level is initialized
+	/// with the data, and the other levels are generated from it
+	pub fn with_pixels_and_mipmaps<T>(
+		width: u32,
+		height: u32,
+		format: TextureFormat,
+		data: &[T]) -> Texture2D
+	{
+		let tex = Texture2D::with_pixels(width, height, max_mip_levels(width, height) as u8, format, Some(data));
+		tex.generate_mipmaps();
+		tex
+	}
+
+	/// Regenerate all mip levels from the base level
+	pub fn generate_mipmaps(&self)
+	{
+		assert!(self.samples == 1, "multisampled textures have no mip levels");
+		unsafe {
+			gl::BindTexture(gl::TEXTURE_2D, self.obj);
+			gl::GenerateMipmap(gl::TEXTURE_2D);
+			gl::BindTexture(gl::TEXTURE_2D, 0);
+		}
+		with_state_cache(|c| c.invalidate_textures());
+	}
+
+	/// Create a multisampled texture, to be used as a render target
+	/// (multisampled textures have no mip levels and cannot be uploaded to)
+	pub fn new_multisample(
+		width: u32,
+		height: u32,
+		samples: u32,
+		format: TextureFormat) -> Texture2D
+	{
+		assert!(format.capabilities().contains(FORMAT_RENDERABLE),
+			"{:?} cannot be used for a multisampled texture", format);
+		let mut tex : GLuint = 0;
+		let (_, int_fmt, _, _) = format.gl_description();
+		unsafe {
+			gl::GenTextures(1, &mut tex);
+			gl::BindTexture(gl::TEXTURE_2D_MULTISAMPLE, tex);
+			gl::TexStorage2DMultisample(gl::TEXTURE_2D_MULTISAMPLE, samples as i32, int_fmt,
+				width as i32, height as i32, gl::TRUE);
+			gl::BindTexture(gl::TEXTURE_2D_MULTISAMPLE, 0);
+		}
+		with_state_cache(|c| c.invalidate_textures());
+		let tex = Texture2D {
+			obj: tex,
+			width: width,
+			height: height,
+			format: format,
+			samples: samples
+		};
+		tex.track(1);
+		tex
+	}
+
+	/// Create a texture from block-compressed data
+	/// mip_levels contains the data of each mip level, starting from the base level
+	pub fn with_compressed_mips(
+		width: u32,
+		height: u32,
+		format: TextureFormat,
+		mip_levels: &[&[u8]]) -> Texture2D
+	{
+		assert!(format.is_compressed(), "{:?} is not a compressed format", format);
+		assert!(!mip_levels.is_empty());
+		for (level, data) in mip_levels.iter().enumerate() {
+			let w = cmp::max(1, width >> level);
+			let h = cmp::max(1, height >> level);
+			assert!(data.len() == format.level_byte_size(w, h), "mip level {}: unexpected data size", level);
+		}
+
+		unsafe
+		{
+			let tex = Texture2D {
+				obj: create_compressed_texture_2d(width, height, format, mip_levels),
+				width: width,
+				height: height,
+				format: format,
+				samples: 1
+			};
+			tex.track(mip_levels.len() as u32);
+			tex
+		}
+	}
+
+	fn level_dimensions(&self, level: u32) -> (u32, u32)
+	{
+		(cmp::max(1, self.width >> level), cmp::max(1, self.height >> level))
+	}
+
+	fn readback_format(&self) -> ReadbackFormat
+	{
+		assert!(self.samples == 1, "Cannot read back a multisampled texture: resolve it first");
+		ReadbackFormat::from_texture_format(self.format).expect(
+			&format!("Readback not supported for texture format {:?}", self.format))
+	}
+
+	/// Read back the contents of a mip level (blocking)
+	/// Rows are returned in GL order (bottom row first)
+	pub fn read_pixels_raw(&self, level: u32) -> Vec<u8>
+	{
+		let (w, h) = self.level_dimensions(level);
+		let fmt = self.readback_format();
+		let (ext_fmt, ty) = fmt.gl_format();
+		let byte_size = w as usize * h as usize * fmt.pixel_byte_size();
+		let mut data = vec![0u8; byte_size];
+		unsafe {
+			gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+			gl::GetTextureImage(self.obj, level as i32, ext_fmt, ty,
+				byte_size as i32, data.as_mut_ptr() as *mut GLvoid);
+		}
+		data
+	}
+
+	/// Read back the contents of a mip level into an image (blocking)
+	/// Depth textures are converted to grayscale
+	pub fn read_pixels(&self, level: u32) -> DynamicImage
+	{
+		let (w, h) = self.level_dimensions(level);
+		pixels_to_image(self.readback_format(), w, h, &self.read_pixels_raw(level)[..])
+	}
+
+	/// Start an asynchronous readback of a mip level
+	pub fn read_pixels_async(&self, level: u32) -> PendingReadback
 	{
+		let (w, h) = self.level_dimensions(level);
+		let fmt = self.readback_format();
+		let (ext_fmt, ty) = fmt.gl_format();
+		let readback = PendingReadback::begin(w, h, fmt);
 		unsafe {
-			gl::BindTextures(unit, 1, &self.obj);
+			gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+			gl::GetTextureImage(self.obj, level as i32, ext_fmt, ty,
+				readback.byte_size() as i32, 0 as *mut GLvoid);
 		}
+		readback.fence()
+	}
+
+	pub fn bind(&self, unit: u32)
+	{
+		with_state_cache(|c| c.bind_texture(unit, self.obj));
 	}
 
     pub fn view(&self) -> TextureView
@@ -297,19 +740,49 @@ impl Texture2D
         TextureView { texture: self.obj }
     }
 
+    /// Compressed formats cannot be attached. Other formats not required to be
+    /// color-renderable (e.g. RGB8) are left to the framebuffer completeness check.
     pub fn view_as_render_target(&self) -> RenderTargetView
     {
-        // TODO check that the texture can effectively be used as a render target
-        RenderTargetView { texture: self.obj, viewport: (0, 0, self.width, self.height) }
+        assert!(!self.format.is_compressed(),
+            "{:?} is a compressed format and cannot be a render target", self.format);
+        RenderTargetView {
+            object: AttachmentObject::Texture(self.obj),
+            viewport: (0, 0, self.width, self.height),
+            samples: self.samples
+        }
     }
 
     pub fn view_as_depth_stencil_target(&self) -> DepthStencilView
     {
-        // TODO check that the texture can effectively be used as a depth stencil target
-        DepthStencilView { texture: self.obj, viewport: (0, 0, self.width, self.height) }
+        assert!(self.format.capabilities().contains(FORMAT_DEPTH),
+            "{:?} is not a depth format", self.format);
+        DepthStencilView {
+            object: AttachmentObject::Texture(self.obj),
+            viewport: (0, 0, self.width, self.height),
+            samples: self.samples
+        }
     }
 
+	pub fn format(&self) -> TextureFormat { self.format }
+	pub fn samples(&self) -> u32 { self.samples }
 	pub fn width(&self) -> u32 { self.width }
 	pub fn height(&self) -> u32 { self.height }
 	pub fn dimensions(&self) -> (u32,  u32) { (self.width, self.height) }
+
+	/// Register the texture in the resource tracker
+	fn track(&self, num_levels: u32)
+	{
+		let byte_size = (0..num_levels).map(|l| {
+			let (w, h) = self.level_dimensions(l);
+			self.format.level_byte_size(w, h)
+		}).fold(0, |a, b| a + b) * self.samples as usize;
+		track_resource(ResourceCategory::Texture, self.obj, byte_size);
+	}
+
+	/// Name of the texture in GL debuggers
+	pub fn set_label(&self, label: &str)
+	{
+		set_object_label(gl::TEXTURE, self.obj, label);
+	}
 }
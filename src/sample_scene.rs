@@ -30,9 +30,12 @@ use asset_loader::*;
 use std::collections::HashMap;
 use std::rc::Rc;
 use graphics::*;
+use mesh_data::MeshLoadOptions;
+use primitives;
 use player::*;
 
-use std::io::{BufRead};
+use std::io::{BufRead, Write};
+use std::fs::File;
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -42,79 +45,34 @@ struct ShaderParams
 }
 
 
-fn make_circle(radius: f32, divisions: u16) ->
-	(Vec<MeshVertex>, Vec<u16>)
+/// --headless <output.png>: render the sample scene offscreen (EGL), save it and exit
+#[cfg(feature = "headless")]
+fn run_headless() -> bool
 {
-	assert!(divisions >= 2);
-	let mut result = Vec::with_capacity((1 + divisions) as usize);
-	let mut result_indices = Vec::with_capacity((divisions * 3) as usize);
-	result.push(MeshVertex {
-		pos: [0.0; 3],
-		norm: [0.0; 3],
-		tg: [0.0; 3],
-		tex: [0.0; 2]  });
-	for i in 0..divisions
-	{
-		let th = ((i as f32) / (divisions as f32)) * 2.0 * std::f32::consts::PI;
-		result.push(MeshVertex {
-			pos: [radius*f32::cos(th), radius*f32::sin(th), 0.0f32],
-			norm: [0.0; 3],
-			tg: [0.0; 3],
-			tex: [0.0; 2]  });
-		result_indices.push(0u16);
-		result_indices.push(i+1);
-		result_indices.push(if i == divisions-1 { 1 } else { i+2 });
+	use headless::{HeadlessSettings, render_scene_to_image};
+	let args = std::env::args().collect::<Vec<_>>();
+	let output = match args.iter().position(|a| a == "--headless") {
+		Some(i) => args.get(i + 1).cloned().unwrap_or("headless.png".to_string()),
+		None => return false
+	};
+	let img = render_scene_to_image(
+		HeadlessSettings::new((1024, 768)),
+		Path::new("assets"),
+		Path::new("assets/scenes/scene.json")).unwrap_or_else(|e| panic!("Headless rendering failed: {}", e));
+	if let Err(e) = img.save(&output) {
+		error!("Could not save {}: {:?}", output, e);
 	}
-	(result, result_indices)
+	true
 }
 
+#[cfg(not(feature = "headless"))]
+fn run_headless() -> bool
+{
+	false
+}
 
 pub fn sample_scene()
 {
-	// Cube
-
-    let cube_vertex_data = [
-        //top (0, 0, 1)
-        MeshVertex::new([-1.0, -1.0,  1.0], [0.0, 0.0]),
-        MeshVertex::new([ 1.0, -1.0,  1.0], [1.0, 0.0]),
-        MeshVertex::new([ 1.0,  1.0,  1.0], [1.0, 1.0]),
-        MeshVertex::new([-1.0,  1.0,  1.0], [0.0, 1.0]),
-        //bottom (0.0, 0.0, -1.0)
-        MeshVertex::new([ 1.0,  1.0, -1.0], [0.0, 0.0]),
-        MeshVertex::new([-1.0,  1.0, -1.0], [1.0, 0.0]),
-        MeshVertex::new([-1.0, -1.0, -1.0], [1.0, 1.0]),
-        MeshVertex::new([ 1.0, -1.0, -1.0], [0.0, 1.0]),
-        //right (1.0, 0.0, 0.0)
-        MeshVertex::new([ 1.0, -1.0, -1.0], [0.0, 0.0]),
-        MeshVertex::new([ 1.0,  1.0, -1.0], [1.0, 0.0]),
-        MeshVertex::new([ 1.0,  1.0,  1.0], [1.0, 1.0]),
-        MeshVertex::new([ 1.0, -1.0,  1.0], [0.0, 1.0]),
-        //left (-1.0, 0.0, 0.0)
-        MeshVertex::new([-1.0,  1.0,  1.0], [0.0, 0.0]),
-        MeshVertex::new([-1.0, -1.0,  1.0], [1.0, 0.0]),
-        MeshVertex::new([-1.0, -1.0, -1.0], [1.0, 1.0]),
-        MeshVertex::new([-1.0,  1.0, -1.0], [0.0, 1.0]),
-        //front (0.0, 1.0, 0.0)
-        MeshVertex::new([-1.0,  1.0, -1.0], [0.0, 0.0]),
-        MeshVertex::new([ 1.0,  1.0, -1.0], [1.0, 0.0]),
-        MeshVertex::new([ 1.0,  1.0,  1.0], [1.0, 1.0]),
-        MeshVertex::new([-1.0,  1.0,  1.0], [0.0, 1.0]),
-        //back (0.0, -1.0, 0.0)
-        MeshVertex::new([ 1.0, -1.0,  1.0], [0.0, 0.0]),
-        MeshVertex::new([-1.0, -1.0,  1.0], [1.0, 0.0]),
-        MeshVertex::new([-1.0, -1.0, -1.0], [1.0, 1.0]),
-        MeshVertex::new([ 1.0, -1.0, -1.0], [0.0, 1.0]),
-    ];
-
-    let cube_index_data = [
-         0,  1,  2,  2,  3,  0, // top
-         4,  6,  5,  6,  4,  7, // bottom
-         8,  9, 10, 10, 11,  8, // right
-        12, 14, 13, 14, 12, 16, // left
-        16, 18, 17, 18, 16, 19, // front
-        20, 21, 22, 22, 23, 20, // back
-    ];
-
 	//-------------------------------
 	// LOGGING
 	let logger_config = fern::DispatchConfig {
@@ -128,11 +86,16 @@ pub fn sample_scene()
 	    panic!("Failed to initialize global logger: {}", e);
 	}
 
+	if run_headless() {
+		return;
+	}
+
 	//-------------------------------
 	// GLFW
     let mut glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
     glfw.window_hint(glfw::WindowHint::OpenGlDebugContext(true));
 	glfw.window_hint(glfw::WindowHint::Samples(4));
+	glfw.window_hint(glfw::WindowHint::SRgbCapable(true));
 
 	let mut win = WindowSettings::new("ALMAGEST", (1024, 768)).build(&glfw).expect("Failed to create GLFW window.");
 	let mut input = Input::new();
@@ -151,24 +114,20 @@ pub fn sample_scene()
 		//tex.bind(0);
 	}
 
-	let mut ctx = rd::Context::new();
+	let mut ctx = rd::Context::new(win.framebuffer_dimensions());
+	let setup_alloc = allocation_scope!("sample_scene setup");
+	// warn when GPU memory usage gets suspicious
+	ctx.set_memory_budget(Some(512 << 20), Some(256 << 20));
 
-	let (mesh2_vertex, mesh2_indices) = make_circle(1.0f32, 400);
-	let mesh2 = Mesh::new(
-		&ctx, rd::PrimitiveType::Triangle,
-		&mesh2_vertex,
-		Some(&mesh2_indices));
+	let cube_mesh = Mesh::from_data(&ctx, &primitives::cube([2.0, 2.0, 2.0], 1));
 
-	let cube_mesh = Mesh::new(
-		&ctx,
-		rd::PrimitiveType::Triangle,
-		&cube_vertex_data,
-		Some(&cube_index_data));
-
-	let banana_mesh = Mesh::load_from_obj(
+	let banana_mesh = match Mesh::load_from_obj(
 		&ctx,
 		Path::new("assets/models/banana.obj"),
-		);
+		&MeshLoadOptions::default()) {
+		Ok(mesh) => mesh,
+		Err(e) => panic!("Could not load banana.obj: {}", e)
+	};
 
 	let mut camera_controller = TrackballCameraSettings::default().build();
 	let graphics = Graphics::new(&ctx);
@@ -179,6 +138,9 @@ pub fn sample_scene()
 		&Path::new("assets"),
 		&Path::new("assets/scenes/scene.json"));
 
+	drop(setup_alloc);
+
+	let mut take_screenshot = false;
 	let mut tcur = 0.0f64;
 	let mut tlast =  time::precise_time_s();
 	win.event_loop(&mut glfw, |event, window| {
@@ -195,8 +157,28 @@ pub fn sample_scene()
 		let cam = camera_controller.get_camera(window);
 
 		match event {
+			Event::KeyDown(Key::F12) => {
+				take_screenshot = true;
+			},
+			Event::KeyDown(Key::F10) => {
+				// export the GPU/CPU timings of the last complete frame
+				if let Some(timings) = ctx.profiler().borrow().last_frame_timings() {
+					timings.dump();
+					let mut f = File::create("profile.json").unwrap();
+					f.write_all(timings.to_chrome_trace().as_bytes()).unwrap();
+				}
+			},
 			Event::Render(dt) => {
-				scene.render(&graphics, &terrain_renderer, window, &ctx, &cam);
+				let _alloc = allocation_scope!("sample_scene render");
+				scene.render(&graphics, &terrain_renderer, &rd::RenderTarget::Screen, &ctx, &cam);
+				if take_screenshot {
+					take_screenshot = false;
+					let img = ctx.create_screen_frame(window).read_color(0);
+					if let Err(e) = img.save("screenshot.png") {
+						error!("Could not save screenshot: {:?}", e);
+					}
+				}
+				ctx.end_frame();
 			},
 
 			_ => {}
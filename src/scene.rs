@@ -11,7 +11,14 @@ use scene_data::*;
 use camera::*;
 use std::collections::{HashMap};
 use asset_loader::*;
+use texture_loader::load_texture2d;
+use gltf_loader::load_gltf;
+use mesh_data::{MeshLoadOptions, LodOptions};
+use mesh_optimizer::MeshOptimization;
+use bounds::{Bounds, BoundingBox};
+use primitives;
 use std::rc::Rc;
+use std::cell::RefCell;
 use terrain::{Terrain, TerrainRenderer};
 use shadow_pass::*;
 use graphics::*;
@@ -84,7 +91,10 @@ pub struct JsonSceneFile
 {
 	light_sources: Vec<JsonSceneLightSource>,
 	entities: Vec<JsonSceneEntity>,
-	terrain: Option<JsonSceneTerrain>
+	terrain: Option<JsonSceneTerrain>,
+	/// Run the mesh optimizer on the loaded OBJ files
+	#[serde(default)]
+	optimize_meshes: bool
 }
 // end JSON repr
 //-------------------------------------------
@@ -111,7 +121,38 @@ pub struct Entity
 {
 	mesh: Rc<Mesh>,	// mesh index
 	material: Rc<Material>,	// material index
-	transform: MyTransform
+	/// material of each part of the mesh (from the model file, or the entity material)
+	part_materials: Vec<Rc<Material>>,
+	/// model matrix
+	transform: Mat4<f32>,
+	/// level of detail selected for the current camera
+	lod: usize
+}
+
+impl Entity
+{
+	/// World space bounds of the entity
+	pub fn world_bounds(&self) -> Bounds
+	{
+		self.mesh.bounds.transform(&self.transform)
+	}
+
+	/// World space bounds of a part of the mesh of the entity
+	pub fn part_world_bounds(&self, part_index: usize) -> Bounds
+	{
+		self.mesh.part_bounds[part_index].transform(&self.transform)
+	}
+
+	/// Projected size of the bounding sphere: its diameter over the height of the viewport
+	pub fn screen_size(&self, cam: &Camera) -> f32
+	{
+		let sphere = self.world_bounds().sphere;
+		let view_proj = cam.proj_matrix * cam.view_matrix;
+		let c = sphere.center;
+		// clip space w: the distance along the view axis for perspective projections
+		let w = view_proj[(3, 0)] * c.x + view_proj[(3, 1)] * c.y + view_proj[(3, 2)] * c.z + view_proj[(3, 3)];
+		cam.proj_matrix[(1, 1)] * sphere.radius / w.abs().max(1e-6)
+	}
 }
 
 pub enum LightSource
@@ -148,9 +189,13 @@ pub struct Scene
 {
 	entities: Vec<Entity>,
 	light_sources: Vec<LightSource>,
+	/// Cameras defined in the scene file
+	cameras: Vec<SceneCamera>,
 	terrain: Option<Terrain>,
-	// Shadow map render target
-	shadow_map: Texture2D,
+	// Transient render targets (shadow map)
+	texture_pool: RefCell<TexturePool>,
+	// save the shadow map of the next frame (F11)
+	dump_shadow_map: bool,
 	//
 	shader_cache: ShaderCache,
 	depth_only_pso: PipelineState,
@@ -158,7 +203,9 @@ pub struct Scene
 	player_cam: PlayerCamera,
 	sky: Sky,
 	mode: DisplayMode,
-	mode_index: usize
+	mode_index: usize,
+	/// Relative margin around LOD thresholds (see Mesh::select_lod)
+	lod_hysteresis: f32
 }
 
 fn make_scale_matrix(scale: f32) -> Mat4<f32>
@@ -172,12 +219,27 @@ fn make_scale_matrix(scale: f32) -> Mat4<f32>
 }
 
 
+/// Load a texture referenced by a model file through a texture cache.
+/// The same image can be loaded in both color spaces, so the color space is part of the key.
+/// Returns None, with a warning, if the texture cannot be loaded.
+fn load_model_texture(textures: &AssetCache<Texture2D>, source: &TextureSource, color_space: ColorSpace) -> Option<Rc<Texture2D>>
+{
+	let key = format!("{} ({:?})", source.key(), color_space);
+	match textures.try_load_with(&key, |_| source.load(color_space)) {
+		Ok(texture) => Some(texture),
+		Err(e) => {
+			warn!("Could not load texture {}: {:?}", source.key(), e);
+			None
+		}
+	}
+}
+
 /// Make a PSO directly from a shader file
 /// Using the default draw states
-pub fn load_pipeline_state(path: &Path, kw: Keywords) -> PipelineState
+pub fn load_pipeline_state<V: VertexFormat>(path: &Path, kw: Keywords) -> PipelineState
 {
 	let shader = Shader::load(path);
-	shader.make_pipeline_state(&PipelineStateDesc {
+	shader.make_pipeline_state::<V>(&PipelineStateDesc {
 		keywords: kw,
         pass: StdPass::ForwardBase,
         default_draw_state: DrawState::default(),
@@ -186,47 +248,38 @@ pub fn load_pipeline_state(path: &Path, kw: Keywords) -> PipelineState
 	})
 }
 
-#[derive(Copy,Clone)]
-#[repr(C)]
-struct SkyParams
-{
-	model_matrix: Mat4<f32>,
-	rayleigh_coefficient: f32,
-	mie_coefficient: f32,
-	mie_directional_g: f32,
-	turbidity: f32
+uniform_block! {
+	struct SkyParams
+	{
+		model_matrix: Mat4<f32>,
+		rayleigh_coefficient: f32,
+		mie_coefficient: f32,
+		mie_directional_g: f32,
+		turbidity: f32
+	}
 }
 
 impl Scene
 {
-	/// Load a scene from a JSON file
+	/// Load a scene from a JSON file, or from a glTF file (.gltf or .glb)
 	/// root: asset folder root
 	/// scene: subpath of scene in asset root
 	pub fn load(context: &Context, asset_root: &Path, scene: &Path) -> Scene
 	{
 		use std::io::Read;
+		let ext = scene.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+		match ext.as_ref().map(|e| &e[..]) {
+			Some("gltf") | Some("glb") => return Scene::load_gltf(context, asset_root, scene),
+			_ => {}
+		}
+
+		let _alloc = allocation_scope!("Scene::load");
 		let f = File::open(scene).unwrap();
 		let reader = BufReader::new(&f);
 		// load JSON repr
 		let scene_json : JsonSceneFile = serde_json::de::from_reader(reader).unwrap();
 		//trace!("{:?}", scene_json);
 
-		let sky_dome = Mesh::load_from_obj(context, &asset_root.join("models/dome.obj"));
-		let nightsky = {
-			let img = image::open(&asset_root.join("img/skymap.tif")).unwrap();
-			let (dimx, dimy) = img.dimensions();
-			let img2 = img.as_rgb8().unwrap();
-			Texture2D::with_pixels(dimx, dimy, 1, TextureFormat::Unorm8x3, Some(img2))
-		};
-		let sky_shader = Shader::load(&asset_root.join("shaders/sky.glsl"));
-		let sky_pso = sky_shader.make_pipeline_state(&PipelineStateDesc {
-            keywords: Keywords::empty(),
-            pass: StdPass::ForwardBase,
-            default_draw_state: DrawState::default(),
-            sampler_block_base: 0,
-            uniform_block_base: 0
-        });
-
 		// load all meshes and materials
 		let mut entities = Vec::<Entity>::new();
 		let mut light_sources = Vec::<LightSource>::new();
@@ -236,6 +289,11 @@ impl Scene
 		let textures = AssetCache::<Texture2D>::new();
 		let shaders = AssetCache::<Shader>::new();
 
+		let mut obj_options = MeshLoadOptions::default().with_lods(LodOptions::default());
+		if scene_json.optimize_meshes {
+			obj_options = obj_options.with_optimization(MeshOptimization::default());
+		}
+
 		for scene_ent in scene_json.entities.iter()
 		{
 			//info!("*** Loading entity {:?} ***", scene_ent);
@@ -248,11 +306,8 @@ impl Scene
 
 			let texture = textures.load_with(texture_path,
 					&|path| {
-						let img = image::open(&asset_root.join(path)).unwrap();
-						let (dimx, dimy) = img.dimensions();
-						// TODO correctly handle different formats
-						let img2 = img.as_rgb8().unwrap();
-						Texture2D::with_pixels(dimx, dimy, 1, TextureFormat::Unorm8x3, Some(img2))
+						// material textures hold albedo: color data
+						load_texture2d(&asset_root.join(path), ColorSpace::Srgb).unwrap()
 					});
 
 			let shader_name = if let Some(ref s) = scene_ent.material.shader {
@@ -267,19 +322,45 @@ impl Scene
 
 
 			let material = Rc::new(Material::new_with_shader(
-				shader,
-				texture));
+				shader.clone(),
+				texture.clone()));
+
+			let mesh = meshes.load_with(&scene_ent.mesh, &|path| {
+					let full_path = asset_root.join(path);
+					// procedural meshes, binary mesh files, or OBJ files through a binary cache
+					let mesh = if path.starts_with("builtin:") {
+						primitives::from_spec(path).map(|data| Mesh::from_data(context, &data)).map_err(|e| e.to_string())
+					} else if full_path.extension().map(|e| e == "mesh").unwrap_or(false) {
+						Mesh::load_from_file(context, &full_path).map_err(|e| e.to_string())
+					} else {
+						Mesh::load_from_obj_cached(context, &full_path, &obj_options).map_err(|e| e.to_string())
+					};
+					match mesh {
+						Ok(mesh) => mesh,
+						Err(e) => panic!("Could not load {}: {}", path, e)
+					}
+				});
+
+			// materials of the model file, with the shader of the entity
+			let mesh_materials = mesh.materials.iter().map(|desc| {
+				Rc::new(Material::from_desc(desc, shader.clone(), texture.clone(), |source, color_space| {
+					load_model_texture(&textures, source, color_space)
+				}))
+			}).collect::<Vec<_>>();
+			let part_materials = mesh.part_materials.iter().map(|m| {
+				m.map(|i| mesh_materials[i].clone()).unwrap_or(material.clone())
+			}).collect();
 
 			entities.push(Entity {
-				mesh: meshes.load_with(&scene_ent.mesh, &|path| {
-					Mesh::load_from_obj(context, &asset_root.join(path))
-				}),
+				mesh: mesh,
 				material: material,
+				part_materials: part_materials,
 				transform: MyTransform {
 					position: Vec3::new(scene_ent.transform.position.x, scene_ent.transform.position.y, scene_ent.transform.position.z),
 					rotation: Vec3::new(scene_ent.transform.rotation.x, scene_ent.transform.rotation.y, scene_ent.transform.rotation.z),
 					scale: scene_ent.transform.scale
-				}
+				}.to_mat4(),
+				lod: 0
 			});
 		}
 
@@ -306,6 +387,95 @@ impl Scene
 		// create terrain
 		let terrain = scene_json.terrain.map(|t| Terrain::new(context, &asset_root.join(&t.heightmap), t.scale, t.height_scale));
 
+		Scene::with_contents(context, asset_root, entities, light_sources, Vec::new(), terrain)
+	}
+
+	/// Load a scene from a glTF file: one entity per mesh node,
+	/// with the default shader, plus the cameras and directional lights of the file
+	pub fn load_gltf(context: &Context, asset_root: &Path, scene: &Path) -> Scene
+	{
+		let _alloc = allocation_scope!("Scene::load_gltf");
+		let mut gltf = match load_gltf(scene) {
+			Ok(gltf) => gltf,
+			Err(e) => panic!("Could not load {}: {:?}", scene.display(), e)
+		};
+		for data in gltf.meshes.iter_mut() {
+			data.generate_lods(&LodOptions::default());
+		}
+
+		let shader = Rc::new(Shader::load(&asset_root.join("shaders/default.glsl")));
+		// base color factor only
+		let white = Rc::new(Texture2D::with_pixels(1, 1, 1, TextureFormat::Srgb8, Some(&[255u8, 255, 255][..])));
+		let textures = AssetCache::<Texture2D>::new();
+		let materials = gltf.materials.iter().map(|desc| {
+			Rc::new(Material::from_desc(desc, shader.clone(), white.clone(), |source, color_space| {
+				load_model_texture(&textures, source, color_space)
+			}))
+		}).collect::<Vec<_>>();
+		let default_material = Rc::new(Material::new_with_shader(shader.clone(), white.clone()));
+
+		let meshes = gltf.meshes.iter().enumerate().map(|(i, data)| {
+			let mesh = Mesh::from_data(context, data);
+			mesh.vb.raw.set_label(&format!("{} mesh {} (vertices)", scene.display(), i));
+			Rc::new(mesh)
+		}).collect::<Vec<_>>();
+
+		let entities = gltf.instances.iter().map(|inst| {
+			let mesh = meshes[inst.mesh].clone();
+			let part_materials = mesh.part_materials.iter().map(|m| {
+				m.map(|i| materials[i].clone()).unwrap_or(default_material.clone())
+			}).collect();
+			Entity {
+				mesh: mesh,
+				material: default_material.clone(),
+				part_materials: part_materials,
+				transform: inst.world_matrix,
+				lod: 0
+			}
+		}).collect::<Vec<_>>();
+
+		Scene::with_contents(context, asset_root, entities, gltf.lights, gltf.cameras, None)
+	}
+
+	fn with_contents(
+		context: &Context,
+		asset_root: &Path,
+		entities: Vec<Entity>,
+		light_sources: Vec<LightSource>,
+		cameras: Vec<SceneCamera>,
+		terrain: Option<Terrain>) -> Scene
+	{
+		let num_directional = light_sources.iter().filter(|l| match **l {
+			LightSource::Directional(..) => true,
+			_ => false
+		}).count();
+		if num_directional == 0 {
+			warn!("no directional light, using a default one");
+		}
+		if num_directional != light_sources.len() {
+			warn!("{} point or spot lights ignored: only the first directional light is rendered",
+				light_sources.len() - num_directional);
+		}
+
+		let sky_dome = match Mesh::load_from_obj(context, &asset_root.join("models/dome.obj"), &MeshLoadOptions::default()) {
+			Ok(mesh) => mesh,
+			Err(e) => panic!("Could not load the sky dome: {}", e)
+		};
+		let nightsky = {
+			let img = image::open(&asset_root.join("img/skymap.tif")).unwrap();
+			let (dimx, dimy) = img.dimensions();
+			let img2 = img.as_rgb8().unwrap();
+			Texture2D::with_pixels(dimx, dimy, 1, TextureFormat::Srgb8, Some(img2))
+		};
+		let sky_shader = Shader::load(&asset_root.join("shaders/sky.glsl"));
+		let sky_pso = sky_shader.make_pipeline_state::<MeshVertex>(&PipelineStateDesc {
+            keywords: Keywords::empty(),
+            pass: StdPass::ForwardBase,
+            default_draw_state: DrawState::default(),
+            sampler_block_base: 0,
+            uniform_block_base: 0
+        });
+
 		// display shaders
 
 		Scene {
@@ -317,12 +487,15 @@ impl Scene
 			},
 			mode: DisplayMode::Shade,
 			mode_index: 0,
+			lod_hysteresis: 0.1,
 			entities: entities,
 			light_sources: light_sources,
+			cameras: cameras,
 			terrain: terrain,
-			depth_only_pso: load_pipeline_state(&asset_root.join("shaders/render_depth.glsl"), Keywords::empty()),
-			normals_only_pso: load_pipeline_state(&asset_root.join("shaders/render_normals.glsl"), Keywords::empty()),
-			shadow_map: Texture2D::new(1024, 1024, 1, TextureFormat::Depth24),
+			depth_only_pso: load_pipeline_state::<MeshVertex>(&asset_root.join("shaders/render_depth.glsl"), Keywords::empty()),
+			normals_only_pso: load_pipeline_state::<MeshVertex>(&asset_root.join("shaders/render_normals.glsl"), Keywords::empty()),
+			texture_pool: RefCell::new(TexturePool::new()),
+			dump_shadow_map: false,
 			shader_cache: ShaderCache::new(),
 			player_cam: PlayerCamera::new(PlayerCameraSettings
 				{
@@ -334,6 +507,34 @@ impl Scene
 		}
 	}
 
+	/// Cameras defined in the scene file
+	pub fn cameras(&self) -> &[SceneCamera]
+	{
+		&self.cameras
+	}
+
+	/// Direction, color and intensity of the light used for shading and shadows:
+	/// the first directional light (point and spot lights are not rendered)
+	fn main_light(&self) -> (Vec3<f32>, Vec3<f32>, f32)
+	{
+		self.light_sources.iter().filter_map(|l| match *l {
+			LightSource::Directional(dir, color, intensity) => Some((dir, color, intensity)),
+			_ => None
+		}).next().unwrap_or((Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 1.0))
+	}
+
+	/// World space bounding box of the entities
+	pub fn bounding_box(&self) -> BoundingBox
+	{
+		self.entities.iter().fold(BoundingBox::empty(), |b, ent| b.union(&ent.world_bounds().aabb))
+	}
+
+	/// 0 switches LODs exactly at their thresholds
+	pub fn set_lod_hysteresis(&mut self, hysteresis: f32)
+	{
+		self.lod_hysteresis = hysteresis;
+	}
+
 	pub fn event(&mut self, event: &Event)
 	{
 		self.player_cam.event(event);
@@ -352,6 +553,10 @@ impl Scene
 				self.mode = DISPLAY_MODE_CYCLE[self.mode_index];
 				println!("DisplayMode: {:?}", self.mode);
 			},
+			&Event::KeyDown(glfw::Key::F11) => {
+				// dump the shadow map of the next frame for debugging
+				self.dump_shadow_map = true;
+			},
 			_ => {}
 		}
 	}
@@ -361,241 +566,340 @@ impl Scene
 		self.player_cam.update(dt, input);
 	}
 
-	pub fn render(&mut self, graphics: &Graphics, terrain_renderer: &TerrainRenderer, window: &Window, context: &Context, cam: &Camera)
+	/// Shadow pass: render the depth of all entities from the light
+	fn render_shadow_pass(&self, frame: &Frame, pass_cfg: &PipelineStateDesc, light_data: &LightData)
 	{
-		use num::traits::One;
-
-		let (width, height) = window.dimensions();
-
-		// XXX these should be constants
-		let pass_cfg_shadow = PipelineStateDesc {
-				keywords: Keywords::empty(),
-				pass: StdPass::Shadow,
-				default_draw_state: DrawState::default(),
-				sampler_block_base: 0,
-				uniform_block_base: 2
-		};
-
-		let pass_cfg_forward = PipelineStateDesc {
-				keywords: POINT_LIGHT | SHADOWS_SIMPLE,
-				pass: StdPass::ForwardBase,
-				default_draw_state: DrawState::default(),
-				sampler_block_base: 0,
-				uniform_block_base: 2
-		};
-
-		// TODO use another camera if there is no terrain
-		//let cam = self.player_cam.get_camera(&self.terrain.as_ref().unwrap(), window);
-
-		// TODO do not assume that the first light source is directional
-		let light_direction = if let LightSource::Directional(dir, _, _) = self.light_sources[0] {
-			dir
-		} else {
-			Vec3::new(0.0f32, -1.0f32, 0.0f32)
-		};
-
-		let (light_color, light_intensity) = match self.light_sources[0] {
-			LightSource::Directional(_, color, intensity) => (color, intensity),
-			LightSource::Point(_, color, intensity) => (color, intensity)
-		};
-
-		// light matrix setup
-		// TODO compute bounding box of view frustum?
-		// TODO cascaded shadow maps
-		let depth_proj_matrix = OrthoMat3::<f32>::new(20.0, 20.0, -10.0, 10.0);
-		let mut depth_view_matrix = Iso3::<f32>::one();
-		depth_view_matrix.look_at_z(
-			&Pnt3::new(0.0, 0.0, 0.0),
-			&(Pnt3::new(0.0, 0.0, 0.0) + light_direction),
-			&Vec3::new(0.0, 0.0, -1.0));
-		depth_view_matrix.inv_mut();
-
-		let light_data = LightData {
-			light_dir: light_direction,
-			light_matrix: *depth_proj_matrix.as_mat() * depth_view_matrix.to_homogeneous()
-		};
+		let mut shadow_psos = Vec::with_capacity(self.entities.len());
+		for ent in self.entities.iter() {
+			shadow_psos.push(self.shader_cache.get::<MeshVertex>(&ent.material.shader, pass_cfg));
+		}
 
+		// record, then sort by pipeline and mesh to minimize state changes,
+		// and front to back from the light within a mesh.
+		// No material is bound in the shadow pass.
+		let mut commands = CommandList::new();
+		let mut pipeline_ids = SortIds::new(SORT_KEY_PIPELINE_BITS);
+		let mut mesh_ids = SortIds::new(SORT_KEY_MESH_BITS);
+		for (ent, pso) in self.entities.iter().zip(shadow_psos.iter())
 		{
-			// shadow map: create render target with only one depth map
-			let mut shadow_frame = graphics.context().create_frame(
-				&[], Some(self.shadow_map.view_as_depth_stencil_target()));
-
-			//let mut shadow_frame = context.create_frame(RenderTarget::screen((640, 480)));
-			shadow_frame.clear(None, Some(1.0));
-			for ent in self.entities.iter()
-			{
-				#[repr(C)]
-				#[derive(Copy, Clone)]
+			uniform_block! {
 				struct LightParams
 				{
 					light_matrix: Mat4<f32>,
 					model_matrix: Mat4<f32>
 				}
+			}
 
-				let model_data = shadow_frame.make_uniform_buffer(&ent.transform.to_mat4());
-
-				let light_params = shadow_frame.make_uniform_buffer(&LightParams {
-					light_matrix: light_data.light_matrix,
-					model_matrix: ent.transform.to_mat4()
-					});
-
-				graphics.draw_mesh_with_shader(
-					&ent.mesh,
-					&self.shader_cache.get(&ent.material.shader, &pass_cfg_shadow),
+			let model_data = frame.make_uniform_buffer(&ent.transform);
+
+			let light_params = frame.make_uniform_block(&LightParams {
+				light_matrix: light_data.light_matrix,
+				model_matrix: ent.transform
+				});
+
+			pso.layout.check_vertex_format::<MeshVertex>();
+			let pipeline_id = pipeline_ids.get(pso.program.obj);
+			let mesh_id = mesh_ids.get(&*ent.mesh as *const Mesh);
+			for i in 0..ent.mesh.parts.len() {
+				// orthographic light projection: clip space z in [-1,1]
+				let c = ent.part_world_bounds(i).sphere.center;
+				let m = &light_data.light_matrix;
+				let z = m[(2, 0)] * c.x + m[(2, 1)] * c.y + m[(2, 2)] * c.z + m[(2, 3)];
+				commands.draw(
+					SortKey::new(0, pipeline_id, 0, mesh_id, z * 0.5 + 0.5),
+					ent.mesh.vb.raw.as_raw_buf_slice(),
+					ent.mesh.index_buffer(),
+					pso,
+					ent.mesh.lod_part(i, ent.lod),
 					&[Binding{slot:0, slice: light_params.as_raw()},
 					  Binding{slot:1, slice: model_data.as_raw()}],
-					&shadow_frame);
+					&[]);
 			}
 		}
+		commands.sort();
+		frame.submit(&commands);
+	}
 
-		{
-			// frame for main pass
-			let mut frame = graphics.context().create_screen_frame(window);
-			frame.clear(Some([0.1, 0.1, 0.2, 1.0]), Some(1.0));
-			let rt_dim = frame.dimensions();
-
-			// For shadows:
-			// depth matrix with bias
-			let depth_matrix = {
-				let bias = Mat4::<f32>::new(
-					0.5, 0.0, 0.0, 0.5,
-					0.0, 0.5, 0.0, 0.5,
-					0.0, 0.0, 0.5, 0.5,
-					0.0, 0.0, 0.0, 1.0
-					);
-				bias * light_data.light_matrix
-			};
+	/// Main pass: terrain, sky and entities, or debug views depending on the display mode
+	fn render_main_pass(
+		&self,
+		frame: &Frame,
+		shadow_map: &Texture2D,
+		graphics: &Graphics,
+		terrain_renderer: &TerrainRenderer,
+		context: &Context,
+		cam: &Camera,
+		pass_cfg: &PipelineStateDesc,
+		light_data: &LightData,
+		light_color: Vec3<f32>,
+		light_intensity: f32)
+	{
+		let light_direction = light_data.light_dir;
+		let rt_dim = frame.dimensions();
+
+		// For shadows:
+		// depth matrix with bias
+		let depth_matrix = {
+			let bias = Mat4::<f32>::new(
+				0.5, 0.0, 0.0, 0.5,
+				0.0, 0.5, 0.0, 0.5,
+				0.0, 0.0, 0.5, 0.5,
+				0.0, 0.0, 0.0, 1.0
+				);
+			bias * light_data.light_matrix
+		};
 
-			let scene_data = {
-				let data = SceneContext {
-					view_mat: cam.view_matrix,
-					proj_mat: cam.proj_matrix,
-					view_proj_mat: cam.proj_matrix * cam.view_matrix,
-					light_dir: Vec4::new(
-						light_direction.x,
-						-light_direction.y,
-						light_direction.z,
-						0.0),
-					w_eye: Vec4::new(cam.w_eye.x,cam.w_eye.y,cam.w_eye.z,1.0),
-					viewport_size: Vec2::new(rt_dim.0 as f32, rt_dim.1 as f32),
-					light_pos: light_direction,
-					light_color: light_color,
-					light_intensity: light_intensity
-				};
-				let buf = frame.make_uniform_buffer(&data);
-				SceneData {
-					data: data,
-					buffer: buf.as_raw()
-				}
+		let scene_data = {
+			let data = SceneContext {
+				view_mat: cam.view_matrix,
+				proj_mat: cam.proj_matrix,
+				view_proj_mat: cam.proj_matrix * cam.view_matrix,
+				light_dir: Vec4::new(
+					light_direction.x,
+					-light_direction.y,
+					light_direction.z,
+					0.0),
+				w_eye: Vec4::new(cam.w_eye.x,cam.w_eye.y,cam.w_eye.z,1.0),
+				viewport_size: Vec2::new(rt_dim.0 as f32, rt_dim.1 as f32),
+				_pad0: Padding::default(),
+				light_pos: light_direction,
+				_pad1: Padding::default(),
+				light_color: light_color,
+				light_intensity: light_intensity
 			};
+			let buf = frame.make_uniform_block(&data);
+			SceneData {
+				data: data,
+				buffer: buf.as_raw()
+			}
+		};
 
-			let light_data = frame.make_uniform_buffer(&depth_matrix);
+		let light_data = frame.make_uniform_buffer(&depth_matrix);
 
-			// debug shadow map
-			/*graphics.blit(
-				&self.shadow_map,
-				&Rect { top: 0.0, bottom: 300.0, left: 0.0, right: 300.0 },
-				&frame);*/
+		// debug shadow map
+		/*let mut batch = SpriteBatch::new();
+		batch.draw(Sprite::new(shadow_map, Rect { top: 0.0, bottom: 300.0, left: 0.0, right: 300.0 }));
+		graphics.draw_sprites(&mut batch, &frame);*/
 
 
-			match self.mode 
-			{
+		match self.mode 
+		{
+			//================================================
+			//
+			// Render the full scene with shading
+			//
+			DisplayMode::Shade => {
 				//================================================
-				//
-				// Render the full scene with shading
-				//
-				DisplayMode::Shade => {
-					//================================================
-					// TERRAIN
-					if let Some(ref terrain) = self.terrain
-					{
-						terrain_renderer.render_terrain(&terrain, &scene_data, &frame);
-					}
+				// TERRAIN
+				if let Some(ref terrain) = self.terrain
+				{
+					let _scope = context.profile_scope("terrain");
+					let _group = context.debug_group("terrain");
+					terrain_renderer.render_terrain(&terrain, &scene_data, frame);
+				}
 
-					//================================================
-					// SKY
-					{
-						let model_data = frame.make_uniform_buffer(
-							&SkyParams {
-								model_matrix: make_scale_matrix(100.0),
-								rayleigh_coefficient: 0.0,	// unused
-								mie_coefficient: 0.005,
-								mie_directional_g: 0.80,
-								turbidity: 5.0
-							});
-
-						self.sky.nightsky.bind(0);	// TODO fix this hack
-						graphics.draw_mesh_with_shader(
-							&self.sky.dome_mesh,
-							&self.sky.pso,
-							&[Binding {slot:0, slice:scene_data.buffer},
-							  Binding {slot:1, slice:model_data.as_raw()},
-							  Binding {slot:2, slice:light_data.as_raw()}],
-							&frame);
-					}
+				//================================================
+				// SKY
+				{
+					let _scope = context.profile_scope("sky");
+					let _group = context.debug_group("sky");
+					let model_data = frame.make_uniform_block(
+						&SkyParams {
+							model_matrix: make_scale_matrix(100.0),
+							rayleigh_coefficient: 0.0,	// unused
+							mie_coefficient: 0.005,
+							mie_directional_g: 0.80,
+							turbidity: 5.0
+						});
+
+					self.sky.nightsky.bind(0);	// TODO fix this hack
+					graphics.draw_mesh_with_shader(
+						&self.sky.dome_mesh,
+						&self.sky.pso,
+						&[Binding {slot:0, slice:scene_data.buffer},
+						  Binding {slot:1, slice:model_data.as_raw()},
+						  Binding {slot:2, slice:light_data.as_raw()}],
+						frame);
+				}
 
-					//================================================
-					// SCENE
-					for ent in self.entities.iter()
+				//================================================
+				// SCENE
+				let _scope = context.profile_scope("entities");
+				let _group = context.debug_group("entities");
+				for ent in self.entities.iter()
+				{
+					let model_data = frame.make_uniform_buffer(&ent.transform);
+					for (i, material) in ent.part_materials.iter().enumerate()
 					{
-						let model_data = frame.make_uniform_buffer(&ent.transform.to_mat4());
-						ent.material.bind();
-						self.shadow_map.bind(1);
-						graphics.draw_mesh_with_shader(
+						let material_data = frame.make_uniform_block(&material.params);
+						material.bind();
+						shadow_map.bind(1);
+						graphics.draw_mesh_lod_with_shader(
 							&ent.mesh,
-							&self.shader_cache.get(&ent.material.shader, &pass_cfg_forward),
+							i,
+							ent.lod,
+							&self.shader_cache.get::<MeshVertex>(&material.shader, pass_cfg),
 							&[Binding {slot:0, slice:scene_data.buffer},
 							  Binding {slot:1, slice:model_data.as_raw()},
-							  Binding {slot:2, slice:light_data.as_raw()}], &frame);
+							  Binding {slot:2, slice:light_data.as_raw()},
+							  Binding {slot:3, slice:material_data.as_raw()}], frame);
 					}
-				},
+				}
+			},
 
+			//================================================
+			//
+			// Render only scene items, without shadows,
+			// sky and terrain
+			DisplayMode::Normals => {
 				//================================================
-				//
-				// Render only scene items, without shadows,
-				// sky and terrain
-				DisplayMode::Normals => {
-					//================================================
-					// SCENE
-					for ent in self.entities.iter()
-					{
-						let model_data = frame.make_uniform_buffer(&ent.transform.to_mat4());
-						graphics.draw_mesh_with_shader(
+				// SCENE
+				for ent in self.entities.iter()
+				{
+					let model_data = frame.make_uniform_buffer(&ent.transform);
+					for i in 0..ent.mesh.parts.len() {
+						graphics.draw_mesh_lod_with_shader(
 							&ent.mesh,
+							i,
+							ent.lod,
 							&self.normals_only_pso,
 							&[Binding {slot:0, slice:scene_data.buffer},
-							  Binding {slot:1, slice:model_data.as_raw()}], 
-							&frame);
+							  Binding {slot:1, slice:model_data.as_raw()}],
+							frame);
 					}
-				},
+				}
+			},
+			//================================================
+			//
+			// Render and show depth
+			//
+			DisplayMode::Depth => {
 				//================================================
-				//
-				// Render and show depth
-				//
-				DisplayMode::Depth => {
-					//================================================
-					// SCENE
-					for ent in self.entities.iter()
-					{
-						let model_data = frame.make_uniform_buffer(&ent.transform.to_mat4());
-						graphics.draw_mesh_with_shader(
+				// SCENE
+				for ent in self.entities.iter()
+				{
+					let model_data = frame.make_uniform_buffer(&ent.transform);
+					for i in 0..ent.mesh.parts.len() {
+						graphics.draw_mesh_lod_with_shader(
 							&ent.mesh,
+							i,
+							ent.lod,
 							&self.depth_only_pso,
 							&[Binding {slot:0, slice:scene_data.buffer},
-							  Binding {slot:1, slice:model_data.as_raw()}], 
-							&frame);
+							  Binding {slot:1, slice:model_data.as_raw()}],
+							frame);
 					}
-				},
-				//================================================
-				//
-				// Render and show shadow maps
-				//
-				DisplayMode::Shadow => {
-					// unimplemented
 				}
+			},
+			//================================================
+			//
+			// Render and show shadow maps
+			//
+			DisplayMode::Shadow => {
+				// unimplemented
 			}
+		}
+
+		// HUD
+		graphics.flush_sprites(frame);
+	}
+
+	/// Render the scene into target (the window, or offscreen textures)
+	pub fn render(&mut self, graphics: &Graphics, terrain_renderer: &TerrainRenderer, target: &RenderTarget, context: &Context, cam: &Camera)
+	{
+		use num::traits::One;
+
+		let _scope = context.profile_scope("scene");
+		let _group = context.debug_group("scene");
+
+		// XXX these should be constants
+		let pass_cfg_shadow = PipelineStateDesc {
+				keywords: Keywords::empty(),
+				pass: StdPass::Shadow,
+				default_draw_state: DrawState::default(),
+				sampler_block_base: 0,
+				uniform_block_base: 2
+		};
+
+		let pass_cfg_forward = PipelineStateDesc {
+				keywords: POINT_LIGHT | SHADOWS_SIMPLE,
+				pass: StdPass::ForwardBase,
+				default_draw_state: DrawState::default(),
+				sampler_block_base: 0,
+				uniform_block_base: 2
+		};
+
+		// TODO use another camera if there is no terrain
+		//let cam = self.player_cam.get_camera(&self.terrain.as_ref().unwrap(), window);
+
+		// levels of detail for this camera, also used by the shadow pass
+		let lod_hysteresis = self.lod_hysteresis;
+		for ent in self.entities.iter_mut() {
+			ent.lod = ent.mesh.select_lod(ent.screen_size(cam), ent.lod, lod_hysteresis);
+		}
+
+		let (light_direction, light_color, light_intensity) = self.main_light();
+
+		// light matrix setup
+		// TODO compute bounding box of view frustum?
+		// TODO cascaded shadow maps
+		let depth_proj_matrix = OrthoMat3::<f32>::new(20.0, 20.0, -10.0, 10.0);
+		let mut depth_view_matrix = Iso3::<f32>::one();
+		depth_view_matrix.look_at_z(
+			&Pnt3::new(0.0, 0.0, 0.0),
+			&(Pnt3::new(0.0, 0.0, 0.0) + light_direction),
+			&Vec3::new(0.0, 0.0, -1.0));
+		depth_view_matrix.inv_mut();
 
+		let light_data = LightData {
+			light_dir: light_direction,
+			light_matrix: *depth_proj_matrix.as_mat() * depth_view_matrix.to_homogeneous()
+		};
 
+		let dump_shadow_map = ::std::mem::replace(&mut self.dump_shadow_map, false);
+
+		// pass closures borrow these
+		let this = &*self;
+		let pass_cfg_shadow = &pass_cfg_shadow;
+		let pass_cfg_forward = &pass_cfg_forward;
+		let light_data = &light_data;
+
+		let mut graph = FrameGraph::new();
+		let shadow_map = graph.create_texture("shadow_map", TextureDesc::new(1024, 1024, TextureFormat::Depth24));
+		let backbuffer = graph.backbuffer();
+
+		graph.add_pass(
+			PassDesc::new("shadow")
+				.write_depth(shadow_map)
+				.clear_depth(1.0),
+			move |ctx| this.render_shadow_pass(ctx.frame(), pass_cfg_shadow, light_data));
+
+		graph.add_pass(
+			PassDesc::new("main")
+				.read(shadow_map)
+				.write_color(backbuffer)
+				.write_depth(backbuffer)
+				.clear_color([0.1, 0.1, 0.2, 1.0])
+				.clear_depth(1.0),
+			move |ctx| this.render_main_pass(
+				ctx.frame(), ctx.texture(shadow_map),
+				graphics, terrain_renderer, context, cam,
+				pass_cfg_forward, light_data, light_color, light_intensity));
+
+		if dump_shadow_map {
+			graph.add_pass(
+				PassDesc::new("dump_shadow_map")
+					.read(shadow_map)
+					.has_side_effects(),
+				move |ctx| {
+					if let Err(e) = ctx.texture(shadow_map).read_pixels(0).save("shadow_map.png") {
+						error!("Could not save shadow map: {:?}", e);
+					}
+				});
 		}
+
+		let mut texture_pool = this.texture_pool.borrow_mut();
+		graph.execute(context, target, &mut texture_pool);
+		texture_pool.end_frame();
 	}
 }
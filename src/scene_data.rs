@@ -1,5 +1,5 @@
 use nalgebra::*;
-use rendering::RawBufSlice;
+use rendering::{RawBufSlice, Padding};
 
 pub struct SceneData<'a>
 {
@@ -9,17 +9,19 @@ pub struct SceneData<'a>
 	pub buffer: RawBufSlice<'a>
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct SceneContext
-{
-	pub view_mat: Mat4<f32>,
-	pub proj_mat: Mat4<f32>,
-	pub view_proj_mat: Mat4<f32>,
-	pub light_dir: Vec4<f32>,
-	pub w_eye: Vec4<f32>,
-	pub viewport_size: Vec2<f32>,
-	pub light_pos: Vec3<f32>,
-	pub light_color: Vec3<f32>,
-	pub light_intensity: f32
+uniform_block! {
+	pub struct SceneContext
+	{
+		pub view_mat: Mat4<f32>,
+		pub proj_mat: Mat4<f32>,
+		pub view_proj_mat: Mat4<f32>,
+		pub light_dir: Vec4<f32>,
+		pub w_eye: Vec4<f32>,
+		pub viewport_size: Vec2<f32>,
+		pub _pad0: Padding<[f32; 2]>,
+		pub light_pos: Vec3<f32>,
+		pub _pad1: Padding<f32>,
+		pub light_color: Vec3<f32>,
+		pub light_intensity: f32
+	}
 }
@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+use rendering::*;
+use rendering::shader::PipelineState;
+use graphics::Rect;
+
+//-------------------------------------------
+// 2D quads (HUD, overlays, debug views of textures), drawn in batches:
+// sprites are accumulated, then flushed in one vertex buffer, with one
+// draw call per run of sprites using the same texture.
+//
+// Coordinates are in pixels, with the origin at the top left corner of the frame.
+// Sprites are blended without depth test: they are drawn back to front, and sprites
+// of the same depth are grouped by texture, so overlapping sprites with different
+// textures should be ordered with their depth, not their submission order.
+
+vertex_format! {
+	/// Vertex of a sprite (position in pixels and depth, texture coordinates, tint color)
+	pub struct SpriteVertex
+	{
+		pub pos: [f32; 3],
+		pub tex: [f32; 2],
+		pub color: [f32; 4]
+	}
+}
+
+uniform_block! {
+	struct BlitData
+	{
+		viewport_size: [f32; 2]
+	}
+}
+
+/// A textured quad. Defaults: the whole texture, white tint, no rotation, depth 0.
+///
+///     batch.draw(Sprite::new(&atlas, Rect::from_dimensions(10.0, 10.0, 32.0, 32.0))
+///         .source(Rect::from_dimensions(64.0, 0.0, 32.0, 32.0))
+///         .color([1.0, 0.5, 0.5, 1.0])
+///         .rotation(0.25 * PI));
+#[derive(Copy, Clone)]
+pub struct Sprite<'a>
+{
+	texture: &'a Texture2D,
+	dest: Rect,
+	source: Option<Rect>,
+	color: [f32; 4],
+	rotation: f32,
+	depth: f32
+}
+
+impl<'a> Sprite<'a>
+{
+	/// Sprite covering dest in the frame
+	pub fn new(texture: &'a Texture2D, dest: Rect) -> Sprite<'a>
+	{
+		Sprite {
+			texture: texture,
+			dest: dest,
+			source: None,
+			color: [1.0; 4],
+			rotation: 0.0,
+			depth: 0.0
+		}
+	}
+
+	/// Region of the texture, in texels (e.g. an image in an atlas)
+	pub fn source(self, source: Rect) -> Sprite<'a>
+	{
+		Sprite { source: Some(source), .. self }
+	}
+
+	/// Multiplied with the texture color
+	pub fn color(self, color: [f32; 4]) -> Sprite<'a>
+	{
+		Sprite { color: color, .. self }
+	}
+
+	/// Clockwise rotation around the center of the sprite, in radians
+	pub fn rotation(self, rotation: f32) -> Sprite<'a>
+	{
+		Sprite { rotation: rotation, .. self }
+	}
+
+	/// Between 0 (front) and 1 (back)
+	pub fn depth(self, depth: f32) -> Sprite<'a>
+	{
+		Sprite { depth: depth, .. self }
+	}
+
+	fn vertices(&self) -> [SpriteVertex; 6]
+	{
+		let (tw, th) = self.texture.dimensions();
+		let (u0, v0, u1, v1) = match self.source {
+			Some(ref s) => (s.left / tw as f32, s.top / th as f32, s.right / tw as f32, s.bottom / th as f32),
+			None => (0.0, 0.0, 1.0, 1.0)
+		};
+		let ref d = self.dest;
+		let (cx, cy) = ((d.left + d.right) * 0.5, (d.top + d.bottom) * 0.5);
+		let (s, c) = self.rotation.sin_cos();
+		let vertex = |x: f32, y: f32, u: f32, v: f32| {
+			let (dx, dy) = (x - cx, y - cy);
+			SpriteVertex {
+				pos: [cx + dx * c - dy * s, cy + dx * s + dy * c, self.depth],
+				tex: [u, v],
+				color: self.color
+			}
+		};
+		let top_left = vertex(d.left, d.top, u0, v0);
+		let top_right = vertex(d.right, d.top, u1, v0);
+		let bottom_left = vertex(d.left, d.bottom, u0, v1);
+		let bottom_right = vertex(d.right, d.bottom, u1, v1);
+		[top_left, top_right, bottom_left, bottom_left, top_right, bottom_right]
+	}
+}
+
+pub struct SpriteBatch<'a>
+{
+	sprites: Vec<Sprite<'a>>
+}
+
+impl<'a> SpriteBatch<'a>
+{
+	pub fn new() -> SpriteBatch<'a>
+	{
+		SpriteBatch {
+			sprites: Vec::new()
+		}
+	}
+
+	pub fn draw(&mut self, sprite: Sprite<'a>)
+	{
+		self.sprites.push(sprite);
+	}
+
+	pub fn len(&self) -> usize
+	{
+		self.sprites.len()
+	}
+
+	/// Draw the accumulated sprites with a pipeline state using the SpriteVertex format
+	/// and a BlitData uniform block (see blit.glsl), and clear the batch.
+	/// Sprites are drawn back to front, then by texture in the order of their first use.
+	pub fn flush(&mut self, pipeline_state: &PipelineState, sampler: &Sampler2D, frame: &Frame)
+	{
+		if self.sprites.is_empty() {
+			return;
+		}
+
+		// group by texture
+		let mut textures: Vec<&'a Texture2D> = Vec::new();
+		let mut groups = Vec::with_capacity(self.sprites.len());
+		for sprite in self.sprites.iter() {
+			let group = match textures.iter().position(|&t| t as *const Texture2D == sprite.texture as *const Texture2D) {
+				Some(i) => i,
+				None => {
+					textures.push(sprite.texture);
+					textures.len() - 1
+				}
+			};
+			groups.push(group);
+		}
+		// stable: sprites of the same depth and texture keep their submission order
+		let mut order = (0..self.sprites.len()).collect::<Vec<_>>();
+		order.sort_by(|&a, &b| {
+			match self.sprites[b].depth.partial_cmp(&self.sprites[a].depth) {
+				Some(Ordering::Equal) | None => groups[a].cmp(&groups[b]),
+				Some(o) => o
+			}
+		});
+
+		let mut vertices = Vec::with_capacity(self.sprites.len() * 6);
+		for &i in order.iter() {
+			vertices.push_all(&self.sprites[i].vertices());
+		}
+
+		let (width, height) = frame.dimensions();
+		let vertex_buffer = frame.alloc_temporary_buffer(vertices.len(), BufferBindingHint::VertexBuffer, Some(&vertices[..]));
+		let blit_data = frame.make_uniform_block(&BlitData {
+			viewport_size: [width as f32, height as f32]
+		});
+
+		pipeline_state.layout.check_vertex_format::<SpriteVertex>();
+		let mut start = 0;
+		while start < order.len() {
+			let group = groups[order[start]];
+			let count = order[start..].iter().take_while(|&&i| groups[i] == group).count();
+			frame.draw_call(pipeline_state)
+				.vertex_buffer(vertex_buffer.as_raw())
+				.vertices(PrimitiveType::Triangle, (6 * start) as u32, (6 * count) as u32)
+				.uniform_buffer_by_name("BlitData", blit_data.as_raw())
+				.texture(0, textures[group], sampler)
+				.submit();
+			start += count;
+		}
+
+		self.sprites.clear();
+	}
+}
+
+#[cfg(feature = "headless")]
+#[test]
+fn test_sprite_vertices()
+{
+	use std::f32::consts::PI;
+	use headless::HeadlessSettings;
+
+	let _headless = HeadlessSettings::new((16, 16)).build().unwrap();
+	let texture = Texture2D::new(64, 32, 1, TextureFormat::Srgb8Alpha8);
+	let dest = Rect { left: 10.0, top: 20.0, right: 30.0, bottom: 60.0 };
+	let close = |a: [f32; 3], b: [f32; 3]| (0..3).all(|i| (a[i] - b[i]).abs() < 1e-4);
+
+	// texels to texture coordinates
+	let v = Sprite::new(&texture, dest)
+		.source(Rect { left: 16.0, top: 8.0, right: 48.0, bottom: 24.0 })
+		.depth(0.5)
+		.vertices();
+	assert_eq!(v[0].pos, [10.0, 20.0, 0.5]);
+	assert_eq!(v[0].tex, [0.25, 0.25]);
+	assert_eq!(v[5].pos, [30.0, 60.0, 0.5]);
+	assert_eq!(v[5].tex, [0.75, 0.75]);
+	assert_eq!(v[0].color, [1.0; 4]);
+	// whole texture by default
+	let v = Sprite::new(&texture, dest).vertices();
+	assert_eq!((v[1].tex, v[2].tex), ([1.0, 0.0], [0.0, 1.0]));
+
+	// clockwise quarter turn around the center (20, 40): the top left corner goes to the top right
+	let v = Sprite::new(&texture, dest).rotation(0.5 * PI).vertices();
+	assert!(close(v[0].pos, [40.0, 30.0, 0.0]));
+	assert!(close(v[5].pos, [0.0, 50.0, 0.0]));
+	assert_eq!(v[0].tex, [0.0, 0.0]);
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::mem;
+use nalgebra::*;
+use graphics::MeshVertex;
+
+//-------------------------------------------
+// Normal and tangent generation.
+//
+// Tangents follow the MikkTSpace conventions:
+// per-triangle tangents from the texture coordinate derivatives,
+// weighted by the angle of the triangle at each vertex, orthogonalized
+// against the vertex normal. The bitangent is sign * cross(normal, tangent),
+// with the sign stored in the w component of the tangent.
+// As in the reference implementation, the corners of a vertex are grouped by
+// handedness, and vertices are split where it differs (mirrored texture coordinates).
+// Unlike the reference implementation, vertices are not split where the tangent
+// directions are otherwise discontinuous: meshes should already be split at UV seams.
+
+fn vec3(v: [f32; 3]) -> Vec3<f32>
+{
+	Vec3::new(v[0], v[1], v[2])
+}
+
+/// Any unit vector orthogonal to n (or the X axis if n is null)
+fn orthogonal(n: Vec3<f32>) -> Vec3<f32>
+{
+	if n.norm() < 1e-6 {
+		Vec3::new(1.0, 0.0, 0.0)
+	} else if n.x.abs() < 0.9 {
+		n.cross(&Vec3::new(1.0, 0.0, 0.0)).normalize()
+	} else {
+		n.cross(&Vec3::new(0.0, 1.0, 0.0)).normalize()
+	}
+}
+
+/// Angle between two edges
+fn angle(a: Vec3<f32>, b: Vec3<f32>) -> f32
+{
+	let (la, lb) = (a.norm(), b.norm());
+	if la < 1e-12 || lb < 1e-12 {
+		0.0
+	} else {
+		(a.dot(&b) / (la * lb)).max(-1.0).min(1.0).acos()
+	}
+}
+
+/// Bit pattern of a vector, for hashing (-0.0 and 0.0 are merged)
+fn vec3_key(v: [f32; 3]) -> [u32; 3]
+{
+	unsafe {
+		[mem::transmute(v[0] + 0.0), mem::transmute(v[1] + 0.0), mem::transmute(v[2] + 0.0)]
+	}
+}
+
+/// Generate the normals of an indexed triangle list.
+/// The normal of a vertex is the angle-weighted average of the normals of the
+/// faces that share its position and form an angle smaller than crease_angle
+/// (in radians) with the face being shaded: 0 gives flat normals, PI smooth normals.
+/// Vertices are duplicated where they get several normals (the indices are
+/// rewritten), and vertices that are not referenced are removed.
+/// Returns the original index of each new vertex.
+pub fn generate_normals(vertices: &mut Vec<MeshVertex>, indices: &mut Vec<u32>, crease_angle: f32) -> Vec<u32>
+{
+	let zero = Vec3::new(0.0f32, 0.0, 0.0);
+	let num_corners = indices.len() / 3 * 3;
+
+	// face normals, and angle of the faces at each corner
+	let mut face_normals = Vec::with_capacity(num_corners / 3);
+	let mut corner_angles = Vec::with_capacity(num_corners);
+	for t in indices[..num_corners].chunks(3) {
+		let p = [vec3(vertices[t[0] as usize].pos), vec3(vertices[t[1] as usize].pos), vec3(vertices[t[2] as usize].pos)];
+		let n = (p[1] - p[0]).cross(&(p[2] - p[0]));
+		face_normals.push(if n.norm() > 1e-12 { n.normalize() } else { zero });
+		for c in 0..3 {
+			corner_angles.push(angle(p[(c + 1) % 3] - p[c], p[(c + 2) % 3] - p[c]));
+		}
+	}
+
+	// corners sharing a position
+	let mut corners_at = HashMap::<[u32; 3], Vec<usize>>::new();
+	for k in 0..num_corners {
+		corners_at.entry(vec3_key(vertices[indices[k] as usize].pos)).or_insert(Vec::new()).push(k);
+	}
+
+	let cos_crease = crease_angle.cos() - 1e-6;
+	let mut new_vertices = Vec::with_capacity(vertices.len());
+	let mut remap = Vec::with_capacity(vertices.len());
+	let mut new_indices = Vec::with_capacity(num_corners);
+	let mut vertex_map = HashMap::<(u32, [u32; 3]), u32>::new();
+	for k in 0..num_corners
+	{
+		let i = indices[k];
+		let face_normal = face_normals[k / 3];
+		let mut n = zero;
+		for &other in corners_at[&vec3_key(vertices[i as usize].pos)].iter() {
+			let other_normal = face_normals[other / 3];
+			if face_normal.dot(&other_normal) >= cos_crease {
+				n = n + other_normal * corner_angles[other];
+			}
+		}
+		let n = if n.norm() > 1e-12 { n.normalize() } else { face_normal };
+		let norm = [n.x, n.y, n.z];
+		let index = *vertex_map.entry((i, vec3_key(norm))).or_insert_with(|| {
+			let mut v = vertices[i as usize];
+			v.norm = norm;
+			new_vertices.push(v);
+			remap.push(i);
+			(new_vertices.len() - 1) as u32
+		});
+		new_indices.push(index);
+	}
+
+	*vertices = new_vertices;
+	*indices = new_indices;
+	remap
+}
+
+/// Compute the tangents (tg) of the vertices of an indexed triangle list
+/// from their positions, normals and texture coordinates.
+/// Vertices are duplicated where the triangles that share them have tangent
+/// frames of opposite handedness (the indices are rewritten), and vertices that
+/// are not referenced are removed. Vertices that only belong to triangles with
+/// degenerate texture coordinates get an arbitrary tangent orthogonal to their normal.
+/// Returns the original index of each new vertex.
+pub fn generate_tangents(vertices: &mut Vec<MeshVertex>, indices: &mut Vec<u32>) -> Vec<u32>
+{
+	let zero = Vec3::new(0.0f32, 0.0, 0.0);
+	let num_corners = indices.len() / 3 * 3;
+
+	// tangent and bitangent of each corner (None for degenerate texture coordinates),
+	// weighted by the angle of the triangle at the corner
+	let mut corner_frames = Vec::with_capacity(num_corners);
+	for tri in indices[..num_corners].chunks(3)
+	{
+		let v = [vertices[tri[0] as usize], vertices[tri[1] as usize], vertices[tri[2] as usize]];
+		let e1 = vec3(v[1].pos) - vec3(v[0].pos);
+		let e2 = vec3(v[2].pos) - vec3(v[0].pos);
+		let (du1, dv1) = (v[1].tex[0] - v[0].tex[0], v[1].tex[1] - v[0].tex[1]);
+		let (du2, dv2) = (v[2].tex[0] - v[0].tex[0], v[2].tex[1] - v[0].tex[1]);
+		let det = du1 * dv2 - du2 * dv1;
+		let t = (e1 * dv2 - e2 * dv1) * (1.0 / det);
+		let b = (e2 * du1 - e1 * du2) * (1.0 / det);
+		let degenerate = det.abs() < 1e-12 || t.norm() < 1e-12 || b.norm() < 1e-12;
+
+		for corner in 0..3 {
+			if degenerate {
+				corner_frames.push(None);
+			} else {
+				let p = vec3(v[corner].pos);
+				let w = angle(vec3(v[(corner + 1) % 3].pos) - p, vec3(v[(corner + 2) % 3].pos) - p);
+				corner_frames.push(Some((t.normalize() * w, b.normalize() * w)));
+			}
+		}
+	}
+
+	// handedness of each corner, relative to the vertex normal.
+	// Degenerate corners join the first group of their vertex.
+	let handedness = (0..num_corners).map(|k| corner_frames[k].map(|(t, b)| {
+		let n = vec3(vertices[indices[k] as usize].norm);
+		n.cross(&t).dot(&b) >= 0.0
+	})).collect::<Vec<_>>();
+	let mut first_handedness = vec![None; vertices.len()];
+	for k in 0..num_corners {
+		let i = indices[k] as usize;
+		if first_handedness[i].is_none() {
+			first_handedness[i] = handedness[k];
+		}
+	}
+
+	// one vertex per original vertex and handedness
+	let mut new_vertices = Vec::with_capacity(vertices.len());
+	let mut remap = Vec::with_capacity(vertices.len());
+	let mut new_indices = Vec::with_capacity(num_corners);
+	let mut tangents = Vec::with_capacity(vertices.len());
+	let mut bitangents = Vec::with_capacity(vertices.len());
+	let mut vertex_map = HashMap::<(u32, bool), u32>::new();
+	for k in 0..num_corners
+	{
+		let i = indices[k];
+		let right_handed = handedness[k].or(first_handedness[i as usize]).unwrap_or(true);
+		let index = *vertex_map.entry((i, right_handed)).or_insert_with(|| {
+			new_vertices.push(vertices[i as usize]);
+			remap.push(i);
+			tangents.push(zero);
+			bitangents.push(zero);
+			(new_vertices.len() - 1) as u32
+		});
+		if let Some((t, b)) = corner_frames[k] {
+			tangents[index as usize] = tangents[index as usize] + t;
+			bitangents[index as usize] = bitangents[index as usize] + b;
+		}
+		new_indices.push(index);
+	}
+
+	for (i, v) in new_vertices.iter_mut().enumerate()
+	{
+		let n = vec3(v.norm);
+		let n = if n.norm() > 1e-6 { n.normalize() } else { n };
+		// Gram-Schmidt
+		let t = tangents[i] - n * n.dot(&tangents[i]);
+		let t = if t.norm() > 1e-6 { t.normalize() } else { orthogonal(n) };
+		let w = if n.cross(&t).dot(&bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+		v.tg = [t.x, t.y, t.z, w];
+	}
+
+	*vertices = new_vertices;
+	*indices = new_indices;
+	remap
+}
+
+#[test]
+fn test_generate_tangents_quad()
+{
+	// quad in the XY plane facing +Z, U along +X, V along -Y: mirrored bitangent
+	let vertex = |x: f32, y: f32, u: f32, v: f32| MeshVertex {
+		pos: [x, y, 0.0],
+		norm: [0.0, 0.0, 1.0],
+		tg: [0.0; 4],
+		tex: [u, v]
+	};
+	let mut vertices = vec![
+		vertex(0.0, 0.0, 0.0, 1.0),
+		vertex(1.0, 0.0, 1.0, 1.0),
+		vertex(1.0, 1.0, 1.0, 0.0),
+		vertex(0.0, 1.0, 0.0, 0.0)];
+	let mut indices = vec![0, 1, 2, 2, 3, 0];
+	generate_tangents(&mut vertices, &mut indices);
+	assert_eq!(vertices.len(), 4);
+	for v in vertices.iter() {
+		assert!((v.tg[0] - 1.0).abs() < 1e-5 && v.tg[1].abs() < 1e-5 && v.tg[2].abs() < 1e-5);
+		assert_eq!(v.tg[3], -1.0);
+	}
+}
+
+#[test]
+fn test_generate_tangents_mirrored()
+{
+	// two quads in the XY plane facing +Z, with U mirrored at x = 1 and V along +Y
+	let vertex = |x: f32, y: f32| MeshVertex {
+		pos: [x, y, 0.0],
+		norm: [0.0, 0.0, 1.0],
+		tg: [0.0; 4],
+		tex: [1.0 - (x - 1.0).abs(), y]
+	};
+	let mut vertices = vec![
+		vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(2.0, 0.0),
+		vertex(0.0, 1.0), vertex(1.0, 1.0), vertex(2.0, 1.0)];
+	let mut indices = vec![
+		0, 1, 4, 4, 3, 0,
+		1, 2, 5, 5, 4, 1];
+	let positions = vertices.iter().map(|v| v.pos).collect::<Vec<_>>();
+	let remap = generate_tangents(&mut vertices, &mut indices);
+
+	// the vertices on the mirror line are split
+	assert_eq!(vertices.len(), 8);
+	assert_eq!(remap.iter().filter(|&&i| i == 1 || i == 4).count(), 4);
+	for (k, &i) in indices.iter().enumerate() {
+		let v = vertices[i as usize];
+		assert_eq!(v.pos, positions[remap[i as usize] as usize]);
+		// left half: U along +X, right-handed; right half: U along -X, left-handed
+		let (tx, w) = if k < 6 { (1.0, 1.0) } else { (-1.0, -1.0) };
+		assert!((v.tg[0] - tx).abs() < 1e-5 && v.tg[1].abs() < 1e-5 && v.tg[2].abs() < 1e-5);
+		assert_eq!(v.tg[3], w);
+	}
+}
+
+#[test]
+fn test_generate_normals_cube()
+{
+	// cube with one vertex per corner (bit 0: x, bit 1: y, bit 2: z), counter-clockwise faces
+	let vertices = (0..8).map(|i| MeshVertex {
+		pos: [(i & 1) as f32 * 2.0 - 1.0, ((i >> 1) & 1) as f32 * 2.0 - 1.0, ((i >> 2) & 1) as f32 * 2.0 - 1.0],
+		norm: [0.0; 3],
+		tg: [0.0; 4],
+		tex: [i as f32, 0.0]
+	}).collect::<Vec<_>>();
+	let quads = [[5, 1, 3, 7], [0, 4, 6, 2], [2, 6, 7, 3], [0, 1, 5, 4], [4, 5, 7, 6], [0, 2, 3, 1]];
+	let indices = quads.iter().flat_map(|q| vec![q[0], q[1], q[2], q[0], q[2], q[3]].into_iter()).collect::<Vec<u32>>();
+
+	let check_remap = |new_vertices: &[MeshVertex], new_indices: &[u32], remap: &[u32]| {
+		assert_eq!(remap.len(), new_vertices.len());
+		assert_eq!(new_indices.len(), indices.len());
+		for (&new, &old) in new_indices.iter().zip(indices.iter()) {
+			let v = new_vertices[new as usize];
+			assert_eq!(remap[new as usize], old);
+			assert_eq!(v.pos, vertices[old as usize].pos);
+			assert_eq!(v.tex, vertices[old as usize].tex);
+		}
+	};
+
+	// faces meet at 90 degrees: flat normals below, one vertex per corner and face
+	let (mut flat_vertices, mut flat_indices) = (vertices.clone(), indices.clone());
+	let remap = generate_normals(&mut flat_vertices, &mut flat_indices, 80f32.to_radians());
+	check_remap(&flat_vertices, &flat_indices, &remap);
+	assert_eq!(flat_vertices.len(), 24);
+	for t in flat_indices.chunks(3) {
+		let p = [vec3(flat_vertices[t[0] as usize].pos), vec3(flat_vertices[t[1] as usize].pos), vec3(flat_vertices[t[2] as usize].pos)];
+		let n = (p[1] - p[0]).cross(&(p[2] - p[0])).normalize();
+		for &i in t.iter() {
+			assert_eq!(flat_vertices[i as usize].norm, [n.x, n.y, n.z]);
+		}
+	}
+
+	// shared normals above, along the diagonals
+	let (mut smooth_vertices, mut smooth_indices) = (vertices.clone(), indices.clone());
+	let remap = generate_normals(&mut smooth_vertices, &mut smooth_indices, 100f32.to_radians());
+	check_remap(&smooth_vertices, &smooth_indices, &remap);
+	assert_eq!(smooth_vertices.len(), 8);
+	for v in smooth_vertices.iter() {
+		for c in 0..3 {
+			assert!((v.norm[c] - v.pos[c] / 3f32.sqrt()).abs() < 1e-5);
+		}
+	}
+}
@@ -8,17 +8,25 @@ use asset_loader::*;
 use std::rc::Rc;
 use std;
 
-struct TerrainVertex
-{
-    // position AND texture coordinates
-    pos: Vec2<f32>
+vertex_format! {
+    struct TerrainVertex
+    {
+        // position AND texture coordinates
+        pos: Vec2<f32>
+    }
 }
 
+const TERRAIN_RESTART_INDEX: u32 = 0xFFFFFFFF;
+
 pub struct Terrain
 {
     heightmap_tex: Texture2D,
     heightmap_img: GrayImage,
     vertex_buffer: Buffer<TerrainVertex>,
+    // one triangle strip per row, separated by restart indices
+    index_buffer: Buffer<u32>,
+    num_vertices: u32,
+    num_indices: u32,
     scale: f32,
     height_scale: f32
 }
@@ -29,11 +37,12 @@ pub struct TerrainRenderer
     pipeline_state: PipelineState
 }
 
-#[derive(Copy, Clone)]
-struct TerrainShaderParams
-{
-    scale: f32,
-    height_scale: f32
+uniform_block! {
+    struct TerrainShaderParams
+    {
+        scale: f32,
+        height_scale: f32
+    }
 }
 
 impl Terrain
@@ -48,33 +57,32 @@ impl Terrain
     }
 
     pub fn new(context: &Context, heightmap: &Path, scale: f32, height_scale: f32) -> Terrain {
+        let _alloc = allocation_scope!("Terrain::new");
         // create a 2D grid of vertices
         let img = image::open(heightmap).unwrap();
         let (dimx, dimy) = img.dimensions();
         let gray_img = img.as_luma8().expect("Wrong heightmap format");
+        // one strip between each pair of rows
+        assert!(dimx >= 2 && dimy >= 2, "heightmap {} must be at least 2x2 pixels (is {}x{})", heightmap.display(), dimx, dimy);
 
-        let mut vertices = Vec::<TerrainVertex>::with_capacity((6*(dimx-1)*(dimy-1)) as usize);
-
-        for i in 0..(dimy-1) {
-            for j in 0..(dimx-1) {
+        let mut vertices = Vec::<TerrainVertex>::with_capacity((dimx*dimy) as usize);
+        for i in 0..dimy {
+            for j in 0..dimx {
                 vertices.push(TerrainVertex {
                     pos: Vec2::new(j as f32 / (dimx-1) as f32, i as f32 / (dimy-1) as f32)
                 });
-                vertices.push(TerrainVertex {
-                    pos: Vec2::new((j+1) as f32 / (dimx-1) as f32, i as f32 / (dimy-1) as f32)
-                });
-                vertices.push(TerrainVertex {
-                    pos: Vec2::new(j as f32 / (dimx-1) as f32, (i+1) as f32 / (dimy-1) as f32)
-                });
-                vertices.push(TerrainVertex {
-                    pos: Vec2::new(j as f32 / (dimx-1) as f32, (i+1) as f32 / (dimy-1) as f32)
-                });
-                vertices.push(TerrainVertex {
-                    pos: Vec2::new((j+1) as f32 / (dimx-1) as f32, i as f32 / (dimy-1) as f32)
-                });
-                vertices.push(TerrainVertex {
-                    pos: Vec2::new((j+1) as f32 / (dimx-1) as f32, (i+1) as f32 / (dimy-1) as f32)
-                });
+            }
+        }
+
+        // a strip for each row of quads: (i+1,j), (i,j), (i+1,j+1), (i,j+1), ...
+        let mut indices = Vec::<u32>::with_capacity((2*dimx*(dimy-1) + dimy-2) as usize);
+        for i in 0..(dimy-1) {
+            if i != 0 {
+                indices.push(TERRAIN_RESTART_INDEX);
+            }
+            for j in 0..dimx {
+                indices.push((i+1)*dimx + j);
+                indices.push(i*dimx + j);
             }
         }
 
@@ -83,6 +91,11 @@ impl Terrain
             BufferAccess::WriteOnly,
             BufferBindingHint::VertexBuffer,
             BufferUsage::Static);
+        let index_buf = context.alloc_buffer_from_data(
+            &indices[..],
+            BufferAccess::WriteOnly,
+            BufferBindingHint::IndexBuffer,
+            BufferUsage::Static);
 
         let heightmap_tex = Texture2D::with_pixels(dimx, dimy, 1, TextureFormat::Unorm8, Some(gray_img));
 
@@ -91,6 +104,9 @@ impl Terrain
             // TODO should not clone here
             heightmap_img: gray_img.clone(),
             vertex_buffer: buf,
+            index_buffer: index_buf,
+            num_vertices: vertices.len() as u32,
+            num_indices: indices.len() as u32,
             height_scale: height_scale,
             scale: scale
         }
@@ -109,7 +125,7 @@ impl TerrainRenderer
             uniform_block_base: 0
         };
         let shader = Shader::load(Path::new("assets/shaders/terrain.glsl"));
-        let pso = shader.make_pipeline_state(&pso_desc);
+        let pso = shader.make_pipeline_state::<TerrainVertex>(&pso_desc);
         TerrainRenderer {
 			shader: shader,
             pipeline_state: pso
@@ -120,24 +136,18 @@ impl TerrainRenderer
     {
         use num::traits::One;
         terrain.heightmap_tex.bind(0);
-		let terrain_params = frame.make_uniform_buffer(&TerrainShaderParams {
+		let terrain_params = frame.make_uniform_block(&TerrainShaderParams {
             scale: terrain.scale,
             height_scale: terrain.height_scale
         });
-		frame.draw(
-			terrain.vertex_buffer.raw.as_raw_buf_slice(),
-			None,
-            &self.pipeline_state,
-			MeshPart {
-                primitive_type: PrimitiveType::Triangle,
-                start_vertex: 0,
-                start_index: 0,
-                num_vertices: 6*terrain.heightmap_tex.dimensions().0*terrain.heightmap_tex.dimensions().1,
-                num_indices: 0
-            },
-			&[
-				Binding{slot:0, slice: scene_data.buffer},
-                Binding{slot:1, slice: terrain_params.as_raw()}],
-			&[]);
+		self.pipeline_state.layout.check_vertex_format::<TerrainVertex>();
+		frame.draw_call(&self.pipeline_state)
+			.vertex_buffer(terrain.vertex_buffer.raw.as_raw_buf_slice())
+			.index_buffer(terrain.index_buffer.raw.as_raw_buf_slice())
+			.part(MeshPart::indexed(PrimitiveType::TriangleStrip, IndexType::U32, 0, terrain.num_indices, 0, terrain.num_vertices)
+				.with_restart_index(TERRAIN_RESTART_INDEX))
+			.uniform_buffer(0, scene_data.buffer)
+			.uniform_buffer(1, terrain_params.as_raw())
+			.submit();
     }
 }
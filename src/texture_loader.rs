@@ -0,0 +1,443 @@
+use image::{self, GenericImage};
+use rendering::*;
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+//-------------------------------------------
+// Texture file loading:
+// DDS and KTX2 containers are uploaded as-is (compressed),
+// everything else goes through the image crate.
+
+#[derive(Debug)]
+pub enum TextureLoadError
+{
+	Io(io::Error),
+	Image(image::ImageError),
+	InvalidHeader(&'static str),
+	UnsupportedFormat(String),
+	TruncatedData
+}
+
+impl From<io::Error> for TextureLoadError
+{
+	fn from(err: io::Error) -> TextureLoadError
+	{
+		TextureLoadError::Io(err)
+	}
+}
+
+impl From<image::ImageError> for TextureLoadError
+{
+	fn from(err: image::ImageError) -> TextureLoadError
+	{
+		TextureLoadError::Image(err)
+	}
+}
+
+/// Texture data read from a container file, ready for upload
+pub struct CompressedImage
+{
+	pub width: u32,
+	pub height: u32,
+	pub format: TextureFormat,
+	/// Data of each mip level, starting from the base level
+	pub levels: Vec<Vec<u8>>
+}
+
+impl CompressedImage
+{
+	pub fn upload(&self) -> Texture2D
+	{
+		let levels = self.levels.iter().map(|l| &l[..]).collect::<Vec<_>>();
+		Texture2D::with_compressed_mips(self.width, self.height, self.format, &levels[..])
+	}
+}
+
+fn read_u32_le(bytes: &[u8], offset: usize) -> u32
+{
+	(bytes[offset] as u32) |
+	((bytes[offset+1] as u32) << 8) |
+	((bytes[offset+2] as u32) << 16) |
+	((bytes[offset+3] as u32) << 24)
+}
+
+fn read_u64_le(bytes: &[u8], offset: usize) -> u64
+{
+	(read_u32_le(bytes, offset) as u64) | ((read_u32_le(bytes, offset+4) as u64) << 32)
+}
+
+fn fourcc(s: &[u8; 4]) -> u32
+{
+	read_u32_le(s, 0)
+}
+
+fn check_mip_levels(width: u32, height: u32, num_levels: u32) -> Result<(), TextureLoadError>
+{
+	if num_levels > max_mip_levels(width, height) {
+		Err(TextureLoadError::InvalidHeader("too many mip levels for the texture size"))
+	} else {
+		Ok(())
+	}
+}
+
+/// Split the data following a header into mip levels
+fn split_mip_levels(
+	data: &[u8],
+	width: u32,
+	height: u32,
+	format: TextureFormat,
+	num_levels: u32) -> Result<Vec<Vec<u8>>, TextureLoadError>
+{
+	try!(check_mip_levels(width, height, num_levels));
+	let mut levels = Vec::with_capacity(num_levels as usize);
+	let mut offset = 0;
+	for level in 0..num_levels {
+		let w = cmp::max(1, width >> level);
+		let h = cmp::max(1, height >> level);
+		let size = format.level_byte_size(w, h);
+		if offset + size > data.len() {
+			return Err(TextureLoadError::TruncatedData);
+		}
+		levels.push(data[offset..offset+size].to_vec());
+		offset += size;
+	}
+	Ok(levels)
+}
+
+//-------------------------------------------
+// DDS
+const DDS_MAGIC: u32 = 0x20534444;	// "DDS "
+const DDS_HEADER_SIZE: u32 = 124;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDPF_FOURCC: u32 = 0x4;
+
+fn dxgi_format_to_texture_format(dxgi_format: u32) -> Option<TextureFormat>
+{
+	match dxgi_format {
+		71 => Some(TextureFormat::Bc1AlphaUnorm),	// DXGI_FORMAT_BC1_UNORM
+		72 => Some(TextureFormat::Bc1AlphaSrgb),	// DXGI_FORMAT_BC1_UNORM_SRGB
+		74 => Some(TextureFormat::Bc2Unorm),	// DXGI_FORMAT_BC2_UNORM
+		75 => Some(TextureFormat::Bc2Srgb),	// DXGI_FORMAT_BC2_UNORM_SRGB
+		77 => Some(TextureFormat::Bc3Unorm),	// DXGI_FORMAT_BC3_UNORM
+		78 => Some(TextureFormat::Bc3Srgb),	// DXGI_FORMAT_BC3_UNORM_SRGB
+		80 => Some(TextureFormat::Bc4Unorm),	// DXGI_FORMAT_BC4_UNORM
+		81 => Some(TextureFormat::Bc4Snorm),	// DXGI_FORMAT_BC4_SNORM
+		83 => Some(TextureFormat::Bc5Unorm),	// DXGI_FORMAT_BC5_UNORM
+		84 => Some(TextureFormat::Bc5Snorm),	// DXGI_FORMAT_BC5_SNORM
+		95 => Some(TextureFormat::Bc6hUfloat),	// DXGI_FORMAT_BC6H_UF16
+		96 => Some(TextureFormat::Bc6hSfloat),	// DXGI_FORMAT_BC6H_SF16
+		98 => Some(TextureFormat::Bc7Unorm),	// DXGI_FORMAT_BC7_UNORM
+		99 => Some(TextureFormat::Bc7Srgb),	// DXGI_FORMAT_BC7_UNORM_SRGB
+		_ => None
+	}
+}
+
+fn dds_fourcc_to_texture_format(code: u32) -> Option<TextureFormat>
+{
+	// DXT1 blocks may use the 1-bit alpha mode
+	if code == fourcc(b"DXT1") { Some(TextureFormat::Bc1AlphaUnorm) }
+	else if code == fourcc(b"DXT3") { Some(TextureFormat::Bc2Unorm) }
+	else if code == fourcc(b"DXT5") { Some(TextureFormat::Bc3Unorm) }
+	else if code == fourcc(b"ATI1") || code == fourcc(b"BC4U") { Some(TextureFormat::Bc4Unorm) }
+	else if code == fourcc(b"BC4S") { Some(TextureFormat::Bc4Snorm) }
+	else if code == fourcc(b"ATI2") || code == fourcc(b"BC5U") { Some(TextureFormat::Bc5Unorm) }
+	else if code == fourcc(b"BC5S") { Some(TextureFormat::Bc5Snorm) }
+	else { None }
+}
+
+/// Parse a DDS file (2D textures only, with optional DX10 header)
+pub fn parse_dds(bytes: &[u8]) -> Result<CompressedImage, TextureLoadError>
+{
+	if bytes.len() < 128 || read_u32_le(bytes, 0) != DDS_MAGIC {
+		return Err(TextureLoadError::InvalidHeader("not a DDS file"));
+	}
+	if read_u32_le(bytes, 4) != DDS_HEADER_SIZE {
+		return Err(TextureLoadError::InvalidHeader("invalid DDS header size"));
+	}
+
+	let flags = read_u32_le(bytes, 8);
+	let height = read_u32_le(bytes, 12);
+	let width = read_u32_le(bytes, 16);
+	let num_levels = if flags & DDSD_MIPMAPCOUNT != 0 {
+			cmp::max(1, read_u32_le(bytes, 28))
+		} else {
+			1
+		};
+
+	// pixel format
+	let pf_flags = read_u32_le(bytes, 80);
+	let pf_fourcc = read_u32_le(bytes, 84);
+	if pf_flags & DDPF_FOURCC == 0 {
+		return Err(TextureLoadError::UnsupportedFormat("uncompressed DDS".to_string()));
+	}
+
+	let (format, data_offset) = if pf_fourcc == fourcc(b"DX10") {
+		if bytes.len() < 148 {
+			return Err(TextureLoadError::InvalidHeader("truncated DX10 header"));
+		}
+		let dxgi_format = read_u32_le(bytes, 128);
+		let format = try!(dxgi_format_to_texture_format(dxgi_format).ok_or(
+			TextureLoadError::UnsupportedFormat(format!("DXGI format {}", dxgi_format))));
+		(format, 148)
+	} else {
+		let format = try!(dds_fourcc_to_texture_format(pf_fourcc).ok_or(
+			TextureLoadError::UnsupportedFormat(format!("FourCC {:08x}", pf_fourcc))));
+		(format, 128)
+	};
+
+	let levels = try!(split_mip_levels(&bytes[data_offset..], width, height, format, num_levels));
+
+	Ok(CompressedImage {
+		width: width,
+		height: height,
+		format: format,
+		levels: levels
+	})
+}
+
+//-------------------------------------------
+// KTX2
+const KTX2_IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const KTX2_HEADER_SIZE: usize = 80;
+
+fn vk_format_to_texture_format(vk_format: u32) -> Option<TextureFormat>
+{
+	match vk_format {
+		131 => Some(TextureFormat::Bc1Unorm),		// VK_FORMAT_BC1_RGB_UNORM_BLOCK
+		132 => Some(TextureFormat::Bc1Srgb),		// VK_FORMAT_BC1_RGB_SRGB_BLOCK
+		133 => Some(TextureFormat::Bc1AlphaUnorm),	// VK_FORMAT_BC1_RGBA_UNORM_BLOCK
+		134 => Some(TextureFormat::Bc1AlphaSrgb),	// VK_FORMAT_BC1_RGBA_SRGB_BLOCK
+		135 => Some(TextureFormat::Bc2Unorm),		// VK_FORMAT_BC2_UNORM_BLOCK
+		136 => Some(TextureFormat::Bc2Srgb),		// VK_FORMAT_BC2_SRGB_BLOCK
+		137 => Some(TextureFormat::Bc3Unorm),		// VK_FORMAT_BC3_UNORM_BLOCK
+		138 => Some(TextureFormat::Bc3Srgb),		// VK_FORMAT_BC3_SRGB_BLOCK
+		139 => Some(TextureFormat::Bc4Unorm),		// VK_FORMAT_BC4_UNORM_BLOCK
+		140 => Some(TextureFormat::Bc4Snorm),		// VK_FORMAT_BC4_SNORM_BLOCK
+		141 => Some(TextureFormat::Bc5Unorm),		// VK_FORMAT_BC5_UNORM_BLOCK
+		142 => Some(TextureFormat::Bc5Snorm),		// VK_FORMAT_BC5_SNORM_BLOCK
+		143 => Some(TextureFormat::Bc6hUfloat),		// VK_FORMAT_BC6H_UFLOAT_BLOCK
+		144 => Some(TextureFormat::Bc6hSfloat),		// VK_FORMAT_BC6H_SFLOAT_BLOCK
+		145 => Some(TextureFormat::Bc7Unorm),		// VK_FORMAT_BC7_UNORM_BLOCK
+		146 => Some(TextureFormat::Bc7Srgb),		// VK_FORMAT_BC7_SRGB_BLOCK
+		147 => Some(TextureFormat::Etc2Rgb8),		// VK_FORMAT_ETC2_R8G8B8_UNORM_BLOCK
+		148 => Some(TextureFormat::Etc2Srgb8),		// VK_FORMAT_ETC2_R8G8B8_SRGB_BLOCK
+		149 => Some(TextureFormat::Etc2Rgb8A1),		// VK_FORMAT_ETC2_R8G8B8A1_UNORM_BLOCK
+		150 => Some(TextureFormat::Etc2Srgb8A1),	// VK_FORMAT_ETC2_R8G8B8A1_SRGB_BLOCK
+		151 => Some(TextureFormat::Etc2Rgba8),		// VK_FORMAT_ETC2_R8G8B8A8_UNORM_BLOCK
+		152 => Some(TextureFormat::Etc2Srgb8Alpha8),	// VK_FORMAT_ETC2_R8G8B8A8_SRGB_BLOCK
+		_ => None
+	}
+}
+
+/// Parse a KTX2 file (2D textures only, no supercompression)
+pub fn parse_ktx2(bytes: &[u8]) -> Result<CompressedImage, TextureLoadError>
+{
+	if bytes.len() < KTX2_HEADER_SIZE || &bytes[0..12] != &KTX2_IDENTIFIER[..] {
+		return Err(TextureLoadError::InvalidHeader("not a KTX2 file"));
+	}
+
+	let vk_format = read_u32_le(bytes, 12);
+	let width = read_u32_le(bytes, 20);
+	let height = read_u32_le(bytes, 24);
+	let depth = read_u32_le(bytes, 28);
+	let layer_count = read_u32_le(bytes, 32);
+	let face_count = read_u32_le(bytes, 36);
+	let num_levels = cmp::max(1, read_u32_le(bytes, 40));
+	let supercompression = read_u32_le(bytes, 44);
+
+	if depth != 0 || layer_count > 1 || face_count != 1 {
+		return Err(TextureLoadError::UnsupportedFormat("only 2D KTX2 textures are supported".to_string()));
+	}
+	if supercompression != 0 {
+		return Err(TextureLoadError::UnsupportedFormat(format!("supercompression scheme {}", supercompression)));
+	}
+
+	let format = try!(vk_format_to_texture_format(vk_format).ok_or(
+		TextureLoadError::UnsupportedFormat(format!("VkFormat {}", vk_format))));
+
+	try!(check_mip_levels(width, height, num_levels));
+
+	// level index: (byteOffset, byteLength, uncompressedByteLength) for each level
+	let level_index_end = KTX2_HEADER_SIZE + 24 * num_levels as usize;
+	if bytes.len() < level_index_end {
+		return Err(TextureLoadError::InvalidHeader("truncated level index"));
+	}
+
+	let mut levels = Vec::with_capacity(num_levels as usize);
+	for level in 0..num_levels {
+		let entry = KTX2_HEADER_SIZE + 24 * level as usize;
+		let offset = read_u64_le(bytes, entry);
+		let length = read_u64_le(bytes, entry + 8);
+		let w = cmp::max(1, width >> level);
+		let h = cmp::max(1, height >> level);
+		if length != format.level_byte_size(w, h) as u64 {
+			return Err(TextureLoadError::InvalidHeader("unexpected mip level size"));
+		}
+		match offset.checked_add(length) {
+			Some(end) if end <= bytes.len() as u64 => (),
+			_ => return Err(TextureLoadError::TruncatedData)
+		}
+		let (offset, length) = (offset as usize, length as usize);
+		levels.push(bytes[offset..offset+length].to_vec());
+	}
+
+	Ok(CompressedImage {
+		width: width,
+		height: height,
+		format: format,
+		levels: levels
+	})
+}
+
+//-------------------------------------------
+fn read_file(path: &Path) -> Result<Vec<u8>, TextureLoadError>
+{
+	let mut f = try!(File::open(path));
+	let mut bytes = Vec::new();
+	try!(f.read_to_end(&mut bytes));
+	Ok(bytes)
+}
+
+/// Load a 2D texture from a file
+/// The container format is deduced from the file extension.
+/// color_space overrides the color space stored in the file, when the
+/// format has an sRGB counterpart.
+pub fn load_texture2d(path: &Path, color_space: ColorSpace) -> Result<Texture2D, TextureLoadError>
+{
+	let tex = try!(load_texture2d_unlabeled(path, color_space));
+	tex.set_label(&path.to_string_lossy());
+	Ok(tex)
+}
+
+fn load_texture2d_unlabeled(path: &Path, color_space: ColorSpace) -> Result<Texture2D, TextureLoadError>
+{
+	let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+	match ext.as_ref().map(|e| &e[..]) {
+		Some("dds") => {
+			let bytes = try!(read_file(path));
+			let mut img = try!(parse_dds(&bytes[..]));
+			img.format = img.format.with_color_space(color_space);
+			Ok(img.upload())
+		},
+		Some("ktx2") => {
+			let bytes = try!(read_file(path));
+			let mut img = try!(parse_ktx2(&bytes[..]));
+			img.format = img.format.with_color_space(color_space);
+			Ok(img.upload())
+		},
+		_ => {
+			let img = try!(image::open(path));
+			Ok(upload_image(&img, color_space))
+		}
+	}
+}
+
+/// Load a 2D texture from an encoded image in memory (PNG, JPEG, ...),
+/// for instance an image embedded in a model file.
+/// name is used as the label of the texture.
+pub fn load_texture2d_from_memory(bytes: &[u8], name: &str, color_space: ColorSpace) -> Result<Texture2D, TextureLoadError>
+{
+	let img = try!(image::load_from_memory(bytes));
+	let tex = upload_image(&img, color_space);
+	tex.set_label(name);
+	Ok(tex)
+}
+
+/// Upload an image with a full mip chain (samplers default to trilinear filtering)
+fn upload_image(img: &image::DynamicImage, color_space: ColorSpace) -> Texture2D
+{
+	let (w, h) = img.dimensions();
+	match *img {
+		image::DynamicImage::ImageLuma8(ref buf) =>
+			Texture2D::with_pixels_and_mipmaps(w, h, TextureFormat::Unorm8, &buf[..]),
+		image::DynamicImage::ImageRgba8(ref buf) =>
+			Texture2D::with_pixels_and_mipmaps(w, h,
+				TextureFormat::Unorm8x4.with_color_space(color_space), &buf[..]),
+		_ => {
+			let buf = img.to_rgb();
+			Texture2D::with_pixels_and_mipmaps(w, h,
+				TextureFormat::Unorm8x3.with_color_space(color_space), &buf[..])
+		}
+	}
+}
+
+#[cfg(test)]
+fn write_u32_le(bytes: &mut [u8], offset: usize, v: u32)
+{
+	bytes[offset] = v as u8;
+	bytes[offset+1] = (v >> 8) as u8;
+	bytes[offset+2] = (v >> 16) as u8;
+	bytes[offset+3] = (v >> 24) as u8;
+}
+
+#[cfg(test)]
+fn write_u64_le(bytes: &mut [u8], offset: usize, v: u64)
+{
+	write_u32_le(bytes, offset, v as u32);
+	write_u32_le(bytes, offset+4, (v >> 32) as u32);
+}
+
+#[test]
+fn test_parse_dds_dxt1()
+{
+	// 8x8 DXT1 texture with 2 mip levels: 4 blocks + 1 block
+	let mut bytes = vec![0u8; 128 + 5 * 8];
+	write_u32_le(&mut bytes, 0, DDS_MAGIC);
+	write_u32_le(&mut bytes, 4, DDS_HEADER_SIZE);
+	write_u32_le(&mut bytes, 8, DDSD_MIPMAPCOUNT);
+	write_u32_le(&mut bytes, 12, 8);
+	write_u32_le(&mut bytes, 16, 8);
+	write_u32_le(&mut bytes, 28, 2);
+	write_u32_le(&mut bytes, 80, DDPF_FOURCC);
+	write_u32_le(&mut bytes, 84, fourcc(b"DXT1"));
+
+	let img = parse_dds(&bytes[..]).unwrap();
+	assert_eq!((img.width, img.height), (8, 8));
+	assert_eq!(img.format, TextureFormat::Bc1AlphaUnorm);
+	assert_eq!(img.levels.len(), 2);
+	assert_eq!(img.levels[0].len(), 32);
+	assert_eq!(img.levels[1].len(), 8);
+
+	// an 8x8 texture has at most 4 levels
+	write_u32_le(&mut bytes, 28, 40);
+	assert!(parse_dds(&bytes[..]).is_err());
+}
+
+#[test]
+fn test_parse_ktx2_bc7()
+{
+	// 8x8 BC7 texture with 2 mip levels: 4 blocks + 1 block, stored smallest first
+	let data_offset = KTX2_HEADER_SIZE + 2 * 24;
+	let mut bytes = vec![0u8; data_offset + 5 * 16];
+	for (b, &k) in bytes.iter_mut().zip(KTX2_IDENTIFIER.iter()) {
+		*b = k;
+	}
+	write_u32_le(&mut bytes, 12, 145);
+	write_u32_le(&mut bytes, 20, 8);
+	write_u32_le(&mut bytes, 24, 8);
+	write_u32_le(&mut bytes, 36, 1);
+	write_u32_le(&mut bytes, 40, 2);
+	write_u64_le(&mut bytes, KTX2_HEADER_SIZE, (data_offset + 16) as u64);
+	write_u64_le(&mut bytes, KTX2_HEADER_SIZE + 8, 64);
+	write_u64_le(&mut bytes, KTX2_HEADER_SIZE + 24, data_offset as u64);
+	write_u64_le(&mut bytes, KTX2_HEADER_SIZE + 32, 16);
+	bytes[data_offset] = 1;
+	bytes[data_offset + 16] = 2;
+
+	let img = parse_ktx2(&bytes[..]).unwrap();
+	assert_eq!((img.width, img.height), (8, 8));
+	assert_eq!(img.format, TextureFormat::Bc7Unorm);
+	assert_eq!(img.levels.len(), 2);
+	assert_eq!((img.levels[0].len(), img.levels[0][0]), (64, 2));
+	assert_eq!((img.levels[1].len(), img.levels[1][0]), (16, 1));
+
+	// offset + length overflows
+	write_u64_le(&mut bytes, KTX2_HEADER_SIZE + 24, !0u64 - 8);
+	assert!(parse_ktx2(&bytes[..]).is_err());
+
+	// more levels than the size allows
+	write_u32_le(&mut bytes, 40, 33);
+	assert!(parse_ktx2(&bytes[..]).is_err());
+}
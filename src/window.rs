@@ -79,6 +79,14 @@ impl Window
 		(dim.0 as u32, dim.1 as u32)
 	}
 
+	/// Size of the default framebuffer, in pixels
+	/// (may differ from the window size on high-DPI displays)
+	pub fn framebuffer_dimensions(&self) -> (u32, u32)
+	{
+		let dim = self.win.get_framebuffer_size();
+		(dim.0 as u32, dim.1 as u32)
+	}
+
 	pub fn event_loop<F: FnMut(Event, &Window) -> bool>(
 		&mut self,
 		glfw: &mut glfw::Glfw,
@@ -113,6 +121,12 @@ impl Window
 					glfw::WindowEvent::Scroll(delta_x, delta_y) => {
 						event_handler(Event::MouseWheel(delta_y), self);
 					},
+					glfw::WindowEvent::Size(w, h) => {
+						event_handler(Event::WindowResize(w as u32, h as u32), self);
+					},
+					glfw::WindowEvent::FramebufferSize(w, h) => {
+						event_handler(Event::FramebufferResize(w as u32, h as u32), self);
+					},
 					_ => {}
 				}
 			}